@@ -1,8 +1,15 @@
 // MSR
+pub const MSR_IA32_APIC_BASE: u32 = 0x0000_001b;
+
 pub const MSR_IA32_SYSENTER_CS: u32 = 0x0000_0174;
 pub const MSR_IA32_SYSENTER_ESP: u32 = 0x0000_0175;
 pub const MSR_IA32_SYSENTER_EIP: u32 = 0x0000_0176;
 
+pub const MSR_IA32_MCG_CAP: u32 = 0x0000_0179;
+pub const MSR_IA32_MCG_STATUS: u32 = 0x0000_017a;
+
+pub const MSR_IA32_DEBUGCTL: u32 = 0x0000_01d9;
+
 pub const MSR_IA32_CR_PAT: u32 = 0x0000_0277;
 
 pub const MSR_IA32_VMX_BASIC: u32 = 0x0000_0480;
@@ -18,5 +25,16 @@ pub const MSR_IA32_VMX_CR4_FIXED1: u32 = 0x0000_0489;
 pub const MSR_IA32_VMX_VMCS_ENUM: u32 = 0x0000_048a;
 pub const MSR_IA32_VMX_PROCBASED_CTLS2: u32 = 0x0000_048b;
 pub const MSR_IA32_VMX_EPT_VPID_CAP: u32 = 0x0000_048c;
+pub const MSR_IA32_VMX_TRUE_PINBASED_CTLS: u32 = 0x0000_048d;
+pub const MSR_IA32_VMX_TRUE_PROCBASED_CTLS: u32 = 0x0000_048e;
+pub const MSR_IA32_VMX_TRUE_EXIT_CTLS: u32 = 0x0000_048f;
+pub const MSR_IA32_VMX_TRUE_ENTRY_CTLS: u32 = 0x0000_0490;
+pub const MSR_IA32_VMX_VMFUNC: u32 = 0x0000_0491;
+
+/// IA32_MCi_CTL of bank 0. Each bank has CTL, STATUS, ADDR and MISC in that order.
+pub const MSR_IA32_MC0_CTL: u32 = 0x0000_0400;
+
+/// x2APIC registers start here; the xAPIC MMIO offset shifted right by 4 gives the index.
+pub const MSR_X2APIC_BASE: u32 = 0x0000_0800;
 
 pub const MSR_EFER: u32 = 0xc000_0080;
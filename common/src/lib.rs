@@ -18,6 +18,7 @@ pub struct BootArgs {
     pub memory_size: u64,
     pub uefi_write_char: u64,
     pub uefi_output: u64,
+    pub acpi_rsdp: u64,
 }
 
 impl BootArgs {
@@ -29,6 +30,7 @@ impl BootArgs {
             memory_size: 0,
             uefi_write_char: 0,
             uefi_output: 0,
+            acpi_rsdp: 0,
         }
     }
 }
@@ -18,7 +18,10 @@ use uefi::{
         console::text::Output,
         media::file::{File, FileAttribute, FileInfo, FileMode},
     },
-    table::boot::{AllocateType, MemoryType},
+    table::{
+        boot::{AllocateType, MemoryType},
+        cfg::{ACPI2_GUID, ACPI_GUID},
+    },
     CStr16,
 };
 use uefi_services::{self, println};
@@ -40,6 +43,8 @@ fn efi_main(image_handle: Handle, mut systab: SystemTable<Boot>) -> Status {
     let uefi_write_char = Output::write_char as *const () as u64;
     let mut systab_clone = unsafe { systab.unsafe_clone() };
     let uefi_output = systab_clone.stdout() as *mut Output as u64;
+    let acpi_rsdp = get_acpi_rsdp(&systab);
+    println!("ACPI RSDP: 0x{acpi_rsdp:x}");
 
     let simple_fs = boot_services.get_image_file_system(image_handle);
     if simple_fs.is_err() {
@@ -116,6 +121,7 @@ fn efi_main(image_handle: Handle, mut systab: SystemTable<Boot>) -> Status {
         memory_size,
         uefi_write_char,
         uefi_output,
+        acpi_rsdp,
     };
 
     println!(
@@ -198,6 +204,16 @@ fn halt(error_msg: &str) -> ! {
     }
 }
 
+fn get_acpi_rsdp(systab: &SystemTable<Boot>) -> u64 {
+    let config_table = systab.config_table();
+    config_table
+        .iter()
+        .find(|entry| entry.guid == ACPI2_GUID)
+        .or_else(|| config_table.iter().find(|entry| entry.guid == ACPI_GUID))
+        .map(|entry| entry.address as u64)
+        .unwrap_or(0)
+}
+
 fn get_memory_size(bs: &BootServices) -> u64 {
     let mut size = 0;
     loop {
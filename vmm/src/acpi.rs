@@ -0,0 +1,257 @@
+use crate::BOOT_ARGS;
+use alloc::vec::Vec;
+use core::{mem::size_of, ptr};
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // ACPI 2.0+
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+pub fn rsdp() -> Option<u64> {
+    let rsdp = BOOT_ARGS.load().acpi_rsdp;
+    if rsdp == 0 {
+        None
+    } else {
+        Some(rsdp)
+    }
+}
+
+/// Looks up a system description table by signature, following the XSDT if the firmware
+/// provides one and the RSDT otherwise.
+///
+/// # Safety
+/// The RSDP handed over by the loader and the tables it points to must be identity mapped.
+pub unsafe fn find_table(signature: &[u8; 4]) -> Option<*const SdtHeader> {
+    let rsdp = ptr::read_unaligned(rsdp()? as *const Rsdp);
+    if &rsdp.signature != b"RSD PTR " {
+        return None;
+    }
+
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address as *const SdtHeader, 8)
+    } else {
+        (rsdp.rsdt_address as u64 as *const SdtHeader, 4)
+    };
+    let root_len = ptr::read_unaligned(root).length as usize;
+    let entries = (root as *const u8).add(size_of::<SdtHeader>());
+    let count = (root_len - size_of::<SdtHeader>()) / entry_size;
+
+    for i in 0..count {
+        let table = if entry_size == 8 {
+            ptr::read_unaligned(entries.add(i * 8) as *const u64)
+        } else {
+            ptr::read_unaligned(entries.add(i * 4) as *const u32) as u64
+        } as *const SdtHeader;
+        if table.is_null() {
+            continue;
+        }
+        if &ptr::read_unaligned(table).signature == signature && is_valid_checksum(table) {
+            return Some(table);
+        }
+    }
+
+    None
+}
+
+unsafe fn is_valid_checksum(table: *const SdtHeader) -> bool {
+    let len = ptr::read_unaligned(table).length as usize;
+    let bytes = core::slice::from_raw_parts(table as *const u8, len);
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MadtEntry {
+    LocalApic {
+        processor_id: u8,
+        apic_id: u8,
+        flags: u32,
+    },
+    IoApic {
+        id: u8,
+        address: u32,
+        gsi_base: u32,
+    },
+    InterruptSourceOverride {
+        bus: u8,
+        source: u8,
+        gsi: u32,
+        flags: u16,
+    },
+    LocalApicAddressOverride {
+        address: u64,
+    },
+    LocalX2Apic {
+        x2apic_id: u32,
+        flags: u32,
+        processor_uid: u32,
+    },
+    Other {
+        entry_type: u8,
+    },
+}
+
+/// Physical address of the local APIC as reported by the MADT.
+#[allow(unused)]
+pub fn local_apic_address() -> Option<u64> {
+    let madt = unsafe { find_table(b"APIC")? };
+    let address = unsafe { ptr::read_unaligned((madt as *const u8).add(36) as *const u32) };
+    let address = madt_entries()
+        .into_iter()
+        .find_map(|entry| match entry {
+            MadtEntry::LocalApicAddressOverride { address } => Some(address),
+            _ => None,
+        })
+        .unwrap_or(address as u64);
+    Some(address)
+}
+
+pub fn madt_entries() -> Vec<MadtEntry> {
+    let mut entries = Vec::new();
+    let madt = match unsafe { find_table(b"APIC") } {
+        Some(madt) => madt as *const u8,
+        None => return entries,
+    };
+    let len = unsafe { ptr::read_unaligned(madt as *const SdtHeader).length } as usize;
+
+    // The header is followed by the local APIC address and the flags.
+    let mut offset = size_of::<SdtHeader>() + 8;
+    while offset + 2 <= len {
+        let entry = unsafe { madt.add(offset) };
+        let (entry_type, entry_len) = unsafe { (*entry, *entry.add(1) as usize) };
+        if entry_len < 2 || len < offset + entry_len {
+            break;
+        }
+        let read_u16 = |off: usize| unsafe { ptr::read_unaligned(entry.add(off) as *const u16) };
+        let read_u32 = |off: usize| unsafe { ptr::read_unaligned(entry.add(off) as *const u32) };
+        let read_u64 = |off: usize| unsafe { ptr::read_unaligned(entry.add(off) as *const u64) };
+        let read_u8 = |off: usize| unsafe { *entry.add(off) };
+
+        entries.push(match entry_type {
+            0 => MadtEntry::LocalApic {
+                processor_id: read_u8(2),
+                apic_id: read_u8(3),
+                flags: read_u32(4),
+            },
+            1 => MadtEntry::IoApic {
+                id: read_u8(2),
+                address: read_u32(4),
+                gsi_base: read_u32(8),
+            },
+            2 => MadtEntry::InterruptSourceOverride {
+                bus: read_u8(2),
+                source: read_u8(3),
+                gsi: read_u32(4),
+                flags: read_u16(8),
+            },
+            5 => MadtEntry::LocalApicAddressOverride {
+                address: read_u64(4),
+            },
+            9 => MadtEntry::LocalX2Apic {
+                x2apic_id: read_u32(4),
+                flags: read_u32(8),
+                processor_uid: read_u32(12),
+            },
+            entry_type => MadtEntry::Other { entry_type },
+        });
+
+        offset += entry_len;
+    }
+
+    entries
+}
+
+/// ACPI Generic Address Structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub space_id: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+pub const ADDRESS_SPACE_IO: u8 = 1;
+
+impl GenericAddress {
+    unsafe fn read(ptr: *const u8) -> Self {
+        Self {
+            space_id: *ptr,
+            bit_width: *ptr.add(1),
+            bit_offset: *ptr.add(2),
+            access_size: *ptr.add(3),
+            address: ptr::read_unaligned(ptr.add(4) as *const u64),
+        }
+    }
+}
+
+/// The FADT fields the VMM needs to follow the guest's reset and sleep requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    pub pm1a_control: Option<u16>,
+    pub pm1b_control: Option<u16>,
+    /// Reset register and the value to write, if the platform advertises one.
+    pub reset: Option<(GenericAddress, u8)>,
+}
+
+const FADT_PM1A_CNT_BLK: usize = 64;
+const FADT_PM1B_CNT_BLK: usize = 68;
+const FADT_FLAGS: usize = 112;
+const FADT_RESET_REG: usize = 116;
+const FADT_RESET_VALUE: usize = 128;
+const FADT_X_PM1A_CNT_BLK: usize = 172;
+const FADT_X_PM1B_CNT_BLK: usize = 184;
+const FADT_FLAGS_RESET_REG_SUP: u32 = 1 << 10;
+
+pub fn fadt() -> Option<Fadt> {
+    let fadt = unsafe { find_table(b"FACP")? } as *const u8;
+    let len = unsafe { ptr::read_unaligned(fadt as *const SdtHeader).length } as usize;
+    let read_u32 = |off: usize| unsafe { ptr::read_unaligned(fadt.add(off) as *const u32) };
+    // The 32-bit block addresses win; the extended ones only count if they are I/O ports.
+    let port = |legacy: usize, extended: usize| {
+        let legacy = read_u32(legacy);
+        if legacy != 0 {
+            return u16::try_from(legacy).ok();
+        }
+        if len < extended + 12 {
+            return None;
+        }
+        let gas = unsafe { GenericAddress::read(fadt.add(extended)) };
+        (gas.space_id == ADDRESS_SPACE_IO && gas.address != 0).then_some(gas.address as u16)
+    };
+    let reset = (len > FADT_RESET_VALUE && read_u32(FADT_FLAGS) & FADT_FLAGS_RESET_REG_SUP != 0)
+        .then(|| unsafe {
+            (
+                GenericAddress::read(fadt.add(FADT_RESET_REG)),
+                *fadt.add(FADT_RESET_VALUE),
+            )
+        });
+    Some(Fadt {
+        pm1a_control: port(FADT_PM1A_CNT_BLK, FADT_X_PM1A_CNT_BLK),
+        pm1b_control: port(FADT_PM1B_CNT_BLK, FADT_X_PM1B_CNT_BLK),
+        reset,
+    })
+}
@@ -1,8 +1,42 @@
+use crate::BOOT_ARGS;
+use alloc::alloc::{alloc_zeroed, dealloc};
+use core::alloc::Layout;
 use linked_list_allocator::LockedHeap;
+use x86_64::PhysAddr;
 
-#[global_allocator]
+const PAGE_SIZE: usize = 4096;
+
+#[cfg_attr(not(test), global_allocator)]
 static ALLOCATOR: LockedHeap = LockedHeap::empty();
 
 pub unsafe fn init(heap: usize, len: usize) {
     ALLOCATOR.lock().init(heap as *mut u8, len);
 }
+
+fn page_layout(count: usize) -> Layout {
+    Layout::from_size_align(count * PAGE_SIZE, PAGE_SIZE).unwrap()
+}
+
+/// Allocates `count` zeroed, contiguous 4 KiB pages, for structures the processor finds by
+/// physical address. Returns `None` if the heap is exhausted.
+pub fn alloc_pages(count: usize) -> Option<*mut u8> {
+    let pages = unsafe { alloc_zeroed(page_layout(count)) };
+    (!pages.is_null()).then_some(pages)
+}
+
+/// # Safety
+/// `pages` must come from `alloc_pages(count)` and no longer be in use.
+pub unsafe fn free_pages(pages: *mut u8, count: usize) {
+    dealloc(pages, page_layout(count));
+}
+
+/// Physical address of VMM memory.
+pub fn virt_to_phys(virt: *mut u8) -> PhysAddr {
+    let virt = i64::try_from(virt as u64).unwrap();
+    PhysAddr::new(u64::try_from(virt + BOOT_ARGS.load().vmm_phys_offset).unwrap())
+}
+
+pub fn phys_to_virt(phys: PhysAddr) -> *mut u8 {
+    let phys = i64::try_from(phys.as_u64()).unwrap();
+    u64::try_from(phys - BOOT_ARGS.load().vmm_phys_offset).unwrap() as *mut u8
+}
@@ -0,0 +1,280 @@
+use crate::{
+    allocator,
+    arch::intel::{
+        msr::{self, MsrBitmap, MsrFault},
+        vmcs::{VmcsField, VmcsRegion},
+        vmx::VmExitGeneralPurposeRegister,
+    },
+    emu::{emulate_mmio, MmioAccess},
+    lapic, serial_println,
+};
+use core::ptr;
+use crossbeam::atomic::AtomicCell;
+
+/// Trap guest accesses to the xAPIC MMIO page through an APIC-access page. Every access
+/// except TPR ones (with `TPR_SHADOW`) then exits and is emulated.
+pub const XAPIC_TRAP: bool = cfg!(feature = "xapic-trap");
+/// Keep the guest TPR in a virtual-APIC page. The real TPR stays 0 and the VMM holds back
+/// interrupts the guest TPR masks.
+pub const TPR_SHADOW: bool = cfg!(feature = "tpr-shadow");
+
+/// x2APIC registers whose writes are intercepted. Reads and all other registers pass through,
+/// so that frequent accesses such as EOI do not exit.
+const MONITORED_REGISTERS: [u32; 10] = [
+    lapic::REG_ICR_LOW,
+    lapic::REG_LVT_CMCI,
+    lapic::REG_LVT_TIMER,
+    lapic::REG_LVT_THERMAL,
+    lapic::REG_LVT_PERF,
+    lapic::REG_LVT_LINT0,
+    lapic::REG_LVT_LINT1,
+    lapic::REG_LVT_ERROR,
+    lapic::REG_TIMER_INITIAL_COUNT,
+    lapic::REG_TIMER_DIVIDE,
+];
+
+const APIC_ACCESS_TYPE_SHIFT: u64 = 12;
+const APIC_ACCESS_LINEAR_READ: u64 = 0;
+const APIC_ACCESS_LINEAR_WRITE: u64 = 1;
+
+const ICR_DELIVERY_MODE_SHIFT: u32 = 8;
+const ICR_DELIVERY_MODE_INIT: u32 = 0b101;
+const ICR_DELIVERY_MODE_STARTUP: u32 = 0b110;
+const LVT_MASKED: u32 = 1 << 16;
+
+/// What the guest has programmed into the monitored registers.
+#[derive(Debug, Clone, Copy)]
+pub struct ApicMonitor {
+    /// ICR[63:32] of an xAPIC, held back until the guest writes ICR[31:0].
+    icr_high: u32,
+    pub lvt: [u32; 7],
+    pub timer_initial_count: u32,
+    pub timer_divide: u32,
+    pub init_ipis: u64,
+    pub startup_ipis: u64,
+}
+
+static MONITOR: AtomicCell<ApicMonitor> = AtomicCell::new(ApicMonitor {
+    icr_high: 0,
+    lvt: [LVT_MASKED; 7],
+    timer_initial_count: 0,
+    timer_divide: 0,
+    init_ipis: 0,
+    startup_ipis: 0,
+});
+
+fn monitor() -> &'static mut ApicMonitor {
+    unsafe { MONITOR.as_ptr().as_mut().unwrap() }
+}
+
+/// Virtual address of the virtual-APIC page, 0 without TPR shadowing.
+static VIRTUAL_APIC_PAGE: AtomicCell<u64> = AtomicCell::new(0);
+
+/// Writes the APIC-access and virtual-APIC addresses. The matching execution controls are
+/// set from `XAPIC_TRAP` and `TPR_SHADOW`.
+pub fn setup(vmcs: &mut VmcsRegion) {
+    if XAPIC_TRAP {
+        vmcs.write64(VmcsField::ApicAccessAddr, lapic::mmio_base());
+    }
+    if TPR_SHADOW {
+        let page = allocator::alloc_pages(1).unwrap();
+        VIRTUAL_APIC_PAGE.store(page as u64);
+        // The firmware's TPR moves to the virtual-APIC page.
+        set_tpr(lapic::read(lapic::REG_TPR) as u8);
+        lapic::write(lapic::REG_TPR, 0);
+        vmcs.write64(
+            VmcsField::VirtualApicPageAddr,
+            allocator::virt_to_phys(page).as_u64(),
+        );
+    }
+    vmcs.write32(VmcsField::TprThreshold, 0);
+}
+
+pub fn intercept_msrs(bitmap: &mut MsrBitmap) {
+    for reg in MONITORED_REGISTERS {
+        bitmap.intercept_write(lapic::x2apic_msr_index(reg));
+    }
+    if TPR_SHADOW {
+        bitmap.intercept_read(lapic::x2apic_msr_index(lapic::REG_TPR));
+        bitmap.intercept_write(lapic::x2apic_msr_index(lapic::REG_TPR));
+    }
+}
+
+/// The guest's TPR. Without TPR shadowing it is the real one.
+pub fn tpr() -> u8 {
+    if TPR_SHADOW {
+        let page = VIRTUAL_APIC_PAGE.load();
+        unsafe { ptr::read_volatile((page + lapic::REG_TPR as u64) as *const u32) as u8 }
+    } else {
+        lapic::read(lapic::REG_TPR) as u8
+    }
+}
+
+pub fn set_tpr(value: u8) {
+    if TPR_SHADOW {
+        let page = VIRTUAL_APIC_PAGE.load();
+        unsafe { ptr::write_volatile((page + lapic::REG_TPR as u64) as *mut u32, value as u32) };
+    } else {
+        lapic::write(lapic::REG_TPR, value as u32);
+    }
+}
+
+/// Whether the guest TPR holds back `vector`. The real APIC already did that check
+/// unless the TPR is shadowed.
+pub fn blocked_by_tpr(vector: u8) -> bool {
+    TPR_SHADOW && vector >> 4 <= tpr() >> 4
+}
+
+/// Arms a TPR-below-threshold exit for when the guest lowers its TPR enough to take
+/// `vector`, or disarms it.
+pub fn set_tpr_threshold(vmcs: &mut VmcsRegion, vector: Option<u8>) {
+    if TPR_SHADOW {
+        let threshold = vector.map_or(0, |vector| (vector >> 4) as u32);
+        vmcs.write32(VmcsField::TprThreshold, threshold);
+    }
+}
+
+fn x2apic_register(msr: u32) -> Option<u32> {
+    let index = msr.checked_sub(lapic::x2apic_msr_index(0))?;
+    (index < 0x100).then_some(index << 4)
+}
+
+/// Returns the value of an intercepted x2APIC MSR, or `None` if `msr` is not one.
+pub fn read_msr(msr: u32) -> Option<u64> {
+    match x2apic_register(msr)? {
+        lapic::REG_TPR if TPR_SHADOW => Some(tpr() as u64),
+        _ => None,
+    }
+}
+
+/// Handles a write to an intercepted x2APIC MSR. Returns `None` if `msr` is not one.
+pub fn write_msr(msr: u32, value: u64) -> Option<Result<(), MsrFault>> {
+    let reg = x2apic_register(msr)?;
+    Some(match reg {
+        lapic::REG_TPR if TPR_SHADOW => {
+            set_tpr(value as u8);
+            Ok(())
+        }
+        lapic::REG_ICR_LOW => {
+            observe_ipi((value >> 32) as u32, value as u32);
+            msr::write(msr, value)
+        }
+        _ => {
+            observe_write(reg, value as u32);
+            msr::write(msr, value)
+        }
+    })
+}
+
+/// Emulates a guest read or write of the xAPIC page (exit qualification: SDM Vol. 3C,
+/// Table 28-6) and moves RIP past it. Fetches, accesses during event delivery and
+/// instructions that cannot be emulated stop the VMM, since resuming would exit again.
+pub fn apic_access(qual: u64, gpr: &mut VmExitGeneralPurposeRegister, vmcs: &mut VmcsRegion) {
+    let reg = (qual & 0xfff) as u32;
+    let access_type = (qual >> APIC_ACCESS_TYPE_SHIFT) & 0xf;
+    if access_type != APIC_ACCESS_LINEAR_READ && access_type != APIC_ACCESS_LINEAR_WRITE {
+        serial_println!("APIC access of type {access_type} at offset 0x{reg:x} is not supported");
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+    let emulated = emulate_mmio(vmcs, gpr, |access| match access {
+        MmioAccess::Read { .. } => xapic_read(reg) as u64,
+        MmioAccess::Write { value, .. } => {
+            xapic_write(reg, value as u32);
+            0
+        }
+    });
+    if let Err(e) = emulated {
+        serial_println!("APIC access emulation failed at offset 0x{reg:x}: {e:?}");
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+}
+
+fn xapic_read(reg: u32) -> u32 {
+    match reg {
+        lapic::REG_ICR_HIGH => monitor().icr_high,
+        lapic::REG_TPR if TPR_SHADOW => tpr() as u32,
+        _ => lapic::read(reg),
+    }
+}
+
+/// ICR[63:32] is only written to the APIC together with ICR[31:0], so an IPI the VMM sends
+/// between the two guest writes cannot redirect the guest's IPI.
+fn xapic_write(reg: u32, value: u32) {
+    match reg {
+        lapic::REG_ICR_HIGH => monitor().icr_high = value,
+        lapic::REG_ICR_LOW => {
+            let high = monitor().icr_high;
+            observe_ipi(high >> 24, value);
+            lapic::write(lapic::REG_ICR_HIGH, high);
+            lapic::write(lapic::REG_ICR_LOW, value);
+        }
+        lapic::REG_TPR if TPR_SHADOW => set_tpr(value as u8),
+        _ => {
+            observe_write(reg, value);
+            lapic::write(reg, value);
+        }
+    }
+}
+
+fn observe_ipi(destination: u32, icr_low: u32) {
+    let monitor = monitor();
+    match (icr_low >> ICR_DELIVERY_MODE_SHIFT) & 0b111 {
+        ICR_DELIVERY_MODE_INIT => {
+            monitor.init_ipis += 1;
+            serial_println!("guest INIT IPI to APIC {destination}");
+        }
+        ICR_DELIVERY_MODE_STARTUP => {
+            monitor.startup_ipis += 1;
+            serial_println!(
+                "guest SIPI to APIC {destination}, start 0x{:x}",
+                (icr_low & 0xff) << 12
+            );
+        }
+        _ => {}
+    }
+}
+
+fn observe_write(reg: u32, value: u32) {
+    let monitor = monitor();
+    match reg {
+        lapic::REG_LVT_CMCI..=lapic::REG_LVT_ERROR if reg != lapic::REG_ICR_LOW => {
+            let index = match reg {
+                lapic::REG_LVT_CMCI => 0,
+                _ => ((reg - lapic::REG_LVT_TIMER) >> 4) as usize + 1,
+            };
+            if index < monitor.lvt.len() && monitor.lvt[index] != value {
+                monitor.lvt[index] = value;
+                serial_println!("guest LVT 0x{reg:x} = 0x{value:x}");
+            }
+        }
+        lapic::REG_TIMER_INITIAL_COUNT => monitor.timer_initial_count = value,
+        lapic::REG_TIMER_DIVIDE => monitor.timer_divide = value,
+        _ => {}
+    }
+}
+
+#[allow(unused)]
+pub fn print() {
+    let monitor = monitor();
+    serial_println!(
+        "LAPIC: {} mode, TPR 0x{:x}, INIT {} SIPI {}",
+        if lapic::is_x2apic() {
+            "x2APIC"
+        } else {
+            "xAPIC"
+        },
+        tpr(),
+        monitor.init_ipis,
+        monitor.startup_ipis
+    );
+    serial_println!(
+        "timer: LVT 0x{:x}, initial count {}, divide 0x{:x}",
+        monitor.lvt[1],
+        monitor.timer_initial_count,
+        monitor.timer_divide
+    );
+}
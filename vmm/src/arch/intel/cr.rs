@@ -0,0 +1,331 @@
+use crate::{
+    arch::intel::{
+        apic, sipi, tlb,
+        vmcs::{FieldNatural, VmcsField, VmcsRegion},
+        vmx_caps::VmxCaps,
+    },
+    serial_println,
+};
+use core::arch::asm;
+use crossbeam::atomic::AtomicCell;
+use x86_64::registers::control::{Cr0Flags, Cr4Flags};
+
+const CR0_PE: u64 = 1 << 0;
+const CR0_TS: u64 = 1 << 3;
+const CR0_PG: u64 = 1 << 31;
+const EFER_LME: u64 = 1 << 8;
+const EFER_LMA: u64 = 1 << 10;
+/// Changing one of these flushes the TLB, including global translations.
+const CR0_FLUSH_BITS: u64 = CR0_PG;
+const CR4_FLUSH_BITS: u64 = Cr4Flags::PAGE_SIZE_EXTENSION.bits()
+    | Cr4Flags::PHYSICAL_ADDRESS_EXTENSION.bits()
+    | Cr4Flags::PAGE_GLOBAL.bits()
+    | Cr4Flags::PCID.bits()
+    | Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION.bits()
+    | Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION.bits()
+    | Cr4Flags::PROTECTION_KEY_USER.bits();
+/// CR0 bits LMSW can load.
+const CR0_LMSW_BITS: u64 = 0b1111;
+
+/// Once the guest sets one of these bits it can no longer clear it.
+pub const PINNING: bool = cfg!(feature = "cr-pinning");
+/// Bits that can be pinned. Which of them are is set by `setup` and `add_pinnable`.
+pub const PINNABLE_CR0_BITS: u64 = Cr0Flags::WRITE_PROTECT.bits();
+pub const PINNABLE_CR4_BITS: u64 = Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION.bits()
+    | Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION.bits()
+    | Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION.bits();
+
+/// CR0 and CR4 bits the guest cannot clear once it has set them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pinnable {
+    pub cr0: u64,
+    pub cr4: u64,
+}
+
+impl Pinnable {
+    pub const DEFAULT: Self = if PINNING {
+        Self {
+            cr0: PINNABLE_CR0_BITS,
+            cr4: PINNABLE_CR4_BITS,
+        }
+    } else {
+        Self { cr0: 0, cr4: 0 }
+    };
+
+    fn is_valid(&self) -> bool {
+        self.cr0 & !PINNABLE_CR0_BITS == 0 && self.cr4 & !PINNABLE_CR4_BITS == 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinViolationAction {
+    /// Fail the write with #GP, as if the bits were reserved.
+    InjectGp,
+    /// Complete the write with the pinned bits left set.
+    Ignore,
+    Halt,
+}
+
+pub const PIN_VIOLATION_ACTION: PinViolationAction = if cfg!(feature = "cr-pinning-halt") {
+    PinViolationAction::Halt
+} else if cfg!(feature = "cr-pinning-ignore") {
+    PinViolationAction::Ignore
+} else {
+    PinViolationAction::InjectGp
+};
+
+static PINNABLE: AtomicCell<Pinnable> = AtomicCell::new(Pinnable::DEFAULT);
+static PINNED_CR0: AtomicCell<u64> = AtomicCell::new(0);
+static PINNED_CR4: AtomicCell<u64> = AtomicCell::new(0);
+/// Set by the first `add_pinnable`, which later calls cannot change.
+static PINNABLE_LOCKED: AtomicCell<bool> = AtomicCell::new(false);
+/// Host-owned bits other than the pinnable ones.
+static FORCED_CR0: AtomicCell<u64> = AtomicCell::new(0);
+static FORCED_CR4: AtomicCell<u64> = AtomicCell::new(0);
+
+/// A guest write that tried to clear pinned bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PinViolation {
+    pub cr_number: u8,
+    pub value: u64,
+    pub cleared: u64,
+}
+
+extern "C" {
+    static uefi_cr0: u64;
+    static uefi_cr4: u64;
+}
+
+/// Gives the host ownership of the CR0/CR4 bits that VMX operation pins (the fixed bits and
+/// CR4.VMXE) and shows the guest the values the firmware had before the VMM took over.
+/// Guest writes to owned bits then cause a VM exit instead of breaking VMX operation.
+/// Pinnable bits are owned too so that clearing them can be caught. An unrestricted guest
+/// owns CR0.PE and CR0.PG.
+pub fn setup(vmcs: &mut VmcsRegion, caps: &VmxCaps, pinnable: Pinnable) {
+    let cr0_fixed0 = if sipi::UNRESTRICTED_GUEST {
+        caps.cr0_fixed.fixed0 & !(CR0_PE | CR0_PG)
+    } else {
+        caps.cr0_fixed.fixed0
+    };
+    FORCED_CR0.store(cr0_fixed0 | !caps.cr0_fixed.fixed1);
+    FORCED_CR4.store(
+        caps.cr4_fixed.fixed0
+            | !caps.cr4_fixed.fixed1
+            | Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS.bits(),
+    );
+    vmcs.write_natural(VmcsField::Cr0ReadShadow, unsafe { uefi_cr0 });
+    vmcs.write_natural(VmcsField::Cr4ReadShadow, unsafe { uefi_cr4 });
+    PINNABLE.store(pinnable);
+    write_masks(vmcs, pinnable);
+}
+
+fn write_masks(vmcs: &mut VmcsRegion, pinnable: Pinnable) {
+    vmcs.write_natural(
+        VmcsField::Cr0GuestHostMask,
+        FORCED_CR0.load() | pinnable.cr0,
+    );
+    vmcs.write_natural(
+        VmcsField::Cr4GuestHostMask,
+        FORCED_CR4.load() | pinnable.cr4,
+    );
+}
+
+/// Makes more bits pinnable. Bits the guest already has set are pinned right away. Nothing
+/// is ever released and only the first call is accepted, so a compromised kernel cannot
+/// undo the pinning. Returns false if the call is refused or `pinnable` contains bits that
+/// cannot be pinned.
+pub fn add_pinnable(vmcs: &mut VmcsRegion, pinnable: Pinnable) -> bool {
+    if !pinnable.is_valid() || PINNABLE_LOCKED.swap(true) {
+        return false;
+    }
+    let cr0 = guest_cr0(vmcs);
+    let cr4 = guest_cr4(vmcs);
+    let pinnable = Pinnable {
+        cr0: PINNABLE.load().cr0 | pinnable.cr0,
+        cr4: PINNABLE.load().cr4 | pinnable.cr4,
+    };
+    PINNABLE.store(pinnable);
+    write_masks(vmcs, pinnable);
+    PINNED_CR0.fetch_or(cr0 & pinnable.cr0);
+    PINNED_CR4.fetch_or(cr4 & pinnable.cr4);
+    true
+}
+
+/// Value of a control register as the guest sees it: host-owned bits come from the read
+/// shadow, the rest from the real register.
+fn guest_view(
+    vmcs: &VmcsRegion,
+    real: FieldNatural,
+    mask: FieldNatural,
+    shadow: FieldNatural,
+) -> u64 {
+    let mask = vmcs.read_natural(mask);
+    (vmcs.read_natural(shadow) & mask) | (vmcs.read_natural(real) & !mask)
+}
+
+/// Stores `value` as the guest-visible register. Host-owned bits keep their real values,
+/// except for pinnable bits, which are only owned to observe them.
+fn set_guest_view(
+    vmcs: &mut VmcsRegion,
+    real: FieldNatural,
+    mask: FieldNatural,
+    shadow: FieldNatural,
+    pinnable: u64,
+    value: u64,
+) {
+    let forced = vmcs.read_natural(mask) & !pinnable;
+    let real_value = (vmcs.read_natural(real) & forced) | (value & !forced);
+    vmcs.write_natural(real, real_value);
+    vmcs.write_natural(shadow, value);
+}
+
+pub fn guest_cr0(vmcs: &VmcsRegion) -> u64 {
+    guest_view(
+        vmcs,
+        VmcsField::GuestCr0,
+        VmcsField::Cr0GuestHostMask,
+        VmcsField::Cr0ReadShadow,
+    )
+}
+
+/// Checks `value` against the pinned bits, then records the pinnable bits it sets.
+fn check_pinned(
+    pinned: &AtomicCell<u64>,
+    pinnable: u64,
+    cr_number: u8,
+    value: u64,
+) -> Result<(), PinViolation> {
+    let cleared = pinned.load() & !value;
+    if cleared != 0 {
+        return Err(PinViolation {
+            cr_number,
+            value,
+            cleared,
+        });
+    }
+    pinned.fetch_or(value & pinnable);
+    Ok(())
+}
+
+pub fn set_guest_cr0(vmcs: &mut VmcsRegion, value: u64) -> Result<(), PinViolation> {
+    check_pinned(&PINNED_CR0, PINNABLE.load().cr0, 0, value)?;
+    let old = guest_cr0(vmcs);
+    write_guest_cr0(vmcs, value);
+    if (old ^ value) & CR0_PG != 0 {
+        update_long_mode(vmcs, value & CR0_PG != 0);
+    }
+    if (old ^ value) & CR0_FLUSH_BITS != 0 {
+        tlb::flush_guest(vmcs);
+    }
+    Ok(())
+}
+
+/// With EFER.LME set, turning paging on or off enters or leaves IA-32e mode, which the
+/// processor only does by itself for writes that do not exit.
+fn update_long_mode(vmcs: &mut VmcsRegion, paging: bool) {
+    // The field only exists if the processor can load and save EFER.
+    let efer = match vmcs.try_read(VmcsField::GuestIa32Efer) {
+        Ok(efer) => efer,
+        Err(e) => {
+            serial_println!("cannot follow the guest's IA-32e mode switch: {e}");
+            return;
+        }
+    };
+    if efer & EFER_LME == 0 {
+        return;
+    }
+    let efer = if paging {
+        efer | EFER_LMA
+    } else {
+        efer & !EFER_LMA
+    };
+    vmcs.write64(VmcsField::GuestIa32Efer, efer);
+    vmcs.set_ia32e_mode_guest(paging);
+}
+
+fn write_guest_cr0(vmcs: &mut VmcsRegion, value: u64) {
+    set_guest_view(
+        vmcs,
+        VmcsField::GuestCr0,
+        VmcsField::Cr0GuestHostMask,
+        VmcsField::Cr0ReadShadow,
+        PINNABLE.load().cr0,
+        value,
+    );
+}
+
+/// Loads the CR0 and CR4 of a processor coming out of INIT, which also drops the pinned bits.
+pub fn reset(vmcs: &mut VmcsRegion, cr0: u64) {
+    PINNED_CR0.store(0);
+    PINNED_CR4.store(0);
+    write_guest_cr0(vmcs, cr0);
+    set_guest_view(
+        vmcs,
+        VmcsField::GuestCr4,
+        VmcsField::Cr4GuestHostMask,
+        VmcsField::Cr4ReadShadow,
+        PINNABLE.load().cr4,
+        0,
+    );
+}
+
+pub fn guest_cr4(vmcs: &VmcsRegion) -> u64 {
+    guest_view(
+        vmcs,
+        VmcsField::GuestCr4,
+        VmcsField::Cr4GuestHostMask,
+        VmcsField::Cr4ReadShadow,
+    )
+}
+
+pub fn set_guest_cr4(vmcs: &mut VmcsRegion, value: u64) -> Result<(), PinViolation> {
+    check_pinned(&PINNED_CR4, PINNABLE.load().cr4, 4, value)?;
+    let old = guest_cr4(vmcs);
+    set_guest_view(
+        vmcs,
+        VmcsField::GuestCr4,
+        VmcsField::Cr4GuestHostMask,
+        VmcsField::Cr4ReadShadow,
+        PINNABLE.load().cr4,
+        value,
+    );
+    if (old ^ value) & CR4_FLUSH_BITS != 0 {
+        tlb::flush_guest(vmcs);
+    }
+    Ok(())
+}
+
+/// The guest owns the local APIC, so CR8 is passed straight through to the hardware unless
+/// the TPR is shadowed.
+pub fn guest_cr8() -> u64 {
+    if apic::TPR_SHADOW {
+        return (apic::tpr() >> 4) as u64;
+    }
+    let value: u64;
+    unsafe {
+        asm!("mov {}, cr8", out(reg) value, options(nomem, nostack, preserves_flags));
+    }
+    value
+}
+
+pub fn set_guest_cr8(value: u64) {
+    if apic::TPR_SHADOW {
+        apic::set_tpr((value as u8 & 0xf) << 4);
+        return;
+    }
+    unsafe {
+        asm!("mov cr8, {}", in(reg) value, options(nomem, nostack, preserves_flags));
+    }
+}
+
+pub fn clts(vmcs: &mut VmcsRegion) {
+    let cr0 = guest_cr0(vmcs);
+    write_guest_cr0(vmcs, cr0 & !CR0_TS);
+}
+
+/// LMSW loads CR0[3:0] but can only set PE, never clear it.
+pub fn lmsw(vmcs: &mut VmcsRegion, source: u16) {
+    let cr0 = guest_cr0(vmcs);
+    let value = (cr0 & !CR0_LMSW_BITS) | (source as u64 & CR0_LMSW_BITS) | (cr0 & CR0_PE);
+    write_guest_cr0(vmcs, value);
+}
@@ -0,0 +1,134 @@
+use crate::serial_println;
+use alloc::vec::Vec;
+use crossbeam::atomic::AtomicCell;
+use lazy_static::lazy_static;
+
+/// Request CR3-load exiting so that every address-space switch is seen. Without it the
+/// tracker still learns about address spaces from the CR3 value present at each VM exit.
+pub const LOAD_EXITING: bool = cfg!(feature = "cr3-exiting");
+
+const CR3_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+const CR3_PCID_MASK: u64 = 0xfff;
+/// With CR4.PCIDE set, bit 63 of a MOV to CR3 source keeps the TLB entries of the new PCID.
+/// It is not part of CR3 itself.
+pub const CR3_NO_FLUSH: u64 = 1 << 63;
+/// Number of address spaces remembered. When a new one shows up the one that has gone
+/// unseen for the most exits is forgotten.
+const MAX_SPACES: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct AddressSpace {
+    /// Physical address of the top-level page table.
+    pub root: u64,
+    /// PCID the space was last used with, if PCIDs are enabled.
+    pub pcid: Option<u16>,
+    /// Number of switches into this space.
+    pub switches: u64,
+    /// Value of the exit counter when the space was last seen.
+    pub last_seen: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Cr3Tracker {
+    spaces: Vec<AddressSpace>,
+    current: Option<u64>,
+    switches: u64,
+    exits: u64,
+    filter: Option<u64>,
+}
+
+lazy_static! {
+    static ref TRACKER: AtomicCell<Cr3Tracker> = AtomicCell::new(Cr3Tracker::default());
+}
+
+fn tracker() -> &'static mut Cr3Tracker {
+    unsafe { TRACKER.as_ptr().as_mut().unwrap() }
+}
+
+pub fn root_of(cr3: u64) -> u64 {
+    cr3 & CR3_ADDRESS_MASK
+}
+
+/// Records the guest CR3 seen on a VM exit.
+pub fn on_vmexit(cr3: u64, pcide: bool) {
+    let tracker = tracker();
+    tracker.exits += 1;
+    tracker.observe(cr3, pcide);
+}
+
+/// Records a guest MOV to CR3.
+pub fn on_cr3_load(cr3: u64, pcide: bool) {
+    tracker().observe(cr3 & !CR3_NO_FLUSH, pcide);
+}
+
+impl Cr3Tracker {
+    fn observe(&mut self, cr3: u64, pcide: bool) {
+        let root = root_of(cr3);
+        let pcid = pcide.then_some((cr3 & CR3_PCID_MASK) as u16);
+        let switched = self.current != Some(root);
+        if switched {
+            self.switches += 1;
+            self.current = Some(root);
+        }
+        let exits = self.exits;
+        match self.spaces.iter_mut().find(|space| space.root == root) {
+            Some(space) => {
+                space.pcid = pcid;
+                space.last_seen = exits;
+                if switched {
+                    space.switches += 1;
+                }
+            }
+            None => {
+                if self.spaces.len() == MAX_SPACES {
+                    let oldest = (0..self.spaces.len())
+                        .min_by_key(|&i| self.spaces[i].last_seen)
+                        .unwrap();
+                    self.spaces.swap_remove(oldest);
+                }
+                self.spaces.push(AddressSpace {
+                    root,
+                    pcid,
+                    switches: 1,
+                    last_seen: exits,
+                });
+            }
+        }
+    }
+}
+
+/// Limits tracing to exits taken while the guest runs in the address space of `cr3`.
+pub fn set_filter(cr3: Option<u64>) {
+    tracker().filter = cr3.map(root_of);
+}
+
+pub fn is_traced(cr3: u64) -> bool {
+    match tracker().filter {
+        Some(root) => root == root_of(cr3),
+        None => true,
+    }
+}
+
+pub fn print() {
+    let tracker = tracker();
+    serial_println!(
+        "{} address spaces, {} switches over {} exits",
+        tracker.spaces.len(),
+        tracker.switches,
+        tracker.exits
+    );
+    for space in tracker.spaces.iter() {
+        let current = if tracker.current == Some(space.root) {
+            "*"
+        } else {
+            " "
+        };
+        serial_println!(
+            "{current} 0x{:012x} pcid {:?} switches {} last seen at exit {}",
+            space.root,
+            space.pcid,
+            space.switches,
+            space.last_seen
+        );
+    }
+}
@@ -0,0 +1,415 @@
+use crate::{
+    arch::intel::{
+        event::{self, Event, EXCEPTION_GP, EXCEPTION_NP},
+        vmcs::{FieldNatural, VmcsField, VmcsRegion},
+        vmx::VmExitGeneralPurposeRegister,
+    },
+    serial_println,
+};
+use crossbeam::atomic::AtomicCell;
+
+/// Trap LGDT/LIDT/LLDT/LTR and their store counterparts to watch the guest descriptor tables.
+pub const ENABLE_EXITING: bool = cfg!(feature = "desc-table-exiting");
+
+const SEGMENT_BASES: [FieldNatural; 6] = [
+    VmcsField::GuestEsBase,
+    VmcsField::GuestCsBase,
+    VmcsField::GuestSsBase,
+    VmcsField::GuestDsBase,
+    VmcsField::GuestFsBase,
+    VmcsField::GuestGsBase,
+];
+
+/// Access rights bit marking a segment register unusable.
+const AR_UNUSABLE: u32 = 1 << 16;
+/// System descriptor types.
+const TYPE_LDT: u8 = 0x2;
+const TYPE_TSS_AVAILABLE: u8 = 0x9;
+const TYPE_TSS_BUSY: u8 = 0xb;
+const MAX_INSTRUCTION_LEN: u64 = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DescriptorTable {
+    pub base: u64,
+    pub limit: u16,
+}
+
+/// The first IDT loaded by the guest at CPL 0, and the one in use now.
+static FIRST_IDT: AtomicCell<Option<DescriptorTable>> = AtomicCell::new(None);
+static CURRENT_IDT: AtomicCell<Option<DescriptorTable>> = AtomicCell::new(None);
+
+#[allow(unused)]
+pub fn first_idt() -> Option<DescriptorTable> {
+    FIRST_IDT.load()
+}
+
+/// Decoded VM-exit instruction information (SDM Vol. 3C, Tables 28-10 and 28-11).
+#[derive(Debug, Clone, Copy)]
+struct InstructionInfo(u32);
+
+impl InstructionInfo {
+    fn scaling(&self) -> u32 {
+        self.0 & 0b11
+    }
+
+    /// Register operand of LLDT/LTR/SLDT/STR.
+    fn reg1(&self) -> u64 {
+        ((self.0 >> 3) & 0b1111) as u64
+    }
+
+    fn address_size(&self) -> u32 {
+        (self.0 >> 7) & 0b111
+    }
+
+    fn is_register_operand(&self) -> bool {
+        self.0 & (1 << 10) != 0
+    }
+
+    /// Operand size of LGDT/LIDT/SGDT/SIDT outside 64-bit mode. Undefined for the other
+    /// instructions.
+    fn is_32bit_operand(&self) -> bool {
+        self.0 & (1 << 11) != 0
+    }
+
+    fn segment(&self) -> usize {
+        ((self.0 >> 15) & 0b111) as usize
+    }
+
+    fn index(&self) -> Option<u64> {
+        (self.0 & (1 << 22) == 0).then_some(((self.0 >> 18) & 0b1111) as u64)
+    }
+
+    fn base(&self) -> Option<u64> {
+        (self.0 & (1 << 27) == 0).then_some(((self.0 >> 23) & 0b1111) as u64)
+    }
+
+    fn identity(&self) -> u32 {
+        (self.0 >> 28) & 0b11
+    }
+
+    fn linear_address(
+        &self,
+        vmcs: &VmcsRegion,
+        gpr: &VmExitGeneralPurposeRegister,
+        displacement: u64,
+    ) -> u64 {
+        let mut addr = displacement;
+        if let Some(base) = self.base() {
+            addr = addr.wrapping_add(gpr.get(base));
+        }
+        if let Some(index) = self.index() {
+            addr = addr.wrapping_add(gpr.get(index) << self.scaling());
+        }
+        addr = match self.address_size() {
+            0 => addr & 0xffff,
+            1 => addr & 0xffff_ffff,
+            _ => addr,
+        };
+        offset_linear(vmcs, vmcs.read_natural(SEGMENT_BASES[self.segment()]), addr)
+    }
+}
+
+/// Linear address `offset` bytes past `linear`. Outside 64-bit mode linear addresses are
+/// 32 bits wide.
+fn offset_linear(vmcs: &VmcsRegion, linear: u64, offset: u64) -> u64 {
+    let addr = linear.wrapping_add(offset);
+    if vmcs.guest_bitness() == 64 {
+        addr
+    } else {
+        addr & 0xffff_ffff
+    }
+}
+
+/// Translates every byte separately since the operand may straddle a page boundary.
+fn guest_byte(vmcs: &VmcsRegion, linear: u64, write: bool) -> Result<*mut u8, Event> {
+    vmcs.translate_guest(linear, write)
+        .map(|phys| phys.as_u64() as *mut u8)
+        .map_err(|fault| Event::page_fault(fault, linear))
+}
+
+fn read_guest(vmcs: &VmcsRegion, linear: u64, buf: &mut [u8]) -> Result<(), Event> {
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = unsafe { *guest_byte(vmcs, offset_linear(vmcs, linear, i as u64), false)? };
+    }
+    Ok(())
+}
+
+/// Faults before writing anything if any byte of the operand is not writable.
+fn write_guest(vmcs: &VmcsRegion, linear: u64, data: &[u8]) -> Result<(), Event> {
+    for i in 0..data.len() {
+        guest_byte(vmcs, offset_linear(vmcs, linear, i as u64), true)?;
+    }
+    for (i, b) in data.iter().enumerate() {
+        unsafe { *guest_byte(vmcs, offset_linear(vmcs, linear, i as u64), true)? = *b };
+    }
+    Ok(())
+}
+
+/// Operand size of the instruction at the guest RIP, from its default and its 0x66 and
+/// REX.W prefixes.
+fn operand_size(vmcs: &VmcsRegion) -> Result<u32, Event> {
+    let bitness = vmcs.guest_bitness();
+    let rip = vmcs.read_natural(VmcsField::GuestRip);
+    let fetch = if bitness == 64 {
+        rip
+    } else {
+        offset_linear(vmcs, vmcs.read_natural(VmcsField::GuestCsBase), rip)
+    };
+    let mut operand_size_prefix = false;
+    let mut rex_w = false;
+    for i in 0..MAX_INSTRUCTION_LEN {
+        let byte = unsafe { *guest_byte(vmcs, offset_linear(vmcs, fetch, i), false)? };
+        match byte {
+            0x66 => {
+                operand_size_prefix = true;
+                rex_w = false;
+            }
+            // A REX prefix only counts right before the opcode.
+            0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 | 0x67 | 0xf0 | 0xf2 | 0xf3 => rex_w = false,
+            0x40..=0x4f if bitness == 64 => rex_w = byte & 0b1000 != 0,
+            _ => break,
+        }
+    }
+    Ok(if rex_w {
+        64
+    } else if (bitness == 16) != operand_size_prefix {
+        16
+    } else {
+        32
+    })
+}
+
+/// Queues the exception of an emulation that faulted. Returns `false` in that case, the
+/// instruction must then not be skipped.
+fn complete(result: Result<(), Event>) -> bool {
+    match result {
+        Ok(()) => true,
+        Err(event) => {
+            event::queue_event(event);
+            false
+        }
+    }
+}
+
+/// Emulates LGDT, LIDT, SGDT and SIDT. The memory operand is a 2-byte limit followed by an
+/// 8-byte base in 64-bit mode and a 4-byte base otherwise, of which LGDT and LIDT only load
+/// 24 bits with a 16-bit operand size.
+/// Returns `false` if the instruction faulted and must not be skipped.
+pub fn gdtr_idtr_access(
+    qual: u64,
+    gpr: &mut VmExitGeneralPurposeRegister,
+    vmcs: &mut VmcsRegion,
+) -> bool {
+    complete(emulate_gdtr_idtr(qual, gpr, vmcs))
+}
+
+fn emulate_gdtr_idtr(
+    qual: u64,
+    gpr: &VmExitGeneralPurposeRegister,
+    vmcs: &mut VmcsRegion,
+) -> Result<(), Event> {
+    let info = InstructionInfo(vmcs.read32(VmcsField::VmxInstructionInfo));
+    let addr = info.linear_address(vmcs, gpr, qual);
+    let (base_field, limit_field) = if info.identity() & 1 == 0 {
+        (VmcsField::GuestGdtrBase, VmcsField::GuestGdtrLimit)
+    } else {
+        (VmcsField::GuestIdtrBase, VmcsField::GuestIdtrLimit)
+    };
+    let len = if vmcs.guest_bitness() == 64 { 10 } else { 6 };
+    let mut operand = [0u8; 10];
+
+    match info.identity() {
+        // SGDT, SIDT
+        0 | 1 => {
+            let limit = vmcs.read32(limit_field) as u16;
+            let base = vmcs.read_natural(base_field);
+            operand[..2].copy_from_slice(&limit.to_le_bytes());
+            operand[2..].copy_from_slice(&base.to_le_bytes());
+            write_guest(vmcs, addr, &operand[..len])?;
+        }
+        // LGDT, LIDT
+        _ => {
+            if vmcs.guest_cpl() != 0 {
+                return Err(Event::exception(EXCEPTION_GP, Some(0)));
+            }
+            read_guest(vmcs, addr, &mut operand[..len])?;
+            let mut base = u64::from_le_bytes(operand[2..].try_into().unwrap());
+            if len == 6 && !info.is_32bit_operand() {
+                base &= 0xff_ffff;
+            }
+            let table = DescriptorTable {
+                base,
+                limit: u16::from_le_bytes([operand[0], operand[1]]),
+            };
+            vmcs.write_natural(base_field, table.base);
+            vmcs.write32(limit_field, table.limit as u32);
+            if info.identity() == 3 {
+                idt_loaded(vmcs, table);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn idt_loaded(vmcs: &VmcsRegion, table: DescriptorTable) {
+    let previous = CURRENT_IDT.swap(Some(table));
+    match FIRST_IDT.load() {
+        None => {
+            serial_println!(
+                "guest IDT: base 0x{:016x} limit 0x{:x}",
+                table.base,
+                table.limit
+            );
+            FIRST_IDT.store(Some(table));
+        }
+        Some(first) if first != table && previous != Some(table) => {
+            let rip = vmcs.read_natural(VmcsField::GuestRip);
+            let cr3 = vmcs.read_natural(VmcsField::GuestCr3);
+            serial_println!(
+                "guest IDT changed at RIP 0x{rip:016x} (CR3 0x{cr3:x}): base 0x{:016x} limit 0x{:x} -> base 0x{:016x} limit 0x{:x}",
+                previous.unwrap_or(first).base,
+                previous.unwrap_or(first).limit,
+                table.base,
+                table.limit
+            );
+        }
+        Some(_) => {}
+    }
+}
+
+/// Emulates LLDT, LTR, SLDT and STR.
+/// Returns `false` if the instruction faulted and must not be skipped.
+pub fn ldtr_tr_access(
+    qual: u64,
+    gpr: &mut VmExitGeneralPurposeRegister,
+    vmcs: &mut VmcsRegion,
+) -> bool {
+    complete(emulate_ldtr_tr(qual, gpr, vmcs))
+}
+
+fn emulate_ldtr_tr(
+    qual: u64,
+    gpr: &mut VmExitGeneralPurposeRegister,
+    vmcs: &mut VmcsRegion,
+) -> Result<(), Event> {
+    let info = InstructionInfo(vmcs.read32(VmcsField::VmxInstructionInfo));
+    let is_tr = info.identity() & 1 == 1;
+    let selector_field = if is_tr {
+        VmcsField::GuestTrSelector
+    } else {
+        VmcsField::GuestLdtrSelector
+    };
+
+    match info.identity() {
+        // SLDT, STR
+        0 | 1 => {
+            let selector = vmcs.read16(selector_field);
+            if info.is_register_operand() {
+                // Register destinations are zero-extended unless the operand size is 16 bits.
+                let value = if operand_size(vmcs)? == 16 {
+                    (gpr.get(info.reg1()) & !0xffff) | selector as u64
+                } else {
+                    selector as u64
+                };
+                gpr.set(info.reg1(), value);
+            } else {
+                let addr = info.linear_address(vmcs, gpr, qual);
+                write_guest(vmcs, addr, &selector.to_le_bytes())?;
+            }
+        }
+        // LLDT, LTR
+        _ => {
+            if vmcs.guest_cpl() != 0 {
+                return Err(Event::exception(EXCEPTION_GP, Some(0)));
+            }
+            let selector = if info.is_register_operand() {
+                gpr.get(info.reg1()) as u16
+            } else {
+                let mut selector = [0; 2];
+                read_guest(vmcs, info.linear_address(vmcs, gpr, qual), &mut selector)?;
+                u16::from_le_bytes(selector)
+            };
+            load_system_segment(vmcs, selector, is_tr)?;
+            serial_println!(
+                "guest {} loaded: 0x{selector:04x}",
+                if is_tr { "TR" } else { "LDTR" }
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Loads LDTR or TR from the system descriptor `selector` refers to in the guest GDT.
+/// System descriptors take 16 bytes in IA-32e mode, compatibility mode included, and 8
+/// bytes otherwise.
+fn load_system_segment(vmcs: &mut VmcsRegion, selector: u16, is_tr: bool) -> Result<(), Event> {
+    let (selector_field, base_field, limit_field, ar_field) = if is_tr {
+        (
+            VmcsField::GuestTrSelector,
+            VmcsField::GuestTrBase,
+            VmcsField::GuestTrLimit,
+            VmcsField::GuestTrAccessRights,
+        )
+    } else {
+        (
+            VmcsField::GuestLdtrSelector,
+            VmcsField::GuestLdtrBase,
+            VmcsField::GuestLdtrLimit,
+            VmcsField::GuestLdtrAccessRights,
+        )
+    };
+    let error_code = Some(selector as u32 & !0b11);
+    let gp = Event::exception(EXCEPTION_GP, error_code);
+
+    // A null selector leaves LDTR unusable; TR cannot be null. Both must refer to the GDT.
+    if selector & !0b11 == 0 && !is_tr {
+        vmcs.write16(selector_field, selector);
+        vmcs.write32(ar_field, AR_UNUSABLE);
+        return Ok(());
+    }
+    let index = (selector & !0b111) as u64;
+    if selector & 0b100 != 0 || selector & !0b11 == 0 {
+        return Err(gp);
+    }
+    let desc_len = if vmcs.guest_ia32e_mode() { 16 } else { 8 };
+    if index + desc_len - 1 > vmcs.read32(VmcsField::GuestGdtrLimit) as u64 {
+        return Err(gp);
+    }
+
+    let desc_addr = offset_linear(vmcs, vmcs.read_natural(VmcsField::GuestGdtrBase), index);
+    let mut desc = [0u8; 16];
+    read_guest(vmcs, desc_addr, &mut desc[..desc_len as usize])?;
+    let low = u64::from_le_bytes(desc[..8].try_into().unwrap());
+    let high = u64::from_le_bytes(desc[8..].try_into().unwrap());
+    let desc_type = ((low >> 40) & 0xf) as u8;
+    let expected = if is_tr { TYPE_TSS_AVAILABLE } else { TYPE_LDT };
+    // The type field of the upper half of a 16-byte descriptor must be 0.
+    if low & (1 << 44) != 0 || desc_type != expected || (high >> 40) & 0x1f != 0 {
+        return Err(gp);
+    }
+    if low & (1 << 47) == 0 {
+        return Err(Event::exception(EXCEPTION_NP, error_code));
+    }
+
+    let base =
+        ((low >> 16) & 0xff_ffff) | (((low >> 56) & 0xff) << 24) | ((high & 0xffff_ffff) << 32);
+    let mut limit = (low & 0xffff) | (((low >> 48) & 0xf) << 16);
+    let granularity = low & (1 << 55) != 0;
+    if granularity {
+        limit = (limit << 12) | 0xfff;
+    }
+    let mut access_rights = ((low >> 40) & 0xff) as u32 | ((((low >> 52) & 0xf) as u32) << 12);
+
+    if is_tr {
+        // LTR marks the TSS busy, in the descriptor and in the loaded access rights.
+        access_rights = (access_rights & !0xf) | TYPE_TSS_BUSY as u32;
+        let busy = low | ((TYPE_TSS_BUSY as u64) << 40);
+        write_guest(vmcs, desc_addr, &busy.to_le_bytes())?;
+    }
+
+    vmcs.write16(selector_field, selector);
+    vmcs.write_natural(base_field, base);
+    vmcs.write32(limit_field, limit as u32);
+    vmcs.write32(ar_field, access_rights);
+    Ok(())
+}
@@ -0,0 +1,200 @@
+use crate::{
+    arch::intel::{
+        apic, cr, event,
+        vmcs::{VmcsField, VmcsRegion, VMCS_VMEXIT_CTLS_SAVE_IA32_PAT},
+        vmx::{vmclear, vmxoff, VmExitGeneralPurposeRegister, VmxError},
+    },
+    ioapic, lapic, serial_println, BOOT_ARGS,
+};
+use common::constants;
+use core::{arch::asm, convert::Infallible};
+use x86_64::{
+    registers::{control::Cr4Flags, model_specific::Msr},
+    structures::DescriptorTablePointer,
+    VirtAddr,
+};
+
+const CS_ACCESS_RIGHTS_L: u32 = 1 << 13;
+
+/// Guest state that only the final assembly stub can load. The field offsets are used by
+/// `restore_guest_regs` in entry.s.
+#[repr(C)]
+struct NativeState {
+    rax: u64,
+    rbx: u64,
+    rcx: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    rbp: u64,
+    r8: u64,
+    r9: u64,
+    r10: u64,
+    r11: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rsp: u64,
+    rip: u64,
+    rflags: u64,
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+    gdtr: DescriptorTablePointer,
+    _gdtr_padding: [u8; 6],
+    idtr: DescriptorTablePointer,
+    _idtr_padding: [u8; 6],
+    cs: u16,
+    ss: u16,
+    ds: u16,
+    es: u16,
+    fs: u16,
+    gs: u16,
+    ldtr: u16,
+    tr: u16,
+    fs_base: u64,
+    gs_base: u64,
+}
+
+extern "C" {
+    fn restore_guest_regs(state: *const NativeState) -> !;
+}
+
+#[derive(Debug)]
+pub enum DevirtError {
+    /// The guest is not in 64-bit mode.
+    NotLongMode,
+    /// The VMM page at `addr` is not mapped at the same address by the guest, so the VMM
+    /// cannot run on the guest's page tables.
+    NotMapped {
+        addr: u64,
+    },
+    Vmx(VmxError),
+}
+
+impl core::fmt::Display for DevirtError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            DevirtError::NotLongMode => write!(f, "the guest is not in 64-bit mode"),
+            DevirtError::NotMapped { addr } => {
+                write!(f, "the guest does not map the VMM at 0x{addr:x}")
+            }
+            DevirtError::Vmx(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+fn check_mapped(vmcs: &VmcsRegion, addr: u64) -> Result<(), DevirtError> {
+    let virt = i64::try_from(addr).unwrap();
+    let phys = u64::try_from(virt + BOOT_ARGS.load().vmm_phys_offset).unwrap();
+    if vmcs.translate_guest(addr, false).ok().map(|p| p.as_u64()) != Some(phys) {
+        return Err(DevirtError::NotMapped { addr });
+    }
+    Ok(())
+}
+
+/// Loads the current guest state into the processor, leaves VMX operation and continues the
+/// guest at `rip` natively. Only returns if the guest cannot run without the VMM.
+///
+/// The guest asks for this with the DEVIRTUALIZE hypercall. The VMM has no shell or other
+/// console input of its own to offer another entry point.
+pub fn devirtualize(
+    vmcs: &mut VmcsRegion,
+    gpr: &VmExitGeneralPurposeRegister,
+    rip: u64,
+) -> Result<Infallible, DevirtError> {
+    if vmcs.read32(VmcsField::GuestCsAccessRights) & CS_ACCESS_RIGHTS_L == 0 {
+        return Err(DevirtError::NotLongMode);
+    }
+    let cr3 = vmcs.read_natural(VmcsField::GuestCr3);
+
+    let state = NativeState {
+        rax: gpr.rax,
+        rbx: gpr.rbx,
+        rcx: gpr.rcx,
+        rdx: gpr.rdx,
+        rsi: gpr.rsi,
+        rdi: gpr.rdi,
+        rbp: gpr.rbp,
+        r8: gpr.r8,
+        r9: gpr.r9,
+        r10: gpr.r10,
+        r11: gpr.r11,
+        r12: gpr.r12,
+        r13: gpr.r13,
+        r14: gpr.r14,
+        r15: gpr.r15,
+        rsp: vmcs.read_natural(VmcsField::GuestRsp),
+        rip,
+        rflags: vmcs.read_natural(VmcsField::GuestRflags),
+        cr0: cr::guest_cr0(vmcs),
+        cr3,
+        cr4: cr::guest_cr4(vmcs) & !Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS.bits(),
+        gdtr: DescriptorTablePointer {
+            limit: vmcs.read32(VmcsField::GuestGdtrLimit) as u16,
+            base: VirtAddr::new(vmcs.read_natural(VmcsField::GuestGdtrBase)),
+        },
+        _gdtr_padding: [0; 6],
+        idtr: DescriptorTablePointer {
+            limit: vmcs.read32(VmcsField::GuestIdtrLimit) as u16,
+            base: VirtAddr::new(vmcs.read_natural(VmcsField::GuestIdtrBase)),
+        },
+        _idtr_padding: [0; 6],
+        cs: vmcs.read16(VmcsField::GuestCsSelector),
+        ss: vmcs.read16(VmcsField::GuestSsSelector),
+        ds: vmcs.read16(VmcsField::GuestDsSelector),
+        es: vmcs.read16(VmcsField::GuestEsSelector),
+        fs: vmcs.read16(VmcsField::GuestFsSelector),
+        gs: vmcs.read16(VmcsField::GuestGsSelector),
+        ldtr: vmcs.read16(VmcsField::GuestLdtrSelector),
+        tr: vmcs.read16(VmcsField::GuestTrSelector),
+        fs_base: vmcs.read_natural(VmcsField::GuestFsBase),
+        gs_base: vmcs.read_natural(VmcsField::GuestGsBase),
+    };
+    // The code and the stack holding `state` stay in use after the switch to the guest's CR3.
+    check_mapped(vmcs, restore_guest_regs as *const () as u64)?;
+    check_mapped(vmcs, &state as *const NativeState as u64)?;
+    check_mapped(vmcs, &state as *const NativeState as u64 - 0x100)?;
+    let sysenter_cs = vmcs.read32(VmcsField::GuestIa32SysenterCs) as u64;
+    let sysenter_esp = vmcs.read_natural(VmcsField::GuestSysenterEsp);
+    let sysenter_eip = vmcs.read_natural(VmcsField::GuestSysenterEip);
+    let dr7 = vmcs.read_natural(VmcsField::GuestDr7);
+    let debugctl = vmcs.read64(VmcsField::GuestIa32Debugctl);
+    let pat = if vmcs.read32(VmcsField::VmExitControls) & VMCS_VMEXIT_CTLS_SAVE_IA32_PAT != 0 {
+        Some(
+            vmcs.try_read(VmcsField::GuestIa32Pat)
+                .map_err(DevirtError::Vmx)?,
+        )
+    } else {
+        None
+    };
+
+    if !event::is_idle() {
+        serial_println!("devirtualizing with undelivered guest events, they are lost");
+    }
+    if apic::TPR_SHADOW {
+        let tpr = apic::tpr();
+        lapic::write(lapic::REG_TPR, tpr as u32);
+    }
+
+    serial_println!("devirtualizing, guest continues at 0x{rip:x}");
+    // The pins the VMM reserved go back to what the guest programmed.
+    ioapic::restore_guest_entries();
+    // EFER and IA32_KERNEL_GS_BASE are not switched on VM exits and already hold the
+    // guest's values. The PAT is, if the processor supports switching it.
+    unsafe { vmclear(vmcs) }.map_err(DevirtError::Vmx)?;
+    unsafe { vmxoff() }.map_err(DevirtError::Vmx)?;
+    unsafe {
+        Msr::new(constants::MSR_IA32_SYSENTER_CS).write(sysenter_cs);
+        Msr::new(constants::MSR_IA32_SYSENTER_ESP).write(sysenter_esp);
+        Msr::new(constants::MSR_IA32_SYSENTER_EIP).write(sysenter_eip);
+        if let Some(pat) = pat {
+            Msr::new(constants::MSR_IA32_CR_PAT).write(pat);
+        }
+        // VM exits clear both. DR7 goes last so no guest breakpoint hits the VMM.
+        Msr::new(constants::MSR_IA32_DEBUGCTL).write(debugctl);
+        asm!("mov dr7, {}", in(reg) dr7, options(nomem, nostack));
+        restore_guest_regs(&state)
+    }
+}
@@ -0,0 +1,972 @@
+use crate::arch::intel::{
+    vmcs::{Field16, Field32, FieldNatural, VmcsEncoding, VmcsField},
+    vmx_caps::VmxCaps,
+};
+use alloc::vec::Vec;
+
+/// Anything the guest-state fields can be read from: the current VMCS on hardware,
+/// or synthetic contents on the host.
+pub trait VmcsRead {
+    fn read(&self, field: VmcsEncoding) -> u64;
+}
+
+/// Processor properties the checks depend on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CheckEnv {
+    pub cr0_fixed0: u64,
+    pub cr0_fixed1: u64,
+    pub cr4_fixed0: u64,
+    pub cr4_fixed1: u64,
+    pub physical_address_width: u8,
+    pub linear_address_width: u8,
+}
+
+impl CheckEnv {
+    pub fn from_hardware() -> Self {
+        let address_size = unsafe { core::arch::x86_64::__cpuid(0x8000_0008) }.eax;
+        let caps = VmxCaps::read();
+        Self {
+            cr0_fixed0: caps.cr0_fixed.fixed0,
+            cr0_fixed1: caps.cr0_fixed.fixed1,
+            cr4_fixed0: caps.cr4_fixed.fixed0,
+            cr4_fixed1: caps.cr4_fixed.fixed1,
+            physical_address_width: address_size as u8,
+            linear_address_width: (address_size >> 8) as u8,
+        }
+    }
+
+    fn is_canonical(&self, addr: u64) -> bool {
+        let shift = 64 - self.linear_address_width as u32;
+        (((addr << shift) as i64) >> shift) as u64 == addr
+    }
+
+    fn exceeds_physical_address_width(&self, addr: u64) -> bool {
+        addr >> self.physical_address_width != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Violation {
+    pub field: VmcsEncoding,
+    pub value: u64,
+    pub check: &'static str,
+}
+
+const CR0_PE: u64 = 1 << 0;
+const CR0_WP: u64 = 1 << 16;
+const CR0_PG: u64 = 1 << 31;
+const CR4_PAE: u64 = 1 << 5;
+const CR4_PCIDE: u64 = 1 << 17;
+const CR4_CET: u64 = 1 << 23;
+
+const RFLAGS_TF: u64 = 1 << 8;
+const RFLAGS_IF: u64 = 1 << 9;
+const RFLAGS_VM: u64 = 1 << 17;
+
+const EFER_LME: u64 = 1 << 8;
+const EFER_LMA: u64 = 1 << 10;
+
+const PROC_BASED_ACTIVATE_SECONDARY: u64 = 1 << 31;
+const PROC_BASED2_UNRESTRICTED_GUEST: u64 = 1 << 7;
+const PIN_BASED_VIRTUAL_NMIS: u64 = 1 << 5;
+const ENTRY_LOAD_DEBUG_CONTROLS: u64 = 1 << 2;
+const ENTRY_IA32E_MODE_GUEST: u64 = 1 << 9;
+const ENTRY_LOAD_IA32_PAT: u64 = 1 << 14;
+const ENTRY_LOAD_IA32_EFER: u64 = 1 << 15;
+
+const AR_TYPE: u64 = 0xf;
+const AR_S: u64 = 1 << 4;
+const AR_P: u64 = 1 << 7;
+const AR_L: u64 = 1 << 13;
+const AR_DB: u64 = 1 << 14;
+const AR_G: u64 = 1 << 15;
+const AR_UNUSABLE: u64 = 1 << 16;
+const AR_RESERVED: u64 = 0xf00 | 0xfffe_0000;
+
+const INTR_BLOCKING_BY_STI: u64 = 1 << 0;
+const INTR_BLOCKING_BY_MOV_SS: u64 = 1 << 1;
+const INTR_BLOCKING_BY_SMI: u64 = 1 << 2;
+const INTR_BLOCKING_BY_NMI: u64 = 1 << 3;
+
+const ACTIVITY_HLT: u64 = 1;
+
+const DEBUGCTL_VALID_BITS: u64 = 0b11 | 0xffc0;
+
+struct Checker<'a, V: VmcsRead> {
+    vmcs: &'a V,
+    env: &'a CheckEnv,
+    violations: Vec<Violation>,
+}
+
+impl<'a, V: VmcsRead> Checker<'a, V> {
+    fn read(&self, field: impl Into<VmcsEncoding>) -> u64 {
+        self.vmcs.read(field.into())
+    }
+
+    fn expect(&mut self, ok: bool, field: impl Into<VmcsEncoding>, check: &'static str) {
+        if !ok {
+            let field = field.into();
+            let value = self.read(field);
+            self.violations.push(Violation {
+                field,
+                value,
+                check,
+            });
+        }
+    }
+}
+
+struct Segment {
+    selector: Field16,
+    base: FieldNatural,
+    limit: Field32,
+    access_rights: Field32,
+}
+
+const fn segment(
+    selector: Field16,
+    base: FieldNatural,
+    limit: Field32,
+    access_rights: Field32,
+) -> Segment {
+    Segment {
+        selector,
+        base,
+        limit,
+        access_rights,
+    }
+}
+
+const CS: Segment = segment(
+    VmcsField::GuestCsSelector,
+    VmcsField::GuestCsBase,
+    VmcsField::GuestCsLimit,
+    VmcsField::GuestCsAccessRights,
+);
+const SS: Segment = segment(
+    VmcsField::GuestSsSelector,
+    VmcsField::GuestSsBase,
+    VmcsField::GuestSsLimit,
+    VmcsField::GuestSsAccessRights,
+);
+const DATA_SEGMENTS: [Segment; 4] = [
+    segment(
+        VmcsField::GuestDsSelector,
+        VmcsField::GuestDsBase,
+        VmcsField::GuestDsLimit,
+        VmcsField::GuestDsAccessRights,
+    ),
+    segment(
+        VmcsField::GuestEsSelector,
+        VmcsField::GuestEsBase,
+        VmcsField::GuestEsLimit,
+        VmcsField::GuestEsAccessRights,
+    ),
+    segment(
+        VmcsField::GuestFsSelector,
+        VmcsField::GuestFsBase,
+        VmcsField::GuestFsLimit,
+        VmcsField::GuestFsAccessRights,
+    ),
+    segment(
+        VmcsField::GuestGsSelector,
+        VmcsField::GuestGsBase,
+        VmcsField::GuestGsLimit,
+        VmcsField::GuestGsAccessRights,
+    ),
+];
+const TR: Segment = segment(
+    VmcsField::GuestTrSelector,
+    VmcsField::GuestTrBase,
+    VmcsField::GuestTrLimit,
+    VmcsField::GuestTrAccessRights,
+);
+const LDTR: Segment = segment(
+    VmcsField::GuestLdtrSelector,
+    VmcsField::GuestLdtrBase,
+    VmcsField::GuestLdtrLimit,
+    VmcsField::GuestLdtrAccessRights,
+);
+
+/// Software version of the guest-state checks done on VM entry (SDM Vol. 3C, 27.3.1).
+/// The processor only reports "invalid guest state", so this returns every rule that does not hold.
+pub fn check_guest_state(vmcs: &impl VmcsRead, env: &CheckEnv) -> Vec<Violation> {
+    let mut checker = Checker {
+        vmcs,
+        env,
+        violations: Vec::new(),
+    };
+
+    let proc_based = checker.read(VmcsField::ProcBasedVmExecControls);
+    let proc_based2 = if proc_based & PROC_BASED_ACTIVATE_SECONDARY != 0 {
+        checker.read(VmcsField::ProcBasedVmExecControls2)
+    } else {
+        0
+    };
+    let unrestricted = proc_based2 & PROC_BASED2_UNRESTRICTED_GUEST != 0;
+
+    check_control_registers(&mut checker, unrestricted);
+    check_segments(&mut checker, unrestricted);
+    check_descriptor_tables(&mut checker);
+    check_rip_and_rflags(&mut checker);
+    check_non_register_state(&mut checker);
+
+    checker.violations
+}
+
+fn check_control_registers<V: VmcsRead>(c: &mut Checker<V>, unrestricted: bool) {
+    let entry_ctls = c.read(VmcsField::VmEntryControls);
+    let ia32e_mode_guest = entry_ctls & ENTRY_IA32E_MODE_GUEST != 0;
+    let cr0 = c.read(VmcsField::GuestCr0);
+    let cr3 = c.read(VmcsField::GuestCr3);
+    let cr4 = c.read(VmcsField::GuestCr4);
+
+    let cr0_fixed0 = if unrestricted {
+        c.env.cr0_fixed0 & !(CR0_PE | CR0_PG)
+    } else {
+        c.env.cr0_fixed0
+    };
+    c.expect(
+        cr0 & cr0_fixed0 == cr0_fixed0,
+        VmcsField::GuestCr0,
+        "CR0 bits fixed to 1 in IA32_VMX_CR0_FIXED0 are clear",
+    );
+    c.expect(
+        cr0 & !c.env.cr0_fixed1 == 0,
+        VmcsField::GuestCr0,
+        "CR0 bits fixed to 0 in IA32_VMX_CR0_FIXED1 are set",
+    );
+    c.expect(
+        cr0 & CR0_PG == 0 || cr0 & CR0_PE != 0,
+        VmcsField::GuestCr0,
+        "CR0.PG is set but CR0.PE is clear",
+    );
+    c.expect(
+        cr4 & c.env.cr4_fixed0 == c.env.cr4_fixed0,
+        VmcsField::GuestCr4,
+        "CR4 bits fixed to 1 in IA32_VMX_CR4_FIXED0 are clear",
+    );
+    c.expect(
+        cr4 & !c.env.cr4_fixed1 == 0,
+        VmcsField::GuestCr4,
+        "CR4 bits fixed to 0 in IA32_VMX_CR4_FIXED1 are set",
+    );
+    c.expect(
+        cr4 & CR4_CET == 0 || cr0 & CR0_WP != 0,
+        VmcsField::GuestCr0,
+        "CR4.CET is set but CR0.WP is clear",
+    );
+    if ia32e_mode_guest {
+        c.expect(
+            cr0 & CR0_PG != 0,
+            VmcsField::GuestCr0,
+            "IA-32e mode guest requires CR0.PG",
+        );
+        c.expect(
+            cr4 & CR4_PAE != 0,
+            VmcsField::GuestCr4,
+            "IA-32e mode guest requires CR4.PAE",
+        );
+    } else {
+        c.expect(
+            cr4 & CR4_PCIDE == 0,
+            VmcsField::GuestCr4,
+            "CR4.PCIDE is set outside IA-32e mode",
+        );
+    }
+    c.expect(
+        !c.env
+            .exceeds_physical_address_width(cr3 & !(0xfff | 0xfff << 52)),
+        VmcsField::GuestCr3,
+        "CR3 has bits set beyond the physical-address width",
+    );
+
+    if entry_ctls & ENTRY_LOAD_DEBUG_CONTROLS != 0 {
+        c.expect(
+            c.read(VmcsField::GuestIa32Debugctl) & !DEBUGCTL_VALID_BITS == 0,
+            VmcsField::GuestIa32Debugctl,
+            "IA32_DEBUGCTL has reserved bits set",
+        );
+        c.expect(
+            c.read(VmcsField::GuestDr7) >> 32 == 0,
+            VmcsField::GuestDr7,
+            "DR7 bits 63:32 are set",
+        );
+    }
+
+    let sysenter_esp = c.read(VmcsField::GuestSysenterEsp);
+    c.expect(
+        c.env.is_canonical(sysenter_esp),
+        VmcsField::GuestSysenterEsp,
+        "IA32_SYSENTER_ESP is not canonical",
+    );
+    let sysenter_eip = c.read(VmcsField::GuestSysenterEip);
+    c.expect(
+        c.env.is_canonical(sysenter_eip),
+        VmcsField::GuestSysenterEip,
+        "IA32_SYSENTER_EIP is not canonical",
+    );
+
+    if entry_ctls & ENTRY_LOAD_IA32_PAT != 0 {
+        let pat = c.read(VmcsField::GuestIa32Pat);
+        let valid = (0..8).all(|i| matches!((pat >> (i * 8)) & 0xff, 0 | 1 | 4 | 5 | 6 | 7));
+        c.expect(
+            valid,
+            VmcsField::GuestIa32Pat,
+            "IA32_PAT has an invalid memory type",
+        );
+    }
+
+    if entry_ctls & ENTRY_LOAD_IA32_EFER != 0 {
+        let efer = c.read(VmcsField::GuestIa32Efer);
+        c.expect(
+            efer & !(1 | EFER_LME | EFER_LMA | 1 << 11) == 0,
+            VmcsField::GuestIa32Efer,
+            "IA32_EFER has reserved bits set",
+        );
+        c.expect(
+            (efer & EFER_LMA != 0) == ia32e_mode_guest,
+            VmcsField::GuestIa32Efer,
+            "IA32_EFER.LMA differs from the IA-32e mode guest entry control",
+        );
+        c.expect(
+            cr0 & CR0_PG == 0 || (efer & EFER_LMA != 0) == (efer & EFER_LME != 0),
+            VmcsField::GuestIa32Efer,
+            "IA32_EFER.LMA differs from IA32_EFER.LME while CR0.PG is set",
+        );
+    }
+}
+
+fn check_limit_granularity<V: VmcsRead>(c: &mut Checker<V>, segment: &Segment) {
+    let limit = c.read(segment.limit);
+    let ar = c.read(segment.access_rights);
+    c.expect(
+        limit & 0xfff == 0xfff || ar & AR_G == 0,
+        segment.access_rights,
+        "G is set although limit bits 11:0 are not all 1",
+    );
+    c.expect(
+        limit & 0xfff0_0000 == 0 || ar & AR_G != 0,
+        segment.access_rights,
+        "G is clear although limit bits 31:20 are not all 0",
+    );
+}
+
+fn check_segments<V: VmcsRead>(c: &mut Checker<V>, unrestricted: bool) {
+    let ia32e_mode_guest = c.read(VmcsField::VmEntryControls) & ENTRY_IA32E_MODE_GUEST != 0;
+    let cr0 = c.read(VmcsField::GuestCr0);
+    let rflags = c.read(VmcsField::GuestRflags);
+    if rflags & RFLAGS_VM != 0 {
+        // virtual-8086 mode has its own, much simpler, set of checks
+        return;
+    }
+
+    let cs_sel = c.read(CS.selector);
+    let cs_ar = c.read(CS.access_rights);
+    let ss_sel = c.read(SS.selector);
+    let ss_ar = c.read(SS.access_rights);
+    let ss_usable = ss_ar & AR_UNUSABLE == 0;
+    let cs_type = cs_ar & AR_TYPE;
+    let cs_dpl = (cs_ar >> 5) & 0b11;
+    let ss_dpl = (ss_ar >> 5) & 0b11;
+
+    // selectors
+    c.expect(
+        c.read(TR.selector) & 0b100 == 0,
+        TR.selector,
+        "TR selector TI flag is set",
+    );
+    if c.read(LDTR.access_rights) & AR_UNUSABLE == 0 {
+        c.expect(
+            c.read(LDTR.selector) & 0b100 == 0,
+            LDTR.selector,
+            "LDTR selector TI flag is set",
+        );
+    }
+    if !unrestricted {
+        c.expect(
+            ss_sel & 0b11 == cs_sel & 0b11,
+            SS.selector,
+            "SS.RPL differs from CS.RPL",
+        );
+    }
+
+    // bases
+    for (segment, check) in [
+        (&TR, "TR base is not canonical"),
+        (&DATA_SEGMENTS[2], "FS base is not canonical"),
+        (&DATA_SEGMENTS[3], "GS base is not canonical"),
+    ] {
+        let base = c.read(segment.base);
+        c.expect(c.env.is_canonical(base), segment.base, check);
+    }
+    if c.read(LDTR.access_rights) & AR_UNUSABLE == 0 {
+        let base = c.read(LDTR.base);
+        c.expect(
+            c.env.is_canonical(base),
+            LDTR.base,
+            "LDTR base is not canonical",
+        );
+    }
+    c.expect(
+        c.read(CS.base) >> 32 == 0,
+        CS.base,
+        "CS base bits 63:32 are set",
+    );
+    for segment in [&SS, &DATA_SEGMENTS[0], &DATA_SEGMENTS[1]] {
+        if c.read(segment.access_rights) & AR_UNUSABLE == 0 {
+            c.expect(
+                c.read(segment.base) >> 32 == 0,
+                segment.base,
+                "SS/DS/ES base bits 63:32 are set",
+            );
+        }
+    }
+
+    // CS
+    let cs_type_ok = matches!(cs_type, 9 | 11 | 13 | 15) || (unrestricted && cs_type == 3);
+    c.expect(
+        cs_type_ok,
+        CS.access_rights,
+        "CS type is not an accessed code segment",
+    );
+    c.expect(cs_ar & AR_S != 0, CS.access_rights, "CS S flag is clear");
+    match cs_type {
+        3 => c.expect(
+            cs_dpl == 0,
+            CS.access_rights,
+            "CS.DPL is not 0 for a data-type CS",
+        ),
+        9 | 11 => c.expect(
+            cs_dpl == ss_dpl,
+            CS.access_rights,
+            "CS.DPL differs from SS.DPL for a non-conforming CS",
+        ),
+        13 | 15 => c.expect(
+            cs_dpl <= ss_dpl,
+            CS.access_rights,
+            "CS.DPL is greater than SS.DPL for a conforming CS",
+        ),
+        _ => {}
+    }
+    c.expect(cs_ar & AR_P != 0, CS.access_rights, "CS is not present");
+    c.expect(
+        cs_ar & AR_RESERVED == 0,
+        CS.access_rights,
+        "CS access rights reserved bits are set",
+    );
+    if ia32e_mode_guest && cs_ar & AR_L != 0 {
+        c.expect(
+            cs_ar & AR_DB == 0,
+            CS.access_rights,
+            "CS.D/B is set for a 64-bit code segment",
+        );
+    }
+    check_limit_granularity(c, &CS);
+
+    // SS
+    if ss_usable {
+        c.expect(
+            matches!(ss_ar & AR_TYPE, 3 | 7),
+            SS.access_rights,
+            "SS type is not a read/write data segment",
+        );
+        c.expect(ss_ar & AR_S != 0, SS.access_rights, "SS S flag is clear");
+        c.expect(ss_ar & AR_P != 0, SS.access_rights, "SS is not present");
+        c.expect(
+            ss_ar & AR_RESERVED == 0,
+            SS.access_rights,
+            "SS access rights reserved bits are set",
+        );
+        check_limit_granularity(c, &SS);
+    }
+    if !unrestricted {
+        c.expect(
+            ss_dpl == ss_sel & 0b11,
+            SS.access_rights,
+            "SS.DPL differs from SS.RPL",
+        );
+    }
+    if cs_type == 3 || cr0 & CR0_PE == 0 {
+        c.expect(
+            ss_dpl == 0,
+            SS.access_rights,
+            "SS.DPL is not 0 in real mode or with a data-type CS",
+        );
+    }
+
+    // DS, ES, FS, GS
+    for segment in DATA_SEGMENTS.iter() {
+        let ar = c.read(segment.access_rights);
+        if ar & AR_UNUSABLE != 0 {
+            continue;
+        }
+        let ty = ar & AR_TYPE;
+        c.expect(
+            ty & 0b0001 != 0,
+            segment.access_rights,
+            "data segment type is not accessed",
+        );
+        c.expect(
+            ty & 0b1000 == 0 || ty & 0b0010 != 0,
+            segment.access_rights,
+            "data segment register holds an unreadable code segment",
+        );
+        c.expect(
+            ar & AR_S != 0,
+            segment.access_rights,
+            "data segment S flag is clear",
+        );
+        if !unrestricted && ty <= 11 {
+            let dpl = (ar >> 5) & 0b11;
+            let rpl = c.read(segment.selector) & 0b11;
+            c.expect(
+                dpl >= rpl,
+                segment.access_rights,
+                "data segment DPL is less than its RPL",
+            );
+        }
+        c.expect(
+            ar & AR_P != 0,
+            segment.access_rights,
+            "data segment is not present",
+        );
+        c.expect(
+            ar & AR_RESERVED == 0,
+            segment.access_rights,
+            "data segment access rights reserved bits are set",
+        );
+        check_limit_granularity(c, segment);
+    }
+
+    // TR
+    let tr_ar = c.read(TR.access_rights);
+    let tr_type_ok = if ia32e_mode_guest {
+        tr_ar & AR_TYPE == 11
+    } else {
+        matches!(tr_ar & AR_TYPE, 3 | 11)
+    };
+    c.expect(tr_type_ok, TR.access_rights, "TR type is not a busy TSS");
+    c.expect(tr_ar & AR_S == 0, TR.access_rights, "TR S flag is set");
+    c.expect(tr_ar & AR_P != 0, TR.access_rights, "TR is not present");
+    c.expect(
+        tr_ar & AR_RESERVED == 0,
+        TR.access_rights,
+        "TR access rights reserved bits are set",
+    );
+    c.expect(tr_ar & AR_UNUSABLE == 0, TR.access_rights, "TR is unusable");
+    check_limit_granularity(c, &TR);
+
+    // LDTR
+    let ldtr_ar = c.read(LDTR.access_rights);
+    if ldtr_ar & AR_UNUSABLE == 0 {
+        c.expect(
+            ldtr_ar & AR_TYPE == 2,
+            LDTR.access_rights,
+            "LDTR type is not LDT",
+        );
+        c.expect(
+            ldtr_ar & AR_S == 0,
+            LDTR.access_rights,
+            "LDTR S flag is set",
+        );
+        c.expect(
+            ldtr_ar & AR_P != 0,
+            LDTR.access_rights,
+            "LDTR is not present",
+        );
+        c.expect(
+            ldtr_ar & AR_RESERVED == 0,
+            LDTR.access_rights,
+            "LDTR access rights reserved bits are set",
+        );
+        check_limit_granularity(c, &LDTR);
+    }
+}
+
+fn check_descriptor_tables<V: VmcsRead>(c: &mut Checker<V>) {
+    let gdtr_base = c.read(VmcsField::GuestGdtrBase);
+    c.expect(
+        c.env.is_canonical(gdtr_base),
+        VmcsField::GuestGdtrBase,
+        "GDTR base is not canonical",
+    );
+    let idtr_base = c.read(VmcsField::GuestIdtrBase);
+    c.expect(
+        c.env.is_canonical(idtr_base),
+        VmcsField::GuestIdtrBase,
+        "IDTR base is not canonical",
+    );
+    c.expect(
+        c.read(VmcsField::GuestGdtrLimit) >> 16 == 0,
+        VmcsField::GuestGdtrLimit,
+        "GDTR limit bits 31:16 are set",
+    );
+    c.expect(
+        c.read(VmcsField::GuestIdtrLimit) >> 16 == 0,
+        VmcsField::GuestIdtrLimit,
+        "IDTR limit bits 31:16 are set",
+    );
+}
+
+fn check_rip_and_rflags<V: VmcsRead>(c: &mut Checker<V>) {
+    let ia32e_mode_guest = c.read(VmcsField::VmEntryControls) & ENTRY_IA32E_MODE_GUEST != 0;
+    let cs_l = c.read(VmcsField::GuestCsAccessRights) & AR_L != 0;
+    let rip = c.read(VmcsField::GuestRip);
+    let rflags = c.read(VmcsField::GuestRflags);
+    let cr0 = c.read(VmcsField::GuestCr0);
+
+    if ia32e_mode_guest && cs_l {
+        c.expect(
+            c.env.is_canonical(rip),
+            VmcsField::GuestRip,
+            "RIP is not canonical",
+        );
+    } else {
+        c.expect(
+            rip >> 32 == 0,
+            VmcsField::GuestRip,
+            "RIP bits 63:32 are set outside 64-bit mode",
+        );
+    }
+
+    c.expect(
+        rflags & !0x3f_ffff == 0 && rflags & (1 << 15 | 1 << 5 | 1 << 3) == 0,
+        VmcsField::GuestRflags,
+        "RFLAGS reserved bits are set",
+    );
+    c.expect(
+        rflags & (1 << 1) != 0,
+        VmcsField::GuestRflags,
+        "RFLAGS bit 1 is clear",
+    );
+    if ia32e_mode_guest || cr0 & CR0_PE == 0 {
+        c.expect(
+            rflags & RFLAGS_VM == 0,
+            VmcsField::GuestRflags,
+            "RFLAGS.VM is set in IA-32e mode or real mode",
+        );
+    }
+
+    let entry_intr_info = c.read(VmcsField::VmEntryIntrInfoField);
+    if entry_intr_info & (1 << 31) != 0 && (entry_intr_info >> 8) & 0b111 == 0 {
+        c.expect(
+            rflags & RFLAGS_IF != 0,
+            VmcsField::GuestRflags,
+            "RFLAGS.IF is clear while injecting an external interrupt",
+        );
+    }
+}
+
+fn check_non_register_state<V: VmcsRead>(c: &mut Checker<V>) {
+    let activity = c.read(VmcsField::GuestActivityState);
+    let interruptibility = c.read(VmcsField::GuestInterruptibilityState);
+    let rflags = c.read(VmcsField::GuestRflags);
+    let ss_dpl = (c.read(VmcsField::GuestSsAccessRights) >> 5) & 0b11;
+    let entry_intr_info = c.read(VmcsField::VmEntryIntrInfoField);
+    let injecting = entry_intr_info & (1 << 31) != 0;
+    let injection_type = (entry_intr_info >> 8) & 0b111;
+    let virtual_nmis = c.read(VmcsField::PinBasedVmExecControls) & PIN_BASED_VIRTUAL_NMIS != 0;
+
+    c.expect(
+        activity <= 3,
+        VmcsField::GuestActivityState,
+        "activity state is not one of active, HLT, shutdown or wait-for-SIPI",
+    );
+    if ss_dpl != 0 {
+        c.expect(
+            activity != ACTIVITY_HLT,
+            VmcsField::GuestActivityState,
+            "HLT activity state with SS.DPL other than 0",
+        );
+    }
+    if interruptibility & (INTR_BLOCKING_BY_STI | INTR_BLOCKING_BY_MOV_SS) != 0 {
+        c.expect(
+            activity == 0,
+            VmcsField::GuestActivityState,
+            "blocking by STI or MOV SS while not active",
+        );
+    }
+
+    c.expect(
+        interruptibility >> 5 == 0,
+        VmcsField::GuestInterruptibilityState,
+        "interruptibility state reserved bits are set",
+    );
+    c.expect(
+        interruptibility & (INTR_BLOCKING_BY_STI | INTR_BLOCKING_BY_MOV_SS)
+            != (INTR_BLOCKING_BY_STI | INTR_BLOCKING_BY_MOV_SS),
+        VmcsField::GuestInterruptibilityState,
+        "blocking by both STI and MOV SS",
+    );
+    if rflags & RFLAGS_IF == 0 {
+        c.expect(
+            interruptibility & INTR_BLOCKING_BY_STI == 0,
+            VmcsField::GuestInterruptibilityState,
+            "blocking by STI while RFLAGS.IF is clear",
+        );
+    }
+    if injecting && injection_type == 0 {
+        c.expect(
+            interruptibility & (INTR_BLOCKING_BY_STI | INTR_BLOCKING_BY_MOV_SS) == 0,
+            VmcsField::GuestInterruptibilityState,
+            "injecting an external interrupt while blocked by STI or MOV SS",
+        );
+    }
+    if injecting && injection_type == 2 {
+        c.expect(
+            interruptibility & INTR_BLOCKING_BY_MOV_SS == 0,
+            VmcsField::GuestInterruptibilityState,
+            "injecting an NMI while blocked by MOV SS",
+        );
+        if virtual_nmis {
+            c.expect(
+                interruptibility & INTR_BLOCKING_BY_NMI == 0,
+                VmcsField::GuestInterruptibilityState,
+                "injecting a virtual NMI while blocked by NMI",
+            );
+        }
+    }
+    c.expect(
+        interruptibility & INTR_BLOCKING_BY_SMI == 0,
+        VmcsField::GuestInterruptibilityState,
+        "blocking by SMI outside SMM",
+    );
+
+    let pending_dbg = c.read(VmcsField::GuestPendingDbgExceptions);
+    c.expect(
+        pending_dbg & !(0xf | 1 << 12 | 1 << 14 | 1 << 16) == 0,
+        VmcsField::GuestPendingDbgExceptions,
+        "pending debug exceptions reserved bits are set",
+    );
+    if interruptibility & (INTR_BLOCKING_BY_STI | INTR_BLOCKING_BY_MOV_SS) != 0
+        || activity == ACTIVITY_HLT
+    {
+        let btf = c.read(VmcsField::GuestIa32Debugctl) & (1 << 1) != 0;
+        let single_step = rflags & RFLAGS_TF != 0 && !btf;
+        c.expect(
+            (pending_dbg & (1 << 14) != 0) == single_step,
+            VmcsField::GuestPendingDbgExceptions,
+            "pending debug exceptions BS does not match RFLAGS.TF and IA32_DEBUGCTL.BTF",
+        );
+    }
+
+    let link_pointer = c.read(VmcsField::VmcsLinkPointer);
+    if link_pointer != !0 {
+        c.expect(
+            link_pointer & 0xfff == 0 && !c.env.exceeds_physical_address_width(link_pointer),
+            VmcsField::VmcsLinkPointer,
+            "VMCS link pointer is neither all ones nor a valid page address",
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CR0_NE: u64 = 1 << 5;
+    const CR0_ET: u64 = 1 << 4;
+    const CR4_VMXE: u64 = 1 << 13;
+
+    const ENV: CheckEnv = CheckEnv {
+        cr0_fixed0: CR0_PE | CR0_NE | CR0_PG,
+        cr0_fixed1: 0xffff_ffff,
+        cr4_fixed0: CR4_VMXE,
+        cr4_fixed1: 0x3f_ffff,
+        physical_address_width: 39,
+        linear_address_width: 48,
+    };
+
+    /// Reads zero for every field that was not set.
+    struct SyntheticVmcs(Vec<(VmcsEncoding, u64)>);
+
+    impl VmcsRead for SyntheticVmcs {
+        fn read(&self, field: VmcsEncoding) -> u64 {
+            self.0
+                .iter()
+                .rev()
+                .find(|(f, _)| *f == field)
+                .map_or(0, |(_, value)| *value)
+        }
+    }
+
+    impl SyntheticVmcs {
+        fn set(&mut self, field: impl Into<VmcsEncoding>, value: u64) -> &mut Self {
+            self.0.push((field.into(), value));
+            self
+        }
+    }
+
+    const AR_CODE_64: u64 = 0xa09b;
+    const AR_DATA: u64 = 0xc093;
+    const AR_BUSY_TSS_64: u64 = 0x8b;
+
+    /// A flat 64-bit guest at CPL 0 that passes every check.
+    fn long_mode_guest() -> SyntheticVmcs {
+        let mut vmcs = SyntheticVmcs(Vec::new());
+        vmcs.set(VmcsField::VmEntryControls, ENTRY_IA32E_MODE_GUEST)
+            .set(VmcsField::GuestCr0, CR0_PE | CR0_ET | CR0_NE | CR0_PG)
+            .set(VmcsField::GuestCr3, 0x1000)
+            .set(VmcsField::GuestCr4, CR4_PAE | CR4_VMXE)
+            .set(VmcsField::GuestCsSelector, 0x08)
+            .set(VmcsField::GuestCsLimit, 0xffff_ffff)
+            .set(VmcsField::GuestCsAccessRights, AR_CODE_64)
+            .set(VmcsField::GuestTrSelector, 0x18)
+            .set(VmcsField::GuestTrLimit, 0x67)
+            .set(VmcsField::GuestTrAccessRights, AR_BUSY_TSS_64)
+            .set(VmcsField::GuestLdtrAccessRights, AR_UNUSABLE)
+            .set(VmcsField::GuestRip, 0x1000)
+            .set(VmcsField::GuestRflags, 1 << 1)
+            .set(VmcsField::VmcsLinkPointer, !0);
+        for segment in DATA_SEGMENTS.iter().chain([&SS]) {
+            vmcs.set(segment.selector, 0x10)
+                .set(segment.limit, 0xffff_ffff)
+                .set(segment.access_rights, AR_DATA);
+        }
+        vmcs
+    }
+
+    fn checks(vmcs: &SyntheticVmcs) -> Vec<&'static str> {
+        check_guest_state(vmcs, &ENV)
+            .iter()
+            .map(|violation| violation.check)
+            .collect()
+    }
+
+    #[test]
+    fn valid_guest_passes() {
+        assert_eq!(checks(&long_mode_guest()), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn cr0_fixed_bits() {
+        let mut vmcs = long_mode_guest();
+        vmcs.set(VmcsField::GuestCr0, CR0_PE | CR0_PG);
+        assert_eq!(
+            checks(&vmcs),
+            ["CR0 bits fixed to 1 in IA32_VMX_CR0_FIXED0 are clear"]
+        );
+
+        vmcs.set(VmcsField::GuestCr0, CR0_PE | CR0_NE | CR0_PG | 1 << 32);
+        assert_eq!(
+            checks(&vmcs),
+            ["CR0 bits fixed to 0 in IA32_VMX_CR0_FIXED1 are set"]
+        );
+    }
+
+    #[test]
+    fn unrestricted_guest_may_clear_pe_and_pg() {
+        let mut vmcs = long_mode_guest();
+        vmcs.set(VmcsField::VmEntryControls, 0)
+            .set(VmcsField::GuestCr0, CR0_ET | CR0_NE)
+            .set(VmcsField::GuestCr4, CR4_VMXE)
+            .set(VmcsField::GuestTrAccessRights, AR_BUSY_TSS_64);
+        assert!(checks(&vmcs).contains(&"CR0 bits fixed to 1 in IA32_VMX_CR0_FIXED0 are clear"));
+
+        vmcs.set(
+            VmcsField::ProcBasedVmExecControls,
+            PROC_BASED_ACTIVATE_SECONDARY,
+        )
+        .set(
+            VmcsField::ProcBasedVmExecControls2,
+            PROC_BASED2_UNRESTRICTED_GUEST,
+        );
+        assert!(!checks(&vmcs).contains(&"CR0 bits fixed to 1 in IA32_VMX_CR0_FIXED0 are clear"));
+    }
+
+    #[test]
+    fn cr4_fixed_bits() {
+        let mut vmcs = long_mode_guest();
+        vmcs.set(VmcsField::GuestCr4, CR4_PAE);
+        assert_eq!(
+            checks(&vmcs),
+            ["CR4 bits fixed to 1 in IA32_VMX_CR4_FIXED0 are clear"]
+        );
+
+        vmcs.set(VmcsField::GuestCr4, CR4_PAE | CR4_VMXE | 1 << 22);
+        assert_eq!(
+            checks(&vmcs),
+            ["CR4 bits fixed to 0 in IA32_VMX_CR4_FIXED1 are set"]
+        );
+    }
+
+    #[test]
+    fn code_and_stack_segment_types() {
+        let mut vmcs = long_mode_guest();
+        // not accessed
+        vmcs.set(VmcsField::GuestCsAccessRights, AR_CODE_64 & !1);
+        assert_eq!(checks(&vmcs), ["CS type is not an accessed code segment"]);
+
+        let mut vmcs = long_mode_guest();
+        // a read-only data segment cannot be the stack
+        vmcs.set(VmcsField::GuestSsAccessRights, AR_DATA & !0b10);
+        assert_eq!(checks(&vmcs), ["SS type is not a read/write data segment"]);
+
+        let mut vmcs = long_mode_guest();
+        vmcs.set(VmcsField::GuestDsAccessRights, AR_DATA & !AR_P);
+        assert_eq!(checks(&vmcs), ["data segment is not present"]);
+
+        let mut vmcs = long_mode_guest();
+        vmcs.set(VmcsField::GuestCsAccessRights, AR_CODE_64 | AR_DB);
+        assert_eq!(checks(&vmcs), ["CS.D/B is set for a 64-bit code segment"]);
+    }
+
+    #[test]
+    fn unusable_segments_are_not_checked() {
+        let mut vmcs = long_mode_guest();
+        vmcs.set(VmcsField::GuestEsAccessRights, AR_UNUSABLE);
+        assert_eq!(checks(&vmcs), Vec::<&str>::new());
+
+        vmcs.set(VmcsField::GuestEsAccessRights, 0);
+        let checks = checks(&vmcs);
+        assert!(checks.contains(&"data segment type is not accessed"));
+        assert!(checks.contains(&"data segment is not present"));
+    }
+
+    #[test]
+    fn tr_type_and_usability() {
+        let mut vmcs = long_mode_guest();
+        // a busy 16-bit TSS is only allowed outside IA-32e mode
+        vmcs.set(VmcsField::GuestTrAccessRights, 0x83);
+        assert_eq!(checks(&vmcs), ["TR type is not a busy TSS"]);
+
+        let mut vmcs = long_mode_guest();
+        vmcs.set(VmcsField::GuestTrAccessRights, AR_BUSY_TSS_64 | AR_UNUSABLE);
+        assert_eq!(checks(&vmcs), ["TR is unusable"]);
+    }
+
+    #[test]
+    fn ldtr_type() {
+        let mut vmcs = long_mode_guest();
+        vmcs.set(VmcsField::GuestLdtrAccessRights, 0x82);
+        assert_eq!(checks(&vmcs), Vec::<&str>::new());
+
+        vmcs.set(VmcsField::GuestLdtrAccessRights, 0x83);
+        assert_eq!(checks(&vmcs), ["LDTR type is not LDT"]);
+    }
+
+    #[test]
+    fn activity_state() {
+        let mut vmcs = long_mode_guest();
+        vmcs.set(VmcsField::GuestActivityState, 4);
+        assert_eq!(
+            checks(&vmcs),
+            ["activity state is not one of active, HLT, shutdown or wait-for-SIPI"]
+        );
+
+        let mut vmcs = long_mode_guest();
+        vmcs.set(VmcsField::GuestActivityState, ACTIVITY_HLT)
+            .set(VmcsField::GuestSsAccessRights, AR_DATA | 3 << 5);
+        assert!(checks(&vmcs).contains(&"HLT activity state with SS.DPL other than 0"));
+
+        let mut vmcs = long_mode_guest();
+        vmcs.set(VmcsField::GuestActivityState, ACTIVITY_HLT)
+            .set(VmcsField::GuestRflags, 1 << 1 | RFLAGS_IF)
+            .set(VmcsField::GuestInterruptibilityState, INTR_BLOCKING_BY_STI);
+        assert_eq!(
+            checks(&vmcs),
+            ["blocking by STI or MOV SS while not active"]
+        );
+    }
+}
@@ -1,13 +1,16 @@
-use crate::BOOT_ARGS;
-use alloc::alloc::alloc;
-use bitflags::bitflags;
-use core::{
-    alloc::Layout,
-    ops::{Index, IndexMut},
-    slice,
+use crate::{
+    allocator,
+    arch::intel::{mtrr, sipi, tlb},
+    ioapic,
 };
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use crossbeam::atomic::AtomicCell;
 use x86_64::PhysAddr;
 
+/// EPT stays off unless a feature needs to intercept guest physical accesses.
+pub const ENABLE_EPT: bool = ioapic::TRAP_GUEST_ACCESS || sipi::UNRESTRICTED_GUEST;
+
 bitflags! {
     pub struct EptPointerFlags: u64 {
         const MEMORY_TYPE_UNCACHEABLE = 0;
@@ -45,89 +48,8 @@ impl EptPointer {
     pub fn as_u64(&self) -> u64 {
         self.0
     }
-
-    // pub fn walk_table(&self, guest_phys: PhysAddr) -> *mut EptTableEntry {
-    //     let pml4 = self.addr().as_u64();
-    // }
 }
 
-#[derive(Debug)]
-pub struct EptTable(*mut u8);
-
-impl EptTable {
-    pub fn new() -> Self {
-        let layout = Layout::from_size_align(4096, 4096).unwrap();
-        let table = unsafe { alloc(layout) };
-
-        Self(table)
-    }
-
-    pub fn zero(&mut self) {
-        let table = unsafe { slice::from_raw_parts_mut(self.0, 4096) };
-        table.fill(0);
-    }
-
-    pub fn as_mut_ptr(&self) -> *mut u8 {
-        self.0
-    }
-
-    pub fn paddr(&self) -> PhysAddr {
-        let virt = i64::try_from(self.as_mut_ptr() as u64).unwrap();
-        let phys = u64::try_from(virt + BOOT_ARGS.load().vmm_phys_offset).unwrap();
-        PhysAddr::new(phys)
-    }
-}
-
-impl Index<usize> for EptTable {
-    type Output = EptTableEntry;
-
-    fn index(&self, index: usize) -> &Self::Output {
-        let table = unsafe { slice::from_raw_parts(self.0 as *const EptTableEntry, 512) };
-        &table[index]
-    }
-}
-
-impl IndexMut<usize> for EptTable {
-    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        let table = unsafe { slice::from_raw_parts_mut(self.0 as *mut EptTableEntry, 512) };
-        &mut table[index]
-    }
-}
-
-// #[derive(Clone, Copy, Debug)]
-// #[repr(C, align(4096))]
-// pub struct EptTable {
-//     entries: [EptTableEntry; 512],
-// }
-
-// impl EptTable {
-//     fn new() -> Self {
-//         Self {
-//             entries: [EptTableEntry::new(); 512],
-//         }
-//     }
-
-//     pub fn paddr(&self) -> PhysAddr {
-//         let virt = i64::try_from(self as *const Self as u64).unwrap();
-//         let phys = u64::try_from(virt + BOOT_ARGS.load().vmm_phys_offset).unwrap();
-//         PhysAddr::new(phys)
-//     }
-// }
-
-// impl Index<usize> for EptTable {
-//     type Output = EptTableEntry;
-
-//     fn index(&self, index: usize) -> &Self::Output {
-//         &self.entries[index]
-//     }
-// }
-
-// impl IndexMut<usize> for EptTable {
-//     fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-//         &mut self.entries[index]
-//     }
-// }
-
 #[derive(Clone, Copy, Debug)]
 #[repr(C)]
 pub struct EptTableEntry(u64);
@@ -138,7 +60,6 @@ impl EptTableEntry {
         Self(0)
     }
 
-    #[allow(unused)]
     pub const fn is_unused(&self) -> bool {
         self.0 == 0
     }
@@ -160,6 +81,7 @@ impl EptTableEntry {
         PhysAddr::new(self.0 & 0x000f_ffff_ffff_f000)
     }
 
+    #[allow(unused)]
     pub fn set_addr(&mut self, paddr: PhysAddr) {
         self.0 = paddr.as_u64() | self.flags().bits();
     }
@@ -187,48 +109,819 @@ bitflags! {
     }
 }
 
-pub fn init_ept() -> EptPointer {
-    let memory_size = BOOT_ARGS.load().memory_size;
-    let memory_size_gb = memory_size / (1024 * 1024 * 1024);
+/// Access permissions of a leaf, as opposed to its memory type and status bits.
+const PERMISSIONS: EptTableFlags = EptTableFlags::from_bits_truncate(
+    EptTableFlags::READ_ACCESS.bits()
+        | EptTableFlags::WRITE_ACCESS.bits()
+        | EptTableFlags::EXECUTE_ACCESS.bits()
+        | EptTableFlags::EXECUTE_ACCESS_USER_MODE.bits(),
+);
+const MEMORY_TYPE_MASK: u64 = 0b111 << 3;
+/// Entries that reference a table pass all accesses, the leaves decide.
+const TABLE_FLAGS: EptTableFlags = EptTableFlags::from_bits_truncate(
+    EptTableFlags::READ_ACCESS.bits()
+        | EptTableFlags::WRITE_ACCESS.bits()
+        | EptTableFlags::EXECUTE_ACCESS.bits(),
+);
+
+/// EPT memory types (SDM Vol. 3C, 29.3.7).
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryType {
+    Uncacheable = 0,
+    WriteCombining = 1,
+    WriteThrough = 4,
+    WriteProtected = 5,
+    WriteBack = 6,
+}
+
+impl MemoryType {
+    pub fn flags(self) -> EptTableFlags {
+        EptTableFlags::from_bits_truncate((self as u64) << 3)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PageSize {
+    Size4K,
+    Size2M,
+    Size1G,
+}
 
-    let mut ept_pml4 = EptTable::new();
-    ept_pml4.zero();
+impl PageSize {
+    pub const fn bytes(self) -> u64 {
+        match self {
+            PageSize::Size4K => 1 << 12,
+            PageSize::Size2M => 1 << 21,
+            PageSize::Size1G => 1 << 30,
+        }
+    }
 
-    let mut ept_pdpt = EptTable::new();
-    ept_pdpt.zero();
+    /// Level of the tables whose entries map pages of this size, 1 being the page table.
+    const fn level(self) -> usize {
+        match self {
+            PageSize::Size4K => 1,
+            PageSize::Size2M => 2,
+            PageSize::Size1G => 3,
+        }
+    }
 
-    ept_pml4[0].set_addr(ept_pdpt.paddr());
-    ept_pml4[0].set_flags(
-        EptTableFlags::READ_ACCESS | EptTableFlags::WRITE_ACCESS | EptTableFlags::EXECUTE_ACCESS,
-    );
+    const fn from_level(level: usize) -> Self {
+        match level {
+            1 => PageSize::Size4K,
+            2 => PageSize::Size2M,
+            _ => PageSize::Size1G,
+        }
+    }
 
-    for i_pdpt in 0..(memory_size_gb as usize) {
-        let mut ept_pdt = EptTable::new();
+    const fn smaller(self) -> Option<Self> {
+        match self {
+            PageSize::Size4K => None,
+            PageSize::Size2M => Some(PageSize::Size4K),
+            PageSize::Size1G => Some(PageSize::Size2M),
+        }
+    }
+}
 
-        ept_pdpt[i_pdpt].set_addr(ept_pdt.paddr());
-        ept_pdpt[i_pdpt].set_flags(
-            EptTableFlags::READ_ACCESS
-                | EptTableFlags::WRITE_ACCESS
-                | EptTableFlags::EXECUTE_ACCESS,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EptError {
+    /// The frame allocator could not supply a table.
+    OutOfFrames,
+    NotMapped {
+        gpa: u64,
+    },
+    /// A 1 GiB page was requested without the processor supporting them.
+    HugePagesUnsupported,
+    /// `gpa` or `hpa` is not aligned to the page size.
+    Misaligned {
+        gpa: u64,
+        hpa: u64,
+    },
+}
+
+impl core::fmt::Display for EptError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            EptError::OutOfFrames => write!(f, "out of frames for EPT tables"),
+            EptError::NotMapped { gpa } => write!(f, "GPA 0x{gpa:x} is not mapped by the EPT"),
+            EptError::HugePagesUnsupported => write!(f, "1 GiB EPT pages are not supported"),
+            EptError::Misaligned { gpa, hpa } => {
+                write!(
+                    f,
+                    "GPA 0x{gpa:x} or HPA 0x{hpa:x} is not aligned to the page"
+                )
+            }
+        }
+    }
+}
+
+/// Hands out the 4 KiB frames that hold EPT tables, says where the VMM can access them, and
+/// drops the translations the processor cached from them.
+pub trait FrameAllocator {
+    /// Returns a zeroed frame.
+    fn allocate_frame(&mut self) -> Option<PhysAddr>;
+    fn deallocate_frame(&mut self, frame: PhysAddr);
+    fn frame_ptr(&self, frame: PhysAddr) -> *mut u8;
+    /// Called after entries reachable from `eptp` lost permissions or changed their frame or
+    /// memory type.
+    fn invalidate(&mut self, eptp: EptPointer);
+}
+
+/// Takes EPT tables from the VMM heap.
+#[derive(Debug)]
+pub struct VmmFrameAllocator;
+
+impl FrameAllocator for VmmFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysAddr> {
+        allocator::alloc_pages(1).map(allocator::virt_to_phys)
+    }
+
+    fn deallocate_frame(&mut self, frame: PhysAddr) {
+        unsafe { allocator::free_pages(self.frame_ptr(frame), 1) };
+    }
+
+    fn frame_ptr(&self, frame: PhysAddr) -> *mut u8 {
+        allocator::phys_to_virt(frame)
+    }
+
+    fn invalidate(&mut self, eptp: EptPointer) {
+        tlb::flush_ept(eptp.as_u64());
+    }
+}
+
+/// Guest-physical addresses a 4-level EPT can map.
+pub const GPA_LIMIT: u64 = 1 << 48;
+
+fn table_index(gpa: u64, level: usize) -> usize {
+    ((gpa >> (12 + 9 * (level - 1))) & 0x1ff) as usize
+}
+
+/// Bytes covered by one entry of a table at `level`.
+const fn level_span(level: usize) -> u64 {
+    1 << (12 + 9 * (level - 1))
+}
+
+/// A 4-level EPT hierarchy and the frames it is built from.
+pub struct Ept<A: FrameAllocator> {
+    allocator: A,
+    root: PhysAddr,
+    /// Whether 1 GiB leaves may be used (IA32_VMX_EPT_VPID_CAP bit 17).
+    pages_1g: bool,
+}
+
+impl<A: FrameAllocator> Ept<A> {
+    pub fn new(mut allocator: A, pages_1g: bool) -> Result<Self, EptError> {
+        let root = allocator.allocate_frame().ok_or(EptError::OutOfFrames)?;
+        Ok(Self {
+            allocator,
+            root,
+            pages_1g,
+        })
+    }
+
+    pub fn eptp(&self) -> EptPointer {
+        let mut eptp = EptPointer::new();
+        eptp.set_addr(self.root);
+        eptp.set_flags(
+            EptPointerFlags::MEMORY_TYPE_WRITEBACK | EptPointerFlags::PAGE_WALK_LENGTH_4,
         );
+        eptp
+    }
+
+    fn invalidate(&mut self) {
+        let eptp = self.eptp();
+        self.allocator.invalidate(eptp);
+    }
+
+    fn table(&self, frame: PhysAddr) -> &'static mut [EptTableEntry; 512] {
+        unsafe {
+            (self.allocator.frame_ptr(frame) as *mut [EptTableEntry; 512])
+                .as_mut()
+                .unwrap()
+        }
+    }
+
+    /// Walks towards `gpa` and returns the entry it stops at, with its level: a leaf, an
+    /// unused entry, or the entry at `stop_level`.
+    fn walk(&self, gpa: u64, stop_level: usize) -> (&'static mut EptTableEntry, usize) {
+        let mut table = self.table(self.root);
+        let mut level = 4;
+        loop {
+            let entry = &mut table[table_index(gpa, level)];
+            if level == stop_level
+                || level == 1
+                || entry.is_unused()
+                || entry.flags().contains(EptTableFlags::HUGE_PAGE)
+            {
+                return (entry, level);
+            }
+            table = self.table(entry.addr());
+            level -= 1;
+        }
+    }
+
+    /// Returns the leaf entry that maps `gpa` and the size of the page it maps.
+    pub fn leaf(&mut self, gpa: PhysAddr) -> Option<(&mut EptTableEntry, PageSize)> {
+        let (entry, level) = self.walk(gpa.as_u64(), 1);
+        if entry.is_unused() {
+            return None;
+        }
+        Some((entry, PageSize::from_level(level)))
+    }
+
+    /// Translates a GPA to the host physical address it is mapped to.
+    #[allow(unused)]
+    pub fn translate(&self, gpa: PhysAddr) -> Option<(PhysAddr, EptTableFlags, PageSize)> {
+        let (entry, level) = self.walk(gpa.as_u64(), 1);
+        if entry.is_unused() {
+            return None;
+        }
+        let size = PageSize::from_level(level);
+        let offset = gpa.as_u64() & (size.bytes() - 1);
+        Some((entry.addr() + offset, entry.flags(), size))
+    }
+
+    /// Maps the page of `size` at `gpa` to `hpa`, creating tables on the way and splitting
+    /// larger pages that are in the way.
+    #[allow(unused)]
+    pub fn map(
+        &mut self,
+        gpa: PhysAddr,
+        hpa: PhysAddr,
+        size: PageSize,
+        flags: EptTableFlags,
+    ) -> Result<(), EptError> {
+        let mut unlinked = Vec::new();
+        let result = self.map_page(gpa.as_u64(), hpa, size, flags, &mut unlinked);
+        self.invalidate_and_free(unlinked);
+        result
+    }
+
+    /// Maps one page. Tables the new leaf replaces are added to `unlinked`, for the caller
+    /// to free once the processor no longer caches them.
+    fn map_page(
+        &mut self,
+        gpa: u64,
+        hpa: PhysAddr,
+        size: PageSize,
+        flags: EptTableFlags,
+        unlinked: &mut Vec<PhysAddr>,
+    ) -> Result<(), EptError> {
+        if size == PageSize::Size1G && !self.pages_1g {
+            return Err(EptError::HugePagesUnsupported);
+        }
+        if (gpa | hpa.as_u64()) & (size.bytes() - 1) != 0 {
+            return Err(EptError::Misaligned {
+                gpa,
+                hpa: hpa.as_u64(),
+            });
+        }
+        loop {
+            let (entry, level) = self.walk(gpa, size.level());
+            if level == size.level() {
+                if !entry.is_unused()
+                    && level > 1
+                    && !entry.flags().contains(EptTableFlags::HUGE_PAGE)
+                {
+                    self.collect_tables(entry.addr(), level - 1, unlinked);
+                }
+                let huge = if size == PageSize::Size4K {
+                    EptTableFlags::empty()
+                } else {
+                    EptTableFlags::HUGE_PAGE
+                };
+                *entry = EptTableEntry(hpa.as_u64() | (flags | huge).bits());
+                return Ok(());
+            }
+            if entry.is_unused() {
+                let frame = self
+                    .allocator
+                    .allocate_frame()
+                    .ok_or(EptError::OutOfFrames)?;
+                *entry = EptTableEntry(frame.as_u64() | TABLE_FLAGS.bits());
+            } else {
+                self.split_entry(entry, PageSize::from_level(level))?;
+            }
+        }
+    }
+
+    /// Adds a table at `level` and the tables below it to `frames`.
+    fn collect_tables(&self, frame: PhysAddr, level: usize, frames: &mut Vec<PhysAddr>) {
+        if level > 1 {
+            for entry in self.table(frame).iter() {
+                if !entry.is_unused() && !entry.flags().contains(EptTableFlags::HUGE_PAGE) {
+                    self.collect_tables(entry.addr(), level - 1, frames);
+                }
+            }
+        }
+        frames.push(frame);
+    }
+
+    /// Invalidates, then frees the tables that were unlinked. Until the invalidation the
+    /// paging-structure caches may still point into them.
+    fn invalidate_and_free(&mut self, unlinked: Vec<PhysAddr>) {
+        self.invalidate();
+        for frame in unlinked {
+            self.allocator.deallocate_frame(frame);
+        }
+    }
+
+    /// Identity maps `[0, size)` with the largest pages available.
+    pub fn identity_map(&mut self, size: u64, flags: EptTableFlags) -> Result<(), EptError> {
+        let page = if self.pages_1g {
+            PageSize::Size1G
+        } else {
+            PageSize::Size2M
+        };
+        let mut unlinked = Vec::new();
+        let mut result = Ok(());
+        let mut gpa = 0;
+        while gpa < size && result.is_ok() {
+            result = self.map_page(gpa, PhysAddr::new(gpa), page, flags, &mut unlinked);
+            gpa += page.bytes();
+        }
+        self.invalidate_and_free(unlinked);
+        result
+    }
 
-        for i_pdt in 0..512 {
-            ept_pdt[i_pdt].set_addr(PhysAddr::new(
-                (2 * 1024 * 1024 * i_pdt as u64) + (1024 * 1024 * 1024 * i_pdpt as u64),
-            ));
-            ept_pdt[i_pdt].set_flags(
-                EptTableFlags::HUGE_PAGE
-                    | EptTableFlags::READ_ACCESS
-                    | EptTableFlags::WRITE_ACCESS
-                    | EptTableFlags::EXECUTE_ACCESS
-                    | EptTableFlags::MEMORY_TYPE_WB,
-            );
+    /// Replaces the leaf `entry`, which maps a page of `size`, with a table of smaller pages
+    /// that translate the same way.
+    fn split_entry(&mut self, entry: &mut EptTableEntry, size: PageSize) -> Result<(), EptError> {
+        let child = size.smaller().unwrap();
+        let frame = self
+            .allocator
+            .allocate_frame()
+            .ok_or(EptError::OutOfFrames)?;
+        let flags = if child == PageSize::Size4K {
+            entry.flags() - EptTableFlags::HUGE_PAGE
+        } else {
+            entry.flags()
+        };
+        let base = entry.addr().as_u64();
+        for (i, child_entry) in self.table(frame).iter_mut().enumerate() {
+            *child_entry = EptTableEntry((base + i as u64 * child.bytes()) | flags.bits());
         }
+        *entry = EptTableEntry(frame.as_u64() | TABLE_FLAGS.bits());
+        Ok(())
     }
 
-    let mut eptp = EptPointer::new();
-    eptp.set_addr(ept_pml4.paddr());
-    eptp.set_flags(EptPointerFlags::MEMORY_TYPE_WRITEBACK | EptPointerFlags::PAGE_WALK_LENGTH_4);
+    /// Splits the page around `gpa` until it is mapped by a page no larger than `size`.
+    /// Translations do not change, so no invalidation is needed.
+    pub fn split(&mut self, gpa: PhysAddr, size: PageSize) -> Result<(), EptError> {
+        loop {
+            let (entry, level) = self.walk(gpa.as_u64(), 1);
+            if entry.is_unused() {
+                return Err(EptError::NotMapped { gpa: gpa.as_u64() });
+            }
+            let leaf_size = PageSize::from_level(level);
+            if leaf_size <= size {
+                return Ok(());
+            }
+            self.split_entry(entry, leaf_size)?;
+        }
+    }
+
+    /// Turns the table under the `size` region around `gpa` back into a single leaf if its
+    /// entries map contiguous, aligned memory with the same attributes. Returns whether it
+    /// did.
+    #[allow(unused)]
+    pub fn merge(&mut self, gpa: PhysAddr, size: PageSize) -> bool {
+        if size == PageSize::Size4K || (size == PageSize::Size1G && !self.pages_1g) {
+            return false;
+        }
+        let (entry, level) = self.walk(gpa.as_u64(), size.level());
+        if level != size.level()
+            || entry.is_unused()
+            || entry.flags().contains(EptTableFlags::HUGE_PAGE)
+        {
+            return false;
+        }
+        let child = size.smaller().unwrap();
+        let child_huge = child != PageSize::Size4K;
+        let status = EptTableFlags::ACCESSED | EptTableFlags::DIRTY;
+        let table = self.table(entry.addr());
+        let base = table[0].addr().as_u64();
+        let flags = table[0].flags() - status;
+        if base & (size.bytes() - 1) != 0 {
+            return false;
+        }
+        let mergeable = table.iter().enumerate().all(|(i, child_entry)| {
+            !child_entry.is_unused()
+                && child_entry.flags().contains(EptTableFlags::HUGE_PAGE) == child_huge
+                && child_entry.flags() - status == flags
+                && child_entry.addr().as_u64() == base + i as u64 * child.bytes()
+        });
+        if !mergeable {
+            return false;
+        }
+        let frame = entry.addr();
+        *entry = EptTableEntry(base | (flags | EptTableFlags::HUGE_PAGE).bits());
+        // The old table may still be cached until the invalidation.
+        self.invalidate();
+        self.allocator.deallocate_frame(frame);
+        true
+    }
+
+    /// Sets the memory type of the pages in `[start, end)`. `memory_type` returns the type of
+    /// a whole page, or `None` if parts of it differ and it has to be split; 4 KiB pages always
+    /// have one. IGNORE_PAT_MEMORY_TYPE is cleared, so the guest's PAT combines with the type
+    /// the way it does with the MTRRs on bare metal.
+    pub fn set_memory_types(
+        &mut self,
+        start: u64,
+        end: u64,
+        memory_type: impl Fn(u64, PageSize) -> Option<MemoryType>,
+    ) -> Result<(), EptError> {
+        let mut gpa = start & !(PageSize::Size4K.bytes() - 1);
+        while gpa < end {
+            let (entry, level) = self.walk(gpa, 1);
+            if entry.is_unused() {
+                gpa = (gpa | (level_span(level) - 1)) + 1;
+                continue;
+            }
+            let size = PageSize::from_level(level);
+            let base = gpa & !(size.bytes() - 1);
+            match memory_type(base, size) {
+                Some(memory_type) => {
+                    let flags = entry.flags().bits()
+                        & !MEMORY_TYPE_MASK
+                        & !EptTableFlags::IGNORE_PAT_MEMORY_TYPE.bits();
+                    entry.set_flags(EptTableFlags::from_bits_truncate(flags) | memory_type.flags());
+                    gpa = base + size.bytes();
+                }
+                None => self.split_entry(entry, size)?,
+            }
+        }
+        self.invalidate();
+        Ok(())
+    }
 
-    eptp
+    fn update_4k(
+        &mut self,
+        gpa: PhysAddr,
+        update: impl FnOnce(EptTableFlags) -> EptTableFlags,
+    ) -> Result<(), EptError> {
+        self.split(gpa, PageSize::Size4K)?;
+        let (entry, _) = self.leaf(gpa).unwrap();
+        let flags = update(entry.flags());
+        entry.set_flags(flags);
+        self.invalidate();
+        Ok(())
+    }
+
+    /// Sets the R/W/X permissions of the 4 KiB page containing `gpa`.
+    pub fn set_permissions(
+        &mut self,
+        gpa: PhysAddr,
+        permissions: EptTableFlags,
+    ) -> Result<(), EptError> {
+        self.update_4k(gpa, |flags| {
+            (flags - PERMISSIONS) | (permissions & PERMISSIONS)
+        })
+    }
+
+    /// Sets the memory type of the 4 KiB page containing `gpa`.
+    #[allow(unused)]
+    pub fn set_memory_type(
+        &mut self,
+        gpa: PhysAddr,
+        memory_type: MemoryType,
+    ) -> Result<(), EptError> {
+        self.update_4k(gpa, |flags| {
+            EptTableFlags::from_bits_truncate(flags.bits() & !MEMORY_TYPE_MASK)
+                | memory_type.flags()
+        })
+    }
+}
+
+static EPT: AtomicCell<Option<Ept<VmmFrameAllocator>>> = AtomicCell::new(None);
+
+/// The EPT the guest runs on. Only valid after `init_ept`.
+pub fn ept() -> &'static mut Ept<VmmFrameAllocator> {
+    unsafe { EPT.as_ptr().as_mut().unwrap().as_mut().unwrap() }
+}
+
+/// Builds the EPT for `memory_size` bytes of RAM. `pages_1g` says whether the processor
+/// supports 1 GiB pages.
+pub fn init_ept(memory_size: u64, pages_1g: bool) -> Result<EptPointer, EptError> {
+    // map at least the 32-bit MMIO hole, which holds the IOAPIC and the local APIC
+    let memory_size_gb = ((memory_size + (1 << 30) - 1) >> 30).max(4);
+
+    EPT.store(Some(Ept::new(VmmFrameAllocator, pages_1g)?));
+    let ept = ept();
+    ept.identity_map(
+        memory_size_gb << 30,
+        TABLE_FLAGS | MemoryType::WriteBack.flags(),
+    )?;
+    mtrr::apply(ept, 0, GPA_LIMIT)?;
+    Ok(ept.eptp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RWX: EptTableFlags = TABLE_FLAGS;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Op {
+        Free(PhysAddr),
+        Invalidate,
+    }
+
+    /// Frames live on the host heap. Frame `n` has the physical address `n * 4 KiB`, starting
+    /// at 1 so no table sits at 0.
+    #[derive(Default)]
+    struct VecFrameAllocator {
+        frames: Vec<*mut [EptTableEntry; 512]>,
+        freed: Vec<PhysAddr>,
+        invalidations: usize,
+        ops: Vec<Op>,
+    }
+
+    impl FrameAllocator for VecFrameAllocator {
+        fn allocate_frame(&mut self) -> Option<PhysAddr> {
+            self.frames
+                .push(Box::into_raw(Box::new([EptTableEntry::new(); 512])));
+            Some(PhysAddr::new((self.frames.len() as u64) << 12))
+        }
+
+        fn deallocate_frame(&mut self, frame: PhysAddr) {
+            self.freed.push(frame);
+            self.ops.push(Op::Free(frame));
+        }
+
+        fn frame_ptr(&self, frame: PhysAddr) -> *mut u8 {
+            self.frames[(frame.as_u64() >> 12) as usize - 1] as *mut u8
+        }
+
+        fn invalidate(&mut self, _eptp: EptPointer) {
+            self.invalidations += 1;
+            self.ops.push(Op::Invalidate);
+        }
+    }
+
+    impl Drop for VecFrameAllocator {
+        fn drop(&mut self) {
+            for frame in self.frames.drain(..) {
+                drop(unsafe { Box::from_raw(frame) });
+            }
+        }
+    }
+
+    fn memory_type(flags: EptTableFlags) -> u64 {
+        (flags.bits() & MEMORY_TYPE_MASK) >> 3
+    }
+
+    /// Translation of `gpa`: host address, page size and memory type.
+    fn lookup(ept: &Ept<VecFrameAllocator>, gpa: u64) -> Option<(u64, PageSize, u64)> {
+        ept.translate(PhysAddr::new(gpa))
+            .map(|(hpa, flags, size)| (hpa.as_u64(), size, memory_type(flags)))
+    }
+
+    #[test]
+    fn map_translates_and_invalidates() {
+        let mut ept = Ept::new(VecFrameAllocator::default(), false).unwrap();
+        let flags = RWX | MemoryType::WriteBack.flags();
+        ept.map(
+            PhysAddr::new(0x1234_5000),
+            PhysAddr::new(0x8765_4000),
+            PageSize::Size4K,
+            flags,
+        )
+        .unwrap();
+        assert_eq!(
+            lookup(&ept, 0x1234_5123),
+            Some((0x8765_4123, PageSize::Size4K, 6))
+        );
+        assert_eq!(lookup(&ept, 0x1234_6000), None);
+        assert_eq!(ept.allocator.invalidations, 1);
+
+        ept.map(
+            PhysAddr::new(0x4000_0000),
+            PhysAddr::new(0x20_0000),
+            PageSize::Size2M,
+            flags,
+        )
+        .unwrap();
+        assert_eq!(
+            lookup(&ept, 0x401f_ffff),
+            Some((0x3f_ffff, PageSize::Size2M, 6))
+        );
+    }
+
+    #[test]
+    fn map_rejects_unsupported_and_misaligned_pages() {
+        let mut ept = Ept::new(VecFrameAllocator::default(), false).unwrap();
+        assert_eq!(
+            ept.map(PhysAddr::new(0), PhysAddr::new(0), PageSize::Size1G, RWX),
+            Err(EptError::HugePagesUnsupported)
+        );
+        assert_eq!(
+            ept.map(
+                PhysAddr::new(0x1000),
+                PhysAddr::new(0x20_0000),
+                PageSize::Size2M,
+                RWX
+            ),
+            Err(EptError::Misaligned {
+                gpa: 0x1000,
+                hpa: 0x20_0000
+            })
+        );
+    }
+
+    #[test]
+    fn split_and_merge_back() {
+        let mut ept = Ept::new(VecFrameAllocator::default(), true).unwrap();
+        ept.identity_map(1 << 30, RWX | MemoryType::WriteBack.flags())
+            .unwrap();
+        assert_eq!(
+            lookup(&ept, 0x20_3000),
+            Some((0x20_3000, PageSize::Size1G, 6))
+        );
+
+        ept.split(PhysAddr::new(0x20_3000), PageSize::Size4K)
+            .unwrap();
+        assert_eq!(
+            lookup(&ept, 0x20_3000),
+            Some((0x20_3000, PageSize::Size4K, 6))
+        );
+        assert_eq!(
+            lookup(&ept, 0x40_0000),
+            Some((0x40_0000, PageSize::Size2M, 6))
+        );
+        assert_eq!(
+            ept.split(PhysAddr::new(1 << 30), PageSize::Size4K),
+            Err(EptError::NotMapped { gpa: 1 << 30 })
+        );
+
+        // the 1 GiB region still holds a table, so it cannot be a single page yet
+        assert!(!ept.merge(PhysAddr::new(0), PageSize::Size1G));
+        assert!(ept.merge(PhysAddr::new(0x20_0000), PageSize::Size2M));
+        assert_eq!(
+            lookup(&ept, 0x20_3000),
+            Some((0x20_3000, PageSize::Size2M, 6))
+        );
+        assert!(ept.merge(PhysAddr::new(0), PageSize::Size1G));
+        assert_eq!(
+            lookup(&ept, 0x20_3000),
+            Some((0x20_3000, PageSize::Size1G, 6))
+        );
+        assert_eq!(ept.allocator.freed.len(), 2);
+    }
+
+    #[test]
+    fn merge_keeps_differing_pages() {
+        let mut ept = Ept::new(VecFrameAllocator::default(), false).unwrap();
+        ept.identity_map(4 << 20, RWX | MemoryType::WriteBack.flags())
+            .unwrap();
+        ept.set_permissions(PhysAddr::new(0x20_1000), EptTableFlags::READ_ACCESS)
+            .unwrap();
+        assert!(!ept.merge(PhysAddr::new(0x20_0000), PageSize::Size2M));
+        assert!(!ept.merge(PhysAddr::new(0), PageSize::Size1G));
+        assert!(ept.allocator.freed.is_empty());
+    }
+
+    #[test]
+    fn set_permissions_changes_one_page() {
+        let mut ept = Ept::new(VecFrameAllocator::default(), false).unwrap();
+        ept.identity_map(4 << 20, RWX | MemoryType::WriteBack.flags())
+            .unwrap();
+        let invalidations = ept.allocator.invalidations;
+        ept.set_permissions(PhysAddr::new(0x20_1234), EptTableFlags::READ_ACCESS)
+            .unwrap();
+
+        let (_, flags, size) = ept.translate(PhysAddr::new(0x20_1000)).unwrap();
+        assert_eq!(size, PageSize::Size4K);
+        assert_eq!(flags & PERMISSIONS, EptTableFlags::READ_ACCESS);
+        assert_eq!(memory_type(flags), 6);
+        assert!(!flags.contains(EptTableFlags::HUGE_PAGE));
+        let (_, flags, _) = ept.translate(PhysAddr::new(0x20_2000)).unwrap();
+        assert_eq!(flags & PERMISSIONS, RWX);
+        assert_eq!(ept.allocator.invalidations, invalidations + 1);
+    }
+
+    #[test]
+    fn set_memory_types_splits_mixed_pages() {
+        let mut ept = Ept::new(VecFrameAllocator::default(), false).unwrap();
+        ept.identity_map(
+            4 << 20,
+            RWX | MemoryType::WriteBack.flags() | EptTableFlags::IGNORE_PAT_MEMORY_TYPE,
+        )
+        .unwrap();
+        // Only the first two 4 KiB pages change, so the 2 MiB page around them is mixed.
+        ept.set_memory_types(0x20_0000, 0x20_2000, |base, size| match size {
+            PageSize::Size4K if base == 0x20_0000 => Some(MemoryType::Uncacheable),
+            PageSize::Size4K => Some(MemoryType::WriteThrough),
+            _ => None,
+        })
+        .unwrap();
+
+        assert_eq!(
+            lookup(&ept, 0x20_0000),
+            Some((0x20_0000, PageSize::Size4K, 0))
+        );
+        assert_eq!(
+            lookup(&ept, 0x20_1000),
+            Some((0x20_1000, PageSize::Size4K, 4))
+        );
+        assert_eq!(
+            lookup(&ept, 0x20_2000),
+            Some((0x20_2000, PageSize::Size4K, 6))
+        );
+        assert_eq!(lookup(&ept, 0), Some((0, PageSize::Size2M, 6)));
+        let (_, flags, _) = ept.translate(PhysAddr::new(0x20_0000)).unwrap();
+        assert!(!flags.contains(EptTableFlags::IGNORE_PAT_MEMORY_TYPE));
+    }
+
+    #[test]
+    fn mapping_over_tables_frees_them() {
+        let mut ept = Ept::new(VecFrameAllocator::default(), true).unwrap();
+        ept.map(
+            PhysAddr::new(0x4000_1000),
+            PhysAddr::new(0x1000),
+            PageSize::Size4K,
+            RWX,
+        )
+        .unwrap();
+        // root, PDPT, PD and PT
+        assert_eq!(ept.allocator.frames.len(), 4);
+
+        ept.map(
+            PhysAddr::new(0x4000_0000),
+            PhysAddr::new(0),
+            PageSize::Size2M,
+            RWX,
+        )
+        .unwrap();
+        assert_eq!(ept.allocator.freed, [PhysAddr::new(4 << 12)]);
+
+        ept.map(
+            PhysAddr::new(0x4020_0000),
+            PhysAddr::new(0x20_0000),
+            PageSize::Size4K,
+            RWX,
+        )
+        .unwrap();
+        ept.map(
+            PhysAddr::new(0x4000_0000),
+            PhysAddr::new(0),
+            PageSize::Size1G,
+            RWX,
+        )
+        .unwrap();
+        // the PD and the PT allocated under it
+        assert_eq!(
+            ept.allocator.freed,
+            [
+                PhysAddr::new(4 << 12),
+                PhysAddr::new(5 << 12),
+                PhysAddr::new(3 << 12)
+            ]
+        );
+        assert_eq!(
+            lookup(&ept, 0x4020_1000),
+            Some((0x20_1000, PageSize::Size1G, 0))
+        );
+    }
+
+    #[test]
+    fn unlinked_tables_are_freed_after_invalidation() {
+        let mut ept = Ept::new(VecFrameAllocator::default(), true).unwrap();
+        ept.map(
+            PhysAddr::new(0x1000),
+            PhysAddr::new(0x1000),
+            PageSize::Size4K,
+            RWX,
+        )
+        .unwrap();
+        ept.allocator.ops.clear();
+        ept.map(PhysAddr::new(0), PhysAddr::new(0), PageSize::Size1G, RWX)
+            .unwrap();
+        assert_eq!(
+            ept.allocator.ops,
+            [
+                Op::Invalidate,
+                Op::Free(PhysAddr::new(4 << 12)),
+                Op::Free(PhysAddr::new(3 << 12))
+            ]
+        );
+
+        ept.split(PhysAddr::new(0), PageSize::Size2M).unwrap();
+        ept.allocator.ops.clear();
+        assert!(ept.merge(PhysAddr::new(0), PageSize::Size1G));
+        assert_eq!(
+            ept.allocator.ops,
+            [Op::Invalidate, Op::Free(PhysAddr::new(5 << 12))]
+        );
+
+        ept.split(PhysAddr::new(0), PageSize::Size2M).unwrap();
+        ept.allocator.ops.clear();
+        ept.identity_map(1 << 30, RWX).unwrap();
+        assert_eq!(
+            ept.allocator.ops,
+            [Op::Invalidate, Op::Free(PhysAddr::new(6 << 12))]
+        );
+    }
 }
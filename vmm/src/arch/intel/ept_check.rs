@@ -0,0 +1,409 @@
+use crate::arch::intel::vmx_caps::VmxCaps;
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+/// Processor properties that decide whether an EPT entry is valid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EptCheckEnv {
+    pub physical_address_width: u8,
+    pub execute_only: bool,
+    pub pages_1g: bool,
+}
+
+impl EptCheckEnv {
+    pub fn from_hardware() -> Self {
+        let address_size = unsafe { core::arch::x86_64::__cpuid(0x8000_0008) }.eax;
+        let caps = VmxCaps::read();
+        Self {
+            physical_address_width: address_size as u8,
+            execute_only: caps.ept_vpid.execute_only(),
+            pages_1g: caps.ept_vpid.pdpte_1g(),
+        }
+    }
+}
+
+const ENTRY_READ: u64 = 1 << 0;
+const ENTRY_WRITE: u64 = 1 << 1;
+const ENTRY_EXECUTE: u64 = 1 << 2;
+const ENTRY_LARGE: u64 = 1 << 7;
+const ENTRY_ADDRESS_LIMIT: u64 = 1 << 52;
+const MEMORY_TYPE_SHIFT: u64 = 3;
+
+/// Why the processor refuses an EPT entry (SDM Vol. 3C, 29.3.3.1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misconfiguration {
+    WriteWithoutRead,
+    ExecuteOnly,
+    /// Address bits at or above MAXPHYADDR.
+    AddressBeyondWidth {
+        bits: u64,
+    },
+    ReservedBits {
+        bits: u64,
+    },
+    Page1GUnsupported,
+    MemoryType {
+        memory_type: u8,
+    },
+}
+
+impl core::fmt::Display for Misconfiguration {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Misconfiguration::WriteWithoutRead => write!(f, "writable but not readable"),
+            Misconfiguration::ExecuteOnly => {
+                write!(f, "execute-only, which the processor does not support")
+            }
+            Misconfiguration::AddressBeyondWidth { bits } => {
+                write!(f, "address bits 0x{bits:x} beyond MAXPHYADDR are set")
+            }
+            Misconfiguration::ReservedBits { bits } => {
+                write!(f, "reserved bits 0x{bits:x} are set")
+            }
+            Misconfiguration::Page1GUnsupported => {
+                write!(f, "maps a 1 GiB page, which the processor does not support")
+            }
+            Misconfiguration::MemoryType { memory_type } => {
+                write!(f, "invalid memory type {memory_type}")
+            }
+        }
+    }
+}
+
+pub fn level_name(level: usize) -> &'static str {
+    match level {
+        1 => "PTE",
+        2 => "PDE",
+        3 => "PDPTE",
+        _ => "PML4E",
+    }
+}
+
+/// An entry the processor maps a GPA with is present if it grants any access.
+pub fn is_present(entry: u64) -> bool {
+    entry & (ENTRY_READ | ENTRY_WRITE | ENTRY_EXECUTE) != 0
+}
+
+/// The R/W/X bits of an entry, as in `rw-`.
+pub fn permissions(entry: u64) -> [char; 3] {
+    let flag = |bit: u64, c: char| if entry & bit != 0 { c } else { '-' };
+    [
+        flag(ENTRY_READ, 'r'),
+        flag(ENTRY_WRITE, 'w'),
+        flag(ENTRY_EXECUTE, 'x'),
+    ]
+}
+
+pub fn is_leaf(entry: u64, level: usize) -> bool {
+    level == 1 || (level < 4 && entry & ENTRY_LARGE != 0)
+}
+
+/// Checks a present entry of the table at `level`, 4 being the PML4.
+pub fn check_entry(entry: u64, level: usize, env: &EptCheckEnv) -> Vec<Misconfiguration> {
+    let mut problems = Vec::new();
+    if entry & ENTRY_WRITE != 0 && entry & ENTRY_READ == 0 {
+        problems.push(Misconfiguration::WriteWithoutRead);
+    }
+    if entry & (ENTRY_READ | ENTRY_WRITE | ENTRY_EXECUTE) == ENTRY_EXECUTE && !env.execute_only {
+        problems.push(Misconfiguration::ExecuteOnly);
+    }
+    let beyond = entry & (ENTRY_ADDRESS_LIMIT - 1) & !((1 << env.physical_address_width) - 1);
+    if beyond != 0 {
+        problems.push(Misconfiguration::AddressBeyondWidth { bits: beyond });
+    }
+    let leaf = is_leaf(entry, level);
+    if level == 3 && leaf && !env.pages_1g {
+        problems.push(Misconfiguration::Page1GUnsupported);
+    }
+    let reserved = match (level, leaf) {
+        (4, _) => 0b1_1111 << 3,
+        (_, false) => 0b1111 << 3,
+        (3, true) => 0x3fff_f000,
+        (2, true) => 0x1f_f000,
+        _ => 0,
+    };
+    if entry & reserved != 0 {
+        problems.push(Misconfiguration::ReservedBits {
+            bits: entry & reserved,
+        });
+    }
+    if leaf {
+        let memory_type = ((entry >> MEMORY_TYPE_SHIFT) & 0b111) as u8;
+        if matches!(memory_type, 2 | 3 | 7) {
+            problems.push(Misconfiguration::MemoryType { memory_type });
+        }
+    }
+    problems
+}
+
+/// One entry the processor read to translate a GPA.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalkStep {
+    pub level: usize,
+    pub index: usize,
+    pub entry: u64,
+    pub problems: Vec<Misconfiguration>,
+}
+
+/// Walks the EPT rooted at `root` for `gpa` and checks every entry on the way. Stops at a
+/// leaf, a non-present entry or a misconfigured one. `read_entry` reads entry `index` of
+/// the table at the given address.
+pub fn walk(
+    root: PhysAddr,
+    gpa: u64,
+    env: &EptCheckEnv,
+    read_entry: impl Fn(PhysAddr, usize) -> u64,
+) -> Vec<WalkStep> {
+    let mut steps = Vec::new();
+    let mut table = root;
+    for level in (1..=4).rev() {
+        let index = ((gpa >> (12 + 9 * (level - 1))) & 0x1ff) as usize;
+        let entry = read_entry(table, index);
+        let problems = if is_present(entry) {
+            check_entry(entry, level, env)
+        } else {
+            Vec::new()
+        };
+        let stop = !is_present(entry) || !problems.is_empty() || is_leaf(entry, level);
+        steps.push(WalkStep {
+            level,
+            index,
+            entry,
+            problems,
+        });
+        if stop {
+            break;
+        }
+        table = PhysAddr::new(entry & 0x000f_ffff_ffff_f000);
+    }
+    steps
+}
+
+/// Exit qualification of an EPT violation (SDM Vol. 3C, Table 28-7).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EptViolationQual(pub u64);
+
+impl EptViolationQual {
+    pub fn read(&self) -> bool {
+        self.0 & (1 << 0) != 0
+    }
+
+    pub fn write(&self) -> bool {
+        self.0 & (1 << 1) != 0
+    }
+
+    pub fn fetch(&self) -> bool {
+        self.0 & (1 << 2) != 0
+    }
+
+    /// Whether the GPA was readable, writable and executable, each ANDed over all levels.
+    pub fn permissions(&self) -> (bool, bool, bool) {
+        (
+            self.0 & (1 << 3) != 0,
+            self.0 & (1 << 4) != 0,
+            self.0 & (1 << 5) != 0,
+        )
+    }
+
+    pub fn linear_address_valid(&self) -> bool {
+        self.0 & (1 << 7) != 0
+    }
+
+    /// With a valid linear address: whether the access was to the translated address rather
+    /// than to one of the guest's paging-structure entries.
+    pub fn final_translation(&self) -> bool {
+        self.0 & (1 << 8) != 0
+    }
+
+    pub fn nmi_unblocking(&self) -> bool {
+        self.0 & (1 << 12) != 0
+    }
+}
+
+impl core::fmt::Display for EptViolationQual {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let access = match (self.read(), self.write(), self.fetch()) {
+            (_, true, _) => "write",
+            (_, _, true) => "instruction fetch",
+            _ => "read",
+        };
+        let (r, w, x) = self.permissions();
+        let flag = |set: bool, c: char| if set { c } else { '-' };
+        write!(
+            f,
+            "{access}, GPA permissions {}{}{}",
+            flag(r, 'r'),
+            flag(w, 'w'),
+            flag(x, 'x')
+        )?;
+        if !self.linear_address_valid() {
+            write!(f, ", no guest linear address")?;
+        } else if self.final_translation() {
+            write!(f, ", access to the translated linear address")?;
+        } else {
+            write!(f, ", access to a guest paging-structure entry")?;
+        }
+        if self.nmi_unblocking() {
+            write!(f, ", NMI unblocking by IRET")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENV: EptCheckEnv = EptCheckEnv {
+        physical_address_width: 39,
+        execute_only: false,
+        pages_1g: true,
+    };
+
+    const RWX: u64 = ENTRY_READ | ENTRY_WRITE | ENTRY_EXECUTE;
+    const WB: u64 = 6 << MEMORY_TYPE_SHIFT;
+
+    #[test]
+    fn valid_entries_pass() {
+        assert_eq!(check_entry(0x1000 | RWX, 4, &ENV), []);
+        assert_eq!(check_entry(0x2000 | RWX, 2, &ENV), []);
+        assert_eq!(
+            check_entry(0x4000_0000 | RWX | WB | ENTRY_LARGE, 3, &ENV),
+            []
+        );
+        assert_eq!(check_entry(0x3000 | ENTRY_READ | WB, 1, &ENV), []);
+    }
+
+    #[test]
+    fn reserved_bits() {
+        assert_eq!(
+            check_entry(0x1000 | RWX | WB, 4, &ENV),
+            [Misconfiguration::ReservedBits { bits: WB }]
+        );
+        assert_eq!(
+            check_entry(0x1000 | RWX | 1 << 7, 4, &ENV),
+            [Misconfiguration::ReservedBits { bits: 1 << 7 }]
+        );
+        assert_eq!(
+            check_entry(0x20_1000 | RWX | WB | ENTRY_LARGE, 2, &ENV),
+            [Misconfiguration::ReservedBits { bits: 0x1000 }]
+        );
+        assert_eq!(
+            check_entry(1 << 40 | RWX | WB, 1, &ENV),
+            [Misconfiguration::AddressBeyondWidth { bits: 1 << 40 }]
+        );
+    }
+
+    #[test]
+    fn memory_type() {
+        for memory_type in [2, 3, 7] {
+            assert_eq!(
+                check_entry(0x3000 | RWX | memory_type << MEMORY_TYPE_SHIFT, 1, &ENV),
+                [Misconfiguration::MemoryType {
+                    memory_type: memory_type as u8
+                }]
+            );
+        }
+        // tables have no memory type
+        assert_eq!(check_entry(0x2000 | RWX, 2, &ENV), []);
+    }
+
+    #[test]
+    fn permissions() {
+        assert_eq!(
+            check_entry(0x3000 | ENTRY_WRITE | WB, 1, &ENV),
+            [Misconfiguration::WriteWithoutRead]
+        );
+        assert_eq!(
+            check_entry(0x3000 | ENTRY_EXECUTE | WB, 1, &ENV),
+            [Misconfiguration::ExecuteOnly]
+        );
+        let env = EptCheckEnv {
+            execute_only: true,
+            ..ENV
+        };
+        assert_eq!(check_entry(0x3000 | ENTRY_EXECUTE | WB, 1, &env), []);
+    }
+
+    #[test]
+    fn page_1g() {
+        let entry = 0x4000_0000 | RWX | WB | ENTRY_LARGE;
+        let env = EptCheckEnv {
+            pages_1g: false,
+            ..ENV
+        };
+        assert_eq!(
+            check_entry(entry, 3, &env),
+            [Misconfiguration::Page1GUnsupported]
+        );
+        assert_eq!(
+            check_entry(0x4020_0000 | RWX | WB | ENTRY_LARGE, 3, &ENV),
+            [Misconfiguration::ReservedBits { bits: 0x20_0000 }]
+        );
+    }
+
+    /// Reads the listed (table, index, entry) triples. Every other entry is zero.
+    fn read_from(entries: &[(u64, usize, u64)]) -> impl Fn(PhysAddr, usize) -> u64 + '_ {
+        move |table, index| {
+            entries
+                .iter()
+                .find(|&&(t, i, _)| t == table.as_u64() && i == index)
+                .map_or(0, |&(_, _, entry)| entry)
+        }
+    }
+
+    fn levels(steps: &[WalkStep]) -> Vec<usize> {
+        steps.iter().map(|step| step.level).collect()
+    }
+
+    #[test]
+    fn walk_stops_at_leaf() {
+        let entries = [
+            (0x1000, 0, 0x2000 | RWX),
+            (0x2000, 1, 0x3000 | RWX),
+            (0x3000, 2, 0x40_0000 | RWX | WB | ENTRY_LARGE),
+        ];
+        let steps = walk(
+            PhysAddr::new(0x1000),
+            0x4040_1234,
+            &ENV,
+            read_from(&entries),
+        );
+        assert_eq!(levels(&steps), [4, 3, 2]);
+        assert_eq!(steps[2].index, 2);
+        assert!(steps.iter().all(|step| step.problems.is_empty()));
+
+        let steps = walk(
+            PhysAddr::new(0x1000),
+            0x8000_0000,
+            &ENV,
+            read_from(&entries),
+        );
+        assert_eq!(levels(&steps), [4, 3]);
+        assert_eq!(steps[1].entry, 0);
+    }
+
+    #[test]
+    fn walk_stops_at_misconfiguration() {
+        let entries = [
+            (0x1000, 0, 0x2000 | RWX),
+            (0x2000, 0, 0x3000 | ENTRY_WRITE),
+            (0x3000, 0, 0x40_0000 | RWX | WB | ENTRY_LARGE),
+        ];
+        let steps = walk(PhysAddr::new(0x1000), 0, &ENV, read_from(&entries));
+        assert_eq!(levels(&steps), [4, 3]);
+        assert_eq!(steps[1].problems, [Misconfiguration::WriteWithoutRead]);
+
+        let entries = [
+            (0x1000, 0, 0x2000 | RWX),
+            (0x2000, 0, RWX | WB | ENTRY_LARGE),
+        ];
+        let env = EptCheckEnv {
+            pages_1g: false,
+            ..ENV
+        };
+        let steps = walk(PhysAddr::new(0x1000), 0, &env, read_from(&entries));
+        assert_eq!(levels(&steps), [4, 3]);
+        assert_eq!(steps[1].problems, [Misconfiguration::Page1GUnsupported]);
+    }
+}
@@ -0,0 +1,422 @@
+use crate::{
+    arch::intel::{
+        apic,
+        vmcs::{VmcsField, VmcsRegion},
+        vmexit_handlers,
+    },
+    cpu::PageFault,
+    idt, serial_println,
+};
+use alloc::collections::VecDeque;
+use core::arch::asm;
+use crossbeam::atomic::AtomicCell;
+use lazy_static::lazy_static;
+
+pub const EXCEPTION_DB: u8 = 1;
+pub const EXCEPTION_DF: u8 = 8;
+pub const EXCEPTION_MC: u8 = 18;
+const CONTRIBUTORY_EXCEPTIONS: [u8; 5] = [0, 10, 11, 12, 13];
+pub const EXCEPTION_NP: u8 = 11;
+pub const EXCEPTION_GP: u8 = 13;
+pub const EXCEPTION_PF: u8 = 14;
+
+const INTR_INFO_VECTOR: u32 = 0xff;
+const INTR_INFO_TYPE_SHIFT: u32 = 8;
+const INTR_INFO_TYPE_MASK: u32 = 0b111 << INTR_INFO_TYPE_SHIFT;
+const INTR_INFO_ERROR_CODE_VALID: u32 = 1 << 11;
+const INTR_INFO_VALID: u32 = 1 << 31;
+
+/// B0-B3, BD and BS, at the same positions in DR6 and in the pending debug exceptions.
+const DR6_STATUS: u64 = 0b1111 | 1 << 13 | 1 << 14;
+/// Clear in DR6 when set in the pending debug exceptions.
+const DR6_RTM: u64 = 1 << 16;
+const DR6_BD: u64 = 1 << 13;
+const DR7_GD: u64 = 1 << 13;
+
+const PIN_BASED_VIRTUAL_NMIS: u32 = 1 << 5;
+const PROC_BASED_INTERRUPT_WINDOW_EXITING: u32 = 1 << 2;
+const PROC_BASED_NMI_WINDOW_EXITING: u32 = 1 << 22;
+
+const BLOCKING_BY_STI: u32 = 1 << 0;
+const BLOCKING_BY_MOV_SS: u32 = 1 << 1;
+const BLOCKING_BY_NMI: u32 = 1 << 3;
+const RFLAGS_IF: u64 = 1 << 9;
+const CR0_PE: u64 = 1 << 0;
+/// Activity states from which no event can be delivered: shutdown and wait-for-SIPI.
+const ACTIVITY_STATE_SHUTDOWN: u32 = 2;
+
+/// Interruption types (SDM Vol. 3C, Table 25-17).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptionType {
+    External,
+    Nmi,
+    HardwareException,
+    SoftwareInterrupt,
+    PrivilegedSoftwareException,
+    SoftwareException,
+    Other(u8),
+}
+
+impl InterruptionType {
+    fn from_bits(bits: u32) -> Self {
+        match bits {
+            0 => Self::External,
+            2 => Self::Nmi,
+            3 => Self::HardwareException,
+            4 => Self::SoftwareInterrupt,
+            5 => Self::PrivilegedSoftwareException,
+            6 => Self::SoftwareException,
+            t => Self::Other(t as u8),
+        }
+    }
+
+    fn bits(&self) -> u32 {
+        match self {
+            Self::External => 0,
+            Self::Nmi => 2,
+            Self::HardwareException => 3,
+            Self::SoftwareInterrupt => 4,
+            Self::PrivilegedSoftwareException => 5,
+            Self::SoftwareException => 6,
+            Self::Other(t) => *t as u32,
+        }
+    }
+
+    /// INT1, INT3, INTO and INT n are delivered after the instruction, so injecting them
+    /// needs the instruction length.
+    fn needs_instruction_len(&self) -> bool {
+        matches!(
+            self,
+            Self::SoftwareInterrupt | Self::PrivilegedSoftwareException | Self::SoftwareException
+        )
+    }
+}
+
+/// An event as described by the interruption-information fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub vector: u8,
+    pub kind: InterruptionType,
+    pub error_code: Option<u32>,
+    pub instruction_len: u32,
+    /// Value CR2 takes when a page fault is delivered. Only set for page faults the VMM
+    /// raises itself, the processor fills in CR2 for the others.
+    pub fault_address: Option<u64>,
+    /// Pending debug exceptions a #DB reports in DR6 when it is delivered. Only set for a
+    /// #DB that exited, which left DR6 alone.
+    pub pending_debug: Option<u64>,
+}
+
+impl Event {
+    pub fn external_interrupt(vector: u8) -> Self {
+        Self {
+            vector,
+            kind: InterruptionType::External,
+            error_code: None,
+            instruction_len: 0,
+            fault_address: None,
+            pending_debug: None,
+        }
+    }
+
+    pub fn nmi() -> Self {
+        Self {
+            vector: 2,
+            kind: InterruptionType::Nmi,
+            error_code: None,
+            instruction_len: 0,
+            fault_address: None,
+            pending_debug: None,
+        }
+    }
+
+    pub fn exception(vector: u8, error_code: Option<u32>) -> Self {
+        Self {
+            vector,
+            kind: InterruptionType::HardwareException,
+            error_code,
+            instruction_len: 0,
+            fault_address: None,
+            pending_debug: None,
+        }
+    }
+
+    pub fn page_fault(fault: PageFault, address: u64) -> Self {
+        Self {
+            fault_address: Some(address),
+            ..Self::exception(EXCEPTION_PF, Some(fault.error_code))
+        }
+    }
+
+    #[allow(unused)]
+    pub fn software_interrupt(vector: u8, instruction_len: u32) -> Self {
+        Self {
+            vector,
+            kind: InterruptionType::SoftwareInterrupt,
+            error_code: None,
+            instruction_len,
+            fault_address: None,
+            pending_debug: None,
+        }
+    }
+
+    /// Decodes an interruption-information field. `error_code` and `instruction_len` are
+    /// only read if the information says they are meaningful.
+    pub fn from_info(
+        info: u32,
+        error_code: impl FnOnce() -> u32,
+        instruction_len: impl FnOnce() -> u32,
+    ) -> Self {
+        let kind =
+            InterruptionType::from_bits((info & INTR_INFO_TYPE_MASK) >> INTR_INFO_TYPE_SHIFT);
+        Self {
+            vector: (info & INTR_INFO_VECTOR) as u8,
+            kind,
+            error_code: (info & INTR_INFO_ERROR_CODE_VALID != 0).then(error_code),
+            instruction_len: if kind.needs_instruction_len() {
+                instruction_len()
+            } else {
+                0
+            },
+            fault_address: None,
+            pending_debug: None,
+        }
+    }
+
+    fn info(&self) -> u32 {
+        let mut info =
+            INTR_INFO_VALID | (self.kind.bits() << INTR_INFO_TYPE_SHIFT) | self.vector as u32;
+        if self.error_code.is_some() {
+            info |= INTR_INFO_ERROR_CODE_VALID;
+        }
+        info
+    }
+
+    fn is_contributory(&self) -> bool {
+        self.kind == InterruptionType::HardwareException
+            && CONTRIBUTORY_EXCEPTIONS.contains(&self.vector)
+    }
+
+    fn is_hardware_exception(&self, vector: u8) -> bool {
+        self.kind == InterruptionType::HardwareException && self.vector == vector
+    }
+}
+
+#[derive(Debug, Default)]
+struct EventQueue {
+    /// Exceptions and software interrupts. They are never blocked.
+    unconditional: VecDeque<Event>,
+    nmi: bool,
+    interrupts: VecDeque<u8>,
+    /// Event whose delivery the last VM exit interrupted.
+    vectoring: Option<Event>,
+}
+
+lazy_static! {
+    static ref QUEUE: AtomicCell<EventQueue> = AtomicCell::new(EventQueue::default());
+}
+
+fn queue() -> &'static mut EventQueue {
+    unsafe { QUEUE.as_ptr().as_mut().unwrap() }
+}
+
+/// Queues `event` for delivery as soon as the guest can take it.
+pub fn queue_event(event: Event) {
+    let queue = queue();
+    match event.kind {
+        InterruptionType::Nmi => queue.nmi = true,
+        InterruptionType::External => queue.interrupts.push_back(event.vector),
+        _ => queue.unconditional.push_back(event),
+    }
+}
+
+/// Returns whether no event waits for delivery.
+pub fn is_idle() -> bool {
+    let queue = queue();
+    queue.unconditional.is_empty() && !queue.nmi && queue.interrupts.is_empty()
+}
+
+/// Drops every pending event, as INIT does.
+pub fn clear(vmcs: &mut VmcsRegion) {
+    *queue() = EventQueue::default();
+    vmcs.write32(VmcsField::VmEntryIntrInfoField, 0);
+}
+
+/// Writes `event` to the VM-entry interruption-information fields. Exceptions delivered in
+/// real mode push no error code, so it is dropped when CR0.PE is clear.
+pub fn inject(vmcs: &mut VmcsRegion, mut event: Event) {
+    if vmcs.read_natural(VmcsField::GuestCr0) & CR0_PE == 0 {
+        event.error_code = None;
+    }
+    if let Some(address) = event.fault_address {
+        // CR2 is not part of the guest state, the guest sees the processor's register.
+        unsafe {
+            asm!("mov cr2, {}", in(reg) address, options(nomem, nostack, preserves_flags));
+        }
+    }
+    if let Some(pending) = event.pending_debug {
+        update_debug_status(vmcs, pending);
+    }
+    if let Some(error_code) = event.error_code {
+        vmcs.write32(VmcsField::VmEntryExceptionErrorCode, error_code);
+    }
+    if event.kind.needs_instruction_len() {
+        vmcs.write32(VmcsField::VmEntryInstructionLen, event.instruction_len);
+    }
+    vmcs.write32(VmcsField::VmEntryIntrInfoField, event.info());
+}
+
+/// Does what delivering a #DB does to the debug registers, which neither its VM exit nor its
+/// injection do: DR6 takes the pending debug exceptions and a BD clears DR7.GD.
+fn update_debug_status(vmcs: &mut VmcsRegion, pending: u64) {
+    // DR6 is not part of the guest state, the guest sees the processor's register.
+    let mut dr6: u64;
+    unsafe { asm!("mov {}, dr6", out(reg) dr6, options(nomem, nostack, preserves_flags)) };
+    dr6 |= pending & DR6_STATUS;
+    if pending & DR6_RTM != 0 {
+        dr6 &= !DR6_RTM;
+    }
+    unsafe { asm!("mov dr6, {}", in(reg) dr6, options(nomem, nostack, preserves_flags)) };
+    if pending & DR6_BD != 0 {
+        let dr7 = vmcs.read_natural(VmcsField::GuestDr7);
+        vmcs.write_natural(VmcsField::GuestDr7, dr7 & !DR7_GD);
+    }
+}
+
+fn injected(vmcs: &VmcsRegion) -> Option<Event> {
+    let info = vmcs.read32(VmcsField::VmEntryIntrInfoField);
+    (info & INTR_INFO_VALID != 0).then(|| {
+        Event::from_info(
+            info,
+            || vmcs.read32(VmcsField::VmEntryExceptionErrorCode),
+            || vmcs.read32(VmcsField::VmEntryInstructionLen),
+        )
+    })
+}
+
+/// Records the event reported in the IDT-vectoring fields, if any. Must run on every VM exit.
+pub fn on_vmexit(vmcs: &VmcsRegion) {
+    let info = vmcs.read32(VmcsField::IdtVectoringInfoField);
+    queue().vectoring = (info & INTR_INFO_VALID != 0).then(|| {
+        Event::from_info(
+            info,
+            || vmcs.read32(VmcsField::IdtVectoringErrorCode),
+            || vmcs.read32(VmcsField::VmExitInstructionLen),
+        )
+    });
+}
+
+/// An IRET that faults has already unblocked NMIs, but it will be executed again, so the
+/// blocking has to be put back before the guest resumes.
+pub fn restore_nmi_blocking(vmcs: &mut VmcsRegion) {
+    let blocking = vmcs.read32(VmcsField::GuestInterruptibilityState);
+    vmcs.write32(
+        VmcsField::GuestInterruptibilityState,
+        blocking | BLOCKING_BY_NMI,
+    );
+}
+
+enum Combined {
+    DoubleFault,
+    TripleFault,
+    /// The second event is delivered and the first handled afterwards.
+    Serial,
+}
+
+/// Exception classes of SDM Vol. 3A, Table 6-5.
+fn combine(first: &Event, second: &Event) -> Combined {
+    let second_is_fatal = second.is_contributory() || second.is_hardware_exception(EXCEPTION_PF);
+    if first.is_hardware_exception(EXCEPTION_DF) && second_is_fatal {
+        Combined::TripleFault
+    } else if (first.is_contributory() && second.is_contributory())
+        || (first.is_hardware_exception(EXCEPTION_PF) && second_is_fatal)
+    {
+        Combined::DoubleFault
+    } else {
+        Combined::Serial
+    }
+}
+
+/// Chooses the event to inject on the coming VM entry and arms the interrupt- and NMI-window
+/// exits for whatever is still pending. Must run after the exit handler.
+pub fn inject_pending(vmcs: &mut VmcsRegion) {
+    let queue = queue();
+    if idt::take_nmi() {
+        queue.nmi = true;
+    }
+    if idt::take_machine_check() {
+        vmexit_handlers::reflect_machine_check(vmcs);
+    }
+    match (queue.vectoring.take(), injected(vmcs)) {
+        (Some(first), Some(second)) => match combine(&first, &second) {
+            Combined::DoubleFault => inject(vmcs, Event::exception(EXCEPTION_DF, Some(0))),
+            Combined::TripleFault => {
+                serial_println!("guest triple fault: {second:?} while delivering {first:?}");
+                vmexit_handlers::triple_fault();
+            }
+            // Faults and software interrupts recur when the guest retries the instruction,
+            // asynchronous events would be lost.
+            Combined::Serial => match first.kind {
+                InterruptionType::Nmi => queue.nmi = true,
+                InterruptionType::External => queue.interrupts.push_front(first.vector),
+                _ => {}
+            },
+        },
+        (Some(first), None) => inject(vmcs, first),
+        (None, Some(_)) => {}
+        (None, None) => {
+            if let Some(event) = next_deliverable(vmcs, queue) {
+                inject(vmcs, event);
+            }
+        }
+    }
+    update_windows(vmcs, queue);
+}
+
+/// Events wait in the queue while the guest is in an activity state that cannot take them.
+fn next_deliverable(vmcs: &VmcsRegion, queue: &mut EventQueue) -> Option<Event> {
+    if vmcs.read32(VmcsField::GuestActivityState) >= ACTIVITY_STATE_SHUTDOWN {
+        return None;
+    }
+    if let Some(event) = queue.unconditional.pop_front() {
+        return Some(event);
+    }
+    let blocking = vmcs.read32(VmcsField::GuestInterruptibilityState);
+    if queue.nmi && blocking & (BLOCKING_BY_STI | BLOCKING_BY_MOV_SS | BLOCKING_BY_NMI) == 0 {
+        queue.nmi = false;
+        return Some(Event::nmi());
+    }
+    let interruptible = vmcs.read_natural(VmcsField::GuestRflags) & RFLAGS_IF != 0
+        && blocking & (BLOCKING_BY_STI | BLOCKING_BY_MOV_SS) == 0;
+    let tpr_blocked =
+        matches!(queue.interrupts.front(), Some(&vector) if apic::blocked_by_tpr(vector));
+    if interruptible && !tpr_blocked {
+        return queue.interrupts.pop_front().map(Event::external_interrupt);
+    }
+    None
+}
+
+/// NMI-window exiting needs virtual NMIs. Without them a pending NMI waits for an interrupt
+/// window instead. An interrupt held back by the shadowed TPR waits for a TPR-below-threshold
+/// exit rather than an interrupt window, which would open right away.
+fn update_windows(vmcs: &mut VmcsRegion, queue: &EventQueue) {
+    let virtual_nmis = vmcs.read32(VmcsField::PinBasedVmExecControls) & PIN_BASED_VIRTUAL_NMIS != 0;
+    let tpr_blocked = queue
+        .interrupts
+        .front()
+        .copied()
+        .filter(|&vector| apic::blocked_by_tpr(vector));
+    apic::set_tpr_threshold(vmcs, tpr_blocked);
+    let controls = vmcs.read32(VmcsField::ProcBasedVmExecControls);
+    let mut new_controls =
+        controls & !(PROC_BASED_INTERRUPT_WINDOW_EXITING | PROC_BASED_NMI_WINDOW_EXITING);
+    if (!queue.interrupts.is_empty() && tpr_blocked.is_none()) || (queue.nmi && !virtual_nmis) {
+        new_controls |= PROC_BASED_INTERRUPT_WINDOW_EXITING;
+    }
+    if queue.nmi && virtual_nmis {
+        new_controls |= PROC_BASED_NMI_WINDOW_EXITING;
+    }
+    if new_controls != controls {
+        vmcs.write32(VmcsField::ProcBasedVmExecControls, new_controls);
+    }
+}
@@ -0,0 +1,207 @@
+use crate::{
+    arch::intel::{
+        event::{self, Event, InterruptionType, EXCEPTION_DB, EXCEPTION_DF, EXCEPTION_PF},
+        vmcs::{VmcsField, VmcsRegion},
+        vmx::VmExitGeneralPurposeRegister,
+    },
+    emu, serial_println,
+};
+use crossbeam::atomic::AtomicCell;
+
+const INTR_INFO_NMI_UNBLOCKING: u32 = 1 << 12;
+
+/// An exception or NMI that caused a VM exit.
+#[derive(Debug, Clone, Copy)]
+pub struct ExceptionExit {
+    pub event: Event,
+    /// Exit qualification: the faulting address for #PF, the pending debug exceptions for #DB.
+    pub qualification: u64,
+    /// The exception interrupted an IRET that had already unblocked NMIs.
+    nmi_unblocking: bool,
+}
+
+impl ExceptionExit {
+    fn read(vmcs: &VmcsRegion, qual: u64) -> Self {
+        let info = vmcs.read32(VmcsField::VmExitIntrInfo);
+        let event = Event::from_info(
+            info,
+            || vmcs.read32(VmcsField::VmExitIntrErrorCode),
+            || vmcs.read32(VmcsField::VmExitInstructionLen),
+        );
+        Self {
+            event,
+            qualification: qual,
+            nmi_unblocking: info & INTR_INFO_NMI_UNBLOCKING != 0 && event.vector != EXCEPTION_DF,
+        }
+    }
+
+    fn is_page_fault(&self) -> bool {
+        self.event.kind == InterruptionType::HardwareException && self.event.vector == EXCEPTION_PF
+    }
+
+    fn is_debug(&self) -> bool {
+        self.event.kind == InterruptionType::HardwareException && self.event.vector == EXCEPTION_DB
+    }
+}
+
+/// What to do after a handler has looked at an exception.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disposition {
+    /// Deliver the exception to the guest.
+    Reflect,
+    /// Resume the guest at the faulting instruction, e.g. after fixing up the cause.
+    #[allow(unused)]
+    Retry,
+    /// Resume the guest after the faulting instruction.
+    #[allow(unused)]
+    Skip,
+}
+
+pub type ExceptionHandler =
+    fn(&mut VmcsRegion, &ExceptionExit, &mut VmExitGeneralPurposeRegister) -> Disposition;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ExceptionAction {
+    /// The exception goes straight to the guest without a VM exit.
+    PassThrough,
+    LogAndReflect,
+    #[allow(unused)]
+    Handle(ExceptionHandler),
+}
+
+const fn default_policy() -> [ExceptionAction; 32] {
+    let mut policy = [ExceptionAction::PassThrough; 32];
+    policy[EXCEPTION_DF as usize] = ExceptionAction::LogAndReflect;
+    policy
+}
+
+static POLICY: AtomicCell<[ExceptionAction; 32]> = AtomicCell::new(default_policy());
+
+/// #PF exits only happen for error codes where `error_code & mask == match_`.
+#[derive(Debug, Clone, Copy)]
+pub struct PageFaultFilter {
+    pub mask: u32,
+    pub match_: u32,
+}
+
+static PAGE_FAULT_FILTER: AtomicCell<PageFaultFilter> =
+    AtomicCell::new(PageFaultFilter { mask: 0, match_: 0 });
+
+fn policy() -> &'static mut [ExceptionAction; 32] {
+    unsafe { POLICY.as_ptr().as_mut().unwrap() }
+}
+
+/// Programs the exception bitmap and the #PF error-code filter from the policy.
+pub fn setup(vmcs: &mut VmcsRegion) {
+    let mut bitmap = 0;
+    for (vector, action) in policy().iter().enumerate() {
+        if !matches!(action, ExceptionAction::PassThrough) {
+            bitmap |= 1 << vector;
+        }
+    }
+    // With the #PF bit clear, a #PF exits when the masked error code does *not* equal the
+    // match value, so the filter only applies when #PF is intercepted.
+    let filter = if bitmap & (1 << EXCEPTION_PF) != 0 {
+        PAGE_FAULT_FILTER.load()
+    } else {
+        PageFaultFilter { mask: 0, match_: 0 }
+    };
+    vmcs.write32(VmcsField::ExceptionBitmap, bitmap);
+    vmcs.write32(VmcsField::PageFaultErrorCodeMask, filter.mask);
+    vmcs.write32(VmcsField::PageFaultErrorCodeMatch, filter.match_);
+}
+
+/// Returns false if `vector` is not an exception vector.
+pub fn set_policy(vmcs: &mut VmcsRegion, vector: u64, action: ExceptionAction) -> bool {
+    match policy().get_mut(vector as usize) {
+        Some(entry) => {
+            *entry = action;
+            setup(vmcs);
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn set_page_fault_filter(vmcs: &mut VmcsRegion, filter: PageFaultFilter) {
+    PAGE_FAULT_FILTER.store(filter);
+    setup(vmcs);
+}
+
+/// Handles an exception-or-NMI exit, skipping the faulting instruction if a handler asks
+/// for it.
+pub fn handle(vmcs: &mut VmcsRegion, qual: u64, gpr: &mut VmExitGeneralPurposeRegister) {
+    let exit = ExceptionExit::read(vmcs, qual);
+    if exit.event.kind == InterruptionType::Nmi {
+        // A real NMI that arrived while the guest was running. It waits in the event queue
+        // until the guest's NMI blocking allows it.
+        event::queue_event(Event::nmi());
+        return;
+    }
+    let disposition = match policy()[exit.event.vector as usize] {
+        ExceptionAction::PassThrough => Disposition::Reflect,
+        ExceptionAction::LogAndReflect => {
+            log(vmcs, &exit);
+            Disposition::Reflect
+        }
+        ExceptionAction::Handle(handler) => handler(vmcs, &exit, gpr),
+    };
+    let disposition = if disposition == Disposition::Skip && !skip_instruction(vmcs) {
+        Disposition::Reflect
+    } else {
+        disposition
+    };
+    if exit.nmi_unblocking && disposition != Disposition::Skip {
+        event::restore_nmi_blocking(vmcs);
+    }
+    if disposition == Disposition::Reflect {
+        reflect(vmcs, &exit);
+    }
+}
+
+/// Moves RIP past the faulting instruction. The VM-exit instruction length is only valid for
+/// software exceptions, so the instruction is decoded. Returns false if it cannot be.
+fn skip_instruction(vmcs: &mut VmcsRegion) -> bool {
+    let mut code = [0; 15];
+    let len = vmcs.read_guest_code(&mut code);
+    let rip = vmcs.read_natural(VmcsField::GuestRip);
+    match emu::decode_one(&code[..len], rip, vmcs.guest_bitness()) {
+        Ok(instruction) => {
+            vmcs.write_natural(VmcsField::GuestRip, rip + instruction.len() as u64);
+            true
+        }
+        Err(()) => {
+            serial_println!("cannot skip the instruction at 0x{rip:x}, reflecting instead");
+            false
+        }
+    }
+}
+
+fn log(vmcs: &VmcsRegion, exit: &ExceptionExit) {
+    let rip = vmcs.read_natural(VmcsField::GuestRip);
+    let cr3 = vmcs.read_natural(VmcsField::GuestCr3);
+    serial_println!(
+        "guest exception {} ({:?}) at RIP 0x{rip:016x} (CR3 0x{cr3:x}), error code {:x?}",
+        exit.event.vector,
+        exit.event.kind,
+        exit.event.error_code
+    );
+    if exit.is_page_fault() {
+        serial_println!("faulting address 0x{:016x}", exit.qualification);
+    }
+}
+
+/// Injects the exception exactly as the guest would have received it. A #PF that exits has
+/// not loaded CR2 yet and a #DB has not updated DR6, so the injection does.
+fn reflect(vmcs: &mut VmcsRegion, exit: &ExceptionExit) {
+    let fault_address = exit.is_page_fault().then_some(exit.qualification);
+    let pending_debug = exit.is_debug().then_some(exit.qualification);
+    event::inject(
+        vmcs,
+        Event {
+            fault_address,
+            pending_debug,
+            ..exit.event
+        },
+    );
+}
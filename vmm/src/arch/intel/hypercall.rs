@@ -0,0 +1,89 @@
+use crate::{
+    arch::intel::{
+        cr, cr3_tracker, devirt,
+        exception::{self, ExceptionAction, PageFaultFilter},
+        vmcs::{VmcsField, VmcsRegion},
+        vmx::VmExitGeneralPurposeRegister,
+    },
+    serial_println,
+};
+
+/// Hypercall numbers, passed in RAX to VMCALL. The result comes back in RAX.
+pub const HYPERCALL_DEVIRTUALIZE: u64 = 1;
+/// Adds RBX to the pinnable CR0 bits and RCX to the pinnable CR4 bits. Only the first call
+/// succeeds.
+pub const HYPERCALL_SET_CR_PINNING: u64 = 2;
+/// Traces only the exits taken in the address space whose CR3 is in RBX, or every exit if
+/// RBX is 0.
+pub const HYPERCALL_SET_TRACE_FILTER: u64 = 3;
+/// Prints the address spaces seen so far to the serial port.
+pub const HYPERCALL_PRINT_ADDRESS_SPACES: u64 = 4;
+/// Logs exception RBX before reflecting it to the guest if RCX is not 0, otherwise lets it
+/// reach the guest without a VM exit.
+pub const HYPERCALL_SET_EXCEPTION_LOGGING: u64 = 5;
+/// Limits #PF exits to error codes where `error_code & RBX == RCX`.
+pub const HYPERCALL_SET_PAGE_FAULT_FILTER: u64 = 6;
+
+pub const HYPERCALL_SUCCESS: u64 = 0;
+pub const HYPERCALL_ERROR: u64 = !0;
+
+/// Handles VMCALL. Only ring 0 may call the VMM.
+pub fn vmcall(vmcs: &mut VmcsRegion, gpr: &mut VmExitGeneralPurposeRegister) {
+    if vmcs.guest_cpl() != 0 {
+        gpr.rax = HYPERCALL_ERROR;
+        return;
+    }
+    gpr.rax = match gpr.rax {
+        HYPERCALL_DEVIRTUALIZE => {
+            gpr.rax = HYPERCALL_SUCCESS;
+            let rip = vmcs.read_natural(VmcsField::GuestRip)
+                + vmcs.read32(VmcsField::VmExitInstructionLen) as u64;
+            let e = devirt::devirtualize(vmcs, gpr, rip).unwrap_err();
+            serial_println!("devirtualization failed: {e}");
+            HYPERCALL_ERROR
+        }
+        HYPERCALL_SET_CR_PINNING => {
+            let pinnable = cr::Pinnable {
+                cr0: gpr.rbx,
+                cr4: gpr.rcx,
+            };
+            if cr::add_pinnable(vmcs, pinnable) {
+                HYPERCALL_SUCCESS
+            } else {
+                HYPERCALL_ERROR
+            }
+        }
+        HYPERCALL_SET_TRACE_FILTER => {
+            cr3_tracker::set_filter((gpr.rbx != 0).then_some(gpr.rbx));
+            HYPERCALL_SUCCESS
+        }
+        HYPERCALL_PRINT_ADDRESS_SPACES => {
+            cr3_tracker::print();
+            HYPERCALL_SUCCESS
+        }
+        HYPERCALL_SET_EXCEPTION_LOGGING => {
+            let action = if gpr.rcx != 0 {
+                ExceptionAction::LogAndReflect
+            } else {
+                ExceptionAction::PassThrough
+            };
+            if exception::set_policy(vmcs, gpr.rbx, action) {
+                HYPERCALL_SUCCESS
+            } else {
+                HYPERCALL_ERROR
+            }
+        }
+        HYPERCALL_SET_PAGE_FAULT_FILTER => {
+            let filter = PageFaultFilter {
+                mask: gpr.rbx as u32,
+                match_: gpr.rcx as u32,
+            };
+            exception::set_page_fault_filter(vmcs, filter);
+            HYPERCALL_SUCCESS
+        }
+        nr => {
+            serial_println!("unknown hypercall 0x{nr:x}");
+            HYPERCALL_ERROR
+        }
+    };
+}
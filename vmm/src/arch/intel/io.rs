@@ -0,0 +1,98 @@
+use crate::{
+    allocator,
+    arch::intel::{
+        vmcs::{VmcsField, VmcsRegion},
+        vmx::VmExitGeneralPurposeRegister,
+    },
+    power, serial_println,
+};
+use crossbeam::atomic::AtomicCell;
+use lazy_static::lazy_static;
+use x86_64::{instructions::port::Port, PhysAddr};
+
+/// I/O bitmap A covers ports 0x0000-0x7fff, bitmap B the rest (SDM Vol. 3C, 25.6.4).
+/// Both pages are allocated back to back.
+#[derive(Debug)]
+pub struct IoBitmaps(*mut u8);
+
+unsafe impl Send for IoBitmaps {}
+
+impl IoBitmaps {
+    pub fn new() -> Self {
+        Self(allocator::alloc_pages(2).unwrap())
+    }
+
+    pub fn paddr_a(&self) -> PhysAddr {
+        allocator::virt_to_phys(self.0)
+    }
+
+    pub fn paddr_b(&self) -> PhysAddr {
+        self.paddr_a() + 4096u64
+    }
+
+    /// Makes IN and OUT exit if they touch `port`.
+    pub fn intercept(&mut self, port: u16) {
+        unsafe { *self.0.add(port as usize / 8) |= 1 << (port % 8) };
+    }
+}
+
+lazy_static! {
+    static ref BITMAPS: AtomicCell<IoBitmaps> = AtomicCell::new(IoBitmaps::new());
+}
+
+pub fn bitmaps() -> &'static mut IoBitmaps {
+    unsafe { BITMAPS.as_ptr().as_mut().unwrap() }
+}
+
+pub fn setup(vmcs: &mut VmcsRegion) {
+    for port in power::trapped_ports() {
+        bitmaps().intercept(port);
+    }
+    vmcs.write64(VmcsField::IoBitmapA, bitmaps().paddr_a().as_u64());
+    vmcs.write64(VmcsField::IoBitmapB, bitmaps().paddr_b().as_u64());
+}
+
+const QUAL_SIZE_MASK: u64 = 0b111;
+const QUAL_DIRECTION_IN: u64 = 1 << 3;
+const QUAL_STRING: u64 = 1 << 4;
+const QUAL_PORT_SHIFT: u64 = 16;
+
+/// Emulates an intercepted IN or OUT (exit qualification: SDM Vol. 3C, Table 28-5).
+/// Writes that reset or power off the platform do not return.
+pub fn io_instruction(qual: u64, gpr: &mut VmExitGeneralPurposeRegister) {
+    let size = (qual & QUAL_SIZE_MASK) as usize + 1;
+    let port = (qual >> QUAL_PORT_SHIFT) as u16;
+    if qual & QUAL_STRING != 0 {
+        serial_println!("string I/O to port 0x{port:x} is not supported");
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+    if qual & QUAL_DIRECTION_IN != 0 {
+        let value = unsafe {
+            match size {
+                1 => Port::<u8>::new(port).read() as u64,
+                2 => Port::<u16>::new(port).read() as u64,
+                _ => Port::<u32>::new(port).read() as u64,
+            }
+        };
+        // 8- and 16-bit IN keep the rest of RAX, a 32-bit one clears the upper half.
+        gpr.rax = match size {
+            1 => (gpr.rax & !0xff) | value,
+            2 => (gpr.rax & !0xffff) | value,
+            _ => value,
+        };
+        return;
+    }
+    let value = gpr.rax as u32;
+    if power::guest_out(port, size, value) {
+        return;
+    }
+    unsafe {
+        match size {
+            1 => Port::<u8>::new(port).write(value as u8),
+            2 => Port::<u16>::new(port).write(value as u16),
+            _ => Port::<u32>::new(port).write(value),
+        }
+    }
+}
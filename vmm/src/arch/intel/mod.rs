@@ -1,19 +1,37 @@
-mod ept;
-mod vmcs;
+mod apic;
+mod cr;
+mod cr3_tracker;
+mod desc_table;
+mod devirt;
+mod entry_check;
+pub mod ept;
+mod ept_check;
+mod event;
+mod exception;
+mod hypercall;
+mod io;
+mod msr;
+mod mtrr;
+mod sipi;
+mod tlb;
+pub mod vmcs;
+mod vmcs_cache;
 mod vmexit_handlers;
 pub mod vmx;
+mod vmx_caps;
 
 use crate::{
     arch::intel::vmx::VmExitGeneralPurposeRegister,
     cpu::{Cpu, CpuError},
-    serial_println,
+    ioapic, serial_println, BOOT_ARGS,
 };
-use core::arch::asm;
 use crossbeam::atomic::AtomicCell;
-use ept::{init_ept, EptPointer};
+use ept::{init_ept, EptPointer, EptTableFlags};
 use lazy_static::lazy_static;
 use vmcs::{VmcsField, VmcsRegion};
-use vmx::{handle_vmexit, vmlaunch, vmxon, VmxError, VmxonRegion};
+use vmx::{check_vmx_error, handle_vmexit, vmlaunch, vmxoff, vmxon, VmxOperation, VmxonRegion};
+use vmx_caps::VmxCaps;
+use x86_64::PhysAddr;
 
 lazy_static! {
     pub static ref BSP: AtomicCell<IntelCpu> = AtomicCell::new(unsafe { IntelCpu::new() });
@@ -45,14 +63,8 @@ impl IntelCpu {
     }
 
     fn vmxon(&mut self) -> Result<(), CpuError> {
-        unsafe {
-            let vmxon = vmxon(&mut self.vmxon_region);
-            if let Err(_e) = vmxon {
-                Err(CpuError::VmxRelated)
-            } else {
-                Ok(())
-            }
-        }
+        unsafe { vmxon(&mut self.vmxon_region)? };
+        Ok(())
     }
 }
 
@@ -65,47 +77,78 @@ impl Cpu for IntelCpu {
         if !self.is_virtualization_supported() {
             Err(CpuError::NotSupported)
         } else {
+            VmxCaps::read().report();
             Self::vmxon(self)
         }
     }
 
     fn disable_virtualization(&mut self) -> Result<(), CpuError> {
+        self.vmcs_region.clear()?;
+        unsafe { vmxoff()? };
         Ok(())
     }
 
-    fn init_as_bsp(&mut self) {
-        self.vmcs_region.clear();
-        self.vmcs_region.load();
-
-        self.eptp = init_ept();
-        self.vmcs_region
-            .setup(self.eptp, unsafe { &vmexit_handler as *const u8 as u64 });
-    }
-
-    fn run_vm(&mut self) {
-        unsafe {
-            if let Err(e) = vmlaunch() {
-                match e {
-                    VmxError::InvalidPointer => panic!(),
-                    VmxError::VmInstructionError => {
-                        let error_code = self.vmcs_region.read(VmcsField::VmInstructionError);
-                        asm!("mov r15, {}; hlt", in(reg) error_code, options(readonly, nostack, preserves_flags));
-                    }
+    fn init_as_bsp(&mut self) -> Result<(), CpuError> {
+        self.vmcs_region.clear()?;
+        self.vmcs_region.load()?;
+
+        self.eptp = init_ept(
+            BOOT_ARGS.load().memory_size,
+            VmxCaps::read().ept_vpid.pdpte_1g(),
+        )?;
+        if ioapic::TRAP_GUEST_ACCESS {
+            for ioapic in ioapic::ioapics() {
+                if let Err(e) =
+                    ept::ept().set_permissions(PhysAddr::new(ioapic.base), EptTableFlags::empty())
+                {
+                    serial_println!("failed to trap IOAPIC accesses: {e}");
                 }
             }
         }
+        self.vmcs_region.setup(
+            self.eptp,
+            unsafe { &vmexit_handler as *const u8 as u64 },
+            cr::Pinnable::DEFAULT,
+        )?;
+        Ok(())
+    }
+
+    fn run_vm(&mut self) -> Result<(), CpuError> {
+        unsafe { vmlaunch()? };
+        Ok(())
+    }
+}
+
+#[no_mangle]
+unsafe extern "sysv64" fn vmresume_failed(rflags: u64) -> ! {
+    if let Err(e) = check_vmx_error(rflags, VmxOperation::Vmresume) {
+        serial_println!("{e}");
+    }
+    vmcs::dump();
+    loop {
+        x86_64::instructions::hlt();
     }
 }
 
 #[no_mangle]
 unsafe fn resume_vm(gpr: *mut VmExitGeneralPurposeRegister) {
-    let bsp = BSP.as_ptr().as_ref().unwrap();
-    let exit_reason = bsp.vmcs_region.read(VmcsField::VmExitReason);
-    let exit_qual = bsp.vmcs_region.read(VmcsField::ExitQualification);
+    let bsp = BSP.as_ptr().as_mut().unwrap();
+    bsp.vmcs_region.invalidate_cache();
+    event::on_vmexit(&bsp.vmcs_region);
+    let exit_reason = bsp.vmcs_region.read32(VmcsField::VmExitReason) as u64;
+    let exit_qual = bsp.vmcs_region.read_natural(VmcsField::ExitQualification);
 
     serial_println!("=== VMExit!!!!! ===");
 
     handle_vmexit(exit_reason, exit_qual, gpr);
+    event::inject_pending(&mut bsp.vmcs_region);
+    if let Err(e) = bsp.vmcs_region.flush() {
+        serial_println!("failed to update the VMCS: {e}");
+        vmcs::dump();
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
 
     serial_println!("=== VMEntry!!!! ===");
 }
@@ -0,0 +1,124 @@
+use crate::{
+    allocator,
+    arch::intel::{
+        apic,
+        event::{self, Event, EXCEPTION_GP},
+        mtrr,
+        vmcs::{VmcsField, VmcsRegion},
+        vmx::VmExitGeneralPurposeRegister,
+    },
+    cpu,
+};
+use crossbeam::atomic::AtomicCell;
+use lazy_static::lazy_static;
+use x86_64::PhysAddr;
+
+const MSR_LOW_LAST: u32 = 0x0000_1fff;
+const MSR_HIGH_FIRST: u32 = 0xc000_0000;
+const MSR_HIGH_LAST: u32 = 0xc000_1fff;
+
+/// Offsets of the four 1 KiB bitmaps in the MSR-bitmap page (SDM Vol. 3C, 25.6.9).
+const READ_LOW: usize = 0;
+const READ_HIGH: usize = 1024;
+const WRITE_LOW: usize = 2048;
+const WRITE_HIGH: usize = 3072;
+
+/// Selects which RDMSR/WRMSR cause VM exits. Everything not set passes through.
+#[derive(Debug)]
+pub struct MsrBitmap(*mut u8);
+
+unsafe impl Send for MsrBitmap {}
+
+impl MsrBitmap {
+    pub fn new() -> Self {
+        Self(allocator::alloc_pages(1).unwrap())
+    }
+
+    pub fn paddr(&self) -> PhysAddr {
+        allocator::virt_to_phys(self.0)
+    }
+
+    /// Returns the byte offset in the page and the bit for `msr`, or `None` for MSRs outside
+    /// both ranges, which always exit.
+    fn position(msr: u32, low: usize, high: usize) -> Option<(usize, u8)> {
+        let (base, index) = match msr {
+            0..=MSR_LOW_LAST => (low, msr),
+            MSR_HIGH_FIRST..=MSR_HIGH_LAST => (high, msr - MSR_HIGH_FIRST),
+            _ => return None,
+        };
+        Some((base + (index / 8) as usize, 1 << (index % 8)))
+    }
+
+    fn set(&mut self, position: Option<(usize, u8)>) {
+        if let Some((offset, bit)) = position {
+            unsafe { *self.0.add(offset) |= bit };
+        }
+    }
+
+    pub fn intercept_read(&mut self, msr: u32) {
+        self.set(Self::position(msr, READ_LOW, READ_HIGH));
+    }
+
+    pub fn intercept_write(&mut self, msr: u32) {
+        self.set(Self::position(msr, WRITE_LOW, WRITE_HIGH));
+    }
+}
+
+lazy_static! {
+    static ref BITMAP: AtomicCell<MsrBitmap> = AtomicCell::new(MsrBitmap::new());
+}
+
+pub fn bitmap() -> &'static mut MsrBitmap {
+    unsafe { BITMAP.as_ptr().as_mut().unwrap() }
+}
+
+pub fn setup(vmcs: &mut VmcsRegion) {
+    apic::intercept_msrs(bitmap());
+    mtrr::intercept_msrs(bitmap());
+    vmcs.write64(VmcsField::MsrBitmap, bitmap().paddr().as_u64());
+}
+
+/// An MSR access that raises #GP, in the guest as it would have on the processor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsrFault;
+
+/// Passes a guest WRMSR on to the processor.
+pub fn write(msr: u32, value: u64) -> Result<(), MsrFault> {
+    if unsafe { cpu::wrmsr_checked(msr, value) } {
+        Ok(())
+    } else {
+        Err(MsrFault)
+    }
+}
+
+fn raise_gp() -> bool {
+    event::queue_event(Event::exception(EXCEPTION_GP, Some(0)));
+    false
+}
+
+/// Emulates an intercepted RDMSR. MSRs outside the bitmap ranges exit too, so the read may
+/// fault. Returns `false` if it did and the instruction must not be skipped.
+pub fn rdmsr(gpr: &mut VmExitGeneralPurposeRegister) -> bool {
+    let msr = gpr.rcx as u32;
+    let value = match apic::read_msr(msr).or_else(|| cpu::rdmsr_checked(msr)) {
+        Some(value) => value,
+        None => return raise_gp(),
+    };
+    gpr.rax = value & 0xffff_ffff;
+    gpr.rdx = value >> 32;
+    true
+}
+
+/// Emulates an intercepted WRMSR. Returns `false` if it faulted and the instruction must not
+/// be skipped.
+pub fn wrmsr(gpr: &mut VmExitGeneralPurposeRegister) -> bool {
+    let msr = gpr.rcx as u32;
+    let value = (gpr.rdx << 32) | (gpr.rax & 0xffff_ffff);
+    let result = apic::write_msr(msr, value)
+        .or_else(|| mtrr::write_msr(msr, value))
+        .unwrap_or_else(|| write(msr, value));
+    match result {
+        Ok(()) => true,
+        Err(MsrFault) => raise_gp(),
+    }
+}
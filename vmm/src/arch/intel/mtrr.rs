@@ -0,0 +1,255 @@
+use crate::{
+    arch::intel::{
+        ept::{self, Ept, EptError, FrameAllocator, MemoryType, PageSize, GPA_LIMIT},
+        msr::{self, MsrBitmap, MsrFault},
+    },
+    serial_println,
+};
+use alloc::vec::Vec;
+use x86_64::registers::model_specific::Msr;
+
+const MSR_IA32_MTRRCAP: u32 = 0xfe;
+const MSR_IA32_MTRR_PHYSBASE0: u32 = 0x200;
+const MSR_IA32_MTRR_DEF_TYPE: u32 = 0x2ff;
+
+/// The fixed-range MTRRs in address order, with the size each of their eight fields covers.
+const FIXED_MTRRS: [(u32, u64); 11] = [
+    (0x250, 0x10000),
+    (0x258, 0x4000),
+    (0x259, 0x4000),
+    (0x268, 0x1000),
+    (0x269, 0x1000),
+    (0x26a, 0x1000),
+    (0x26b, 0x1000),
+    (0x26c, 0x1000),
+    (0x26d, 0x1000),
+    (0x26e, 0x1000),
+    (0x26f, 0x1000),
+];
+const FIXED_RANGE_END: u64 = 0x10_0000;
+
+const MTRRCAP_VCNT_MASK: u64 = 0xff;
+const MTRRCAP_FIX: u64 = 1 << 8;
+const DEF_TYPE_FE: u64 = 1 << 10;
+const DEF_TYPE_E: u64 = 1 << 11;
+const DEF_TYPE_RESERVED: u64 = !(0xff | DEF_TYPE_FE | DEF_TYPE_E);
+const PHYSBASE_RESERVED: u64 = 0xf00;
+const PHYSMASK_VALID: u64 = 1 << 11;
+const PHYSMASK_RESERVED: u64 = 0x7ff;
+const PHYS_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+fn memory_type(raw: u64) -> Option<MemoryType> {
+    match raw & 0xff {
+        0 => Some(MemoryType::Uncacheable),
+        1 => Some(MemoryType::WriteCombining),
+        4 => Some(MemoryType::WriteThrough),
+        5 => Some(MemoryType::WriteProtected),
+        6 => Some(MemoryType::WriteBack),
+        _ => None,
+    }
+}
+
+/// Type of memory covered by two overlapping variable ranges (SDM Vol. 3A, 12.11.4.1).
+fn overlap(a: MemoryType, b: MemoryType) -> MemoryType {
+    match (a, b) {
+        _ if a == b => a,
+        (MemoryType::WriteThrough, MemoryType::WriteBack)
+        | (MemoryType::WriteBack, MemoryType::WriteThrough) => MemoryType::WriteThrough,
+        _ => MemoryType::Uncacheable,
+    }
+}
+
+/// A snapshot of the MTRRs.
+#[derive(Debug, Clone)]
+pub struct Mtrrs {
+    def_type: u64,
+    /// Empty if the processor has no fixed-range MTRRs.
+    fixed: Vec<u64>,
+    /// IA32_MTRR_PHYSBASEn and IA32_MTRR_PHYSMASKn pairs.
+    variable: Vec<(u64, u64)>,
+}
+
+impl Mtrrs {
+    pub fn read() -> Self {
+        let read = |msr: u32| unsafe { Msr::new(msr).read() };
+        let cap = read(MSR_IA32_MTRRCAP);
+        let fixed = if cap & MTRRCAP_FIX != 0 {
+            FIXED_MTRRS.iter().map(|&(msr, _)| read(msr)).collect()
+        } else {
+            Vec::new()
+        };
+        let variable = (0..(cap & MTRRCAP_VCNT_MASK) as u32)
+            .map(|i| {
+                let base = MSR_IA32_MTRR_PHYSBASE0 + 2 * i;
+                (read(base), read(base + 1))
+            })
+            .collect();
+        Self {
+            def_type: read(MSR_IA32_MTRR_DEF_TYPE),
+            fixed,
+            variable,
+        }
+    }
+
+    /// Type the fixed-range MTRRs give `addr`, or `None` if none of them covers it.
+    fn fixed_type(&self, addr: u64) -> Option<MemoryType> {
+        let mut base = 0;
+        for (&(_, field_size), &value) in FIXED_MTRRS.iter().zip(self.fixed.iter()) {
+            if addr < base + 8 * field_size {
+                let field = (addr - base) / field_size;
+                return Some(memory_type(value >> (8 * field)).unwrap_or(MemoryType::Uncacheable));
+            }
+            base += 8 * field_size;
+        }
+        None
+    }
+
+    /// Memory type of the page of `size` at `base`, or `None` if the MTRRs give parts of it
+    /// different types.
+    pub fn page_type(&self, base: u64, size: PageSize) -> Option<MemoryType> {
+        if self.def_type & DEF_TYPE_E == 0 {
+            return Some(MemoryType::Uncacheable);
+        }
+        if self.def_type & DEF_TYPE_FE != 0 {
+            if let Some(fixed_type) = self.fixed_type(base) {
+                return match size {
+                    PageSize::Size4K => Some(fixed_type),
+                    _ => None,
+                };
+            }
+        }
+        let offset_bits = size.bytes() - 1;
+        let mut page_type = None;
+        for &(phys_base, phys_mask) in &self.variable {
+            if phys_mask & PHYSMASK_VALID == 0 {
+                continue;
+            }
+            let mask = phys_mask & PHYS_ADDR_MASK;
+            // No address of the page matches if the bits above the page offset differ.
+            if (base ^ phys_base) & mask & !offset_bits != 0 {
+                continue;
+            }
+            // Otherwise every address matches only if the mask ignores the page offset.
+            if mask & offset_bits != 0 {
+                return None;
+            }
+            let range_type = memory_type(phys_base).unwrap_or(MemoryType::Uncacheable);
+            page_type = Some(match page_type {
+                Some(page_type) => overlap(page_type, range_type),
+                None => range_type,
+            });
+        }
+        Some(
+            page_type
+                .unwrap_or_else(|| memory_type(self.def_type).unwrap_or(MemoryType::Uncacheable)),
+        )
+    }
+}
+
+/// Sets the EPT memory types of `[start, end)` from the current MTRRs.
+pub fn apply<A: FrameAllocator>(ept: &mut Ept<A>, start: u64, end: u64) -> Result<(), EptError> {
+    let mtrrs = Mtrrs::read();
+    ept.set_memory_types(start, end, |base, size| mtrrs.page_type(base, size))
+}
+
+/// Bits of `msr` that raise #GP when set. Addresses may not exceed MAXPHYADDR.
+fn reserved_bits(msr: u32, is_fixed: bool) -> u64 {
+    let address_size = unsafe { core::arch::x86_64::__cpuid(0x8000_0008) }.eax & 0xff;
+    let beyond_address = !((1 << address_size) - 1);
+    if is_fixed {
+        0
+    } else if msr == MSR_IA32_MTRR_DEF_TYPE {
+        DEF_TYPE_RESERVED
+    } else if msr & 1 == 0 {
+        PHYSBASE_RESERVED | beyond_address
+    } else {
+        PHYSMASK_RESERVED | beyond_address
+    }
+}
+
+fn is_variable(msr: u32, count: u32) -> bool {
+    (MSR_IA32_MTRR_PHYSBASE0..MSR_IA32_MTRR_PHYSBASE0 + 2 * count).contains(&msr)
+}
+
+fn variable_count() -> u32 {
+    (unsafe { Msr::new(MSR_IA32_MTRRCAP).read() } & MTRRCAP_VCNT_MASK) as u32
+}
+
+/// With EPT the MTRRs no longer apply to guest accesses, so their writes are intercepted and
+/// turned into EPT memory types.
+pub fn intercept_msrs(bitmap: &mut MsrBitmap) {
+    if !ept::ENABLE_EPT {
+        return;
+    }
+    bitmap.intercept_write(MSR_IA32_MTRR_DEF_TYPE);
+    for (msr, _) in FIXED_MTRRS {
+        bitmap.intercept_write(msr);
+    }
+    for i in 0..2 * variable_count() {
+        bitmap.intercept_write(MSR_IA32_MTRR_PHYSBASE0 + i);
+    }
+}
+
+/// Guest-physical range a variable MTRR covers. A non-contiguous mask is taken to cover
+/// everything.
+fn variable_range(phys_base: u64, phys_mask: u64) -> (u64, u64) {
+    if phys_mask & PHYSMASK_VALID == 0 {
+        return (0, 0);
+    }
+    let mask = phys_mask & PHYS_ADDR_MASK;
+    let ones = mask >> mask.trailing_zeros().min(63);
+    if mask == 0 || ones & (ones + 1) != 0 {
+        return (0, GPA_LIMIT);
+    }
+    let size = 1 << mask.trailing_zeros();
+    let start = phys_base & mask;
+    (start, start + size)
+}
+
+/// Handles a write to an intercepted MTRR. The write reaches the hardware too, which still
+/// types the VMM's own accesses. Returns `None` if `msr` is not an MTRR.
+pub fn write_msr(msr: u32, value: u64) -> Option<Result<(), MsrFault>> {
+    let count = variable_count();
+    let is_fixed = FIXED_MTRRS.iter().any(|&(fixed, _)| fixed == msr);
+    if msr != MSR_IA32_MTRR_DEF_TYPE && !is_fixed && !is_variable(msr, count) {
+        return None;
+    }
+    let mut ranges = Vec::new();
+    if msr == MSR_IA32_MTRR_DEF_TYPE {
+        ranges.push((0, GPA_LIMIT));
+    } else if is_fixed {
+        ranges.push((0, FIXED_RANGE_END));
+    } else {
+        let base_msr = msr & !1;
+        let (base, mask) = unsafe { (Msr::new(base_msr).read(), Msr::new(base_msr + 1).read()) };
+        ranges.push(variable_range(base, mask));
+        ranges.push(if msr == base_msr {
+            variable_range(value, mask)
+        } else {
+            variable_range(base, value)
+        });
+    }
+    // Set reserved bits and invalid types raise #GP in the guest rather than in the VMM.
+    let types = if is_fixed {
+        8
+    } else if msr == MSR_IA32_MTRR_DEF_TYPE || msr & 1 == 0 {
+        1
+    } else {
+        0
+    };
+    if value & reserved_bits(msr, is_fixed) != 0
+        || (0..types).any(|i| memory_type(value >> (8 * i)).is_none())
+    {
+        serial_println!("invalid MTRR 0x{msr:x} write 0x{value:x}");
+        return Some(Err(MsrFault));
+    }
+    if let Err(e) = msr::write(msr, value) {
+        return Some(Err(e));
+    }
+    for (start, end) in ranges {
+        if let Err(e) = apply(ept::ept(), start, end) {
+            serial_println!("failed to update the EPT memory types: {e}");
+        }
+    }
+    Some(Ok(()))
+}
@@ -0,0 +1,131 @@
+use crate::{
+    arch::intel::{
+        apic, cr, event, tlb,
+        vmcs::{Field16, Field32, FieldNatural, VmcsField, VmcsRegion},
+        vmx::VmExitGeneralPurposeRegister,
+        vmx_caps::VmxCaps,
+    },
+    serial_println,
+};
+
+/// Run the guest in real mode and with paging off, which INIT/SIPI handling needs. Requires EPT.
+pub const UNRESTRICTED_GUEST: bool = cfg!(feature = "unrestricted-guest");
+
+const ACTIVITY_STATE_ACTIVE: u32 = 0;
+const ACTIVITY_STATE_WAIT_FOR_SIPI: u32 = 3;
+
+const CR0_RESET: u64 = 0x6000_0010;
+const RFLAGS_RESET: u64 = 1 << 1;
+const DR7_RESET: u64 = 0x400;
+const RIP_RESET: u64 = 0xfff0;
+
+/// Real-mode segment access rights: present, accessed code (execute/read) or data (read/write).
+const AR_CODE: u32 = 0x9b;
+const AR_DATA: u32 = 0x93;
+const AR_LDT: u32 = 0x82;
+const AR_BUSY_TSS: u32 = 0x8b;
+
+const DATA_SEGMENTS: [(Field16, FieldNatural, Field32, Field32); 5] = [
+    (
+        VmcsField::GuestDsSelector,
+        VmcsField::GuestDsBase,
+        VmcsField::GuestDsLimit,
+        VmcsField::GuestDsAccessRights,
+    ),
+    (
+        VmcsField::GuestEsSelector,
+        VmcsField::GuestEsBase,
+        VmcsField::GuestEsLimit,
+        VmcsField::GuestEsAccessRights,
+    ),
+    (
+        VmcsField::GuestFsSelector,
+        VmcsField::GuestFsBase,
+        VmcsField::GuestFsLimit,
+        VmcsField::GuestFsAccessRights,
+    ),
+    (
+        VmcsField::GuestGsSelector,
+        VmcsField::GuestGsBase,
+        VmcsField::GuestGsLimit,
+        VmcsField::GuestGsAccessRights,
+    ),
+    (
+        VmcsField::GuestSsSelector,
+        VmcsField::GuestSsBase,
+        VmcsField::GuestSsLimit,
+        VmcsField::GuestSsAccessRights,
+    ),
+];
+
+fn set_code_segment(vmcs: &mut VmcsRegion, selector: u16, base: u64) {
+    vmcs.write16(VmcsField::GuestCsSelector, selector);
+    vmcs.write_natural(VmcsField::GuestCsBase, base);
+    vmcs.write32(VmcsField::GuestCsLimit, 0xffff);
+    vmcs.write32(VmcsField::GuestCsAccessRights, AR_CODE);
+}
+
+/// Puts the vCPU into the state an INIT leaves a processor in (SDM Vol. 3A, Table 10-1) and
+/// lets it wait for a SIPI.
+pub fn init_signal(vmcs: &mut VmcsRegion, gpr: &mut VmExitGeneralPurposeRegister) {
+    // Without a way to wait for the SIPI the vCPU can only stay in INIT for good.
+    if !UNRESTRICTED_GUEST || !VmxCaps::read().misc.activity_wait_for_sipi() {
+        serial_println!(
+            "INIT signal needs the unrestricted-guest feature and the wait-for-SIPI activity state"
+        );
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+    serial_println!("INIT signal, waiting for SIPI");
+    event::clear(vmcs);
+
+    cr::reset(vmcs, CR0_RESET);
+    tlb::flush_guest(vmcs);
+    vmcs.write_natural(VmcsField::GuestCr3, 0);
+    vmcs.write64(VmcsField::GuestIa32Efer, 0);
+    vmcs.set_ia32e_mode_guest(false);
+    apic::set_tpr(0);
+
+    set_code_segment(vmcs, 0xf000, 0xffff_0000);
+    for (selector, base, limit, access_rights) in DATA_SEGMENTS {
+        vmcs.write16(selector, 0);
+        vmcs.write_natural(base, 0);
+        vmcs.write32(limit, 0xffff);
+        vmcs.write32(access_rights, AR_DATA);
+    }
+    vmcs.write16(VmcsField::GuestLdtrSelector, 0);
+    vmcs.write_natural(VmcsField::GuestLdtrBase, 0);
+    vmcs.write32(VmcsField::GuestLdtrLimit, 0xffff);
+    vmcs.write32(VmcsField::GuestLdtrAccessRights, AR_LDT);
+    vmcs.write16(VmcsField::GuestTrSelector, 0);
+    vmcs.write_natural(VmcsField::GuestTrBase, 0);
+    vmcs.write32(VmcsField::GuestTrLimit, 0xffff);
+    vmcs.write32(VmcsField::GuestTrAccessRights, AR_BUSY_TSS);
+    vmcs.write_natural(VmcsField::GuestGdtrBase, 0);
+    vmcs.write32(VmcsField::GuestGdtrLimit, 0xffff);
+    vmcs.write_natural(VmcsField::GuestIdtrBase, 0);
+    vmcs.write32(VmcsField::GuestIdtrLimit, 0xffff);
+
+    for index in 0..16 {
+        gpr.set(index, 0);
+    }
+    // EDX holds the processor signature after INIT.
+    gpr.rdx = unsafe { core::arch::x86_64::__cpuid(1) }.eax as u64;
+    vmcs.write_natural(VmcsField::GuestRip, RIP_RESET);
+    vmcs.write_natural(VmcsField::GuestRflags, RFLAGS_RESET);
+    vmcs.write_natural(VmcsField::GuestDr7, DR7_RESET);
+
+    vmcs.write32(VmcsField::GuestInterruptibilityState, 0);
+    vmcs.write_natural(VmcsField::GuestPendingDbgExceptions, 0);
+    vmcs.write32(VmcsField::GuestActivityState, ACTIVITY_STATE_WAIT_FOR_SIPI);
+}
+
+/// Starts a vCPU waiting for SIPI in real mode at `vector` * 4 KiB.
+pub fn startup_ipi(vmcs: &mut VmcsRegion, qual: u64) {
+    let vector = qual & 0xff;
+    serial_println!("SIPI, starting at 0x{:x}", vector << 12);
+    set_code_segment(vmcs, (vector << 8) as u16, vector << 12);
+    vmcs.write_natural(VmcsField::GuestRip, 0);
+    vmcs.write32(VmcsField::GuestActivityState, ACTIVITY_STATE_ACTIVE);
+}
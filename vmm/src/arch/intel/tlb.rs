@@ -0,0 +1,111 @@
+use crate::{
+    arch::intel::{
+        ept,
+        vmcs::{VmcsField, VmcsRegion},
+        vmx::{invept, invvpid, InveptType, InvvpidType, VmxError},
+        vmx_caps::{EptVpidCap, VmxCaps, PROC_BASED2_ENABLE_VPID},
+    },
+    serial_println,
+};
+use crossbeam::atomic::AtomicCell;
+use lazy_static::lazy_static;
+
+lazy_static! {
+    static ref EPT_VPID_CAP: EptVpidCap = VmxCaps::read().ept_vpid;
+}
+
+/// VPID 0 tags the VMM's own translations, vCPUs get the others in order.
+static NEXT_VPID: AtomicCell<u16> = AtomicCell::new(1);
+
+fn report(result: Result<(), VmxError>) {
+    if let Err(e) = result {
+        serial_println!("TLB invalidation failed: {e}");
+    }
+}
+
+/// Allocates a VPID for a vCPU. Without one, every VM entry and exit flushes the TLB.
+/// Returns `None` if the processor cannot tag translations or flush a single VPID.
+pub fn allocate_vpid(caps: &VmxCaps) -> Option<u16> {
+    let cap = caps.ept_vpid;
+    if !caps.proc_based2.is_supported(PROC_BASED2_ENABLE_VPID)
+        || !cap.invvpid()
+        || !(cap.invvpid_single_context() || cap.invvpid_all_context())
+    {
+        return None;
+    }
+    let vpid = NEXT_VPID.fetch_add(1);
+    if vpid == 0 {
+        serial_println!("out of VPIDs");
+        return None;
+    }
+    // A previous user of the VPID may have left translations behind.
+    flush_vpid(vpid);
+    Some(vpid)
+}
+
+fn flush_vpid(vpid: u16) {
+    let kind = if EPT_VPID_CAP.invvpid_single_context() {
+        InvvpidType::SingleContext
+    } else {
+        InvvpidType::AllContext
+    };
+    report(unsafe { invvpid(kind, vpid, 0) });
+}
+
+/// The VPID of the current vCPU, 0 if it has none.
+fn vpid(vmcs: &VmcsRegion) -> u16 {
+    if vmcs.read32(VmcsField::ProcBasedVmExecControls2) & PROC_BASED2_ENABLE_VPID == 0 {
+        return 0;
+    }
+    vmcs.read16(VmcsField::VirtualProcessorId)
+}
+
+/// Drops all translations of the guest, as a processor does on a change to CR0.PG or to the
+/// paging bits of CR4. Without a VPID the next VM entry does it.
+pub fn flush_guest(vmcs: &VmcsRegion) {
+    let vpid = vpid(vmcs);
+    if vpid != 0 {
+        flush_vpid(vpid);
+    }
+}
+
+/// Drops the non-global translations of the guest, as MOV to CR3 does.
+pub fn flush_guest_non_global(vmcs: &VmcsRegion) {
+    let vpid = vpid(vmcs);
+    if vpid == 0 {
+        return;
+    }
+    if EPT_VPID_CAP.invvpid_single_context_retaining_globals() {
+        report(unsafe { invvpid(InvvpidType::SingleContextRetainingGlobals, vpid, 0) });
+    } else {
+        flush_vpid(vpid);
+    }
+}
+
+/// Drops the translations of one guest linear address, as INVLPG does.
+#[allow(unused)]
+pub fn flush_guest_address(vmcs: &VmcsRegion, addr: u64) {
+    let vpid = vpid(vmcs);
+    if vpid == 0 {
+        return;
+    }
+    if EPT_VPID_CAP.invvpid_individual_address() {
+        report(unsafe { invvpid(InvvpidType::IndividualAddress, vpid, addr) });
+    } else {
+        flush_vpid(vpid);
+    }
+}
+
+/// Drops the guest-physical and combined translations derived from `eptp`. Needed after
+/// an EPT entry loses permissions or changes its frame, whether or not a VPID is in use.
+pub fn flush_ept(eptp: u64) {
+    if !ept::ENABLE_EPT || !EPT_VPID_CAP.invept() {
+        return;
+    }
+    let kind = if EPT_VPID_CAP.invept_single_context() {
+        InveptType::SingleContext
+    } else {
+        InveptType::AllContext
+    };
+    report(unsafe { invept(kind, eptp) });
+}
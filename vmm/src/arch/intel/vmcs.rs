@@ -1,24 +1,34 @@
 use crate::{
+    allocator,
     arch::intel::{
-        ept::EptPointer,
-        vmx::{vmclear, vmptrld, vmread, vmwrite},
+        apic, cr, cr3_tracker, desc_table,
+        entry_check::VmcsRead,
+        ept::{self, EptPointer},
+        exception, io, msr, sipi, tlb,
+        vmcs_cache::VmcsCache,
+        vmx::{vmclear, vmptrld, vmread, vmwrite, VmxError},
+        vmx_caps::VmxCaps,
+        BSP,
     },
-    cpu::{Ldtr, SegmentDescriptor, Tr},
-    BOOT_ARGS,
+    cpu::{
+        guest_linear_to_phys, GuestAccess, GuestPaging, PageFault, SegmentCache, SegmentDescriptor,
+        Tr, ACCESS_RIGHTS_UNUSABLE,
+    },
+    serial_print, serial_println,
 };
 use alloc::alloc::alloc;
 use common::constants;
-use core::{alloc::Layout, ptr, slice};
+use core::{alloc::Layout, ptr};
+use crossbeam::atomic::AtomicCell;
 use x86_64::{
     instructions::tables::{sgdt, sidt},
     registers::{
         control::{Cr0, Cr3, Cr4, Cr4Flags},
-        debug::Dr7,
         model_specific::Msr,
         rflags,
         segmentation::{Segment, Segment64, CS, DS, ES, FS, GS, SS},
     },
-    structures::DescriptorTablePointer,
+    structures::{gdt::SegmentSelector, DescriptorTablePointer},
     PhysAddr, VirtAddr,
 };
 
@@ -35,177 +45,410 @@ extern "C" {
     static uefi_ldtr: u64;
     static uefi_tr: u64;
     static uefi_rsp: u64;
+    static uefi_cr0: u64;
+    static uefi_cr4: u64;
+    static uefi_dr7: u64;
+    static uefi_msr_ia32_sysenter_cs: u16;
+    static uefi_msr_ia32_sysenter_esp: u32;
+    static uefi_msr_ia32_sysenter_esp_high: u32;
+    static uefi_msr_ia32_sysenter_eip: u32;
+    static uefi_msr_ia32_sysenter_eip_high: u32;
+    static uefi_msr_ia32_debugctl: u64;
+    static uefi_msr_ia32_pat: u64;
+    static uefi_msr_efer: u64;
+    static uefi_msr_fs_base: u64;
+    static uefi_msr_gs_base: u64;
+}
+
+/// Present 64-bit busy TSS.
+const AR_BUSY_TSS: u32 = 0x8b;
+const AR_LONG_MODE: u32 = 1 << 13;
+const AR_DEFAULT_BIG: u32 = 1 << 14;
+const AR_DPL_SHIFT: u32 = 5;
+const CR0_WP: u64 = 1 << 16;
+const CR0_PG: u64 = 1 << 31;
+const CR4_PSE: u64 = 1 << 4;
+const CR4_PAE: u64 = 1 << 5;
+const CR4_LA57: u64 = 1 << 12;
+
+/// Reads a descriptor-table pointer stored by SGDT/SIDT.
+unsafe fn descriptor_table_pointer(stored: &u8) -> DescriptorTablePointer {
+    let stored = stored as *const u8;
+    DescriptorTablePointer {
+        limit: ptr::read_unaligned(stored as *const u16),
+        base: VirtAddr::new(ptr::read_unaligned(stored.add(2) as *const u64)),
+    }
 }
 
 #[derive(Debug)]
-pub struct VmcsRegion(*mut u8);
+pub struct VmcsRegion {
+    region: *mut u8,
+    cache: VmcsCache,
+    /// The first write that failed since the last flush, reported by `flush`.
+    write_error: Option<VmxError>,
+}
 
 unsafe impl Send for VmcsRegion {}
 
 impl VmcsRegion {
     pub unsafe fn new() -> Self {
-        let layout = Layout::from_size_align(4096, 4096).unwrap();
-        let region = alloc(layout);
-        let region_slice = slice::from_raw_parts_mut(region, 4096);
-        region_slice.fill(0);
+        let region = allocator::alloc_pages(1).unwrap();
 
         let ia32_vmx_basic = Msr::new(constants::MSR_IA32_VMX_BASIC).read();
         let vmcs_rev_id = (ia32_vmx_basic & 0x7fff_ffff) as u32;
 
         ptr::write_volatile(region as *mut u32, vmcs_rev_id);
 
-        Self(region)
+        Self {
+            region,
+            cache: VmcsCache::new(),
+            write_error: None,
+        }
     }
 
     fn as_mut_ptr(&self) -> *mut u8 {
-        self.0
+        self.region
     }
 
     pub fn paddr(&self) -> PhysAddr {
-        let virt = i64::try_from(self.as_mut_ptr() as u64).unwrap();
-        let phys = u64::try_from(virt + BOOT_ARGS.load().vmm_phys_offset).unwrap();
-        PhysAddr::new(phys)
+        allocator::virt_to_phys(self.as_mut_ptr())
     }
 
-    pub fn clear(&mut self) {
-        unsafe {
-            vmclear(self);
+    pub fn clear(&mut self) -> Result<(), VmxError> {
+        unsafe { vmclear(self) }
+    }
+
+    pub fn load(&self) -> Result<(), VmxError> {
+        unsafe { vmptrld(self)? };
+        VMCS_LOADED.store(true);
+        Ok(())
+    }
+
+    fn try_read_raw(&self, field: VmcsEncoding) -> Result<u64, VmxError> {
+        let read = || unsafe { vmread(field) };
+        self.cache.read(field, read).unwrap_or_else(read)
+    }
+
+    /// Reads a field of the current VMCS. VMREAD only fails without a current VMCS or for a
+    /// field the processor does not implement. The VM-exit handlers run with the VMCS loaded
+    /// and only read fields that exist whenever their exit can happen, so a failure means the
+    /// VMM's own state is corrupt and the guest cannot be resumed safely: it is fatal, and the
+    /// panic handler dumps the VMCS. Fields that depend on optional VMX features are read
+    /// with `try_read`, whose error the caller reports.
+    fn read(&self, field: VmcsEncoding) -> u64 {
+        self.try_read_raw(field).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Reads a field, returning the VMREAD error instead of treating it as fatal.
+    pub fn try_read<F: TypedField>(&self, field: F) -> Result<F::Value, VmxError> {
+        self.try_read_raw(field.into()).map(F::from_raw)
+    }
+
+    /// Writes a field. Cached fields reach the VMCS on `flush`, which also reports the first
+    /// failed write.
+    fn write(&mut self, field: VmcsEncoding, val: u64) {
+        if !self.cache.write(field, val) {
+            if let Err(e) = unsafe { vmwrite(field, val) } {
+                self.write_error.get_or_insert(e);
+            }
         }
     }
 
-    pub fn load(&self) {
-        unsafe {
-            vmptrld(self);
+    /// Writes back the cached fields modified since the last flush. Must run before VM entry.
+    pub fn flush(&mut self) -> Result<(), VmxError> {
+        for (field, val) in self.cache.take_dirty() {
+            if let Err(e) = unsafe { vmwrite(field, val) } {
+                self.write_error.get_or_insert(e);
+            }
+        }
+        match self.write_error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
         }
     }
 
-    pub fn read(&self, field: VmcsField) -> u64 {
-        unsafe { vmread(field) }
+    /// Whether the guest runs in IA-32e mode, that is with EFER.LMA set.
+    pub fn guest_ia32e_mode(&self) -> bool {
+        self.read32(VmcsField::VmEntryControls) & VMCS_VMENTRY_CTLS_IA32E_MODE_GUEST != 0
     }
 
-    pub fn write(&mut self, field: VmcsField, val: u64) {
-        unsafe {
-            vmwrite(field, val);
+    /// Default operand and address size of the guest's code segment: 16, 32 or 64.
+    pub fn guest_bitness(&self) -> u32 {
+        let cs = self.read32(VmcsField::GuestCsAccessRights);
+        if self.guest_ia32e_mode() && cs & AR_LONG_MODE != 0 {
+            64
+        } else if cs & AR_DEFAULT_BIG != 0 {
+            32
+        } else {
+            16
         }
     }
 
-    pub fn setup(&mut self, eptp: EptPointer, vmexit_host_rip: u64) {
-        self.setup_guest_state_area();
-        self.setup_host_state_area(vmexit_host_rip);
-        self.setup_vm_control_fields(eptp);
+    pub fn guest_cpl(&self) -> u32 {
+        (self.read32(VmcsField::GuestSsAccessRights) >> AR_DPL_SHIFT) & 0b11
     }
 
-    fn setup_guest_state_area(&mut self) {
-        // 16 bit guest state fields
-        let _cs = CS::get_reg();
-        let _ds = DS::get_reg();
-        let _es = ES::get_reg();
-        let _fs = FS::get_reg();
-        let _gs = GS::get_reg();
-        let _ss = SS::get_reg();
-        let _ldtr = Ldtr::get_reg();
-        let _tr = Tr::get_reg();
-        let cs = unsafe { uefi_cs };
-        let ds = unsafe { uefi_ds };
-        let es = unsafe { uefi_es };
-        let fs = unsafe { uefi_fs };
-        let gs = unsafe { uefi_gs };
-        let ss = unsafe { uefi_ss };
-        let ldtr = unsafe { uefi_ldtr };
-        let tr = unsafe { uefi_tr };
-        self.write(VmcsField::GuestCsSelector, cs as u64);
-        self.write(VmcsField::GuestDsSelector, ds as u64);
-        self.write(VmcsField::GuestEsSelector, es as u64);
-        self.write(VmcsField::GuestFsSelector, fs as u64);
-        self.write(VmcsField::GuestGsSelector, gs as u64);
-        self.write(VmcsField::GuestSsSelector, ss as u64);
-        self.write(VmcsField::GuestLdtrSelector, ldtr);
-        self.write(VmcsField::GuestTrSelector, tr);
-
-        // 32 bit guest state fields
-        let gdtr = unsafe {
-            let limit = *(&uefi_gdtr as *const u8 as *const u16);
-            let base = *((&uefi_gdtr as *const u8).offset(2) as *const u64);
-            DescriptorTablePointer {
-                limit,
-                base: VirtAddr::new(base),
+    /// Paging mode of the guest. Uses the real CR0 and CR4, which differ from what the guest
+    /// reads when it has not got unrestricted guest and turned paging off.
+    pub fn guest_paging(&self) -> GuestPaging {
+        let cr0 = self.read_natural(VmcsField::GuestCr0);
+        let cr4 = self.read_natural(VmcsField::GuestCr4);
+        if cr0 & CR0_PG == 0 {
+            GuestPaging::Disabled
+        } else if self.guest_ia32e_mode() {
+            if cr4 & CR4_LA57 != 0 {
+                GuestPaging::Level5
+            } else {
+                GuestPaging::Level4
             }
+        } else if cr4 & CR4_PAE != 0 {
+            GuestPaging::Pae
+        } else {
+            GuestPaging::Bits32 {
+                pse: cr4 & CR4_PSE != 0,
+            }
+        }
+    }
+
+    /// Translates a guest linear address for an access made at the guest's current CPL.
+    pub fn translate_guest(&self, linear: u64, write: bool) -> Result<PhysAddr, PageFault> {
+        let access = GuestAccess {
+            write,
+            user: self.guest_cpl() == 3,
+            write_protect: self.read_natural(VmcsField::GuestCr0) & CR0_WP != 0,
+        };
+        guest_linear_to_phys(
+            self.guest_paging(),
+            self.read_natural(VmcsField::GuestCr3),
+            linear,
+            access,
+        )
+    }
+
+    /// Reads the guest code at RIP into `buf`, translating every byte the way the guest
+    /// fetches it. Stops at the first byte that is not mapped and returns the number read.
+    pub fn read_guest_code(&self, buf: &mut [u8]) -> usize {
+        let rip = self.read_natural(VmcsField::GuestRip);
+        // Outside 64-bit mode the CS base applies and linear addresses have 32 bits.
+        let (linear, mask) = if self.guest_bitness() == 64 {
+            (rip, !0)
+        } else {
+            (self.read_natural(VmcsField::GuestCsBase) + rip, 0xffff_ffff)
         };
-        let idtr = unsafe {
-            let limit = *(&uefi_idtr as *const u8 as *const u16);
-            let base = *((&uefi_idtr as *const u8).offset(2) as *const u64);
-            DescriptorTablePointer {
-                limit,
-                base: VirtAddr::new(base),
+        for (i, b) in buf.iter_mut().enumerate() {
+            match self.translate_guest(linear.wrapping_add(i as u64) & mask, false) {
+                Ok(phys) => *b = unsafe { ptr::read_volatile(phys.as_u64() as *const u8) },
+                Err(_) => return i,
             }
+        }
+        buf.len()
+    }
+
+    /// Sets the "IA-32e mode guest" entry control, which VM entry loads into IA32_EFER.LMA.
+    pub fn set_ia32e_mode_guest(&mut self, enabled: bool) {
+        let entry_ctls = self.read32(VmcsField::VmEntryControls);
+        let entry_ctls = if enabled {
+            entry_ctls | VMCS_VMENTRY_CTLS_IA32E_MODE_GUEST
+        } else {
+            entry_ctls & !VMCS_VMENTRY_CTLS_IA32E_MODE_GUEST
         };
-        let gdtr_limit = gdtr.limit;
-        let idtr_limit = idtr.limit;
-        let sysenter_cs = unsafe { Msr::new(constants::MSR_IA32_SYSENTER_CS).read() };
-        let efer = unsafe { Msr::new(constants::MSR_EFER).read() };
-        self.write(VmcsField::GuestCsLimit, 0xffffffff);
-        self.write(VmcsField::GuestDsLimit, 0xffffffff);
-        self.write(VmcsField::GuestEsLimit, 0xffffffff);
-        self.write(VmcsField::GuestFsLimit, 0xffffffff);
-        self.write(VmcsField::GuestGsLimit, 0xffffffff);
-        self.write(VmcsField::GuestSsLimit, 0xffffffff);
-        self.write(VmcsField::GuestLdtrLimit, 0xffff);
-        self.write(VmcsField::GuestTrLimit, 0xffff);
-        self.write(VmcsField::GuestGdtrLimit, gdtr_limit as u64);
-        self.write(VmcsField::GuestIdtrLimit, idtr_limit as u64);
-        self.write(VmcsField::GuestCsAccessRights, 0xa09b);
-        self.write(VmcsField::GuestDsAccessRights, 0xc093);
-        self.write(VmcsField::GuestEsAccessRights, 0xc093);
-        self.write(VmcsField::GuestFsAccessRights, 0xc093);
-        self.write(VmcsField::GuestGsAccessRights, 0xc093);
-        self.write(VmcsField::GuestSsAccessRights, 0xc093);
-        self.write(VmcsField::GuestLdtrAccessRights, 0x0082);
-        self.write(VmcsField::GuestTrAccessRights, 0x008b);
-        self.write(VmcsField::GuestInterruptibilityState, 0);
-        self.write(VmcsField::GuestActivityState, 0);
-        self.write(VmcsField::GuestPendingDbgExceptions, 0);
-        self.write(VmcsField::GuestIa32SysenterCs, sysenter_cs);
-
-        // 64 bit guest state fields
-        self.write(VmcsField::VmcsLinkPointer, 0xffff_ffff_ffff_ffff_u64);
-        self.write(VmcsField::GuestIa32Debugctl, 0u64);
-        self.write(VmcsField::GuestIa32Efer, efer);
-
-        // natural width guest state fields
-        let cr0 = Cr0::read_raw();
+        self.write32(VmcsField::VmEntryControls, entry_ctls);
+    }
+
+    /// Forgets the cached fields. Must run on every VM exit. Writes that were never flushed
+    /// would overwrite the state of the new exit, so they are dropped.
+    pub fn invalidate_cache(&mut self) {
+        for (field, value) in self.cache.take_dirty() {
+            serial_println!(
+                "dropping unflushed write of 0x{value:x} to {}",
+                field.name()
+            );
+        }
+        self.cache.invalidate();
+    }
+
+    pub fn read16(&self, field: Field16) -> u16 {
+        self.read(field.encoding()) as u16
+    }
+
+    pub fn read32(&self, field: Field32) -> u32 {
+        self.read(field.encoding()) as u32
+    }
+
+    pub fn read64(&self, field: Field64) -> u64 {
+        self.read(field.encoding())
+    }
+
+    pub fn read_natural(&self, field: FieldNatural) -> u64 {
+        self.read(field.encoding())
+    }
+
+    pub fn write16(&mut self, field: Field16, val: u16) {
+        self.write(field.encoding(), val as u64);
+    }
+
+    pub fn write32(&mut self, field: Field32, val: u32) {
+        self.write(field.encoding(), val as u64);
+    }
+
+    pub fn write64(&mut self, field: Field64, val: u64) {
+        self.write(field.encoding(), val);
+    }
+
+    pub fn write_natural(&mut self, field: FieldNatural, val: u64) {
+        self.write(field.encoding(), val);
+    }
+
+    pub fn setup(
+        &mut self,
+        eptp: EptPointer,
+        vmexit_host_rip: u64,
+        pinnable: cr::Pinnable,
+    ) -> Result<(), VmxError> {
+        self.setup_guest_state_area();
+        self.setup_host_state_area(vmexit_host_rip);
+        self.setup_vm_control_fields(eptp, pinnable)?;
+        self.flush()
+    }
+
+    /// The guest picks up where the firmware called the VMM: it returns from `entry` on the
+    /// firmware's stack, with the registers the firmware had. Segment registers are decoded from
+    /// the firmware's GDT. CR3 stays the one the loader called the VMM with, since the guest
+    /// still runs `entry_ret` in the VMM's mapping; the loader switches back to the firmware's
+    /// own page tables afterwards.
+    fn setup_guest_state_area(&mut self) {
+        let gdtr = unsafe { descriptor_table_pointer(&uefi_gdtr) };
+        let idtr = unsafe { descriptor_table_pointer(&uefi_idtr) };
+        let ldtr = SegmentSelector(unsafe { uefi_ldtr } as u16);
+        let segments = [
+            (
+                unsafe { uefi_cs },
+                VmcsField::GuestCsSelector,
+                VmcsField::GuestCsBase,
+                VmcsField::GuestCsLimit,
+                VmcsField::GuestCsAccessRights,
+            ),
+            (
+                unsafe { uefi_ds },
+                VmcsField::GuestDsSelector,
+                VmcsField::GuestDsBase,
+                VmcsField::GuestDsLimit,
+                VmcsField::GuestDsAccessRights,
+            ),
+            (
+                unsafe { uefi_es },
+                VmcsField::GuestEsSelector,
+                VmcsField::GuestEsBase,
+                VmcsField::GuestEsLimit,
+                VmcsField::GuestEsAccessRights,
+            ),
+            (
+                unsafe { uefi_fs },
+                VmcsField::GuestFsSelector,
+                VmcsField::GuestFsBase,
+                VmcsField::GuestFsLimit,
+                VmcsField::GuestFsAccessRights,
+            ),
+            (
+                unsafe { uefi_gs },
+                VmcsField::GuestGsSelector,
+                VmcsField::GuestGsBase,
+                VmcsField::GuestGsLimit,
+                VmcsField::GuestGsAccessRights,
+            ),
+            (
+                unsafe { uefi_ss },
+                VmcsField::GuestSsSelector,
+                VmcsField::GuestSsBase,
+                VmcsField::GuestSsLimit,
+                VmcsField::GuestSsAccessRights,
+            ),
+            (
+                ldtr.0,
+                VmcsField::GuestLdtrSelector,
+                VmcsField::GuestLdtrBase,
+                VmcsField::GuestLdtrLimit,
+                VmcsField::GuestLdtrAccessRights,
+            ),
+            (
+                unsafe { uefi_tr } as u16,
+                VmcsField::GuestTrSelector,
+                VmcsField::GuestTrBase,
+                VmcsField::GuestTrLimit,
+                VmcsField::GuestTrAccessRights,
+            ),
+        ];
+        for (selector, selector_field, base, limit, access_rights) in segments {
+            let mut cache = unsafe { SegmentCache::decode(&gdtr, ldtr, SegmentSelector(selector)) };
+            // VM entry needs a usable, busy TSS, even if the firmware never loaded one.
+            if selector_field == VmcsField::GuestTrSelector {
+                if cache.access_rights & ACCESS_RIGHTS_UNUSABLE != 0 {
+                    cache = SegmentCache {
+                        base: 0,
+                        limit: 0xffff,
+                        access_rights: AR_BUSY_TSS,
+                    };
+                }
+                cache.access_rights |= AR_BUSY_TSS;
+            }
+            self.write16(selector_field, selector);
+            self.write_natural(base, cache.base);
+            self.write32(limit, cache.limit);
+            self.write32(access_rights, cache.access_rights);
+        }
+        // In 64-bit mode the FS and GS bases come from the MSRs, not from the descriptors.
+        self.write_natural(VmcsField::GuestFsBase, unsafe { uefi_msr_fs_base });
+        self.write_natural(VmcsField::GuestGsBase, unsafe { uefi_msr_gs_base });
+
+        self.write32(VmcsField::GuestGdtrLimit, gdtr.limit as u32);
+        self.write32(VmcsField::GuestIdtrLimit, idtr.limit as u32);
+        self.write_natural(VmcsField::GuestGdtrBase, gdtr.base.as_u64());
+        self.write_natural(VmcsField::GuestIdtrBase, idtr.base.as_u64());
+        self.write32(VmcsField::GuestInterruptibilityState, 0);
+        self.write32(VmcsField::GuestActivityState, 0);
+        self.write_natural(VmcsField::GuestPendingDbgExceptions, 0);
+        self.write64(VmcsField::VmcsLinkPointer, 0xffff_ffff_ffff_ffff);
+
+        self.write32(
+            VmcsField::GuestIa32SysenterCs,
+            unsafe { uefi_msr_ia32_sysenter_cs } as u32,
+        );
+        self.write_natural(VmcsField::GuestSysenterEsp, unsafe {
+            (uefi_msr_ia32_sysenter_esp_high as u64) << 32 | uefi_msr_ia32_sysenter_esp as u64
+        });
+        self.write_natural(VmcsField::GuestSysenterEip, unsafe {
+            (uefi_msr_ia32_sysenter_eip_high as u64) << 32 | uefi_msr_ia32_sysenter_eip as u64
+        });
+        self.write64(VmcsField::GuestIa32Debugctl, unsafe {
+            uefi_msr_ia32_debugctl
+        });
+        self.write64(VmcsField::GuestIa32Pat, unsafe { uefi_msr_ia32_pat });
+        self.write64(VmcsField::GuestIa32Efer, unsafe { uefi_msr_efer });
+
+        // VMX operation needs the fixed CR0/CR4 bits, the read shadows show the firmware's
+        // values (see `cr::setup`).
+        let caps = VmxCaps::read();
         let cr3_tuple = Cr3::read_raw();
         let cr3 = cr3_tuple.0.start_address().as_u64() | (cr3_tuple.1 as u64);
-        let cr4 = Cr4::read_raw();
-        // let ldtr_base = SegmentDescriptor::base(&ldtr);
-        // let tr_base = SegmentDescriptor::base(&tr);
-        let gdtr_base = gdtr.base.as_u64();
-        let idtr_base = idtr.base.as_u64();
-        let dr7 = Dr7::read_raw();
-        // VM(bit 17) must be 0, IF(bit 9) is 0
-        let rflags = rflags::read_raw() & !(1 << 17 | 1 << 9);
-        let sysenter_esp = unsafe { Msr::new(constants::MSR_IA32_SYSENTER_ESP).read() };
-        let sysenter_eip = unsafe { Msr::new(constants::MSR_IA32_SYSENTER_EIP).read() };
-        let rsp = unsafe { uefi_rsp };
-        let rip = unsafe { &entry_ret as *const u8 as u64 };
-        self.write(VmcsField::GuestCr0, cr0);
-        self.write(VmcsField::GuestCr3, cr3);
-        self.write(VmcsField::GuestCr4, cr4);
-        self.write(VmcsField::GuestCsBase, 0);
-        self.write(VmcsField::GuestDsBase, 0);
-        self.write(VmcsField::GuestEsBase, 0);
-        self.write(VmcsField::GuestFsBase, 0);
-        self.write(VmcsField::GuestGsBase, 0);
-        self.write(VmcsField::GuestSsBase, 0);
-        self.write(VmcsField::GuestLdtrBase, 0);
-        self.write(VmcsField::GuestTrBase, 0);
-        self.write(VmcsField::GuestGdtrBase, gdtr_base);
-        self.write(VmcsField::GuestIdtrBase, idtr_base);
-        self.write(VmcsField::GuestDr7, dr7);
-        self.write(VmcsField::GuestRsp, rsp);
-        self.write(VmcsField::GuestRip, rip);
-        self.write(VmcsField::GuestRflags, rflags);
-        self.write(VmcsField::GuestSysenterEsp, sysenter_esp);
-        self.write(VmcsField::GuestSysenterEip, sysenter_eip);
+        self.write_natural(
+            VmcsField::GuestCr0,
+            caps.cr0_fixed.apply(unsafe { uefi_cr0 }),
+        );
+        self.write_natural(VmcsField::GuestCr3, cr3);
+        self.write_natural(
+            VmcsField::GuestCr4,
+            caps.cr4_fixed.apply(unsafe { uefi_cr4 }),
+        );
+        self.write_natural(VmcsField::GuestDr7, unsafe { uefi_dr7 });
+        self.write_natural(VmcsField::GuestRsp, unsafe { uefi_rsp });
+        self.write_natural(VmcsField::GuestRip, unsafe {
+            &entry_ret as *const u8 as u64
+        });
+        // The firmware called the VMM with interrupts disabled.
+        self.write_natural(
+            VmcsField::GuestRflags,
+            rflags::read_raw() & !(1 << 17 | 1 << 9),
+        );
     }
 
     fn setup_host_state_area(&mut self, vmexit_host_rip: u64) {
@@ -217,17 +460,17 @@ impl VmcsRegion {
         let gs = GS::get_reg();
         let ss = SS::get_reg();
         let tr = Tr::get_reg();
-        self.write(VmcsField::HostCsSelector, cs.0 as u64);
-        self.write(VmcsField::HostDsSelector, ds.0 as u64);
-        self.write(VmcsField::HostEsSelector, es.0 as u64);
-        self.write(VmcsField::HostFsSelector, fs.0 as u64);
-        self.write(VmcsField::HostGsSelector, gs.0 as u64);
-        self.write(VmcsField::HostSsSelector, ss.0 as u64);
-        self.write(VmcsField::HostTrSelector, tr.0 as u64);
+        self.write16(VmcsField::HostCsSelector, cs.0);
+        self.write16(VmcsField::HostDsSelector, ds.0);
+        self.write16(VmcsField::HostEsSelector, es.0);
+        self.write16(VmcsField::HostFsSelector, fs.0);
+        self.write16(VmcsField::HostGsSelector, gs.0);
+        self.write16(VmcsField::HostSsSelector, ss.0);
+        self.write16(VmcsField::HostTrSelector, tr.0);
 
         // 32 bit host state fields
         let sysenter_cs = unsafe { Msr::new(constants::MSR_IA32_SYSENTER_CS).read() };
-        self.write(VmcsField::HostIa32SysenterCs, sysenter_cs);
+        self.write32(VmcsField::HostIa32SysenterCs, sysenter_cs as u32);
 
         // native width host state fields
         let cr3_tuple = Cr3::read_raw();
@@ -260,258 +503,537 @@ impl VmcsRegion {
         let layout = Layout::from_size_align(4096, 16).unwrap();
         let stack_bottom = unsafe { alloc(layout) };
         let stack_top = stack_bottom as u64 + 4096 - 16;
-        self.write(VmcsField::HostCr0, cr0);
-        self.write(VmcsField::HostCr3, cr3);
-        self.write(VmcsField::HostCr4, cr4);
-        self.write(VmcsField::HostFsBase, fs_base);
-        self.write(VmcsField::HostGsBase, gs_base);
-        self.write(VmcsField::HostTrBase, tr_base);
-        self.write(VmcsField::HostGdtrBase, gdtr_base);
-        self.write(VmcsField::HostIdtrBase, idtr_base);
-        self.write(VmcsField::HostIa32SysenterEsp, sysenter_esp);
-        self.write(VmcsField::HostIa32SysenterEip, sysenter_eip);
-        self.write(VmcsField::HostRsp, stack_top);
-        self.write(VmcsField::HostRip, vmexit_host_rip);
-        self.write(VmcsField::HostIa32Efer, efer);
-        self.write(VmcsField::HostIa32Pat, pat);
-    }
-
-    fn setup_vm_control_fields(&mut self, eptp: EptPointer) {
+        self.write_natural(VmcsField::HostCr0, cr0);
+        self.write_natural(VmcsField::HostCr3, cr3);
+        self.write_natural(VmcsField::HostCr4, cr4);
+        self.write_natural(VmcsField::HostFsBase, fs_base);
+        self.write_natural(VmcsField::HostGsBase, gs_base);
+        self.write_natural(VmcsField::HostTrBase, tr_base);
+        self.write_natural(VmcsField::HostGdtrBase, gdtr_base);
+        self.write_natural(VmcsField::HostIdtrBase, idtr_base);
+        self.write_natural(VmcsField::HostIa32SysenterEsp, sysenter_esp);
+        self.write_natural(VmcsField::HostIa32SysenterEip, sysenter_eip);
+        self.write_natural(VmcsField::HostRsp, stack_top);
+        self.write_natural(VmcsField::HostRip, vmexit_host_rip);
+        self.write64(VmcsField::HostIa32Efer, efer);
+        self.write64(VmcsField::HostIa32Pat, pat);
+    }
+
+    fn setup_vm_control_fields(
+        &mut self,
+        eptp: EptPointer,
+        pinnable: cr::Pinnable,
+    ) -> Result<(), VmxError> {
         // 32 bit control fields
-        let pin_based_ctls = unsafe { Msr::new(constants::MSR_IA32_VMX_PINBASED_CTLS).read() };
-        let pin_based_ctls_or = (pin_based_ctls & 0xffff_ffff) as u32;
-        let pin_based_ctls_and = ((pin_based_ctls >> 32) & 0xffff_ffff) as u32;
-        let pin_based_ctls = (pin_based_ctls_or & pin_based_ctls_and) as u64;
-        let proc_based_ctls = unsafe { Msr::new(constants::MSR_IA32_VMX_PROCBASED_CTLS).read() };
-        let proc_based_ctls_or = (proc_based_ctls & 0xffff_ffff) as u32;
-        let proc_based_ctls_and = ((proc_based_ctls >> 32) & 0xffff_ffff) as u32;
-        let proc_based_ctls = (proc_based_ctls_or & proc_based_ctls_and) as u64;
-        let proc_based_ctls2 = unsafe { Msr::new(constants::MSR_IA32_VMX_PROCBASED_CTLS2).read() };
-        let proc_based_ctls2_or = (proc_based_ctls2 & 0xffff_ffff) as u32;
-        let proc_based_ctls2_and = ((proc_based_ctls2 >> 32) & 0xffff_ffff) as u32;
-        let proc_based_ctls2 = (proc_based_ctls2_or & proc_based_ctls2_and) as u64;
-        let exit_ctls = unsafe { Msr::new(constants::MSR_IA32_VMX_EXIT_CTLS).read() };
-        let exit_ctls_or = (exit_ctls & 0xffff_ffff) as u32;
-        let exit_ctls_and = ((exit_ctls >> 32) & 0xffff_ffff) as u32;
-        let exit_ctls = (exit_ctls_or & exit_ctls_and) as u64;
-        let entry_ctls = unsafe { Msr::new(constants::MSR_IA32_VMX_ENTRY_CTLS).read() };
-        let entry_ctls_or = (entry_ctls & 0xffff_ffff) as u32;
-        let entry_ctls_and = ((entry_ctls >> 32) & 0xffff_ffff) as u32;
-        let entry_ctls = (entry_ctls_or & entry_ctls_and) as u64;
-        let msr_bitmap_phys = unsafe {
-            let layout = Layout::from_size_align(4096, 4096).unwrap();
-            let bitmap_region = alloc(layout);
-            let bitmap_slice = core::slice::from_raw_parts_mut(bitmap_region, 4096);
-            bitmap_slice.fill(0);
-            let virt = i64::try_from(bitmap_region as u64).unwrap();
-            let phys = u64::try_from(virt + BOOT_ARGS.load().vmm_phys_offset).unwrap();
-            PhysAddr::new(phys)
+        let caps = VmxCaps::read();
+        // External interrupts exit so that the VMM can keep its own vectors; the rest are
+        // re-injected.
+        // NMIs are taken away from the firmware IDT and handed to the guest through the event
+        // queue. Virtual NMIs let the processor track the guest's NMI blocking.
+        let virtual_nmis = if caps
+            .pin_based
+            .is_supported(VMCS_PIN_BASED_VMEXEC_CTLS_VIRTUAL_NMIS)
+        {
+            VMCS_PIN_BASED_VMEXEC_CTLS_VIRTUAL_NMIS
+        } else {
+            0
         };
-
-        self.write(VmcsField::PinBasedVmExecControls, pin_based_ctls);
-        self.write(
-            VmcsField::ProcBasedVmExecControls,
-            proc_based_ctls
-                // | VMCS_PROC_BASED_VMEXEC_CTLS_HLTEXIT
+        let pin_based_ctls = caps.pin_based.adjust(
+            VMCS_PIN_BASED_VMEXEC_CTLS_EXTERNAL_INTERRUPT_EXITING
+                | VMCS_PIN_BASED_VMEXEC_CTLS_NMI_EXITING
+                | virtual_nmis,
+        )?;
+        let cr3_load_exiting = if cr3_tracker::LOAD_EXITING {
+            VMCS_PROC_BASED_VMEXEC_CTLS_CR3_LOAD_EXITING
+        } else {
+            0
+        };
+        let use_tpr_shadow = if apic::TPR_SHADOW {
+            VMCS_PROC_BASED_VMEXEC_CTLS_USE_TPR_SHADOW
+        } else {
+            0
+        };
+        let proc_based_ctls = caps.proc_based.adjust(
+            // VMCS_PROC_BASED_VMEXEC_CTLS_HLTEXIT |
+            VMCS_PROC_BASED_VMEXEC_CTLS_USE_IO_BITMAPS
                 | VMCS_PROC_BASED_VMEXEC_CTLS_USE_MSR_BITMAPS
-                | VMCS_PROC_BASED_VMEXEC_CTLS_ACTIVE_SECOND_CTLS,
-        );
-        self.write(
-            VmcsField::ProcBasedVmExecControls2,
-            proc_based_ctls2, //| VMCS_PROC_BASED_VMEXEC_CTLS2_ENABLE_EPT,
-        );
-        self.write(VmcsField::ExceptionBitmap, 0xffff_ffff);
-        self.write(VmcsField::PageFaultErrorCodeMask, 0);
-        self.write(VmcsField::PageFaultErrorCodeMatch, 0);
-        self.write(VmcsField::Cr3TargetCount, 0);
-        self.write(
-            VmcsField::VmExitControls,
-            exit_ctls | VMCS_VMEXIT_CTLS_HOST_ADDR_SPACE_SIZE,
-        );
-        self.write(VmcsField::VmExitMsrStoreCount, 0);
-        self.write(VmcsField::VmExitMsrLoadCount, 0);
-        self.write(
-            VmcsField::VmEntryControls,
-            entry_ctls | VMCS_VMENTRY_CTLS_IA32E_MODE_GUEST,
-        );
-        self.write(VmcsField::VmEntryMsrLoadCount, 0);
-        self.write(VmcsField::VmEntryIntrInfoField, 0);
-        self.write(VmcsField::VmEntryExceptionErrorCode, 0);
-        self.write(VmcsField::VmEntryInstructionLen, 0);
-        self.write(VmcsField::TprThreshold, 0);
-        self.write(VmcsField::MsrBitmap, msr_bitmap_phys.as_u64());
+                | VMCS_PROC_BASED_VMEXEC_CTLS_ACTIVE_SECOND_CTLS
+                | cr3_load_exiting
+                | use_tpr_shadow,
+        )?;
+        let enable_ept = if ept::ENABLE_EPT {
+            VMCS_PROC_BASED_VMEXEC_CTLS2_ENABLE_EPT
+        } else {
+            0
+        };
+        let desc_table_exiting = if desc_table::ENABLE_EXITING {
+            VMCS_PROC_BASED_VMEXEC_CTLS2_DESC_TABLE_EXITING
+        } else {
+            0
+        };
+        let virtualize_apic_accesses = if apic::XAPIC_TRAP {
+            VMCS_PROC_BASED_VMEXEC_CTLS2_VIRTUALIZE_APIC_ACCESSES
+        } else {
+            0
+        };
+        let unrestricted_guest = if sipi::UNRESTRICTED_GUEST {
+            VMCS_PROC_BASED_VMEXEC_CTLS2_UNRESTRICTED_GUEST
+        } else {
+            0
+        };
+        let vpid = tlb::allocate_vpid(&caps);
+        let enable_vpid = if vpid.is_some() {
+            VMCS_PROC_BASED_VMEXEC_CTLS2_ENABLE_VPID
+        } else {
+            0
+        };
+        let proc_based_ctls2 = caps.proc_based2.adjust(
+            enable_ept
+                | desc_table_exiting
+                | virtualize_apic_accesses
+                | enable_vpid
+                | unrestricted_guest,
+        )?;
+        // The guest's PAT combines with the EPT memory types, the VMM keeps the firmware's.
+        let (exit_pat, entry_pat) = if caps
+            .exit
+            .is_supported(VMCS_VMEXIT_CTLS_SAVE_IA32_PAT | VMCS_VMEXIT_CTLS_LOAD_IA32_PAT)
+            && caps.entry.is_supported(VMCS_VMENTRY_CTLS_LOAD_IA32_PAT)
+        {
+            (
+                VMCS_VMEXIT_CTLS_SAVE_IA32_PAT | VMCS_VMEXIT_CTLS_LOAD_IA32_PAT,
+                VMCS_VMENTRY_CTLS_LOAD_IA32_PAT,
+            )
+        } else {
+            (0, 0)
+        };
+        // Keeps the guest IA32_EFER field current, `cr::set_guest_cr0` needs EFER.LME.
+        let exit_efer = if caps.exit.is_supported(VMCS_VMEXIT_CTLS_SAVE_IA32_EFER) {
+            VMCS_VMEXIT_CTLS_SAVE_IA32_EFER
+        } else {
+            0
+        };
+        let exit_ctls = caps.exit.adjust(
+            VMCS_VMEXIT_CTLS_HOST_ADDR_SPACE_SIZE
+                | VMCS_VMEXIT_CTLS_ACK_INTERRUPT_ON_EXIT
+                | exit_pat
+                | exit_efer,
+        )?;
+        let entry_ctls = caps
+            .entry
+            .adjust(VMCS_VMENTRY_CTLS_IA32E_MODE_GUEST | entry_pat)?;
+
+        self.write32(VmcsField::PinBasedVmExecControls, pin_based_ctls);
+        self.write32(VmcsField::ProcBasedVmExecControls, proc_based_ctls);
+        self.write32(VmcsField::ProcBasedVmExecControls2, proc_based_ctls2);
+        if let Some(vpid) = vpid {
+            self.write16(VmcsField::VirtualProcessorId, vpid);
+        }
+        exception::setup(self);
+        self.write32(VmcsField::Cr3TargetCount, 0);
+        self.write32(VmcsField::VmExitControls, exit_ctls);
+        self.write32(VmcsField::VmExitMsrStoreCount, 0);
+        self.write32(VmcsField::VmExitMsrLoadCount, 0);
+        self.write32(VmcsField::VmEntryControls, entry_ctls);
+        self.write32(VmcsField::VmEntryMsrLoadCount, 0);
+        self.write32(VmcsField::VmEntryIntrInfoField, 0);
+        self.write32(VmcsField::VmEntryExceptionErrorCode, 0);
+        self.write32(VmcsField::VmEntryInstructionLen, 0);
+        io::setup(self);
+        msr::setup(self);
+        apic::setup(self);
 
         // 64 bit control fields
-        self.write(VmcsField::VmExitMsrLoadAddr, 0);
-        self.write(VmcsField::VmExitMsrStoreAddr, 0);
-        self.write(VmcsField::VmEntryMsrLoadAddr, 0);
-        self.write(VmcsField::TscOffset, 0);
-        self.write(VmcsField::EptPointer, eptp.as_u64());
+        self.write64(VmcsField::VmExitMsrLoadAddr, 0);
+        self.write64(VmcsField::VmExitMsrStoreAddr, 0);
+        self.write64(VmcsField::VmEntryMsrLoadAddr, 0);
+        self.write64(VmcsField::TscOffset, 0);
+        self.write64(VmcsField::EptPointer, eptp.as_u64());
 
         // natural width control fields
-        // let cr0 = Cr0::read_raw();
-        // let cr4 = Cr4::read_raw();
-        self.write(VmcsField::Cr0GuestHostMask, 0);
-        self.write(VmcsField::Cr4GuestHostMask, 0);
-        self.write(VmcsField::Cr0ReadShadow, 0);
-        self.write(VmcsField::Cr4ReadShadow, 0);
-        // self.write(VmcsField::Cr3TargetValue0, 0); // hung
-        // self.write(VmcsField::Cr3TargetValue1, 0); // hung
-        // self.write(VmcsField::Cr3TargetValue2, 0); // hung
-        // self.write(VmcsField::Cr3TargetValue3, 0); // hung
+        cr::setup(self, &caps, pinnable);
+        // self.write_natural(VmcsField::Cr3TargetValue0, 0); // hung
+        // self.write_natural(VmcsField::Cr3TargetValue1, 0); // hung
+        // self.write_natural(VmcsField::Cr3TargetValue2, 0); // hung
+        // self.write_natural(VmcsField::Cr3TargetValue3, 0); // hung
+
+        Ok(())
+    }
+}
+
+impl VmcsRead for VmcsRegion {
+    fn read(&self, field: VmcsEncoding) -> u64 {
+        VmcsRegion::read(self, field)
+    }
+}
+
+/// Set once a VMCS has been made current, so that `dump` can be called from the panic handler.
+static VMCS_LOADED: AtomicCell<bool> = AtomicCell::new(false);
+
+/// Prints every readable field of the current VMCS grouped by area, with the writes the BSP
+/// still holds back in its cache. Does nothing if no VMCS has been loaded yet.
+pub fn dump() {
+    if !VMCS_LOADED.load() {
+        return;
+    }
+    let cache = unsafe { BSP.as_ptr().as_ref() }.map(|bsp| &bsp.vmcs_region.cache);
+    let areas = [
+        (VmcsFieldType::Control, "control"),
+        (VmcsFieldType::ReadOnly, "VM-exit information"),
+        (VmcsFieldType::Guest, "guest-state"),
+        (VmcsFieldType::Host, "host-state"),
+    ];
+    for (field_type, area) in areas {
+        serial_println!("---- {area} fields ----");
+        for field in VmcsEncoding::ALL {
+            if field.field_type() != field_type || field.is_high() {
+                continue;
+            }
+            // Fields that the processor does not support fail to read and are skipped.
+            if let Ok(value) = unsafe { vmread(*field) } {
+                let width = field.width().bytes() * 2;
+                serial_print!(
+                    "{:<32} ({:#06x}) = {:#0w$x}",
+                    field.name(),
+                    *field as u32,
+                    value,
+                    w = width + 2
+                );
+                match cache.and_then(|cache| cache.pending(*field)) {
+                    Some(pending) => {
+                        serial_println!(", pending write {pending:#0w$x}", w = width + 2)
+                    }
+                    None => serial_println!(),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmcsFieldWidth {
+    Bits16,
+    Bits64,
+    Bits32,
+    Natural,
+}
+
+impl VmcsFieldWidth {
+    pub fn bytes(self) -> usize {
+        match self {
+            VmcsFieldWidth::Bits16 => 2,
+            VmcsFieldWidth::Bits32 => 4,
+            VmcsFieldWidth::Bits64 | VmcsFieldWidth::Natural => 8,
+        }
     }
 }
 
-#[allow(unused)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u32)]
-pub enum VmcsField {
-    GuestEsSelector = 0x00000800,
-    GuestCsSelector = 0x00000802,
-    GuestSsSelector = 0x00000804,
-    GuestDsSelector = 0x00000806,
-    GuestFsSelector = 0x00000808,
-    GuestGsSelector = 0x0000080a,
-    GuestLdtrSelector = 0x0000080c,
-    GuestTrSelector = 0x0000080e,
-    HostEsSelector = 0x00000c00,
-    HostCsSelector = 0x00000c02,
-    HostSsSelector = 0x00000c04,
-    HostDsSelector = 0x00000c06,
-    HostFsSelector = 0x00000c08,
-    HostGsSelector = 0x00000c0a,
-    HostTrSelector = 0x00000c0c,
-    IoBitmapA = 0x00002000,
-    IoBitmapAHigh = 0x00002001,
-    IoBitmapB = 0x00002002,
-    IoBitmapBHigh = 0x00002003,
-    MsrBitmap = 0x00002004,
-    MsrBitmapHigh = 0x00002005,
-    VmExitMsrStoreAddr = 0x00002006,
-    VmExitMsrStoreAddrHigh = 0x00002007,
-    VmExitMsrLoadAddr = 0x00002008,
-    VmExitMsrLoadAddrHigh = 0x00002009,
-    VmEntryMsrLoadAddr = 0x0000200a,
-    VmEntryMsrLoadAddrHigh = 0x0000200b,
-    ExecVmcsPointer = 0x0000200c,
-    TscOffset = 0x00002010,
-    TscOffsetHigh = 0x00002011,
-    VirtualApicPageAddr = 0x00002012,
-    VirtualApicPageAddrHigh = 0x00002013,
-    VmfuncControls = 0x00002018,
-    VmfuncControlsHigh = 0x00002019,
-    EptPointer = 0x0000201A,
-    EptPointerHigh = 0x0000201B,
-    EptpList = 0x00002024,
-    EptpListHigh = 0x00002025,
-    GuestPhysicalAddress = 0x00002400,
-    GuestPhysicalAddressHigh = 0x00002401,
-    VmcsLinkPointer = 0x00002800,
-    VmcsLinkPointerHigh = 0x00002801,
-    GuestIa32Debugctl = 0x00002802,
-    GuestIa32DebugctlHigh = 0x00002803,
-    GuestIa32Efer = 0x00002806,
-    HostIa32Pat = 0x00002c00,
-    HostIa32Efer = 0x00002c02,
-    HostIa32EferHigh = 0x00002c03,
-    PinBasedVmExecControls = 0x00004000,
-    ProcBasedVmExecControls = 0x00004002,
-    ExceptionBitmap = 0x00004004,
-    PageFaultErrorCodeMask = 0x00004006,
-    PageFaultErrorCodeMatch = 0x00004008,
-    Cr3TargetCount = 0x0000400a,
-    VmExitControls = 0x0000400c,
-    VmExitMsrStoreCount = 0x0000400e,
-    VmExitMsrLoadCount = 0x00004010,
-    VmEntryControls = 0x00004012,
-    VmEntryMsrLoadCount = 0x00004014,
-    VmEntryIntrInfoField = 0x00004016,
-    VmEntryExceptionErrorCode = 0x00004018,
-    VmEntryInstructionLen = 0x0000401a,
-    TprThreshold = 0x0000401c,
-    ProcBasedVmExecControls2 = 0x0000401e,
-    VmInstructionError = 0x00004400,
-    VmExitReason = 0x00004402,
-    VmExitIntrInfo = 0x00004404,
-    VmExitIntrErrorCode = 0x00004406,
-    IdtVectoringInfoField = 0x00004408,
-    IdtVectoringErrorCode = 0x0000440a,
-    VmExitInstructionLen = 0x0000440c,
-    VmxInstructionInfo = 0x0000440e,
-    GuestEsLimit = 0x00004800,
-    GuestCsLimit = 0x00004802,
-    GuestSsLimit = 0x00004804,
-    GuestDsLimit = 0x00004806,
-    GuestFsLimit = 0x00004808,
-    GuestGsLimit = 0x0000480a,
-    GuestLdtrLimit = 0x0000480c,
-    GuestTrLimit = 0x0000480e,
-    GuestGdtrLimit = 0x00004810,
-    GuestIdtrLimit = 0x00004812,
-    GuestEsAccessRights = 0x00004814,
-    GuestCsAccessRights = 0x00004816,
-    GuestSsAccessRights = 0x00004818,
-    GuestDsAccessRights = 0x0000481a,
-    GuestFsAccessRights = 0x0000481c,
-    GuestGsAccessRights = 0x0000481e,
-    GuestLdtrAccessRights = 0x00004820,
-    GuestTrAccessRights = 0x00004822,
-    GuestInterruptibilityState = 0x00004824,
-    GuestActivityState = 0x00004826,
-    GuestSmBase = 0x00004828,
-    GuestIa32SysenterCs = 0x0000482A,
-    HostIa32SysenterCs = 0x00004c00,
-    Cr0GuestHostMask = 0x00006000,
-    Cr4GuestHostMask = 0x00006002,
-    Cr0ReadShadow = 0x00006004,
-    Cr4ReadShadow = 0x00006006,
-    Cr3TargetValue0 = 0x00006008,
-    Cr3TargetValue1 = 0x0000600a,
-    Cr3TargetValue2 = 0x0000600c,
-    Cr3TargetValue3 = 0x0000600e,
-    ExitQualification = 0x00006400,
-    GuestLinearAddress = 0x0000640a,
-    GuestCr0 = 0x00006800,
-    GuestCr3 = 0x00006802,
-    GuestCr4 = 0x00006804,
-    GuestEsBase = 0x00006806,
-    GuestCsBase = 0x00006808,
-    GuestSsBase = 0x0000680a,
-    GuestDsBase = 0x0000680c,
-    GuestFsBase = 0x0000680e,
-    GuestGsBase = 0x00006810,
-    GuestLdtrBase = 0x00006812,
-    GuestTrBase = 0x00006814,
-    GuestGdtrBase = 0x00006816,
-    GuestIdtrBase = 0x00006818,
-    GuestDr7 = 0x0000681a,
-    GuestRsp = 0x0000681c,
-    GuestRip = 0x0000681e,
-    GuestRflags = 0x00006820,
-    GuestPendingDbgExceptions = 0x00006822,
-    GuestSysenterEsp = 0x00006824,
-    GuestSysenterEip = 0x00006826,
-    HostCr0 = 0x00006c00,
-    HostCr3 = 0x00006c02,
-    HostCr4 = 0x00006c04,
-    HostFsBase = 0x00006c06,
-    HostGsBase = 0x00006c08,
-    HostTrBase = 0x00006c0a,
-    HostGdtrBase = 0x00006c0c,
-    HostIdtrBase = 0x00006c0e,
-    HostIa32SysenterEsp = 0x00006c10,
-    HostIa32SysenterEip = 0x00006c12,
-    HostRsp = 0x00006c14,
-    HostRip = 0x00006c16,
+pub enum VmcsFieldType {
+    Control,
+    ReadOnly,
+    Guest,
+    Host,
+}
+
+/// Defines the `VmcsEncoding` of every field together with their names, and a typed handle of
+/// each in `VmcsField` whose width is checked against the encoding at compile time.
+macro_rules! vmcs_fields {
+    ($($ty:ident { $($name:ident = $encoding:expr,)* })*) => {
+        #[allow(unused)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[repr(u32)]
+        pub enum VmcsEncoding {
+            $($($name = $encoding,)*)*
+        }
+
+        impl VmcsEncoding {
+            pub const ALL: &'static [VmcsEncoding] = &[$($(VmcsEncoding::$name,)*)*];
+
+            pub fn name(self) -> &'static str {
+                match self {
+                    $($(VmcsEncoding::$name => stringify!($name),)*)*
+                }
+            }
+
+            #[allow(unused)]
+            pub fn from_encoding(encoding: u32) -> Option<VmcsEncoding> {
+                match encoding {
+                    $($($encoding => Some(VmcsEncoding::$name),)*)*
+                    _ => None,
+                }
+            }
+        }
+
+        /// Namespace of the typed fields, e.g. `VmcsField::GuestRip`.
+        pub struct VmcsField;
+
+        #[allow(unused, non_upper_case_globals)]
+        impl VmcsField {
+            $($(pub const $name: $ty = $ty(VmcsEncoding::$name);)*)*
+        }
+
+        $($(const _: () = assert!(($encoding >> 13) & 0b11 == $ty::WIDTH);)*)*
+    };
+}
+
+/// Defines a field handle type for each width, named after the encoding's width bits.
+macro_rules! field_types {
+    ($($(#[$doc:meta])* $ty:ident: $value:ty = $width:expr,)*) => {
+        $(
+            $(#[$doc])*
+            #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+            pub struct $ty(VmcsEncoding);
+
+            impl $ty {
+                const WIDTH: u32 = $width;
+
+                pub const fn encoding(self) -> VmcsEncoding {
+                    self.0
+                }
+            }
+
+            impl From<$ty> for VmcsEncoding {
+                fn from(field: $ty) -> Self {
+                    field.0
+                }
+            }
+
+            impl TypedField for $ty {
+                type Value = $value;
+
+                fn from_raw(value: u64) -> $value {
+                    value as $value
+                }
+            }
+        )*
+    };
+}
+
+/// A field handle that knows the type of the field's value.
+pub trait TypedField: Copy + Into<VmcsEncoding> {
+    type Value;
+
+    fn from_raw(value: u64) -> Self::Value;
+}
+
+field_types! {
+    /// A 16-bit field, accessed with `read16`/`write16`.
+    Field16: u16 = 0,
+    /// A 64-bit field, accessed with `read64`/`write64`.
+    Field64: u64 = 1,
+    /// A 32-bit field, accessed with `read32`/`write32`.
+    Field32: u32 = 2,
+    /// A natural-width field, accessed with `read_natural`/`write_natural`.
+    FieldNatural: u64 = 3,
+}
+
+// Layout of a field encoding (SDM Vol. 3C, 25.11.2):
+// bit 0 access type (high half of a 64-bit field), bits 11:10 type, bits 14:13 width.
+impl VmcsEncoding {
+    pub fn width(self) -> VmcsFieldWidth {
+        match (self as u32 >> 13) & 0b11 {
+            0 => VmcsFieldWidth::Bits16,
+            1 => VmcsFieldWidth::Bits64,
+            2 => VmcsFieldWidth::Bits32,
+            _ => VmcsFieldWidth::Natural,
+        }
+    }
+
+    pub fn field_type(self) -> VmcsFieldType {
+        match (self as u32 >> 10) & 0b11 {
+            0 => VmcsFieldType::Control,
+            1 => VmcsFieldType::ReadOnly,
+            2 => VmcsFieldType::Guest,
+            _ => VmcsFieldType::Host,
+        }
+    }
+
+    /// Whether this is the upper 32 bits of a 64-bit field.
+    pub fn is_high(self) -> bool {
+        self as u32 & 1 == 1
+    }
+}
+
+vmcs_fields! {
+    Field16 {
+        VirtualProcessorId = 0x00000000,
+        GuestEsSelector = 0x00000800,
+        GuestCsSelector = 0x00000802,
+        GuestSsSelector = 0x00000804,
+        GuestDsSelector = 0x00000806,
+        GuestFsSelector = 0x00000808,
+        GuestGsSelector = 0x0000080a,
+        GuestLdtrSelector = 0x0000080c,
+        GuestTrSelector = 0x0000080e,
+        HostEsSelector = 0x00000c00,
+        HostCsSelector = 0x00000c02,
+        HostSsSelector = 0x00000c04,
+        HostDsSelector = 0x00000c06,
+        HostFsSelector = 0x00000c08,
+        HostGsSelector = 0x00000c0a,
+        HostTrSelector = 0x00000c0c,
+    }
+    Field64 {
+        IoBitmapA = 0x00002000,
+        IoBitmapAHigh = 0x00002001,
+        IoBitmapB = 0x00002002,
+        IoBitmapBHigh = 0x00002003,
+        MsrBitmap = 0x00002004,
+        MsrBitmapHigh = 0x00002005,
+        VmExitMsrStoreAddr = 0x00002006,
+        VmExitMsrStoreAddrHigh = 0x00002007,
+        VmExitMsrLoadAddr = 0x00002008,
+        VmExitMsrLoadAddrHigh = 0x00002009,
+        VmEntryMsrLoadAddr = 0x0000200a,
+        VmEntryMsrLoadAddrHigh = 0x0000200b,
+        ExecVmcsPointer = 0x0000200c,
+        TscOffset = 0x00002010,
+        TscOffsetHigh = 0x00002011,
+        VirtualApicPageAddr = 0x00002012,
+        VirtualApicPageAddrHigh = 0x00002013,
+        ApicAccessAddr = 0x00002014,
+        ApicAccessAddrHigh = 0x00002015,
+        VmfuncControls = 0x00002018,
+        VmfuncControlsHigh = 0x00002019,
+        EptPointer = 0x0000201A,
+        EptPointerHigh = 0x0000201B,
+        EptpList = 0x00002024,
+        EptpListHigh = 0x00002025,
+        GuestPhysicalAddress = 0x00002400,
+        GuestPhysicalAddressHigh = 0x00002401,
+        VmcsLinkPointer = 0x00002800,
+        VmcsLinkPointerHigh = 0x00002801,
+        GuestIa32Debugctl = 0x00002802,
+        GuestIa32DebugctlHigh = 0x00002803,
+        GuestIa32Pat = 0x00002804,
+        GuestIa32PatHigh = 0x00002805,
+        GuestIa32Efer = 0x00002806,
+        GuestIa32EferHigh = 0x00002807,
+        GuestIa32PerfGlobalCtrl = 0x00002808,
+        GuestIa32PerfGlobalCtrlHigh = 0x00002809,
+        HostIa32Pat = 0x00002c00,
+        HostIa32Efer = 0x00002c02,
+        HostIa32EferHigh = 0x00002c03,
+    }
+    Field32 {
+        PinBasedVmExecControls = 0x00004000,
+        ProcBasedVmExecControls = 0x00004002,
+        ExceptionBitmap = 0x00004004,
+        PageFaultErrorCodeMask = 0x00004006,
+        PageFaultErrorCodeMatch = 0x00004008,
+        Cr3TargetCount = 0x0000400a,
+        VmExitControls = 0x0000400c,
+        VmExitMsrStoreCount = 0x0000400e,
+        VmExitMsrLoadCount = 0x00004010,
+        VmEntryControls = 0x00004012,
+        VmEntryMsrLoadCount = 0x00004014,
+        VmEntryIntrInfoField = 0x00004016,
+        VmEntryExceptionErrorCode = 0x00004018,
+        VmEntryInstructionLen = 0x0000401a,
+        TprThreshold = 0x0000401c,
+        ProcBasedVmExecControls2 = 0x0000401e,
+        VmInstructionError = 0x00004400,
+        VmExitReason = 0x00004402,
+        VmExitIntrInfo = 0x00004404,
+        VmExitIntrErrorCode = 0x00004406,
+        IdtVectoringInfoField = 0x00004408,
+        IdtVectoringErrorCode = 0x0000440a,
+        VmExitInstructionLen = 0x0000440c,
+        VmxInstructionInfo = 0x0000440e,
+        GuestEsLimit = 0x00004800,
+        GuestCsLimit = 0x00004802,
+        GuestSsLimit = 0x00004804,
+        GuestDsLimit = 0x00004806,
+        GuestFsLimit = 0x00004808,
+        GuestGsLimit = 0x0000480a,
+        GuestLdtrLimit = 0x0000480c,
+        GuestTrLimit = 0x0000480e,
+        GuestGdtrLimit = 0x00004810,
+        GuestIdtrLimit = 0x00004812,
+        GuestEsAccessRights = 0x00004814,
+        GuestCsAccessRights = 0x00004816,
+        GuestSsAccessRights = 0x00004818,
+        GuestDsAccessRights = 0x0000481a,
+        GuestFsAccessRights = 0x0000481c,
+        GuestGsAccessRights = 0x0000481e,
+        GuestLdtrAccessRights = 0x00004820,
+        GuestTrAccessRights = 0x00004822,
+        GuestInterruptibilityState = 0x00004824,
+        GuestActivityState = 0x00004826,
+        GuestSmBase = 0x00004828,
+        GuestIa32SysenterCs = 0x0000482A,
+        HostIa32SysenterCs = 0x00004c00,
+    }
+    FieldNatural {
+        Cr0GuestHostMask = 0x00006000,
+        Cr4GuestHostMask = 0x00006002,
+        Cr0ReadShadow = 0x00006004,
+        Cr4ReadShadow = 0x00006006,
+        Cr3TargetValue0 = 0x00006008,
+        Cr3TargetValue1 = 0x0000600a,
+        Cr3TargetValue2 = 0x0000600c,
+        Cr3TargetValue3 = 0x0000600e,
+        ExitQualification = 0x00006400,
+        GuestLinearAddress = 0x0000640a,
+        GuestCr0 = 0x00006800,
+        GuestCr3 = 0x00006802,
+        GuestCr4 = 0x00006804,
+        GuestEsBase = 0x00006806,
+        GuestCsBase = 0x00006808,
+        GuestSsBase = 0x0000680a,
+        GuestDsBase = 0x0000680c,
+        GuestFsBase = 0x0000680e,
+        GuestGsBase = 0x00006810,
+        GuestLdtrBase = 0x00006812,
+        GuestTrBase = 0x00006814,
+        GuestGdtrBase = 0x00006816,
+        GuestIdtrBase = 0x00006818,
+        GuestDr7 = 0x0000681a,
+        GuestRsp = 0x0000681c,
+        GuestRip = 0x0000681e,
+        GuestRflags = 0x00006820,
+        GuestPendingDbgExceptions = 0x00006822,
+        GuestSysenterEsp = 0x00006824,
+        GuestSysenterEip = 0x00006826,
+        HostCr0 = 0x00006c00,
+        HostCr3 = 0x00006c02,
+        HostCr4 = 0x00006c04,
+        HostFsBase = 0x00006c06,
+        HostGsBase = 0x00006c08,
+        HostTrBase = 0x00006c0a,
+        HostGdtrBase = 0x00006c0c,
+        HostIdtrBase = 0x00006c0e,
+        HostIa32SysenterEsp = 0x00006c10,
+        HostIa32SysenterEip = 0x00006c12,
+        HostRsp = 0x00006c14,
+        HostRip = 0x00006c16,
+    }
 }
 
+const VMCS_PIN_BASED_VMEXEC_CTLS_EXTERNAL_INTERRUPT_EXITING: u32 = 1 << 0;
+const VMCS_PIN_BASED_VMEXEC_CTLS_NMI_EXITING: u32 = 1 << 3;
+const VMCS_PIN_BASED_VMEXEC_CTLS_VIRTUAL_NMIS: u32 = 1 << 5;
+
 #[allow(unused)]
-const VMCS_PROC_BASED_VMEXEC_CTLS_HLTEXIT: u64 = 1 << 7;
-const VMCS_PROC_BASED_VMEXEC_CTLS_USE_MSR_BITMAPS: u64 = 1 << 28;
-const VMCS_PROC_BASED_VMEXEC_CTLS_ACTIVE_SECOND_CTLS: u64 = 1 << 31;
+const VMCS_PROC_BASED_VMEXEC_CTLS_HLTEXIT: u32 = 1 << 7;
+const VMCS_PROC_BASED_VMEXEC_CTLS_CR3_LOAD_EXITING: u32 = 1 << 15;
+const VMCS_PROC_BASED_VMEXEC_CTLS_USE_TPR_SHADOW: u32 = 1 << 21;
+const VMCS_PROC_BASED_VMEXEC_CTLS_USE_IO_BITMAPS: u32 = 1 << 25;
+const VMCS_PROC_BASED_VMEXEC_CTLS_USE_MSR_BITMAPS: u32 = 1 << 28;
+const VMCS_PROC_BASED_VMEXEC_CTLS_ACTIVE_SECOND_CTLS: u32 = 1 << 31;
 
-const VMCS_PROC_BASED_VMEXEC_CTLS2_ENABLE_EPT: u64 = 1 << 1;
+const VMCS_PROC_BASED_VMEXEC_CTLS2_VIRTUALIZE_APIC_ACCESSES: u32 = 1 << 0;
+const VMCS_PROC_BASED_VMEXEC_CTLS2_ENABLE_EPT: u32 = 1 << 1;
+const VMCS_PROC_BASED_VMEXEC_CTLS2_DESC_TABLE_EXITING: u32 = 1 << 2;
+const VMCS_PROC_BASED_VMEXEC_CTLS2_ENABLE_VPID: u32 = 1 << 5;
+const VMCS_PROC_BASED_VMEXEC_CTLS2_UNRESTRICTED_GUEST: u32 = 1 << 7;
 
-const VMCS_VMEXIT_CTLS_HOST_ADDR_SPACE_SIZE: u64 = 1 << 9;
+const VMCS_VMEXIT_CTLS_HOST_ADDR_SPACE_SIZE: u32 = 1 << 9;
+const VMCS_VMEXIT_CTLS_ACK_INTERRUPT_ON_EXIT: u32 = 1 << 15;
+pub const VMCS_VMEXIT_CTLS_SAVE_IA32_PAT: u32 = 1 << 18;
+const VMCS_VMEXIT_CTLS_LOAD_IA32_PAT: u32 = 1 << 19;
+const VMCS_VMEXIT_CTLS_SAVE_IA32_EFER: u32 = 1 << 20;
 
-const VMCS_VMENTRY_CTLS_IA32E_MODE_GUEST: u64 = 1 << 9;
+const VMCS_VMENTRY_CTLS_IA32E_MODE_GUEST: u32 = 1 << 9;
+const VMCS_VMENTRY_CTLS_LOAD_IA32_PAT: u32 = 1 << 14;
@@ -0,0 +1,100 @@
+use crate::arch::intel::vmcs::VmcsEncoding;
+use core::cell::Cell;
+
+/// Fields that nearly every exit handler touches.
+const CACHED_FIELDS: [VmcsEncoding; 15] = [
+    VmcsEncoding::VmExitReason,
+    VmcsEncoding::ExitQualification,
+    VmcsEncoding::VmExitIntrInfo,
+    VmcsEncoding::VmExitIntrErrorCode,
+    VmcsEncoding::VmExitInstructionLen,
+    VmcsEncoding::IdtVectoringInfoField,
+    VmcsEncoding::GuestPhysicalAddress,
+    VmcsEncoding::GuestLinearAddress,
+    VmcsEncoding::GuestRip,
+    VmcsEncoding::GuestRsp,
+    VmcsEncoding::GuestRflags,
+    VmcsEncoding::GuestCr0,
+    VmcsEncoding::GuestCr3,
+    VmcsEncoding::GuestCr4,
+    VmcsEncoding::GuestInterruptibilityState,
+];
+
+/// Per-exit copy of the hot VMCS fields.
+/// Values are loaded with VMREAD on first use and writes are held back until `take_dirty`
+/// hands them out right before VM entry.
+#[derive(Debug)]
+pub struct VmcsCache {
+    values: [Cell<u64>; CACHED_FIELDS.len()],
+    valid: Cell<u32>,
+    dirty: u32,
+}
+
+impl VmcsCache {
+    pub fn new() -> Self {
+        Self {
+            values: Default::default(),
+            valid: Cell::new(0),
+            dirty: 0,
+        }
+    }
+
+    fn slot(field: VmcsEncoding) -> Option<usize> {
+        CACHED_FIELDS.iter().position(|f| *f == field)
+    }
+
+    /// Returns `None` if `field` is not cached.
+    pub fn read<E>(
+        &self,
+        field: VmcsEncoding,
+        load: impl FnOnce() -> Result<u64, E>,
+    ) -> Option<Result<u64, E>> {
+        let slot = Self::slot(field)?;
+        if self.valid.get() & (1 << slot) == 0 {
+            match load() {
+                Ok(value) => self.values[slot].set(value),
+                Err(e) => return Some(Err(e)),
+            }
+            self.valid.set(self.valid.get() | (1 << slot));
+        }
+        Some(Ok(self.values[slot].get()))
+    }
+
+    /// Returns `false` if `field` is not cached and has to be written to the VMCS directly.
+    pub fn write(&mut self, field: VmcsEncoding, value: u64) -> bool {
+        let slot = match Self::slot(field) {
+            Some(slot) => slot,
+            None => return false,
+        };
+        self.values[slot].set(value);
+        self.valid.set(self.valid.get() | (1 << slot));
+        self.dirty |= 1 << slot;
+        true
+    }
+
+    /// Returns the fields written since the last call and marks them clean.
+    pub fn take_dirty(&mut self) -> impl Iterator<Item = (VmcsEncoding, u64)> + '_ {
+        let dirty = core::mem::take(&mut self.dirty);
+        CACHED_FIELDS
+            .iter()
+            .enumerate()
+            .filter(move |(slot, _)| dirty & (1 << slot) != 0)
+            .map(|(slot, field)| (*field, self.values[slot].get()))
+    }
+
+    /// Returns the value written to `field` that has not reached the VMCS yet.
+    pub fn pending(&self, field: VmcsEncoding) -> Option<u64> {
+        let slot = Self::slot(field)?;
+        if self.dirty & (1 << slot) == 0 {
+            return None;
+        }
+        Some(self.values[slot].get())
+    }
+
+    /// Drops every cached value, pending writes included. Called on VM exit, when the VMCS
+    /// has changed under us.
+    pub fn invalidate(&mut self) {
+        self.dirty = 0;
+        self.valid.set(0);
+    }
+}
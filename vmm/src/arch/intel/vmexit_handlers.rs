@@ -1,11 +1,22 @@
 use crate::{
-    arch::intel::{vmcs::VmcsField, vmx::VmExitGeneralPurposeRegister, BSP},
-    cpu::guest_virt_to_guest_phys,
-    serial_print, serial_println,
+    arch::intel::{
+        cr::{self, PinViolation, PinViolationAction, PIN_VIOLATION_ACTION},
+        cr3_tracker,
+        entry_check::{check_guest_state, CheckEnv},
+        ept::{FrameAllocator, VmmFrameAllocator},
+        ept_check::{self, EptCheckEnv, EptViolationQual},
+        event::{self, Event, EXCEPTION_GP, EXCEPTION_MC},
+        tlb,
+        vmcs::{self, VmcsField, VmcsRegion},
+        vmx::VmExitGeneralPurposeRegister,
+        BSP,
+    },
+    emu::{emulate_mmio, MmioAccess},
+    interrupt, ioapic, mce, power, serial_print, serial_println,
 };
 use alloc::string::String;
 use iced_x86::{Decoder, DecoderOptions, Formatter, GasFormatter, Instruction};
-use x86_64::PhysAddr;
+use x86_64::{registers::control::Cr4Flags, PhysAddr};
 
 pub fn cpuid(gpr: &mut VmExitGeneralPurposeRegister) {
     let eax = gpr.rax as u32;
@@ -17,113 +28,262 @@ pub fn cpuid(gpr: &mut VmExitGeneralPurposeRegister) {
     gpr.rdx = cpuid.edx as u64;
 }
 
-pub fn cr_access(qual: u64, gpr: &mut VmExitGeneralPurposeRegister) {
+/// Emulates a control-register access (exit qualification layout: SDM Vol. 3C, Table 28-3).
+/// Returns `false` if the instruction faulted and must not be skipped.
+pub fn cr_access(qual: u64, gpr: &mut VmExitGeneralPurposeRegister) -> bool {
     let bsp = unsafe { BSP.as_ptr().as_mut().unwrap() };
+    let vmcs = &mut bsp.vmcs_region;
     let cr_number = qual & 0b1111;
     let access_type = (qual & 0b11_0000) >> 4;
-    let _lmsw_operand_size = (qual & 0b100_0000) >> 6;
     let gpr_for_mov = (qual & 0b1111_0000_0000) >> 8;
+    let lmsw_source = (qual >> 16) as u16;
     serial_println!("[CR{cr_number}] ACCESS TYPE: {access_type}, GPR: {gpr_for_mov}");
 
     match access_type {
         0 => {
             // mov to cr
-            let value = match gpr_for_mov {
-                0 => gpr.rax,
-                1 => gpr.rcx,
-                2 => gpr.rdx,
-                3 => gpr.rbx,
-                4 => bsp.vmcs_region.read(VmcsField::GuestRsp),
-                5 => gpr.rbp,
-                6 => gpr.rsi,
-                7 => gpr.rdi,
-                8 => gpr.r8,
-                9 => gpr.r9,
-                10 => gpr.r10,
-                11 => gpr.r11,
-                12 => gpr.r12,
-                13 => gpr.r13,
-                14 => gpr.r14,
-                15 => gpr.r15,
-                _ => panic!(),
-            };
-            match cr_number {
-                0 => bsp.vmcs_region.write(VmcsField::GuestCr0, value),
-                3 => bsp.vmcs_region.write(VmcsField::GuestCr3, value),
-                4 => bsp.vmcs_region.write(VmcsField::GuestCr4, value),
-                _ => panic!(),
+            let value = gpr.get(gpr_for_mov);
+            let result = match cr_number {
+                0 => cr::set_guest_cr0(vmcs, value),
+                3 => {
+                    let pcide = cr::guest_cr4(vmcs) & Cr4Flags::PCID.bits() != 0;
+                    cr3_tracker::on_cr3_load(value, pcide);
+                    if !pcide || value & cr3_tracker::CR3_NO_FLUSH == 0 {
+                        tlb::flush_guest_non_global(vmcs);
+                    }
+                    let value = if pcide {
+                        value & !cr3_tracker::CR3_NO_FLUSH
+                    } else {
+                        value
+                    };
+                    vmcs.write_natural(VmcsField::GuestCr3, value);
+                    Ok(())
+                }
+                4 => cr::set_guest_cr4(vmcs, value),
+                8 => {
+                    cr::set_guest_cr8(value);
+                    Ok(())
+                }
+                _ => panic!("mov to CR{cr_number} is not supported"),
             };
+            if let Err(violation) = result {
+                return cr_pin_violation(violation);
+            }
             serial_println!("CR{cr_number} write: 0x{value:016x}");
         }
         1 => {
             // mov from cr
             let value = match cr_number {
-                0 => bsp.vmcs_region.read(VmcsField::GuestCr0),
-                3 => bsp.vmcs_region.read(VmcsField::GuestCr3),
-                4 => bsp.vmcs_region.read(VmcsField::GuestCr4),
-                _ => panic!(),
+                0 => cr::guest_cr0(vmcs),
+                3 => vmcs.read_natural(VmcsField::GuestCr3),
+                4 => cr::guest_cr4(vmcs),
+                8 => cr::guest_cr8(),
+                _ => panic!("mov from CR{cr_number} is not supported"),
             };
-            match gpr_for_mov {
-                0 => gpr.rax = value,
-                1 => gpr.rcx = value,
-                2 => gpr.rdx = value,
-                3 => gpr.rbx = value,
-                4 => bsp.vmcs_region.write(VmcsField::GuestRsp, value),
-                5 => gpr.rbp = value,
-                6 => gpr.rsi = value,
-                7 => gpr.rdi = value,
-                8 => gpr.r8 = value,
-                9 => gpr.r9 = value,
-                10 => gpr.r10 = value,
-                11 => gpr.r11 = value,
-                12 => gpr.r12 = value,
-                13 => gpr.r13 = value,
-                14 => gpr.r14 = value,
-                15 => gpr.r15 = value,
-                _ => panic!(),
-            }
+            gpr.set(gpr_for_mov, value);
             serial_println!("CR{cr_number} read: 0x{value:016x}");
         }
-        _ => panic!(),
+        2 => cr::clts(vmcs),
+        3 => cr::lmsw(vmcs, lmsw_source),
+        _ => unreachable!(),
+    }
+    true
+}
+
+fn cr_pin_violation(violation: PinViolation) -> bool {
+    let bsp = unsafe { BSP.as_ptr().as_mut().unwrap() };
+    let vmcs = &mut bsp.vmcs_region;
+    let guest_rip = vmcs.read_natural(VmcsField::GuestRip);
+    serial_println!(
+        "CR{} pinning violation at RIP 0x{guest_rip:016x}: write 0x{:016x} clears 0x{:x}",
+        violation.cr_number,
+        violation.value,
+        violation.cleared
+    );
+    dump_instructions(vmcs);
+
+    match PIN_VIOLATION_ACTION {
+        PinViolationAction::InjectGp => {
+            event::queue_event(Event::exception(EXCEPTION_GP, Some(0)));
+            false
+        }
+        PinViolationAction::Ignore => {
+            let value = violation.value | violation.cleared;
+            let result = match violation.cr_number {
+                0 => cr::set_guest_cr0(vmcs, value),
+                _ => cr::set_guest_cr4(vmcs, value),
+            };
+            result.is_ok()
+        }
+        PinViolationAction::Halt => loop {
+            x86_64::instructions::hlt();
+        },
     }
 }
 
-pub fn triple_fault() {
+pub fn triple_fault() -> ! {
     let bsp = unsafe { BSP.as_ptr().as_ref().unwrap() };
-    let guest_rip = bsp.vmcs_region.read(VmcsField::GuestRip);
-    let guest_cr3 = bsp.vmcs_region.read(VmcsField::GuestCr3);
-    let guest_rip_phys = guest_virt_to_guest_phys(guest_rip, guest_cr3);
+    dump_instructions(&bsp.vmcs_region);
 
-    dump_instructions(guest_rip_phys, 0x20);
+    power::triple_fault();
+}
 
-    x86_64::instructions::hlt();
+/// Prints every EPT entry the processor reads to translate `gpa`, with the problems found in
+/// them. Returns whether one is misconfigured.
+fn print_ept_walk(vmcs: &VmcsRegion, gpa: u64) -> bool {
+    let root = PhysAddr::new(vmcs.read64(VmcsField::EptPointer) & 0x000f_ffff_ffff_f000);
+    let steps = ept_check::walk(root, gpa, &EptCheckEnv::from_hardware(), |table, index| {
+        let table = VmmFrameAllocator.frame_ptr(table) as *const u64;
+        unsafe { table.add(index).read_volatile() }
+    });
+    let mut misconfigured = false;
+    serial_println!("EPT walk for GPA 0x{gpa:x}:");
+    for step in steps {
+        let [r, w, x] = ept_check::permissions(step.entry);
+        serial_print!(
+            "  {}[{}] = 0x{:016x} {r}{w}{x}",
+            ept_check::level_name(step.level),
+            step.index,
+            step.entry
+        );
+        if !ept_check::is_present(step.entry) {
+            serial_print!(" (not present)");
+        } else if ept_check::is_leaf(step.entry, step.level) {
+            serial_print!(" (leaf)");
+        }
+        serial_println!();
+        for problem in &step.problems {
+            serial_println!("    misconfigured: {problem}");
+            misconfigured = true;
+        }
+    }
+    misconfigured
 }
 
-pub fn ept_violation(_gpr: &mut VmExitGeneralPurposeRegister) {
+/// Emulates trapped IOAPIC accesses and moves RIP past them. Any other violation stops the
+/// VMM.
+pub fn ept_violation(qual: u64, gpr: &mut VmExitGeneralPurposeRegister) {
+    let bsp = unsafe { BSP.as_ptr().as_mut().unwrap() };
+    let guest_rip = bsp.vmcs_region.read_natural(VmcsField::GuestRip);
+    let guest_phys = bsp.vmcs_region.read64(VmcsField::GuestPhysicalAddress);
+
+    if ioapic::is_trapped(guest_phys) {
+        let emulated = emulate_mmio(&mut bsp.vmcs_region, gpr, |access| match access {
+            MmioAccess::Read { .. } => ioapic::guest_read(guest_phys) as u64,
+            MmioAccess::Write { value, .. } => {
+                ioapic::guest_write(guest_phys, value as u32);
+                0
+            }
+        });
+        match emulated {
+            Ok(_) => return,
+            Err(e) => serial_println!("IOAPIC access emulation failed: {e:?}"),
+        }
+    }
+
+    let qual = EptViolationQual(qual);
+    serial_println!("EPT violation at RIP 0x{guest_rip:x}: {qual}");
+    if qual.linear_address_valid() {
+        let guest_linear = bsp.vmcs_region.read_natural(VmcsField::GuestLinearAddress);
+        serial_println!("GPA 0x{guest_phys:x}, guest linear address 0x{guest_linear:x}");
+    } else {
+        serial_println!("GPA 0x{guest_phys:x}");
+    }
+    print_ept_walk(&bsp.vmcs_region, guest_phys);
+    dump_instructions(&bsp.vmcs_region);
+
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+pub fn ept_misconfiguration() -> ! {
     let bsp = unsafe { BSP.as_ptr().as_ref().unwrap() };
-    let guest_rip = bsp.vmcs_region.read(VmcsField::GuestRip);
-    let guest_cr3 = bsp.vmcs_region.read(VmcsField::GuestCr3);
-    let guest_rip_phys = guest_virt_to_guest_phys(guest_rip, guest_cr3);
+    let guest_rip = bsp.vmcs_region.read_natural(VmcsField::GuestRip);
+    let guest_phys = bsp.vmcs_region.read64(VmcsField::GuestPhysicalAddress);
+    serial_println!("EPT misconfiguration at RIP 0x{guest_rip:x}, GPA 0x{guest_phys:x}");
+    if !print_ept_walk(&bsp.vmcs_region, guest_phys) {
+        serial_println!("no misconfigured entry found in software");
+    }
 
-    dump_instructions(guest_rip_phys, 0x20);
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+pub fn vmentry_failure(basic_reason: u64, qual: u64) -> ! {
+    let bsp = unsafe { BSP.as_ptr().as_ref().unwrap() };
+    match basic_reason {
+        33 => {
+            let cause = match qual {
+                2 => "PDPTE loading failed",
+                3 => "NMI injection while blocked",
+                4 => "invalid VMCS link pointer",
+                _ => "invalid guest state",
+            };
+            serial_println!("VM entry failed: {cause}");
+            let violations = check_guest_state(&bsp.vmcs_region, &CheckEnv::from_hardware());
+            if violations.is_empty() {
+                serial_println!("no guest-state check failed in software");
+            }
+            for v in violations {
+                serial_println!("  {:?} = 0x{:x}: {}", v.field, v.value, v.check);
+            }
+        }
+        34 => serial_println!("VM entry failed: MSR loading failed at entry {qual}"),
+        41 => serial_println!("VM entry failed: machine-check event"),
+        _ => serial_println!("VM entry failed: exit reason {basic_reason}"),
+    }
+    vmcs::dump();
 
-    x86_64::instructions::hlt();
+    loop {
+        x86_64::instructions::hlt();
+    }
 }
 
-pub fn init_signal() {
+/// The interrupt was acknowledged on exit. Vectors the VMM does not own go back to the guest.
+pub fn external_interrupt() {
     let bsp = unsafe { BSP.as_ptr().as_ref().unwrap() };
-    let guest_rip = bsp.vmcs_region.read(VmcsField::GuestRip);
-    let guest_cr3 = bsp.vmcs_region.read(VmcsField::GuestCr3);
-    let guest_rip_phys = guest_virt_to_guest_phys(guest_rip, guest_cr3);
+    let vector = bsp.vmcs_region.read32(VmcsField::VmExitIntrInfo) as u8;
+    if !interrupt::dispatch(vector) {
+        event::queue_event(Event::external_interrupt(vector));
+    }
+}
 
-    dump_instructions(guest_rip_phys, 0x20);
+/// A machine check interrupted VM entry. The guest state is untouched, so a recoverable one
+/// is delivered to the guest on the next attempt.
+pub fn vmentry_machine_check() {
+    let bsp = unsafe { BSP.as_ptr().as_mut().unwrap() };
+    serial_println!("VM entry failed: machine-check event");
+    if mce::log_banks().is_recoverable() {
+        reflect_machine_check(&mut bsp.vmcs_region);
+    } else {
+        vmcs::dump();
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+}
 
-    x86_64::instructions::hlt();
+/// Queues #MC for the guest. With CR4.MCE clear the processor would have shut down, so the
+/// VMM stops instead.
+pub fn reflect_machine_check(vmcs: &mut VmcsRegion) {
+    if cr::guest_cr4(vmcs) & Cr4Flags::MACHINE_CHECK_EXCEPTION.bits() == 0 {
+        serial_println!("machine check with guest CR4.MCE clear");
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+    event::queue_event(Event::exception(EXCEPTION_MC, None));
 }
 
-fn dump_instructions(guest_rip_phys: PhysAddr, len: usize) {
-    let code = unsafe { core::slice::from_raw_parts(guest_rip_phys.as_u64() as *const u8, len) };
-    let mut decoder = Decoder::with_ip(64, code, guest_rip_phys.as_u64(), DecoderOptions::NONE);
+/// Disassembles the guest code at RIP, up to the first byte the guest does not map.
+fn dump_instructions(vmcs: &VmcsRegion) {
+    let mut buf = [0; 0x20];
+    let len = vmcs.read_guest_code(&mut buf);
+    let code = &buf[..len];
+    let rip = vmcs.read_natural(VmcsField::GuestRip);
+    let mut decoder = Decoder::with_ip(vmcs.guest_bitness(), code, rip, DecoderOptions::NONE);
     let mut formatter = GasFormatter::new();
     let mut output = String::new();
     let mut instruction = Instruction::default();
@@ -132,7 +292,7 @@ fn dump_instructions(guest_rip_phys: PhysAddr, len: usize) {
         output.clear();
         formatter.format(&instruction, &mut output);
         serial_print!("{:016x} ", instruction.ip());
-        let start_index = (instruction.ip() - guest_rip_phys.as_u64()) as usize;
+        let start_index = (instruction.ip() - rip) as usize;
         let instr_bytes = &code[start_index..(start_index + instruction.len())];
         for b in instr_bytes.iter() {
             serial_print!("{:02x} ", b);
@@ -1,114 +1,174 @@
 use crate::{
+    allocator,
     arch::intel::{
-        vmcs::{VmcsField, VmcsRegion},
-        vmexit_handlers, BSP,
+        apic, cr3_tracker, desc_table, exception, hypercall, io, msr, sipi,
+        vmcs::{VmcsEncoding, VmcsField, VmcsRegion},
+        vmexit_handlers,
+        vmx_caps::{VmxCaps, VmxControls},
+        BSP,
     },
-    cpu::guest_virt_to_guest_phys,
-    emu::decode_one,
-    serial_println, BOOT_ARGS,
+    serial_println,
 };
-use alloc::alloc::alloc;
 use common::constants;
-use core::{alloc::Layout, arch::asm, ptr, slice};
+use core::{arch::asm, ptr};
 use x86_64::{
     registers::{
-        control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+        control::{Cr0, Cr4, Cr4Flags},
         model_specific::Msr,
     },
     PhysAddr,
 };
 
+const IA32_FEATURE_CONTROL_LOCK: u64 = 1 << 0;
+const IA32_FEATURE_CONTROL_VMX_OUTSIDE_SMX: u64 = 1 << 2;
+
+/// Enters VMX operation. The checks that need no register changes run first, and CR0/CR4 are
+/// put back if entering fails after all.
 pub unsafe fn vmxon(vmxon_region: &mut VmxonRegion) -> Result<(), VmxError> {
-    let cr4 = Cr4::read();
-    let cr4_fixed_0 = Msr::new(0x488).read();
-    let cr4_fixed_1 = Msr::new(0x489).read();
-    let cr4 = cr4 & Cr4Flags::from_bits_unchecked(cr4_fixed_1);
-    let cr4 = cr4 | Cr4Flags::from_bits_unchecked(cr4_fixed_0);
-    Cr4::write(cr4);
-    let cr4 = Cr4::read();
-    let cr4 = cr4 | Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS;
-    Cr4::write(cr4);
-
-    let cr0 = Cr0::read();
-    let cr0_fixed_0 = Msr::new(0x486).read();
-    let cr0_fixed_1 = Msr::new(0x487).read();
-    let cr0 = cr0 & Cr0Flags::from_bits_unchecked(cr0_fixed_1);
-    let cr0 = cr0 | Cr0Flags::from_bits_unchecked(cr0_fixed_0);
-    Cr0::write(cr0);
+    let caps = VmxCaps::read();
 
     let mut msr_ia32_feature_control = Msr::new(0x0000003a); // IA32_FEATURE_CONTROL
     let ia32_feature_control = unsafe { msr_ia32_feature_control.read() };
-    let lock = ia32_feature_control & 0b1 == 1;
+    let lock = ia32_feature_control & IA32_FEATURE_CONTROL_LOCK != 0;
     if !lock {
-        msr_ia32_feature_control.write(ia32_feature_control | 5);
+        msr_ia32_feature_control.write(
+            ia32_feature_control | IA32_FEATURE_CONTROL_LOCK | IA32_FEATURE_CONTROL_VMX_OUTSIDE_SMX,
+        );
+    } else if ia32_feature_control & IA32_FEATURE_CONTROL_VMX_OUTSIDE_SMX == 0 {
+        return Err(VmxError::FeatureControlLocked {
+            value: ia32_feature_control,
+        });
+    }
+
+    let expected = caps.basic.revision_id();
+    let actual = vmxon_region.revision_id();
+    if actual != expected {
+        return Err(VmxError::RevisionIdMismatch { expected, actual });
+    }
+
+    let (cr0, cr4) = (Cr0::read_raw(), Cr4::read_raw());
+    let result = enter_vmx_operation(&caps, vmxon_region.paddr());
+    if result.is_err() {
+        Cr0::write_raw(cr0);
+        Cr4::write_raw(cr4);
+    }
+    result
+}
+
+unsafe fn enter_vmx_operation(caps: &VmxCaps, paddr: PhysAddr) -> Result<(), VmxError> {
+    let cr4 = caps.cr4_fixed.apply(Cr4::read_raw()) | Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS.bits();
+    Cr4::write_raw(cr4);
+    let cr4 = Cr4::read_raw();
+    if !caps.cr4_fixed.is_valid(cr4) {
+        return Err(VmxError::Cr4FixedBits {
+            value: cr4,
+            fixed0: caps.cr4_fixed.fixed0,
+            fixed1: caps.cr4_fixed.fixed1,
+        });
+    }
+
+    Cr0::write_raw(caps.cr0_fixed.apply(Cr0::read_raw()));
+    let cr0 = Cr0::read_raw();
+    if !caps.cr0_fixed.is_valid(cr0) {
+        return Err(VmxError::Cr0FixedBits {
+            value: cr0,
+            fixed0: caps.cr0_fixed.fixed0,
+            fixed1: caps.cr0_fixed.fixed1,
+        });
     }
 
-    let paddr = vmxon_region.paddr();
     asm_vmxon(paddr)
 }
 
 unsafe fn asm_vmxon(phys_addr: PhysAddr) -> Result<(), VmxError> {
     let mut flags;
     asm!("vmxon [rdi]; pushfq; pop rax", in("rdi") &phys_addr.as_u64(), out("rax") flags);
-    check_vmx_error(flags)
+    check_vmx_error(flags, VmxOperation::Vmxon)
 }
 
-pub unsafe fn vmclear(vmcs_region: &mut VmcsRegion) {
-    let paddr = vmcs_region.paddr();
-    if let Err(_e) = asm_vmclear(paddr) {
-        panic!();
-    }
+/// Leaves VMX operation and clears CR4.VMXE.
+pub unsafe fn vmxoff() -> Result<(), VmxError> {
+    let mut flags;
+    asm!("vmxoff; pushfq; pop rax", out("rax") flags);
+    check_vmx_error(flags, VmxOperation::Vmxoff)?;
+    Cr4::write_raw(Cr4::read_raw() & !Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS.bits());
+    Ok(())
+}
+
+pub unsafe fn vmclear(vmcs_region: &mut VmcsRegion) -> Result<(), VmxError> {
+    asm_vmclear(vmcs_region.paddr())
 }
 
 unsafe fn asm_vmclear(phys_addr: PhysAddr) -> Result<(), VmxError> {
     let mut flags;
     asm!("vmclear [rdi]; pushfq; pop rax", in("rdi") &phys_addr.as_u64(), out("rax") flags);
-    check_vmx_error(flags)
+    check_vmx_error(flags, VmxOperation::Vmclear)
 }
 
-pub unsafe fn vmptrld(vmcs_region: &VmcsRegion) {
-    let paddr = vmcs_region.paddr();
-    if let Err(_e) = asm_vmptrld(paddr) {
-        panic!();
-    }
+pub unsafe fn vmptrld(vmcs_region: &VmcsRegion) -> Result<(), VmxError> {
+    asm_vmptrld(vmcs_region.paddr())
 }
 
 unsafe fn asm_vmptrld(phys_addr: PhysAddr) -> Result<(), VmxError> {
     let mut flags;
     asm!("vmptrld [rdi]; pushfq; pop rax", in("rdi") &phys_addr.as_u64(), out("rax") flags);
-    check_vmx_error(flags)
-}
-
-pub unsafe fn vmread(field: VmcsField) -> u64 {
-    if let Ok(val) = asm_vmread(field) {
-        val
-    } else {
-        panic!();
-    }
+    check_vmx_error(flags, VmxOperation::Vmptrld)
 }
 
-unsafe fn asm_vmread(field: VmcsField) -> Result<u64, VmxError> {
+pub unsafe fn vmread(field: VmcsEncoding) -> Result<u64, VmxError> {
     let mut flags;
     let mut value;
     asm!("vmread rdi, rsi; pushfq; pop rax", in("rsi") field as u32, out("rdi") value, out("rax") flags);
-    #[allow(clippy::question_mark)]
-    if let Err(e) = check_vmx_error(flags) {
-        Err(e)
-    } else {
-        Ok(value)
-    }
+    check_vmx_error(flags, VmxOperation::Vmread(field)).map(|_| value)
 }
 
-pub unsafe fn vmwrite(field: VmcsField, value: u64) {
-    if let Err(_e) = asm_vmwrite(field, value) {
-        panic!();
-    }
+pub unsafe fn vmwrite(field: VmcsEncoding, value: u64) -> Result<(), VmxError> {
+    let mut flags;
+    asm!("vmwrite rdi, rsi; pushfq; pop rax", in("rdi") field as u32, in("rsi") value, out("rax") flags);
+    check_vmx_error(flags, VmxOperation::Vmwrite(field))
 }
 
-unsafe fn asm_vmwrite(field: VmcsField, value: u64) -> Result<(), VmxError> {
+/// INVEPT types (SDM Vol. 3C, 30.3).
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum InveptType {
+    /// Mappings derived from one EPT pointer.
+    SingleContext = 1,
+    /// Mappings derived from any EPT pointer.
+    AllContext = 2,
+}
+
+/// Invalidates cached EPT mappings.
+pub unsafe fn invept(kind: InveptType, eptp: u64) -> Result<(), VmxError> {
+    let descriptor: [u64; 2] = [eptp, 0];
     let mut flags;
-    asm!("vmwrite rdi, rsi; pushfq; pop rax", in("rdi") field as u32, in("rsi") value, out("rax") flags);
-    check_vmx_error(flags)
+    asm!("invept rdi, [rsi]; pushfq; pop rax", in("rdi") kind as u64, in("rsi") &descriptor, out("rax") flags);
+    check_vmx_error(flags, VmxOperation::Invept(kind))
+}
+
+/// INVVPID types (SDM Vol. 3C, 30.3).
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum InvvpidType {
+    /// Mappings of one linear address tagged with the VPID.
+    IndividualAddress = 0,
+    /// Mappings tagged with the VPID.
+    SingleContext = 1,
+    /// Mappings tagged with any VPID but 0.
+    AllContext = 2,
+    /// Like `SingleContext`, but keeps global translations.
+    SingleContextRetainingGlobals = 3,
+}
+
+/// Invalidates cached linear and combined mappings of `vpid`. `addr` is only used for
+/// `IndividualAddress`.
+pub unsafe fn invvpid(kind: InvvpidType, vpid: u16, addr: u64) -> Result<(), VmxError> {
+    let descriptor: [u64; 2] = [vpid as u64, addr];
+    let mut flags;
+    asm!("invvpid rdi, [rsi]; pushfq; pop rax", in("rdi") kind as u64, in("rsi") &descriptor, out("rax") flags);
+    check_vmx_error(flags, VmxOperation::Invvpid(kind))
 }
 
 pub unsafe fn vmlaunch() -> Result<(), VmxError> {
@@ -118,17 +178,26 @@ pub unsafe fn vmlaunch() -> Result<(), VmxError> {
 unsafe fn asm_vmlaunch() -> Result<(), VmxError> {
     let mut flags;
     asm!("vmlaunch; pushfq; pop rax", out("rax") flags);
-    check_vmx_error(flags)
+    check_vmx_error(flags, VmxOperation::Vmlaunch)
 }
 
-fn check_vmx_error(flags: u64) -> Result<(), VmxError> {
+/// Turns the RFLAGS left by a VMX instruction into a result.
+/// On VMfailValid the error number is fetched from the current VMCS.
+pub fn check_vmx_error(flags: u64, op: VmxOperation) -> Result<(), VmxError> {
     let cf = (flags & 0b1) == 1;
     let zf = ((flags >> 6) & 0b1) == 1;
 
     if cf {
-        Err(VmxError::InvalidPointer)
+        Err(VmxError::InvalidPointer { op })
     } else if zf {
-        Err(VmxError::VmInstructionError)
+        let error: u64;
+        unsafe {
+            asm!("vmread rdi, rsi", in("rsi") VmcsEncoding::VmInstructionError as u32, out("rdi") error);
+        }
+        Err(VmxError::VmInstructionError {
+            op,
+            error: VmInstructionError::from_u64(error),
+        })
     } else {
         Ok(())
     }
@@ -141,10 +210,7 @@ unsafe impl Send for VmxonRegion {}
 
 impl VmxonRegion {
     pub unsafe fn new() -> Self {
-        let layout = Layout::from_size_align_unchecked(4096, 4096);
-        let region = alloc(layout);
-        let region_slice = slice::from_raw_parts_mut(region, 4096);
-        region_slice.fill(0);
+        let region = allocator::alloc_pages(1).unwrap();
 
         let ia32_vmx_basic = Msr::new(constants::MSR_IA32_VMX_BASIC).read();
         let vmcs_rev_id = (ia32_vmx_basic & 0x7fff_ffff) as u32;
@@ -158,18 +224,224 @@ impl VmxonRegion {
         self.0
     }
 
+    fn revision_id(&self) -> u32 {
+        unsafe { ptr::read_volatile(self.as_mut_ptr() as *const u32) }
+    }
+
     fn paddr(&self) -> PhysAddr {
-        let virt = self.as_mut_ptr() as u64 as i64;
-        let phys = (virt + BOOT_ARGS.load().vmm_phys_offset) as u64;
-        PhysAddr::new(phys)
+        allocator::virt_to_phys(self.as_mut_ptr())
     }
 }
 
+/// The VMX instruction that failed, with the field for VMREAD/VMWRITE.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmxOperation {
+    Vmxon,
+    Vmxoff,
+    Vmclear,
+    Vmptrld,
+    Vmread(VmcsEncoding),
+    Vmwrite(VmcsEncoding),
+    Vmlaunch,
+    Vmresume,
+    Invept(InveptType),
+    Invvpid(InvvpidType),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum VmxError {
-    InvalidPointer,
-    VmInstructionError,
+    /// VMfailInvalid: there is no current VMCS or the operand pointer is invalid.
+    InvalidPointer { op: VmxOperation },
+    /// VMfailValid: the reason is in the VM-instruction error field of the current VMCS.
+    VmInstructionError {
+        op: VmxOperation,
+        error: VmInstructionError,
+    },
+    /// IA32_FEATURE_CONTROL was locked by the firmware with VMX outside SMX disabled.
+    FeatureControlLocked { value: u64 },
+    Cr0FixedBits {
+        value: u64,
+        fixed0: u64,
+        fixed1: u64,
+    },
+    Cr4FixedBits {
+        value: u64,
+        fixed0: u64,
+        fixed1: u64,
+    },
+    /// The VMXON region does not carry the revision identifier from IA32_VMX_BASIC.
+    RevisionIdMismatch { expected: u32, actual: u32 },
+    /// The processor does not allow `bits` to be set in the given VM-execution, VM-exit or
+    /// VM-entry controls.
+    UnsupportedControls { controls: VmxControls, bits: u32 },
 }
 
+impl core::fmt::Display for VmxError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            VmxError::InvalidPointer { op } => write!(f, "{op:?} failed: invalid VMCS pointer"),
+            VmxError::VmInstructionError { op, error } => write!(f, "{op:?} failed: {error}"),
+            VmxError::FeatureControlLocked { value } => write!(
+                f,
+                "IA32_FEATURE_CONTROL (0x{value:x}) is locked with VMX outside SMX disabled"
+            ),
+            VmxError::Cr0FixedBits {
+                value,
+                fixed0,
+                fixed1,
+            } => write!(
+                f,
+                "CR0 0x{value:x} violates the VMX fixed bits (fixed0 0x{fixed0:x}, fixed1 0x{fixed1:x})"
+            ),
+            VmxError::Cr4FixedBits {
+                value,
+                fixed0,
+                fixed1,
+            } => write!(
+                f,
+                "CR4 0x{value:x} violates the VMX fixed bits (fixed0 0x{fixed0:x}, fixed1 0x{fixed1:x})"
+            ),
+            VmxError::RevisionIdMismatch { expected, actual } => write!(
+                f,
+                "VMXON region revision ID 0x{actual:x} does not match IA32_VMX_BASIC (0x{expected:x})"
+            ),
+            VmxError::UnsupportedControls { controls, bits } => {
+                write!(f, "unsupported {controls:?} controls: 0x{bits:08x}")
+            }
+        }
+    }
+}
+
+/// VM-instruction error numbers (SDM Vol. 3C, 31.4).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmInstructionError {
+    VmcallInVmxRoot,
+    VmclearInvalidAddress,
+    VmclearVmxonPointer,
+    VmlaunchNonClearVmcs,
+    VmresumeNonLaunchedVmcs,
+    VmresumeAfterVmxoff,
+    EntryInvalidControlFields,
+    EntryInvalidHostStateFields,
+    VmptrldInvalidAddress,
+    VmptrldVmxonPointer,
+    VmptrldIncorrectRevisionId,
+    UnsupportedVmcsComponent,
+    VmwriteReadOnlyComponent,
+    VmxonInVmxRoot,
+    EntryInvalidExecutiveVmcsPointer,
+    EntryNonLaunchedExecutiveVmcs,
+    EntryExecutiveVmcsPointerNotVmxonPointer,
+    VmcallNonClearVmcs,
+    VmcallInvalidExitControlFields,
+    VmcallIncorrectMsegRevisionId,
+    VmxoffUnderDualMonitor,
+    VmcallInvalidSmmMonitorFeatures,
+    EntryInvalidExecutiveVmcsControlFields,
+    EntryEventsBlockedByMovSs,
+    InvalidInveptInvvpidOperand,
+    /// A number the SDM does not define (yet).
+    Unknown(u32),
+}
+
+impl VmInstructionError {
+    const NUMBERS: [(u32, Self); 25] = [
+        (1, Self::VmcallInVmxRoot),
+        (2, Self::VmclearInvalidAddress),
+        (3, Self::VmclearVmxonPointer),
+        (4, Self::VmlaunchNonClearVmcs),
+        (5, Self::VmresumeNonLaunchedVmcs),
+        (6, Self::VmresumeAfterVmxoff),
+        (7, Self::EntryInvalidControlFields),
+        (8, Self::EntryInvalidHostStateFields),
+        (9, Self::VmptrldInvalidAddress),
+        (10, Self::VmptrldVmxonPointer),
+        (11, Self::VmptrldIncorrectRevisionId),
+        (12, Self::UnsupportedVmcsComponent),
+        (13, Self::VmwriteReadOnlyComponent),
+        (15, Self::VmxonInVmxRoot),
+        (16, Self::EntryInvalidExecutiveVmcsPointer),
+        (17, Self::EntryNonLaunchedExecutiveVmcs),
+        (18, Self::EntryExecutiveVmcsPointerNotVmxonPointer),
+        (19, Self::VmcallNonClearVmcs),
+        (20, Self::VmcallInvalidExitControlFields),
+        (22, Self::VmcallIncorrectMsegRevisionId),
+        (23, Self::VmxoffUnderDualMonitor),
+        (24, Self::VmcallInvalidSmmMonitorFeatures),
+        (25, Self::EntryInvalidExecutiveVmcsControlFields),
+        (26, Self::EntryEventsBlockedByMovSs),
+        (28, Self::InvalidInveptInvvpidOperand),
+    ];
+
+    pub fn from_u64(error: u64) -> Self {
+        Self::NUMBERS
+            .iter()
+            .find(|(number, _)| *number as u64 == error)
+            .map(|(_, error)| *error)
+            .unwrap_or(Self::Unknown(error as u32))
+    }
+
+    pub fn number(&self) -> u32 {
+        match self {
+            Self::Unknown(number) => *number,
+            _ => {
+                Self::NUMBERS
+                    .iter()
+                    .find(|(_, error)| error == self)
+                    .unwrap()
+                    .0
+            }
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::VmcallInVmxRoot => "VMCALL executed in VMX root operation",
+            Self::VmclearInvalidAddress => "VMCLEAR with invalid physical address",
+            Self::VmclearVmxonPointer => "VMCLEAR with VMXON pointer",
+            Self::VmlaunchNonClearVmcs => "VMLAUNCH with non-clear VMCS",
+            Self::VmresumeNonLaunchedVmcs => "VMRESUME with non-launched VMCS",
+            Self::VmresumeAfterVmxoff => "VMRESUME after VMXOFF",
+            Self::EntryInvalidControlFields => "VM entry with invalid control field(s)",
+            Self::EntryInvalidHostStateFields => "VM entry with invalid host-state field(s)",
+            Self::VmptrldInvalidAddress => "VMPTRLD with invalid physical address",
+            Self::VmptrldVmxonPointer => "VMPTRLD with VMXON pointer",
+            Self::VmptrldIncorrectRevisionId => "VMPTRLD with incorrect VMCS revision identifier",
+            Self::UnsupportedVmcsComponent => "VMREAD/VMWRITE from/to unsupported VMCS component",
+            Self::VmwriteReadOnlyComponent => "VMWRITE to read-only VMCS component",
+            Self::VmxonInVmxRoot => "VMXON executed in VMX root operation",
+            Self::EntryInvalidExecutiveVmcsPointer => {
+                "VM entry with invalid executive-VMCS pointer"
+            }
+            Self::EntryNonLaunchedExecutiveVmcs => "VM entry with non-launched executive VMCS",
+            Self::EntryExecutiveVmcsPointerNotVmxonPointer => {
+                "VM entry with executive-VMCS pointer not VMXON pointer"
+            }
+            Self::VmcallNonClearVmcs => "VMCALL with non-clear VMCS",
+            Self::VmcallInvalidExitControlFields => "VMCALL with invalid VM-exit control fields",
+            Self::VmcallIncorrectMsegRevisionId => "VMCALL with incorrect MSEG revision identifier",
+            Self::VmxoffUnderDualMonitor => "VMXOFF under dual-monitor treatment of SMIs and SMM",
+            Self::VmcallInvalidSmmMonitorFeatures => "VMCALL with invalid SMM-monitor features",
+            Self::EntryInvalidExecutiveVmcsControlFields => {
+                "VM entry with invalid VM-execution control fields in executive VMCS"
+            }
+            Self::EntryEventsBlockedByMovSs => "VM entry with events blocked by MOV SS",
+            Self::InvalidInveptInvvpidOperand => "Invalid operand to INVEPT/INVVPID",
+            Self::Unknown(_) => "unknown VM-instruction error",
+        }
+    }
+}
+
+impl core::fmt::Display for VmInstructionError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{} ({})", self.name(), self.number())
+    }
+}
+
+const VMEXIT_REASON_BASIC: u64 = 0xffff;
+const CR4_PCIDE: u64 = 1 << 17;
+const VMEXIT_REASON_VMENTRY_FAILURE: u64 = 1 << 31;
+
 #[allow(unused)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u64)]
@@ -263,15 +535,161 @@ pub struct VmExitGeneralPurposeRegister {
     pub rbp: u64,
 }
 
+impl VmExitGeneralPurposeRegister {
+    /// Reads a register by its encoding number (0: rax, 1: rcx, ..., 15: r15).
+    /// RSP is not saved on VM exit and is taken from the VMCS.
+    pub fn get(&self, index: u64) -> u64 {
+        match index {
+            0 => self.rax,
+            1 => self.rcx,
+            2 => self.rdx,
+            3 => self.rbx,
+            4 => unsafe { BSP.as_ptr().as_ref().unwrap() }
+                .vmcs_region
+                .read_natural(VmcsField::GuestRsp),
+            5 => self.rbp,
+            6 => self.rsi,
+            7 => self.rdi,
+            8 => self.r8,
+            9 => self.r9,
+            10 => self.r10,
+            11 => self.r11,
+            12 => self.r12,
+            13 => self.r13,
+            14 => self.r14,
+            15 => self.r15,
+            _ => panic!("invalid register number: {index}"),
+        }
+    }
+
+    pub fn set(&mut self, index: u64, value: u64) {
+        match index {
+            0 => self.rax = value,
+            1 => self.rcx = value,
+            2 => self.rdx = value,
+            3 => self.rbx = value,
+            4 => unsafe { BSP.as_ptr().as_mut().unwrap() }
+                .vmcs_region
+                .write_natural(VmcsField::GuestRsp, value),
+            5 => self.rbp = value,
+            6 => self.rsi = value,
+            7 => self.rdi = value,
+            8 => self.r8 = value,
+            9 => self.r9 = value,
+            10 => self.r10 = value,
+            11 => self.r11 = value,
+            12 => self.r12 = value,
+            13 => self.r13 = value,
+            14 => self.r14 = value,
+            15 => self.r15 = value,
+            _ => panic!("invalid register number: {index}"),
+        }
+    }
+}
+
 pub fn handle_vmexit(reason: u64, qual: u64, gpr: *mut VmExitGeneralPurposeRegister) {
-    serial_println!("reason: {reason}");
-    let reason = unsafe { core::mem::transmute(reason) };
-    serial_println!("{reason:?}, qualification: 0x{qual:x}");
+    if reason & VMEXIT_REASON_VMENTRY_FAILURE != 0 {
+        if reason & VMEXIT_REASON_BASIC == VmExitReason::VmentryFailMachineCheckEvent as u64 {
+            vmexit_handlers::vmentry_machine_check();
+            return;
+        }
+        serial_println!("reason: {reason}");
+        vmexit_handlers::vmentry_failure(reason & VMEXIT_REASON_BASIC, qual);
+    }
     let bsp = unsafe { BSP.as_ptr().as_mut().unwrap() };
     let gpr = unsafe { gpr.as_mut().unwrap() };
-    let rsp = bsp.vmcs_region.read(VmcsField::GuestRsp);
-    let rip = bsp.vmcs_region.read(VmcsField::GuestRip);
-    let rflags = bsp.vmcs_region.read(VmcsField::GuestRflags);
+    let cr3 = bsp.vmcs_region.read_natural(VmcsField::GuestCr3);
+    let pcide = bsp.vmcs_region.read_natural(VmcsField::GuestCr4) & CR4_PCIDE != 0;
+    cr3_tracker::on_vmexit(cr3, pcide);
+    let traced = cr3_tracker::is_traced(cr3);
+
+    let reason = unsafe { core::mem::transmute(reason & VMEXIT_REASON_BASIC) };
+    let rsp = bsp.vmcs_region.read_natural(VmcsField::GuestRsp);
+    let rip = bsp.vmcs_region.read_natural(VmcsField::GuestRip);
+    let rflags = bsp.vmcs_region.read_natural(VmcsField::GuestRflags);
+    if traced {
+        print_vmexit(reason, qual, gpr, rip, rsp, rflags);
+    }
+
+    match reason {
+        VmExitReason::TripleFault => vmexit_handlers::triple_fault(),
+        // The VM-exit instruction length is not valid for EPT violations and APIC accesses,
+        // so their emulation moves RIP itself.
+        VmExitReason::EptViolation => {
+            vmexit_handlers::ept_violation(qual, gpr);
+            return;
+        }
+        VmExitReason::EptMisconfiguration => vmexit_handlers::ept_misconfiguration(),
+        VmExitReason::CrAccess => {
+            if !vmexit_handlers::cr_access(qual, gpr) {
+                return;
+            }
+        }
+        VmExitReason::AccessGdtrOrIdtr => {
+            if !desc_table::gdtr_idtr_access(qual, gpr, &mut bsp.vmcs_region) {
+                return;
+            }
+        }
+        VmExitReason::AccessLdtrOrTr => {
+            if !desc_table::ldtr_tr_access(qual, gpr, &mut bsp.vmcs_region) {
+                return;
+            }
+        }
+        VmExitReason::ExceptionOrNmi => {
+            exception::handle(&mut bsp.vmcs_region, qual, gpr);
+            return;
+        }
+        VmExitReason::ExternalInterrupt => {
+            vmexit_handlers::external_interrupt();
+            return;
+        }
+        // The pending event is injected on the way back in.
+        VmExitReason::InterruptWindow | VmExitReason::NmiWindow => return,
+        VmExitReason::Cpuid => vmexit_handlers::cpuid(gpr),
+        VmExitReason::Vmcall => hypercall::vmcall(&mut bsp.vmcs_region, gpr),
+        VmExitReason::IoInstruction => io::io_instruction(qual, gpr),
+        VmExitReason::Rdmsr => {
+            if !msr::rdmsr(gpr) {
+                return;
+            }
+        }
+        VmExitReason::Wrmsr => {
+            if !msr::wrmsr(gpr) {
+                return;
+            }
+        }
+        VmExitReason::ApicAccess => {
+            apic::apic_access(qual, gpr, &mut bsp.vmcs_region);
+            return;
+        }
+        // The guest lowered its TPR below a held-back interrupt, which is injected on the
+        // way back in.
+        VmExitReason::TprBelowThreshold => return,
+        VmExitReason::InitSignal => {
+            sipi::init_signal(&mut bsp.vmcs_region, gpr);
+            return;
+        }
+        VmExitReason::StartupIpi => {
+            sipi::startup_ipi(&mut bsp.vmcs_region, qual);
+            return;
+        }
+        _ => x86_64::instructions::hlt(),
+    }
+
+    let len = bsp.vmcs_region.read32(VmcsField::VmExitInstructionLen) as u64;
+    bsp.vmcs_region
+        .write_natural(VmcsField::GuestRip, rip + len);
+}
+
+fn print_vmexit(
+    reason: VmExitReason,
+    qual: u64,
+    gpr: &VmExitGeneralPurposeRegister,
+    rip: u64,
+    rsp: u64,
+    rflags: u64,
+) {
+    serial_println!("{reason:?}, qualification: 0x{qual:x}");
     serial_println!("rip: 0x{:016x} flg: 0x{:016x}", rip, rflags);
     serial_println!(
         "rax: 0x{:016x} rbx: 0x{:016x} rcx: 0x{:016x} rdx: 0x{:016x}",
@@ -301,48 +719,4 @@ pub fn handle_vmexit(reason: u64, qual: u64, gpr: *mut VmExitGeneralPurposeRegis
         gpr.r14,
         gpr.r15
     );
-
-    match reason {
-        VmExitReason::TripleFault => vmexit_handlers::triple_fault(),
-        VmExitReason::EptViolation => vmexit_handlers::ept_violation(gpr),
-        VmExitReason::CrAccess => vmexit_handlers::cr_access(qual, gpr),
-        VmExitReason::ExceptionOrNmi => {
-            let vmexit_intr_info = bsp.vmcs_region.read(VmcsField::VmExitIntrInfo);
-            let vector = vmexit_intr_info & 0b1111_1111;
-            let intr_type = (vmexit_intr_info & 0b111_0000_0000) >> 8;
-            let error_code_valid = ((vmexit_intr_info & 0b1000_0000_0000) >> 11) == 1;
-            serial_println!("Interruption vector: {vector}");
-            serial_println!("Interruption type number: {intr_type}");
-            if error_code_valid {
-                let error_code = bsp.vmcs_region.read(VmcsField::VmExitIntrErrorCode);
-                serial_println!("Error code: {error_code}");
-            }
-
-            x86_64::instructions::hlt();
-
-            let rip = bsp.vmcs_region.read(VmcsField::GuestRip);
-            let instruction = decode_one(rip);
-            if instruction.is_err() {
-                serial_println!("decode error");
-                panic!();
-            }
-            let instruction = instruction.unwrap();
-            bsp.vmcs_region
-                .write(VmcsField::GuestRip, rip + instruction.len() as u64);
-        }
-        VmExitReason::Cpuid => vmexit_handlers::cpuid(gpr),
-        VmExitReason::InitSignal => vmexit_handlers::init_signal(),
-        _ => x86_64::instructions::hlt(),
-    }
-
-    let cr3 = bsp.vmcs_region.read(VmcsField::GuestCr3);
-    let rip_phys = guest_virt_to_guest_phys(rip, cr3);
-    let instruction = decode_one(rip_phys.as_u64());
-    if instruction.is_err() {
-        panic!("instruction decode error");
-    }
-    let instruction = instruction.unwrap();
-    serial_println!("{instruction:x?}");
-    bsp.vmcs_region
-        .write(VmcsField::GuestRip, rip + instruction.len() as u64);
 }
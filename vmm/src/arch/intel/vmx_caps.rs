@@ -0,0 +1,368 @@
+use crate::{arch::intel::vmx::VmxError, serial_println};
+use common::constants;
+use x86_64::registers::model_specific::Msr;
+
+const PROC_BASED_ACTIVATE_SECONDARY_CTLS: u32 = 1 << 31;
+const PROC_BASED2_ENABLE_EPT: u32 = 1 << 1;
+pub const PROC_BASED2_ENABLE_VPID: u32 = 1 << 5;
+const PROC_BASED2_ENABLE_VM_FUNCTIONS: u32 = 1 << 13;
+
+/// IA32_VMX_BASIC (SDM Vol. 3D, A.1).
+#[derive(Debug, Clone, Copy)]
+pub struct VmxBasic(pub u64);
+
+impl VmxBasic {
+    pub fn revision_id(&self) -> u32 {
+        (self.0 & 0x7fff_ffff) as u32
+    }
+
+    pub fn region_size(&self) -> u32 {
+        ((self.0 >> 32) & 0x1fff) as u32
+    }
+
+    /// Memory type the processor uses to access the VMCS (0: UC, 6: WB).
+    pub fn memory_type(&self) -> u8 {
+        ((self.0 >> 50) & 0xf) as u8
+    }
+
+    pub fn ins_outs_info(&self) -> bool {
+        self.0 & (1 << 54) != 0
+    }
+
+    /// Whether the IA32_VMX_TRUE_*_CTLS MSRs exist.
+    pub fn true_controls(&self) -> bool {
+        self.0 & (1 << 55) != 0
+    }
+}
+
+/// IA32_VMX_MISC (SDM Vol. 3D, A.6).
+#[derive(Debug, Clone, Copy)]
+pub struct VmxMisc(pub u64);
+
+impl VmxMisc {
+    /// The preemption timer counts down every 2^n TSC ticks.
+    pub fn preemption_timer_rate(&self) -> u8 {
+        (self.0 & 0x1f) as u8
+    }
+
+    pub fn stores_lma(&self) -> bool {
+        self.0 & (1 << 5) != 0
+    }
+
+    pub fn activity_hlt(&self) -> bool {
+        self.0 & (1 << 6) != 0
+    }
+
+    pub fn activity_shutdown(&self) -> bool {
+        self.0 & (1 << 7) != 0
+    }
+
+    pub fn activity_wait_for_sipi(&self) -> bool {
+        self.0 & (1 << 8) != 0
+    }
+
+    pub fn cr3_target_count(&self) -> u8 {
+        ((self.0 >> 16) & 0x1ff) as u8
+    }
+
+    pub fn max_msr_list_entries(&self) -> u32 {
+        512 * (((self.0 >> 25) & 0b111) as u32 + 1)
+    }
+
+    pub fn vmwrite_any_field(&self) -> bool {
+        self.0 & (1 << 29) != 0
+    }
+}
+
+/// IA32_VMX_EPT_VPID_CAP (SDM Vol. 3D, A.10).
+#[derive(Debug, Clone, Copy)]
+pub struct EptVpidCap(pub u64);
+
+impl EptVpidCap {
+    pub fn execute_only(&self) -> bool {
+        self.0 & (1 << 0) != 0
+    }
+
+    pub fn page_walk_length_4(&self) -> bool {
+        self.0 & (1 << 6) != 0
+    }
+
+    pub fn memory_type_uc(&self) -> bool {
+        self.0 & (1 << 8) != 0
+    }
+
+    pub fn memory_type_wb(&self) -> bool {
+        self.0 & (1 << 14) != 0
+    }
+
+    pub fn pde_2m(&self) -> bool {
+        self.0 & (1 << 16) != 0
+    }
+
+    pub fn pdpte_1g(&self) -> bool {
+        self.0 & (1 << 17) != 0
+    }
+
+    pub fn invept(&self) -> bool {
+        self.0 & (1 << 20) != 0
+    }
+
+    pub fn accessed_dirty(&self) -> bool {
+        self.0 & (1 << 21) != 0
+    }
+
+    pub fn invept_single_context(&self) -> bool {
+        self.0 & (1 << 25) != 0
+    }
+
+    pub fn invept_all_context(&self) -> bool {
+        self.0 & (1 << 26) != 0
+    }
+
+    pub fn invvpid(&self) -> bool {
+        self.0 & (1 << 32) != 0
+    }
+
+    pub fn invvpid_individual_address(&self) -> bool {
+        self.0 & (1 << 40) != 0
+    }
+
+    pub fn invvpid_single_context(&self) -> bool {
+        self.0 & (1 << 41) != 0
+    }
+
+    pub fn invvpid_all_context(&self) -> bool {
+        self.0 & (1 << 42) != 0
+    }
+
+    pub fn invvpid_single_context_retaining_globals(&self) -> bool {
+        self.0 & (1 << 43) != 0
+    }
+}
+
+/// IA32_VMX_VMFUNC (SDM Vol. 3D, A.11): the VM functions that may be enabled.
+#[derive(Debug, Clone, Copy)]
+pub struct VmFunctions(pub u64);
+
+impl VmFunctions {
+    pub fn eptp_switching(&self) -> bool {
+        self.0 & (1 << 0) != 0
+    }
+}
+
+/// IA32_VMX_CR0_FIXED0/1 or IA32_VMX_CR4_FIXED0/1: bits set in `fixed0` must be 1 and bits
+/// clear in `fixed1` must be 0 while in VMX operation.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedBits {
+    pub fixed0: u64,
+    pub fixed1: u64,
+}
+
+impl FixedBits {
+    pub fn apply(&self, value: u64) -> u64 {
+        (value | self.fixed0) & self.fixed1
+    }
+
+    pub fn is_valid(&self, value: u64) -> bool {
+        self.apply(value) == value
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmxControls {
+    PinBased,
+    ProcBased,
+    ProcBased2,
+    Exit,
+    Entry,
+}
+
+/// One of the control capability MSRs. The low half holds the allowed 0-settings (bits that
+/// must be 1) and the high half the allowed 1-settings (bits that may be 1).
+#[derive(Debug, Clone, Copy)]
+pub struct ControlCaps {
+    pub controls: VmxControls,
+    pub allowed0: u32,
+    pub allowed1: u32,
+}
+
+impl ControlCaps {
+    fn from_msr(controls: VmxControls, value: u64) -> Self {
+        Self {
+            controls,
+            allowed0: value as u32,
+            allowed1: (value >> 32) as u32,
+        }
+    }
+
+    pub fn is_supported(&self, bits: u32) -> bool {
+        bits & !self.allowed1 == 0
+    }
+
+    /// Returns `requested` plus the bits that must be set, or the requested bits the
+    /// processor does not support.
+    pub fn adjust(&self, requested: u32) -> Result<u32, VmxError> {
+        let unsupported = requested & !self.allowed1;
+        if unsupported != 0 {
+            return Err(VmxError::UnsupportedControls {
+                controls: self.controls,
+                bits: unsupported,
+            });
+        }
+        Ok(requested | self.allowed0)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VmxCaps {
+    pub basic: VmxBasic,
+    pub misc: VmxMisc,
+    pub ept_vpid: EptVpidCap,
+    pub vmfunc: VmFunctions,
+    pub cr0_fixed: FixedBits,
+    pub cr4_fixed: FixedBits,
+    pub pin_based: ControlCaps,
+    pub proc_based: ControlCaps,
+    pub proc_based2: ControlCaps,
+    pub exit: ControlCaps,
+    pub entry: ControlCaps,
+}
+
+impl VmxCaps {
+    /// Reads the capability MSRs, preferring the TRUE control MSRs when IA32_VMX_BASIC says
+    /// they exist: only they report which default1 controls may actually be cleared.
+    pub fn read() -> Self {
+        let read = |msr: u32| unsafe { Msr::new(msr).read() };
+        let basic = VmxBasic(read(constants::MSR_IA32_VMX_BASIC));
+        let (pin, proc, exit, entry) = if basic.true_controls() {
+            (
+                constants::MSR_IA32_VMX_TRUE_PINBASED_CTLS,
+                constants::MSR_IA32_VMX_TRUE_PROCBASED_CTLS,
+                constants::MSR_IA32_VMX_TRUE_EXIT_CTLS,
+                constants::MSR_IA32_VMX_TRUE_ENTRY_CTLS,
+            )
+        } else {
+            (
+                constants::MSR_IA32_VMX_PINBASED_CTLS,
+                constants::MSR_IA32_VMX_PROCBASED_CTLS,
+                constants::MSR_IA32_VMX_EXIT_CTLS,
+                constants::MSR_IA32_VMX_ENTRY_CTLS,
+            )
+        };
+        let proc_based = ControlCaps::from_msr(VmxControls::ProcBased, read(proc));
+        let proc_based2 = if proc_based.is_supported(PROC_BASED_ACTIVATE_SECONDARY_CTLS) {
+            read(constants::MSR_IA32_VMX_PROCBASED_CTLS2)
+        } else {
+            0
+        };
+        let proc_based2 = ControlCaps::from_msr(VmxControls::ProcBased2, proc_based2);
+        let ept_vpid = if proc_based2.is_supported(PROC_BASED2_ENABLE_EPT)
+            || proc_based2.is_supported(PROC_BASED2_ENABLE_VPID)
+        {
+            read(constants::MSR_IA32_VMX_EPT_VPID_CAP)
+        } else {
+            0
+        };
+        let vmfunc = if proc_based2.is_supported(PROC_BASED2_ENABLE_VM_FUNCTIONS) {
+            read(constants::MSR_IA32_VMX_VMFUNC)
+        } else {
+            0
+        };
+
+        Self {
+            basic,
+            misc: VmxMisc(read(constants::MSR_IA32_VMX_MISC)),
+            ept_vpid: EptVpidCap(ept_vpid),
+            vmfunc: VmFunctions(vmfunc),
+            cr0_fixed: FixedBits {
+                fixed0: read(constants::MSR_IA32_VMX_CR0_FIXED0),
+                fixed1: read(constants::MSR_IA32_VMX_CR0_FIXED1),
+            },
+            cr4_fixed: FixedBits {
+                fixed0: read(constants::MSR_IA32_VMX_CR4_FIXED0),
+                fixed1: read(constants::MSR_IA32_VMX_CR4_FIXED1),
+            },
+            pin_based: ControlCaps::from_msr(VmxControls::PinBased, read(pin)),
+            proc_based,
+            proc_based2,
+            exit: ControlCaps::from_msr(VmxControls::Exit, read(exit)),
+            entry: ControlCaps::from_msr(VmxControls::Entry, read(entry)),
+        }
+    }
+
+    pub fn report(&self) {
+        let basic = &self.basic;
+        serial_println!("---- VMX capabilities ----");
+        serial_println!(
+            "VMCS revision 0x{:x}, region size {}, memory type {}, INS/OUTS info {}, TRUE controls {}",
+            basic.revision_id(),
+            basic.region_size(),
+            basic.memory_type(),
+            basic.ins_outs_info(),
+            basic.true_controls()
+        );
+        let misc = &self.misc;
+        serial_println!(
+            "preemption timer rate {}, CR3 targets {}, MSR list {}, stores LMA {}, VMWRITE any field {}",
+            misc.preemption_timer_rate(),
+            misc.cr3_target_count(),
+            misc.max_msr_list_entries(),
+            misc.stores_lma(),
+            misc.vmwrite_any_field()
+        );
+        serial_println!(
+            "activity states: hlt {}, shutdown {}, wait-for-SIPI {}",
+            misc.activity_hlt(),
+            misc.activity_shutdown(),
+            misc.activity_wait_for_sipi()
+        );
+        for caps in [
+            &self.pin_based,
+            &self.proc_based,
+            &self.proc_based2,
+            &self.exit,
+            &self.entry,
+        ] {
+            serial_println!(
+                "{:?}: allowed0 0x{:08x} allowed1 0x{:08x}",
+                caps.controls,
+                caps.allowed0,
+                caps.allowed1
+            );
+        }
+        serial_println!(
+            "CR0 fixed0 0x{:x} fixed1 0x{:x}, CR4 fixed0 0x{:x} fixed1 0x{:x}",
+            self.cr0_fixed.fixed0,
+            self.cr0_fixed.fixed1,
+            self.cr4_fixed.fixed0,
+            self.cr4_fixed.fixed1
+        );
+        let ept = &self.ept_vpid;
+        serial_println!(
+            "EPT: 4-level {}, execute-only {}, UC {}, WB {}, 2M {}, 1G {}, A/D {}, INVEPT {} (single {}, all {})",
+            ept.page_walk_length_4(),
+            ept.execute_only(),
+            ept.memory_type_uc(),
+            ept.memory_type_wb(),
+            ept.pde_2m(),
+            ept.pdpte_1g(),
+            ept.accessed_dirty(),
+            ept.invept(),
+            ept.invept_single_context(),
+            ept.invept_all_context()
+        );
+        serial_println!(
+            "VPID: INVVPID {} (address {}, single {}, all {}, single retaining globals {})",
+            ept.invvpid(),
+            ept.invvpid_individual_address(),
+            ept.invvpid_single_context(),
+            ept.invvpid_all_context(),
+            ept.invvpid_single_context_retaining_globals()
+        );
+        serial_println!(
+            "VM functions 0x{:x} (EPTP switching {})",
+            self.vmfunc.0,
+            self.vmfunc.eptp_switching()
+        );
+    }
+}
@@ -1,26 +1,114 @@
-use core::arch::asm;
+use crate::arch::intel::{ept::EptError, vmx::VmxError};
+use core::{
+    arch::{asm, global_asm},
+    ptr,
+};
 use x86_64::{
     instructions::tables::sgdt,
     registers::segmentation::Segment,
-    structures::gdt::SegmentSelector,
-    structures::paging::{PageTable, PageTableFlags},
+    structures::{gdt::SegmentSelector, DescriptorTablePointer},
     PhysAddr,
 };
 
+// MSR accesses the processor may refuse. A #GP at one of the `_insn` labels resumes at
+// `vmm_msr_fault` (see `gp_fixup`), which returns 1.
+global_asm!(
+    ".global vmm_rdmsr_checked, vmm_rdmsr_insn, vmm_wrmsr_checked, vmm_wrmsr_insn, vmm_msr_fault",
+    "vmm_rdmsr_checked:",
+    "mov ecx, edi",
+    "vmm_rdmsr_insn:",
+    "rdmsr",
+    "shl rdx, 32",
+    "or rax, rdx",
+    "mov [rsi], rax",
+    "xor eax, eax",
+    "ret",
+    "vmm_wrmsr_checked:",
+    "mov ecx, edi",
+    "mov eax, esi",
+    "mov rdx, rsi",
+    "shr rdx, 32",
+    "vmm_wrmsr_insn:",
+    "wrmsr",
+    "xor eax, eax",
+    "ret",
+    "vmm_msr_fault:",
+    "mov eax, 1",
+    "ret",
+);
+
+extern "sysv64" {
+    fn vmm_rdmsr_checked(msr: u32, value: *mut u64) -> u32;
+    fn vmm_wrmsr_checked(msr: u32, value: u64) -> u32;
+    static vmm_rdmsr_insn: u8;
+    static vmm_wrmsr_insn: u8;
+    static vmm_msr_fault: u8;
+}
+
+/// RDMSR that returns `None` where the processor raises #GP.
+pub fn rdmsr_checked(msr: u32) -> Option<u64> {
+    let mut value = 0;
+    (unsafe { vmm_rdmsr_checked(msr, &mut value) } == 0).then_some(value)
+}
+
+/// WRMSR that returns `false` where the processor raises #GP.
+///
+/// # Safety
+/// The write must not break the VMM, like any other MSR write.
+pub unsafe fn wrmsr_checked(msr: u32, value: u64) -> bool {
+    vmm_wrmsr_checked(msr, value) == 0
+}
+
+/// Where the VMM continues after a #GP at `rip`, if the instruction there may fault.
+pub fn gp_fixup(rip: u64) -> Option<u64> {
+    let (faulting, fixup) = unsafe {
+        (
+            [&vmm_rdmsr_insn as *const u8, &vmm_wrmsr_insn as *const u8],
+            &vmm_msr_fault as *const u8 as u64,
+        )
+    };
+    faulting.contains(&(rip as *const u8)).then_some(fixup)
+}
+
 pub trait Cpu {
     fn is_virtualization_supported(&self) -> bool;
     fn enable_virtualization(&mut self) -> Result<(), CpuError>;
     fn disable_virtualization(&mut self) -> Result<(), CpuError>;
-    fn init_as_bsp(&mut self);
-    fn run_vm(&mut self);
+    fn init_as_bsp(&mut self) -> Result<(), CpuError>;
+    /// Only returns if the VM could not be launched.
+    fn run_vm(&mut self) -> Result<(), CpuError>;
 }
 
 #[derive(Debug)]
 pub enum CpuError {
     NotSupported,
-    VmxRelated,
+    Vmx(VmxError),
+    Ept(EptError),
 }
 
+impl From<VmxError> for CpuError {
+    fn from(e: VmxError) -> Self {
+        CpuError::Vmx(e)
+    }
+}
+
+impl From<EptError> for CpuError {
+    fn from(e: EptError) -> Self {
+        CpuError::Ept(e)
+    }
+}
+
+impl core::fmt::Display for CpuError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            CpuError::NotSupported => write!(f, "hardware virtualization is not supported"),
+            CpuError::Vmx(e) => write!(f, "{e}"),
+            CpuError::Ept(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+#[allow(unused)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Ldtr;
 
@@ -84,67 +172,207 @@ impl SegmentDescriptor {
     // }
 }
 
-pub fn guest_virt_to_guest_phys(guest_virt: u64, guest_cr3: u64) -> PhysAddr {
-    let pml4_index = ((guest_virt >> 39) & 0b1_1111_1111) as usize;
-    let pdp_index = ((guest_virt >> 30) & 0b1_1111_1111) as usize;
-    let pd_index = ((guest_virt >> 21) & 0b1_1111_1111) as usize;
-    let pt_index = ((guest_virt >> 12) & 0b1_1111_1111) as usize;
-    // serial_println!("pml4: {pml4_index}, pdp: {pdp_index}, pd: {pd_index}, pt: {pt_index}");
+/// A segment register as the processor caches it, with the access rights in the VMCS format
+/// (SDM Vol. 3C, 25.4.1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentCache {
+    pub base: u64,
+    pub limit: u32,
+    pub access_rights: u32,
+}
+
+pub const ACCESS_RIGHTS_UNUSABLE: u32 = 1 << 16;
+const DESCRIPTOR_S: u64 = 1 << 44;
+const DESCRIPTOR_G: u64 = 1 << 55;
 
-    let guest_pml4_phys = PhysAddr::new(guest_cr3 & !0b1111_1111_1111);
-    let guest_pml4 = unsafe {
-        (guest_pml4_phys.as_u64() as *mut PageTable)
-            .as_mut()
-            .unwrap()
+impl SegmentCache {
+    pub const UNUSABLE: Self = Self {
+        base: 0,
+        limit: 0,
+        access_rights: ACCESS_RIGHTS_UNUSABLE,
     };
 
-    // use crate::serial_println;
+    /// Decodes the descriptor `sel` selects, looking in the LDT `ldtr` selects for TI=1.
+    /// A null selector or one beyond the table limit gives an unusable segment.
+    ///
+    /// # Safety
+    /// The descriptor tables must be identity mapped.
+    pub unsafe fn decode(
+        gdtr: &DescriptorTablePointer,
+        ldtr: SegmentSelector,
+        sel: SegmentSelector,
+    ) -> Self {
+        let (table_base, table_limit) = if sel.0 & 0b100 != 0 {
+            let ldt = Self::decode(gdtr, SegmentSelector(0), ldtr);
+            if ldt.access_rights & ACCESS_RIGHTS_UNUSABLE != 0 {
+                return Self::UNUSABLE;
+            }
+            (ldt.base, ldt.limit as u64)
+        } else {
+            if sel.index() == 0 {
+                return Self::UNUSABLE;
+            }
+            (gdtr.base.as_u64(), gdtr.limit as u64)
+        };
+        let offset = sel.index() as u64 * 8;
+        if offset + 7 > table_limit {
+            return Self::UNUSABLE;
+        }
+        let descriptor = ptr::read_unaligned((table_base + offset) as *const u64);
 
-    let guest_pml4_entry = &guest_pml4[pml4_index];
-    // serial_println!("{:?}", guest_pml4_entry);
-    if !guest_pml4_entry.flags().contains(PageTableFlags::PRESENT) {
-        return PhysAddr::new(0);
+        let mut base = ((descriptor >> 16) & 0xff_ffff) | ((descriptor >> 32) & 0xff00_0000);
+        // System descriptors (LDT, TSS) are 16 bytes in IA-32e mode.
+        if descriptor & DESCRIPTOR_S == 0 && offset + 15 <= table_limit {
+            let high = ptr::read_unaligned((table_base + offset + 8) as *const u64);
+            base |= (high & 0xffff_ffff) << 32;
+        }
+        let mut limit = ((descriptor & 0xffff) | ((descriptor >> 32) & 0xf_0000)) as u32;
+        if descriptor & DESCRIPTOR_G != 0 {
+            limit = (limit << 12) | 0xfff;
+        }
+        // Type, S, DPL and P from byte 5, AVL, L, D/B and G from the upper nibble of byte 6.
+        let access_rights = ((descriptor >> 40) & 0xf0ff) as u32;
+        Self {
+            base,
+            limit,
+            access_rights,
+        }
     }
+}
 
-    let guest_pdp = unsafe {
-        (guest_pml4_entry.addr().as_u64() as *mut PageTable)
-            .as_mut()
-            .unwrap()
-    };
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_WRITABLE: u64 = 1 << 1;
+const PTE_USER: u64 = 1 << 2;
+const PTE_PAGE_SIZE: u64 = 1 << 7;
+const PTE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+const PTE32_ADDRESS_MASK: u64 = 0xffff_f000;
+const PAE_PDPT_MASK: u64 = 0xffff_ffe0;
+const PF_PRESENT: u32 = 1 << 0;
+const PF_WRITE: u32 = 1 << 1;
+const PF_USER: u32 = 1 << 2;
 
-    let guest_pdp_entry = &guest_pdp[pdp_index];
-    // serial_println!("{:?}", guest_pdp_entry);
-    if !guest_pdp_entry.flags().contains(PageTableFlags::PRESENT) {
-        return PhysAddr::new(0);
-    }
-    if guest_pdp_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
-        let pdp_offset = guest_virt & 0b1_1111_1111_1111_1111_1111_1111_1111;
-        return guest_pdp_entry.addr() + pdp_offset;
-    }
+/// How the guest translates linear addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestPaging {
+    /// CR0.PG is clear, linear addresses are physical.
+    Disabled,
+    /// 32-bit paging, with 4 MiB pages if CR4.PSE is set.
+    Bits32 {
+        pse: bool,
+    },
+    Pae,
+    Level4,
+    Level5,
+}
 
-    let guest_pd = unsafe {
-        (guest_pdp_entry.addr().as_u64() as *mut PageTable)
-            .as_mut()
-            .unwrap()
-    };
+/// A guest memory access to translate.
+#[derive(Debug, Clone, Copy)]
+pub struct GuestAccess {
+    pub write: bool,
+    /// The access is made at CPL 3.
+    pub user: bool,
+    /// CR0.WP: supervisor writes to read-only pages fault too.
+    pub write_protect: bool,
+}
 
-    let guest_pd_entry = &guest_pd[pd_index];
-    // serial_println!("{:?}", guest_pd_entry);
-    if !guest_pd_entry.flags().contains(PageTableFlags::PRESENT) {
-        return PhysAddr::new(0);
+impl GuestAccess {
+    fn fault(&self, present: bool) -> PageFault {
+        let mut error_code = 0;
+        if present {
+            error_code |= PF_PRESENT;
+        }
+        if self.write {
+            error_code |= PF_WRITE;
+        }
+        if self.user {
+            error_code |= PF_USER;
+        }
+        PageFault { error_code }
     }
-    if guest_pd_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
-        let pd_offset = guest_virt & 0b1_1111_1111_1111_1111_1111;
-        return guest_pd_entry.addr() + pd_offset;
+}
+
+/// A translation the guest's paging structures refuse, with the #PF error code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFault {
+    pub error_code: u32,
+}
+
+fn read_entry(table: u64, index: u64, wide: bool) -> u64 {
+    unsafe {
+        if wide {
+            ptr::read_volatile((table + index * 8) as *const u64)
+        } else {
+            ptr::read_volatile((table + index * 4) as *const u32) as u64
+        }
     }
+}
 
-    let guest_pt = unsafe {
-        (guest_pd_entry.addr().as_u64() as *mut PageTable)
-            .as_mut()
-            .unwrap()
+/// Walks the guest paging structures rooted at `cr3` the way the processor would for
+/// `access` and returns the guest physical address of `linear`.
+pub fn guest_linear_to_phys(
+    paging: GuestPaging,
+    cr3: u64,
+    linear: u64,
+    access: GuestAccess,
+) -> Result<PhysAddr, PageFault> {
+    let (mut table, shifts, wide): (u64, &[u32], bool) = match paging {
+        GuestPaging::Disabled => return Ok(PhysAddr::new(linear & 0xffff_ffff)),
+        GuestPaging::Bits32 { .. } => (cr3 & PTE32_ADDRESS_MASK, &[22, 12], false),
+        // The PDPTEs have no access rights.
+        GuestPaging::Pae => {
+            let pdpte = read_entry(cr3 & PAE_PDPT_MASK, (linear >> 30) & 0b11, true);
+            if pdpte & PTE_PRESENT == 0 {
+                return Err(access.fault(false));
+            }
+            (pdpte & PTE_ADDRESS_MASK, &[21, 12], true)
+        }
+        GuestPaging::Level4 => (cr3 & PTE_ADDRESS_MASK, &[39, 30, 21, 12], true),
+        GuestPaging::Level5 => (cr3 & PTE_ADDRESS_MASK, &[48, 39, 30, 21, 12], true),
     };
+    let pse = paging == (GuestPaging::Bits32 { pse: true });
+    let index_mask = if wide { 0x1ff } else { 0x3ff };
+    let (mut writable, mut user) = (true, true);
+    // The last level maps 4 KiB pages, so the walk always ends.
+    let mut level = 0;
+    loop {
+        let shift = shifts[level];
+        let entry = read_entry(table, (linear >> shift) & index_mask, wide);
+        if entry & PTE_PRESENT == 0 {
+            return Err(access.fault(false));
+        }
+        writable &= entry & PTE_WRITABLE != 0;
+        user &= entry & PTE_USER != 0;
+        let large = entry & PTE_PAGE_SIZE != 0
+            && if wide {
+                shift == 21 || shift == 30
+            } else {
+                shift == 22 && pse
+            };
+        if shift != 12 && !large {
+            table = entry
+                & if wide {
+                    PTE_ADDRESS_MASK
+                } else {
+                    PTE32_ADDRESS_MASK
+                };
+            level += 1;
+            continue;
+        }
 
-    let guest_pt_entry = &guest_pt[pt_index];
-    let pt_offset = guest_virt & 0b1111_1111_1111;
-    guest_pt_entry.addr() + pt_offset
+        if (access.user && !user)
+            || (access.write && !writable && (access.user || access.write_protect))
+        {
+            return Err(access.fault(true));
+        }
+        let page_mask = (1 << shift) - 1;
+        let base = if wide {
+            entry & PTE_ADDRESS_MASK & !page_mask
+        } else if large {
+            // PSE-36 keeps physical address bits 39:32 in bits 20:13.
+            (entry & !page_mask & 0xffff_ffff) | ((entry >> 13) & 0xff) << 32
+        } else {
+            entry & PTE32_ADDRESS_MASK
+        };
+        return Ok(PhysAddr::new(base | (linear & page_mask)));
+    }
 }
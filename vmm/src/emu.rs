@@ -1,14 +1,125 @@
-// use crate::arch::intel::vmx::VmExitGeneralPurposeRegister;
-use iced_x86::{Decoder, DecoderOptions, Instruction};
+use crate::arch::intel::{
+    vmcs::{VmcsField, VmcsRegion},
+    vmx::VmExitGeneralPurposeRegister,
+};
+use iced_x86::{Decoder, DecoderError, DecoderOptions, Instruction, Mnemonic, OpKind, Register};
 
-pub fn decode_one(rip: u64) -> Result<Instruction, ()> {
-    let code = unsafe { core::slice::from_raw_parts(rip as *const u8, 10) };
-    let mut decoder = Decoder::with_ip(64, code, rip, DecoderOptions::NONE);
-    if decoder.can_decode() {
-        Ok(decoder.decode())
+const MAX_INSTRUCTION_LEN: usize = 15;
+
+/// Decodes the instruction in `code`, fetched at `rip` for a code segment of `bitness` (16,
+/// 32 or 64).
+pub fn decode_one(code: &[u8], rip: u64, bitness: u32) -> Result<Instruction, ()> {
+    let mut decoder = Decoder::with_ip(bitness, code, rip, DecoderOptions::NONE);
+    let instruction = decoder.decode();
+    if decoder.last_error() == DecoderError::None {
+        Ok(instruction)
     } else {
         Err(())
     }
 }
 
-// pub fn emulation(instruction: Instruction, gpr: &mut VmExitGeneralPurposeRegister) {}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmioAccess {
+    Read { size: usize },
+    Write { size: usize, value: u64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmuError {
+    DecodeError,
+    UnsupportedInstruction(Mnemonic),
+    UnsupportedOperand(OpKind),
+}
+
+/// Emulates the `mov` at the guest RIP between a register or an immediate and MMIO, and moves
+/// RIP past it. `handler` performs the access and returns the value read (ignored for
+/// writes).
+pub fn emulate_mmio(
+    vmcs: &mut VmcsRegion,
+    gpr: &mut VmExitGeneralPurposeRegister,
+    handler: impl FnOnce(MmioAccess) -> u64,
+) -> Result<Instruction, EmuError> {
+    let mut code = [0; MAX_INSTRUCTION_LEN];
+    let len = vmcs.read_guest_code(&mut code);
+    let rip = vmcs.read_natural(VmcsField::GuestRip);
+    let instruction =
+        decode_one(&code[..len], rip, vmcs.guest_bitness()).map_err(|_| EmuError::DecodeError)?;
+    if instruction.mnemonic() != Mnemonic::Mov {
+        return Err(EmuError::UnsupportedInstruction(instruction.mnemonic()));
+    }
+    let size = instruction.memory_size().size();
+
+    match (instruction.op0_kind(), instruction.op1_kind()) {
+        (OpKind::Memory, OpKind::Register) => {
+            let value = read_register(gpr, instruction.op1_register());
+            handler(MmioAccess::Write { size, value });
+        }
+        (
+            OpKind::Memory,
+            OpKind::Immediate8
+            | OpKind::Immediate16
+            | OpKind::Immediate32
+            | OpKind::Immediate32to64,
+        ) => {
+            let value = instruction.immediate(1) & size_mask(size);
+            handler(MmioAccess::Write { size, value });
+        }
+        (OpKind::Register, OpKind::Memory) => {
+            let value = handler(MmioAccess::Read { size }) & size_mask(size);
+            write_register(gpr, instruction.op0_register(), value);
+        }
+        (OpKind::Memory, kind) | (kind, _) => return Err(EmuError::UnsupportedOperand(kind)),
+    }
+
+    vmcs.write_natural(VmcsField::GuestRip, rip + instruction.len() as u64);
+    Ok(instruction)
+}
+
+fn size_mask(size: usize) -> u64 {
+    if size >= 8 {
+        !0
+    } else {
+        (1 << (size * 8)) - 1
+    }
+}
+
+fn is_high_byte(reg: Register) -> bool {
+    matches!(
+        reg,
+        Register::AH | Register::CH | Register::DH | Register::BH
+    )
+}
+
+fn register_index(reg: Register) -> u64 {
+    if is_high_byte(reg) {
+        reg as u64 - Register::AH as u64
+    } else {
+        reg.full_register().number() as u64
+    }
+}
+
+pub fn read_register(gpr: &VmExitGeneralPurposeRegister, reg: Register) -> u64 {
+    let value = gpr.get(register_index(reg));
+    if is_high_byte(reg) {
+        (value >> 8) & 0xff
+    } else {
+        value & size_mask(reg.size())
+    }
+}
+
+/// Writes `value` to `reg` with the usual merging rules: 32-bit destinations clear the upper
+/// half, 8/16-bit destinations leave the rest of the register untouched.
+pub fn write_register(gpr: &mut VmExitGeneralPurposeRegister, reg: Register, value: u64) {
+    let index = register_index(reg);
+    let old = gpr.get(index);
+    let new = if is_high_byte(reg) {
+        (old & !0xff00) | ((value & 0xff) << 8)
+    } else {
+        match reg.size() {
+            1 | 2 => (old & !size_mask(reg.size())) | (value & size_mask(reg.size())),
+            4 => value & 0xffff_ffff,
+            _ => value,
+        }
+    };
+    gpr.set(index, new);
+}
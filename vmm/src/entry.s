@@ -79,6 +79,28 @@ save_uefi_regs:                         # 1st arg: uefi_cr3
     rdmsr
     mov     %eax, uefi_msr_ia32_sysenter_eip(%rip)
     mov     %edx, uefi_msr_ia32_sysenter_eip_high(%rip)
+    mov     $0x1d9, %rcx                # MSR_IA32_DEBUGCTL
+    rdmsr
+    mov     %eax, uefi_msr_ia32_debugctl(%rip)
+    mov     %edx, uefi_msr_ia32_debugctl+4(%rip)
+    mov     $0x277, %rcx                # MSR_IA32_CR_PAT
+    rdmsr
+    mov     %eax, uefi_msr_ia32_pat(%rip)
+    mov     %edx, uefi_msr_ia32_pat+4(%rip)
+    mov     $0xc0000080, %rcx           # MSR_EFER
+    rdmsr
+    mov     %eax, uefi_msr_efer(%rip)
+    mov     %edx, uefi_msr_efer+4(%rip)
+    mov     $0xc0000100, %rcx           # MSR_FS_BASE, lost when the VMM reloads FS
+    rdmsr
+    mov     %eax, uefi_msr_fs_base(%rip)
+    mov     %edx, uefi_msr_fs_base+4(%rip)
+    mov     $0xc0000101, %rcx           # MSR_GS_BASE
+    rdmsr
+    mov     %eax, uefi_msr_gs_base(%rip)
+    mov     %edx, uefi_msr_gs_base+4(%rip)
+    mov     %dr7, %rax
+    mov     %rax, uefi_dr7(%rip)
     pop     %rdx
     pop     %rcx
     pop     %rax
@@ -133,6 +155,65 @@ restore_uefi_regs:
     pop     %rax
     ret
 
+.global     restore_guest_regs          # fn restore_guest_regs(state: *const NativeState) -> !;
+restore_guest_regs:                     # called after VMXOFF, field offsets from devirt.rs
+    mov     160(%rdi), %rax
+    mov     %rax, %cr4
+    mov     152(%rdi), %rax
+    mov     %rax, %cr3
+    lgdt    168(%rdi)
+    lidt    184(%rdi)
+    movzwl  214(%rdi), %ecx
+    test    %ecx, %ecx
+    jz      1f
+    and     $0xfff8, %ecx
+    mov     %cr0, %rdx
+    btr     $16, %rdx                   # CR0.WP, the guest may map its GDT read-only
+    mov     %rdx, %cr0                  # the guest's CR0 is loaded below
+    mov     170(%rdi), %rax             # GDT base
+    andb    $0xfd, 5(%rax,%rcx)         # LTR faults on a busy TSS
+    ltr     214(%rdi)
+1:
+    lldt    212(%rdi)
+    mov     204(%rdi), %ds
+    mov     206(%rdi), %es
+    mov     208(%rdi), %fs
+    mov     210(%rdi), %gs
+    mov     $0xc0000100, %ecx           # MSR_FS_BASE, clobbered by the FS load
+    mov     216(%rdi), %eax
+    mov     220(%rdi), %edx
+    wrmsr
+    mov     $0xc0000101, %ecx           # MSR_GS_BASE
+    mov     224(%rdi), %eax
+    mov     228(%rdi), %edx
+    wrmsr
+    mov     144(%rdi), %rax
+    mov     %rax, %cr0
+    movzwl  202(%rdi), %eax
+    push    %rax                        # SS
+    push    120(%rdi)                   # RSP
+    push    136(%rdi)                   # RFLAGS
+    movzwl  200(%rdi), %eax
+    push    %rax                        # CS
+    push    128(%rdi)                   # RIP
+    push    40(%rdi)                    # RDI
+    mov     0(%rdi), %rax
+    mov     8(%rdi), %rbx
+    mov     16(%rdi), %rcx
+    mov     24(%rdi), %rdx
+    mov     32(%rdi), %rsi
+    mov     48(%rdi), %rbp
+    mov     56(%rdi), %r8
+    mov     64(%rdi), %r9
+    mov     72(%rdi), %r10
+    mov     80(%rdi), %r11
+    mov     88(%rdi), %r12
+    mov     96(%rdi), %r13
+    mov     104(%rdi), %r14
+    mov     112(%rdi), %r15
+    pop     %rdi
+    iretq
+
 .align      16
 restore_uefi_regs_ljmp_rip:
     .quad   0                           # rip
@@ -152,7 +233,6 @@ init_vmm_regs:
     mov     %rax, vmm_cr3(%rip)
     mov     %cr4, %rax
     or      $(0x20|0x80|0x2000), %rax   # PAE + PGE + VMXE
-    and     $(~0x40), %rax              # !MCE
     mov     %rax, vmm_cr4(%rip)
     mov     %rax, %cr4
     pop     %rbx
@@ -288,6 +368,10 @@ vmexit_handler:
     pop     %rax
     pop     %rbp
     vmresume
+    # only reached when VMRESUME fails
+    pushfq
+    pop     %rdi
+    call    vmresume_failed
 
 .global     asm_init_serial     # fn asm_init_serial(base: u16);
 asm_init_serial:
@@ -410,18 +494,48 @@ uefi_msr_ia32_sysenter_cs:
 .align      16
 .global     uefi_msr_ia32_sysenter_esp
 uefi_msr_ia32_sysenter_esp:
-    .word   0
+    .long   0
 .global     uefi_msr_ia32_sysenter_esp_high
 uefi_msr_ia32_sysenter_esp_high:
-    .word   0
+    .long   0
 
 .align      16
 .global     uefi_msr_ia32_sysenter_eip
 uefi_msr_ia32_sysenter_eip:
-    .word   0
+    .long   0
 .global     uefi_msr_ia32_sysenter_eip_high
 uefi_msr_ia32_sysenter_eip_high:
-    .word   0
+    .long   0
+
+.align      8
+.global     uefi_msr_ia32_debugctl
+uefi_msr_ia32_debugctl:
+    .quad   0
+
+.align      8
+.global     uefi_msr_ia32_pat
+uefi_msr_ia32_pat:
+    .quad   0
+
+.align      8
+.global     uefi_msr_efer
+uefi_msr_efer:
+    .quad   0
+
+.align      8
+.global     uefi_msr_fs_base
+uefi_msr_fs_base:
+    .quad   0
+
+.align      8
+.global     uefi_msr_gs_base
+uefi_msr_gs_base:
+    .quad   0
+
+.align      8
+.global     uefi_dr7
+uefi_dr7:
+    .quad   0
 # === UEFI special registers end ===
 
 # ===== VMM special registers =====
@@ -0,0 +1,93 @@
+use crate::{cpu, mce};
+use crossbeam::atomic::AtomicCell;
+use lazy_static::lazy_static;
+use x86_64::{
+    registers::control::Cr2,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    VirtAddr,
+};
+
+/// Set when an NMI hits while the VMM itself is running. The NMI belongs to the guest and
+/// is handed over on the next VM entry.
+static NMI_PENDING: AtomicCell<bool> = AtomicCell::new(false);
+/// Set by a recoverable machine check in host context, for the guest to handle.
+static MACHINE_CHECK_PENDING: AtomicCell<bool> = AtomicCell::new(false);
+
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.double_fault.set_handler_fn(double_fault_handler);
+        idt.general_protection_fault
+            .set_handler_fn(general_protection_fault_handler);
+        idt.page_fault.set_handler_fn(page_fault_handler);
+        // The x86_64 crate only takes #MC handlers that never return, but a recoverable
+        // machine check is handed to the guest and the VMM carries on.
+        unsafe {
+            idt.machine_check
+                .set_handler_addr(VirtAddr::new(machine_check_handler as *const () as u64));
+        }
+        idt
+    };
+}
+
+/// Replaces the firmware IDT with the VMM's own. Must run before the host state is written
+/// to the VMCS, which takes the IDTR from the current one.
+pub fn init() {
+    IDT.load();
+}
+
+/// Returns whether an NMI arrived in host context since the last call.
+pub fn take_nmi() -> bool {
+    NMI_PENDING.swap(false)
+}
+
+extern "x86-interrupt" fn nmi_handler(_frame: InterruptStackFrame) {
+    NMI_PENDING.store(true);
+}
+
+/// Returns whether a recoverable machine check hit the VMM since the last call.
+pub fn take_machine_check() -> bool {
+    MACHINE_CHECK_PENDING.swap(false)
+}
+
+extern "x86-interrupt" fn machine_check_handler(frame: InterruptStackFrame) {
+    if !mce::log_banks().is_recoverable() {
+        panic!("unrecoverable machine check in VMM\n{frame:#?}");
+    }
+    MACHINE_CHECK_PENDING.store(true);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(frame: InterruptStackFrame) {
+    panic!("#UD in VMM\n{frame:#?}");
+}
+
+extern "x86-interrupt" fn double_fault_handler(frame: InterruptStackFrame, _error_code: u64) -> ! {
+    panic!("#DF in VMM\n{frame:#?}");
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    mut frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    if let Some(fixup) = cpu::gp_fixup(frame.instruction_pointer.as_u64()) {
+        unsafe {
+            frame
+                .as_mut()
+                .update(|frame| frame.instruction_pointer = VirtAddr::new(fixup));
+        }
+        return;
+    }
+    panic!("#GP(0x{error_code:x}) in VMM\n{frame:#?}");
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    panic!(
+        "#PF at {:?} ({error_code:?}) in VMM\n{frame:#?}",
+        Cr2::read()
+    );
+}
@@ -0,0 +1,42 @@
+use crate::{lapic, serial_println};
+use crossbeam::atomic::AtomicCell;
+
+/// Vector other VMM instances use to signal this one.
+pub const VMM_IPI_VECTOR: u8 = 0xe1;
+
+pub type InterruptHandler = fn(vector: u8);
+
+/// Vectors the VMM consumes. Every other vector belongs to the guest.
+static HANDLERS: AtomicCell<[Option<InterruptHandler>; 256]> = AtomicCell::new(default_handlers());
+
+const fn default_handlers() -> [Option<InterruptHandler>; 256] {
+    let mut handlers: [Option<InterruptHandler>; 256] = [None; 256];
+    handlers[VMM_IPI_VECTOR as usize] = Some(vmm_ipi);
+    handlers
+}
+
+fn handlers() -> &'static mut [Option<InterruptHandler>; 256] {
+    unsafe { HANDLERS.as_ptr().as_mut().unwrap() }
+}
+
+/// Takes `vector` away from the guest.
+pub fn register(vector: u8, handler: InterruptHandler) {
+    handlers()[vector as usize] = Some(handler);
+}
+
+/// Runs the VMM's handler for an acknowledged interrupt and signals EOI.
+/// Returns `false` if the vector belongs to the guest, which then owes the EOI itself.
+pub fn dispatch(vector: u8) -> bool {
+    match handlers()[vector as usize] {
+        Some(handler) => {
+            handler(vector);
+            lapic::eoi();
+            true
+        }
+        None => false,
+    }
+}
+
+fn vmm_ipi(vector: u8) {
+    serial_println!("VMM IPI (vector 0x{vector:x})");
+}
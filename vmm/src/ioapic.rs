@@ -1,26 +1,484 @@
-#[repr(C, packed)]
-struct IoApic {
-    reg: u32,
-    pad: [u32; 3],
-    data: u32,
-}
+use crate::acpi::{self, MadtEntry};
+use core::ptr;
+use crossbeam::atomic::AtomicCell;
+
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+pub const IOAPIC_MMIO_SIZE: u64 = 0x1000;
 
-const IOAPIC: *mut IoApic = 0xFEC0_0000 as *mut IoApic;
+const REG_ID: u32 = 0x00;
+const REG_VER: u32 = 0x01;
 const REG_TABLE: u32 = 0x10;
 const T_IRQ0: u32 = 32;
 
-pub unsafe fn write(reg: u32, data: u32) {
-    let ioapic = IoApic {
-        reg,
-        pad: [0; 3],
-        data,
+const LEGACY_IOAPIC_BASE: u64 = 0xFEC0_0000;
+const MAX_IOAPICS: usize = 8;
+const ISA_IRQS: usize = 16;
+const MAX_RESERVED_PINS: usize = 8;
+
+/// Trap guest accesses to the IOAPIC MMIO pages through EPT so that the guest cannot
+/// reprogram the pins the VMM reserved for itself.
+pub const TRAP_GUEST_ACCESS: bool = cfg!(feature = "ioapic-trap");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub base: u64,
+    pub gsi_base: u32,
+}
+
+impl IoApic {
+    pub unsafe fn read(&self, reg: u32) -> u32 {
+        ptr::write_volatile((self.base + IOREGSEL) as *mut u32, reg);
+        ptr::read_volatile((self.base + IOWIN) as *const u32)
+    }
+
+    pub unsafe fn write(&self, reg: u32, data: u32) {
+        ptr::write_volatile((self.base + IOREGSEL) as *mut u32, reg);
+        ptr::write_volatile((self.base + IOWIN) as *mut u32, data);
+    }
+
+    pub fn version(&self) -> u8 {
+        unsafe { self.read(REG_VER) as u8 }
+    }
+
+    pub fn redirection_entries(&self) -> u32 {
+        unsafe { ((self.read(REG_VER) >> 16) & 0xff) + 1 }
+    }
+
+    pub fn contains_gsi(&self, gsi: u32) -> bool {
+        self.gsi_base <= gsi && gsi < self.gsi_base + self.redirection_entries()
+    }
+
+    #[allow(unused)]
+    pub fn read_entry(&self, pin: u32) -> RedirectionEntry {
+        unsafe {
+            let low = self.read(REG_TABLE + 2 * pin) as u64;
+            let high = self.read(REG_TABLE + 2 * pin + 1) as u64;
+            RedirectionEntry::from_u64((high << 32) | low)
+        }
+    }
+
+    pub fn write_entry(&self, pin: u32, entry: RedirectionEntry) {
+        self.write_raw_entry(pin, entry.as_u64());
+    }
+
+    fn write_raw_entry(&self, pin: u32, entry: u64) {
+        unsafe {
+            // keep the pin masked while the two halves disagree
+            self.write(REG_TABLE + 2 * pin, (entry as u32) | RedirectionEntry::MASK);
+            self.write(REG_TABLE + 2 * pin + 1, (entry >> 32) as u32);
+            self.write(REG_TABLE + 2 * pin, entry as u32);
+        }
+    }
+
+    #[allow(unused)]
+    pub fn mask(&self, pin: u32) {
+        let mut entry = self.read_entry(pin);
+        entry.masked = true;
+        self.write_entry(pin, entry);
+    }
+
+    #[allow(unused)]
+    pub fn unmask(&self, pin: u32) {
+        let mut entry = self.read_entry(pin);
+        entry.masked = false;
+        self.write_entry(pin, entry);
+    }
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryMode {
+    Fixed,
+    LowestPriority,
+    Smi,
+    Nmi,
+    Init,
+    ExtInt,
+    Reserved(u8),
+}
+
+impl DeliveryMode {
+    #[allow(unused)]
+    fn from_bits(bits: u8) -> Self {
+        match bits {
+            0b000 => Self::Fixed,
+            0b001 => Self::LowestPriority,
+            0b010 => Self::Smi,
+            0b100 => Self::Nmi,
+            0b101 => Self::Init,
+            0b111 => Self::ExtInt,
+            bits => Self::Reserved(bits),
+        }
+    }
+
+    fn bits(&self) -> u8 {
+        match self {
+            Self::Fixed => 0b000,
+            Self::LowestPriority => 0b001,
+            Self::Smi => 0b010,
+            Self::Nmi => 0b100,
+            Self::Init => 0b101,
+            Self::ExtInt => 0b111,
+            Self::Reserved(bits) => *bits & 0b111,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RedirectionEntry {
+    pub vector: u8,
+    pub delivery_mode: DeliveryMode,
+    pub logical_destination: bool,
+    pub delivery_pending: bool,
+    pub active_low: bool,
+    pub remote_irr: bool,
+    pub level_triggered: bool,
+    pub masked: bool,
+    pub destination: u8,
+}
+
+impl RedirectionEntry {
+    const MASK: u32 = 1 << 16;
+
+    pub fn new(vector: u8, destination: u8) -> Self {
+        Self {
+            vector,
+            delivery_mode: DeliveryMode::Fixed,
+            logical_destination: false,
+            delivery_pending: false,
+            active_low: false,
+            remote_irr: false,
+            level_triggered: false,
+            masked: false,
+            destination,
+        }
+    }
+
+    #[allow(unused)]
+    pub fn from_u64(entry: u64) -> Self {
+        Self {
+            vector: entry as u8,
+            delivery_mode: DeliveryMode::from_bits(((entry >> 8) & 0b111) as u8),
+            logical_destination: (entry >> 11) & 1 == 1,
+            delivery_pending: (entry >> 12) & 1 == 1,
+            active_low: (entry >> 13) & 1 == 1,
+            remote_irr: (entry >> 14) & 1 == 1,
+            level_triggered: (entry >> 15) & 1 == 1,
+            masked: (entry >> 16) & 1 == 1,
+            destination: (entry >> 56) as u8,
+        }
+    }
+
+    /// Delivery status and remote IRR are read-only and therefore not encoded.
+    pub fn as_u64(&self) -> u64 {
+        self.vector as u64
+            | (self.delivery_mode.bits() as u64) << 8
+            | (self.logical_destination as u64) << 11
+            | (self.active_low as u64) << 13
+            | (self.level_triggered as u64) << 15
+            | (self.masked as u64) << 16
+            | (self.destination as u64) << 56
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IsaIrq {
+    gsi: u32,
+    active_low: bool,
+    level_triggered: bool,
+}
+
+static IOAPICS: AtomicCell<[Option<IoApic>; MAX_IOAPICS]> = AtomicCell::new([None; MAX_IOAPICS]);
+static ISA_IRQ_MAP: AtomicCell<[Option<IsaIrq>; ISA_IRQS]> = AtomicCell::new([None; ISA_IRQS]);
+
+/// Discovers the IOAPICs and the ISA interrupt source overrides from the MADT.
+/// Without a MADT, a single IOAPIC at the legacy address with identity mapped ISA IRQs is assumed.
+pub fn init() {
+    let mut ioapics = [None; MAX_IOAPICS];
+    let mut isa_irq_map = [None; ISA_IRQS];
+    let mut count = 0;
+
+    for entry in acpi::madt_entries() {
+        match entry {
+            MadtEntry::IoApic {
+                id,
+                address,
+                gsi_base,
+            } if count < MAX_IOAPICS => {
+                ioapics[count] = Some(IoApic {
+                    id,
+                    base: address as u64,
+                    gsi_base,
+                });
+                count += 1;
+            }
+            MadtEntry::InterruptSourceOverride {
+                bus: 0,
+                source,
+                gsi,
+                flags,
+            } if (source as usize) < ISA_IRQS => {
+                // polarity: 0b11 is active low, trigger mode: 0b11 is level, 0b00 means bus default
+                isa_irq_map[source as usize] = Some(IsaIrq {
+                    gsi,
+                    active_low: flags & 0b11 == 0b11,
+                    level_triggered: (flags >> 2) & 0b11 == 0b11,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    if count == 0 {
+        ioapics[0] = Some(IoApic {
+            id: 0,
+            base: LEGACY_IOAPIC_BASE,
+            gsi_base: 0,
+        });
+    }
+
+    IOAPICS.store(ioapics);
+    ISA_IRQ_MAP.store(isa_irq_map);
+}
+
+pub fn ioapics() -> impl Iterator<Item = IoApic> {
+    IOAPICS.load().into_iter().flatten()
+}
+
+/// Returns the IOAPIC serving `gsi` and the pin number on it.
+pub fn find_gsi(gsi: u32) -> Option<(IoApic, u32)> {
+    ioapics()
+        .find(|ioapic| ioapic.contains_gsi(gsi))
+        .map(|ioapic| (ioapic, gsi - ioapic.gsi_base))
+}
+
+fn isa_irq(irq: u32) -> IsaIrq {
+    ISA_IRQ_MAP
+        .load()
+        .get(irq as usize)
+        .copied()
+        .flatten()
+        .unwrap_or(IsaIrq {
+            gsi: irq,
+            active_low: false,
+            level_triggered: false,
+        })
+}
+
+/// Routes ISA `irq` to vector `T_IRQ0 + irq` on the CPU whose local APIC ID is `cpunum`
+/// and reserves the pin for the VMM. Returns the vector, or `None` if no IOAPIC serves the IRQ.
+pub fn enable(irq: u32, cpunum: u32) -> Option<u8> {
+    let isa_irq = isa_irq(irq);
+    let (ioapic, pin) = find_gsi(isa_irq.gsi)?;
+    let vector = (T_IRQ0 + irq) as u8;
+    let mut entry = RedirectionEntry::new(vector, cpunum as u8);
+    entry.active_low = isa_irq.active_low;
+    entry.level_triggered = isa_irq.level_triggered;
+    ioapic.write_entry(pin, entry);
+    reserve(isa_irq.gsi);
+    Some(vector)
+}
+
+pub fn print_info() {
+    for ioapic in ioapics() {
+        crate::serial_println!(
+            "IOAPIC[{}]: base 0x{:x}, version 0x{:x}, GSI {}-{}",
+            ioapic.id,
+            ioapic.base,
+            ioapic.version(),
+            ioapic.gsi_base,
+            ioapic.gsi_base + ioapic.redirection_entries() - 1,
+        );
+    }
+}
+
+/// What the guest sees of the IOAPICs while their MMIO pages are trapped.
+#[derive(Debug, Clone, Copy)]
+struct GuestView {
+    ioregsel: [u32; MAX_IOAPICS],
+    reserved: [Option<ReservedPin>; MAX_RESERVED_PINS],
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ReservedPin {
+    gsi: u32,
+    // the redirection entry the guest believes it has programmed
+    shadow: u64,
+}
+
+static GUEST_VIEW: AtomicCell<GuestView> = AtomicCell::new(GuestView {
+    ioregsel: [0; MAX_IOAPICS],
+    reserved: [None; MAX_RESERVED_PINS],
+});
+
+/// Marks `gsi` as owned by the VMM. Guest writes to its redirection entry are only recorded.
+pub fn reserve(gsi: u32) {
+    let mut view = GUEST_VIEW.load();
+    if view.reserved.iter().flatten().any(|pin| pin.gsi == gsi) {
+        return;
+    }
+    if let Some(slot) = view.reserved.iter_mut().find(|pin| pin.is_none()) {
+        *slot = Some(ReservedPin {
+            gsi,
+            shadow: 1 << 16,
+        });
+        GUEST_VIEW.store(view);
+    }
+}
+
+/// Programs the reserved pins with the entries the guest wrote, for the guest to own the
+/// IOAPICs again.
+pub fn restore_guest_entries() {
+    for pin in GUEST_VIEW.load().reserved.iter().flatten() {
+        if let Some((ioapic, index)) = find_gsi(pin.gsi) {
+            ioapic.write_raw_entry(index, pin.shadow);
+        }
+    }
+}
+
+pub fn is_trapped(guest_phys: u64) -> bool {
+    TRAP_GUEST_ACCESS && trapped_ioapic(guest_phys).is_some()
+}
+
+fn trapped_ioapic(guest_phys: u64) -> Option<(usize, IoApic)> {
+    ioapics().enumerate().find(|(_, ioapic)| {
+        ioapic.base <= guest_phys && guest_phys < ioapic.base + IOAPIC_MMIO_SIZE
+    })
+}
+
+/// Emulates a guest read of a trapped IOAPIC register.
+pub fn guest_read(guest_phys: u64) -> u32 {
+    let (index, ioapic) = match trapped_ioapic(guest_phys) {
+        Some(ioapic) => ioapic,
+        None => return !0,
     };
-    core::ptr::write_volatile::<IoApic>(IOAPIC, ioapic);
+    let view = GUEST_VIEW.load();
+    let offset = guest_phys - ioapic.base;
+    let reg = view.ioregsel[index];
+
+    match offset {
+        IOREGSEL => reg,
+        IOWIN => match reserved_pin(&view, &ioapic, reg) {
+            Some((pin, high)) => {
+                let shadow = pin.shadow;
+                if high {
+                    (shadow >> 32) as u32
+                } else {
+                    shadow as u32
+                }
+            }
+            None => unsafe { ioapic.read(reg) },
+        },
+        _ => unsafe { ptr::read_volatile((ioapic.base + offset) as *const u32) },
+    }
+}
+
+/// Emulates a guest write to a trapped IOAPIC register.
+pub fn guest_write(guest_phys: u64, value: u32) {
+    let (index, ioapic) = match trapped_ioapic(guest_phys) {
+        Some(ioapic) => ioapic,
+        None => return,
+    };
+    let mut view = GUEST_VIEW.load();
+    let offset = guest_phys - ioapic.base;
+    let reg = view.ioregsel[index];
+
+    match offset {
+        IOREGSEL => {
+            view.ioregsel[index] = value;
+            GUEST_VIEW.store(view);
+        }
+        IOWIN => {
+            if reg == REG_ID {
+                // the APIC ID is firmware's business
+                return;
+            }
+            let slot = redirection_register(&ioapic, reg).and_then(|(gsi, high)| {
+                view.reserved
+                    .iter_mut()
+                    .flatten()
+                    .find(|pin| pin.gsi == gsi)
+                    .map(|pin| (pin, high))
+            });
+            match slot {
+                Some((pin, high)) => {
+                    if high {
+                        pin.shadow = (pin.shadow & 0xffff_ffff) | (value as u64) << 32;
+                    } else {
+                        pin.shadow = (pin.shadow & !0xffff_ffff) | value as u64;
+                    }
+                    GUEST_VIEW.store(view);
+                }
+                None => unsafe { ioapic.write(reg, value) },
+            }
+        }
+        _ => unsafe { ptr::write_volatile((ioapic.base + offset) as *mut u32, value) },
+    }
+}
+
+/// Returns the GSI whose redirection entry `reg` selects and whether it is the high half, or
+/// `None` if `reg` is not a redirection register of `ioapic`.
+fn redirection_register(ioapic: &IoApic, reg: u32) -> Option<(u32, bool)> {
+    let table_index = reg.checked_sub(REG_TABLE)?;
+    if table_index / 2 >= ioapic.redirection_entries() {
+        return None;
+    }
+    Some((ioapic.gsi_base + table_index / 2, table_index % 2 == 1))
+}
+
+fn reserved_pin(view: &GuestView, ioapic: &IoApic, reg: u32) -> Option<(ReservedPin, bool)> {
+    let (gsi, high) = redirection_register(ioapic, reg)?;
+    view.reserved
+        .iter()
+        .flatten()
+        .find(|pin| pin.gsi == gsi)
+        .map(|pin| (*pin, high))
 }
 
-pub fn enable(irq: u32, cpunum: u32) {
-    unsafe {
-        write(REG_TABLE + 2 * irq, T_IRQ0 + irq);
-        write(REG_TABLE + 2 * irq + 1, cpunum << 24);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirection_entry_round_trip() {
+        let entry = RedirectionEntry {
+            delivery_mode: DeliveryMode::LowestPriority,
+            logical_destination: true,
+            active_low: true,
+            level_triggered: true,
+            masked: true,
+            ..RedirectionEntry::new(0x31, 0x0f)
+        };
+        assert_eq!(entry.as_u64(), 0x0f00_0000_0001_a931);
+        assert_eq!(RedirectionEntry::from_u64(entry.as_u64()), entry);
+
+        let entry = RedirectionEntry::new(0x20, 0);
+        assert_eq!(RedirectionEntry::from_u64(entry.as_u64()), entry);
+    }
+
+    #[test]
+    fn read_only_bits_are_decoded_but_not_encoded() {
+        // delivery pending and remote IRR set
+        let entry = RedirectionEntry::from_u64(0x5030);
+        assert!(entry.delivery_pending);
+        assert!(entry.remote_irr);
+        assert_eq!(entry.as_u64(), 0x30);
+    }
+
+    #[test]
+    fn delivery_modes() {
+        for bits in 0..8 {
+            let mode = DeliveryMode::from_bits(bits);
+            assert_eq!(mode.bits(), bits);
+            assert_eq!(
+                matches!(mode, DeliveryMode::Reserved(_)),
+                matches!(bits, 3 | 6)
+            );
+        }
+        let entry = RedirectionEntry::from_u64(0x1_0500);
+        assert_eq!(entry.delivery_mode, DeliveryMode::Init);
+        assert!(entry.masked);
     }
 }
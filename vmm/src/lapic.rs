@@ -0,0 +1,110 @@
+use common::constants;
+use core::ptr;
+use x86_64::registers::model_specific::Msr;
+
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Register offsets in the xAPIC MMIO page. The x2APIC MSR is `MSR_X2APIC_BASE + offset / 16`.
+pub const REG_ID: u32 = 0x20;
+pub const REG_TPR: u32 = 0x80;
+pub const REG_EOI: u32 = 0xb0;
+pub const REG_LVT_CMCI: u32 = 0x2f0;
+pub const REG_ICR_LOW: u32 = 0x300;
+pub const REG_ICR_HIGH: u32 = 0x310;
+pub const REG_LVT_TIMER: u32 = 0x320;
+pub const REG_LVT_THERMAL: u32 = 0x330;
+pub const REG_LVT_PERF: u32 = 0x340;
+pub const REG_LVT_LINT0: u32 = 0x350;
+pub const REG_LVT_LINT1: u32 = 0x360;
+pub const REG_LVT_ERROR: u32 = 0x370;
+pub const REG_TIMER_INITIAL_COUNT: u32 = 0x380;
+pub const REG_TIMER_DIVIDE: u32 = 0x3e0;
+
+const ICR_DELIVERY_MODE_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+/// The local APIC is shared with the guest. Whether it is in xAPIC or x2APIC mode is read
+/// from IA32_APIC_BASE on every access, since the guest may switch it at any time.
+pub fn apic_base() -> u64 {
+    unsafe { Msr::new(constants::MSR_IA32_APIC_BASE).read() }
+}
+
+pub fn is_x2apic() -> bool {
+    apic_base() & APIC_BASE_X2APIC_ENABLE != 0
+}
+
+/// Physical address of the xAPIC MMIO page.
+pub fn mmio_base() -> u64 {
+    apic_base() & APIC_BASE_ADDRESS_MASK
+}
+
+fn mmio(reg: u32) -> *mut u32 {
+    (mmio_base() + reg as u64) as *mut u32
+}
+
+pub fn x2apic_msr_index(reg: u32) -> u32 {
+    constants::MSR_X2APIC_BASE + (reg >> 4)
+}
+
+fn x2apic_msr(reg: u32) -> Msr {
+    Msr::new(x2apic_msr_index(reg))
+}
+
+pub fn read(reg: u32) -> u32 {
+    if is_x2apic() {
+        unsafe { x2apic_msr(reg).read() as u32 }
+    } else {
+        unsafe { ptr::read_volatile(mmio(reg)) }
+    }
+}
+
+pub fn write(reg: u32, value: u32) {
+    if is_x2apic() {
+        unsafe { x2apic_msr(reg).write(value as u64) };
+    } else {
+        unsafe { ptr::write_volatile(mmio(reg), value) };
+    }
+}
+
+#[allow(unused)]
+pub fn id() -> u32 {
+    if is_x2apic() {
+        read(REG_ID)
+    } else {
+        read(REG_ID) >> 24
+    }
+}
+
+pub fn eoi() {
+    write(REG_EOI, 0);
+}
+
+/// Sends a fixed interrupt with `vector` to the CPU whose APIC ID is `destination`.
+#[allow(unused)]
+pub fn send_ipi(destination: u32, vector: u8) {
+    write_icr(destination, ICR_LEVEL_ASSERT | vector as u32);
+}
+
+/// Sends INIT to every other CPU.
+pub fn send_init_all_excluding_self() {
+    write_icr(
+        0,
+        ICR_ALL_EXCLUDING_SELF | ICR_LEVEL_ASSERT | ICR_DELIVERY_MODE_INIT,
+    );
+}
+
+fn write_icr(destination: u32, low: u32) {
+    if is_x2apic() {
+        // The x2APIC ICR is a single 64-bit MSR.
+        unsafe { x2apic_msr(REG_ICR_LOW).write((destination as u64) << 32 | low as u64) };
+    } else {
+        write(REG_ICR_HIGH, destination << 24);
+        write(REG_ICR_LOW, low);
+        while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
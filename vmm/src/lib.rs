@@ -1,19 +1,27 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(default_alloc_error_handler)]
+#![feature(abi_x86_interrupt)]
 
+mod acpi;
 mod allocator;
 mod arch;
 mod cpu;
 mod emu;
+mod idt;
+mod interrupt;
 mod ioapic;
+mod lapic;
+mod mce;
+mod pic;
+mod power;
 mod serial;
 
 extern crate alloc;
 
-use crate::arch::intel::IntelCpu;
+use crate::arch::intel::BSP;
 use alloc::fmt::format;
 use common::{BootArgs, VMM_HEAP_HEAD_VADDR, VMM_HEAP_SIZE};
-use core::{arch::global_asm, panic::PanicInfo, ptr};
+use core::{arch::global_asm, ptr};
 use cpu::Cpu;
 use crossbeam::atomic::AtomicCell;
 
@@ -30,22 +38,49 @@ pub unsafe extern "sysv64" fn vmm_main(boot_args: *const BootArgs) {
     BOOT_ARGS.store(*boot_args);
     UEFI_WRITE_CHAR.store(BOOT_ARGS.load().uefi_write_char);
     allocator::init(VMM_HEAP_HEAD_VADDR, VMM_HEAP_SIZE as usize);
+
+    // The guest still owns the 8259s, so only keep them quiet while the VMM sets itself up.
+    let pic_state = pic::save();
+    pic::mask_all();
+    ioapic::init();
     serial::init(serial::COM);
 
+    ioapic::print_info();
     serial_println!("VMM init complete");
 
-    let mut intel = IntelCpu::new();
+    // The VM-exit handlers, devirtualization and shutdown find the running VMCS through BSP.
+    let intel = BSP.as_ptr().as_mut().unwrap();
 
     if let Err(e) = intel.enable_virtualization() {
-        panic!("failed to enable virtualization: {e:?}");
+        panic!("failed to enable virtualization: {e}");
+    }
+    idt::init();
+    if let Err(e) = intel.init_as_bsp() {
+        panic!("failed to set up the VMCS: {e}");
+    }
+
+    // IRQs raised while the VMM had the PICs masked stay pending for the guest.
+    let (irr, isr) = (pic::read_irr(), pic::read_isr());
+    serial_println!(
+        "PIC masks {:02x}/{:02x}, IRR {:02x}/{:02x}, ISR {:02x}/{:02x}",
+        pic_state.master_mask,
+        pic_state.slave_mask,
+        irr.0,
+        irr.1,
+        isr.0,
+        isr.1
+    );
+    pic::restore(pic_state);
+    if let Err(e) = intel.run_vm() {
+        panic!("failed to launch the VM: {e}");
     }
-    intel.init_as_bsp();
-    intel.run_vm();
 }
 
+#[cfg(not(test))]
 #[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
+fn panic(info: &core::panic::PanicInfo) -> ! {
     serial_println!("{info:?}");
+    arch::intel::vmcs::dump();
     loop {
         x86_64::instructions::hlt();
     }
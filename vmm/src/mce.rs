@@ -0,0 +1,80 @@
+use crate::serial_println;
+use common::constants;
+use x86_64::registers::model_specific::Msr;
+
+const MCG_CAP_COUNT: u64 = 0xff;
+/// Execution can restart at the RIP pushed on the stack.
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+const MCG_STATUS_EIPV: u64 = 1 << 1;
+const MCG_STATUS_MCIP: u64 = 1 << 2;
+
+const MCI_STATUS_VAL: u64 = 1 << 63;
+const MCI_STATUS_OVER: u64 = 1 << 62;
+const MCI_STATUS_UC: u64 = 1 << 61;
+const MCI_STATUS_MISCV: u64 = 1 << 59;
+const MCI_STATUS_ADDRV: u64 = 1 << 58;
+/// The processor context may be corrupt.
+const MCI_STATUS_PCC: u64 = 1 << 57;
+
+fn read(msr: u32) -> u64 {
+    unsafe { Msr::new(msr).read() }
+}
+
+fn bank_msr(bank: u32, offset: u32) -> u32 {
+    constants::MSR_IA32_MC0_CTL + bank * 4 + offset
+}
+
+/// Number of error-reporting banks.
+fn bank_count() -> u32 {
+    (read(constants::MSR_IA32_MCG_CAP) & MCG_CAP_COUNT) as u32
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MachineCheck {
+    pub mcg_status: u64,
+    /// Whether some bank reports a corrupt processor context.
+    pub context_corrupt: bool,
+}
+
+impl MachineCheck {
+    /// The interrupted context can be resumed, so the guest can deal with the error itself.
+    pub fn is_recoverable(&self) -> bool {
+        self.mcg_status & MCG_STATUS_RIPV != 0 && !self.context_corrupt
+    }
+}
+
+/// Logs every bank with a valid error and returns what the guest would need to know. The
+/// banks are left alone so that the guest's own handler can read and clear them.
+pub fn log_banks() -> MachineCheck {
+    let mcg_status = read(constants::MSR_IA32_MCG_STATUS);
+    serial_println!(
+        "machine check: MCG_STATUS 0x{mcg_status:x} (RIPV {}, EIPV {}, MCIP {})",
+        mcg_status & MCG_STATUS_RIPV != 0,
+        mcg_status & MCG_STATUS_EIPV != 0,
+        mcg_status & MCG_STATUS_MCIP != 0
+    );
+    let mut context_corrupt = false;
+    for bank in 0..bank_count() {
+        let status = read(bank_msr(bank, 1));
+        if status & MCI_STATUS_VAL == 0 {
+            continue;
+        }
+        context_corrupt |= status & MCI_STATUS_PCC != 0;
+        serial_println!(
+            "  bank {bank}: STATUS 0x{status:016x} (UC {}, PCC {}, overflow {})",
+            status & MCI_STATUS_UC != 0,
+            status & MCI_STATUS_PCC != 0,
+            status & MCI_STATUS_OVER != 0
+        );
+        if status & MCI_STATUS_ADDRV != 0 {
+            serial_println!("          ADDR 0x{:016x}", read(bank_msr(bank, 2)));
+        }
+        if status & MCI_STATUS_MISCV != 0 {
+            serial_println!("          MISC 0x{:016x}", read(bank_msr(bank, 3)));
+        }
+    }
+    MachineCheck {
+        mcg_status,
+        context_corrupt,
+    }
+}
@@ -0,0 +1,59 @@
+use x86_64::instructions::port::{Port, PortWriteOnly};
+
+const PIC_MASTER_CMD: u16 = 0x20;
+const PIC_MASTER_DATA: u16 = 0x21;
+const PIC_SLAVE_CMD: u16 = 0xa0;
+const PIC_SLAVE_DATA: u16 = 0xa1;
+
+const OCW3_READ_IRR: u8 = 0x0a;
+const OCW3_READ_ISR: u8 = 0x0b;
+
+/// State of the legacy 8259 PICs that the VMM touches during its own initialization.
+///
+/// ICW1-4 are write-only and the VMM never reprograms them, so the interrupt masks (OCW1)
+/// are all that has to be given back to the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PicState {
+    pub master_mask: u8,
+    pub slave_mask: u8,
+}
+
+pub unsafe fn save() -> PicState {
+    PicState {
+        master_mask: Port::<u8>::new(PIC_MASTER_DATA).read(),
+        slave_mask: Port::<u8>::new(PIC_SLAVE_DATA).read(),
+    }
+}
+
+pub unsafe fn restore(state: PicState) {
+    PortWriteOnly::<u8>::new(PIC_SLAVE_DATA).write(state.slave_mask);
+    PortWriteOnly::<u8>::new(PIC_MASTER_DATA).write(state.master_mask);
+}
+
+pub unsafe fn mask_all() {
+    PortWriteOnly::<u8>::new(PIC_SLAVE_DATA).write(0xff);
+    PortWriteOnly::<u8>::new(PIC_MASTER_DATA).write(0xff);
+}
+
+/// Returns the interrupt request registers as (master, slave).
+pub unsafe fn read_irr() -> (u8, u8) {
+    read_ocw3(OCW3_READ_IRR)
+}
+
+/// Returns the in-service registers as (master, slave).
+pub unsafe fn read_isr() -> (u8, u8) {
+    read_ocw3(OCW3_READ_ISR)
+}
+
+// OCW3 selects which register a read of the command port returns; the PIC keeps
+// that selection, so put it back to IRR (the power-on default) afterwards.
+unsafe fn read_ocw3(ocw3: u8) -> (u8, u8) {
+    let mut master = Port::<u8>::new(PIC_MASTER_CMD);
+    let mut slave = Port::<u8>::new(PIC_SLAVE_CMD);
+    master.write(ocw3);
+    slave.write(ocw3);
+    let value = (master.read(), slave.read());
+    master.write(OCW3_READ_IRR);
+    slave.write(OCW3_READ_IRR);
+    value
+}
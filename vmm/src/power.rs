@@ -0,0 +1,126 @@
+use crate::{
+    acpi::{self, Fadt, ADDRESS_SPACE_IO},
+    arch::intel::BSP,
+    cpu::Cpu,
+    lapic, serial_println,
+};
+use alloc::vec::Vec;
+use crossbeam::atomic::AtomicCell;
+use x86_64::instructions::port::PortWriteOnly;
+
+const RESET_CONTROL_PORT: u16 = 0xcf9;
+/// Reset CPU (RST_CPU) and system reset (SYS_RST) in the reset control register.
+const RESET_CONTROL_RST_CPU: u8 = 1 << 2;
+const RESET_CONTROL_HARD_RESET: u8 = 0x06;
+const KBC_COMMAND_PORT: u16 = 0x64;
+const KBC_PULSE_RESET: u8 = 0xfe;
+
+const PM1_CNT_SLP_TYP_SHIFT: u32 = 10;
+const PM1_CNT_SLP_TYP_MASK: u32 = 0b111;
+const PM1_CNT_SLP_EN: u32 = 1 << 13;
+
+/// The FADT, parsed once by `trapped_ports`.
+static FADT: AtomicCell<Option<Fadt>> = AtomicCell::new(None);
+
+/// Why the guest takes the platform down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownReason {
+    TripleFault,
+    ResetControl {
+        value: u8,
+    },
+    KeyboardController,
+    AcpiReset,
+    /// A sleep state entered through PM1x_CNT. S5 is power-off.
+    AcpiSleep {
+        sleep_type: u8,
+    },
+}
+
+/// I/O ports whose writes can reset or power off the platform.
+pub fn trapped_ports() -> Vec<u16> {
+    FADT.store(acpi::fadt());
+    let mut ports = Vec::from([RESET_CONTROL_PORT, KBC_COMMAND_PORT]);
+    if let Some(fadt) = FADT.load() {
+        ports.extend(fadt.pm1a_control);
+        ports.extend(fadt.pm1b_control);
+        match fadt.reset {
+            Some((reg, _)) if reg.space_id == ADDRESS_SPACE_IO => ports.push(reg.address as u16),
+            Some((reg, _)) => {
+                serial_println!("ACPI reset register {reg:x?} is not an I/O port, not trapped");
+            }
+            None => {}
+        }
+    }
+    ports
+}
+
+fn is_acpi_reset(port: u16, value: u32) -> bool {
+    matches!(
+        FADT.load().and_then(|fadt| fadt.reset),
+        Some((reg, reset_value)) if reg.space_id == ADDRESS_SPACE_IO
+            && reg.address == port as u64
+            && value as u8 == reset_value
+    )
+}
+
+fn is_pm1_control(port: u16) -> bool {
+    matches!(
+        FADT.load(),
+        Some(fadt) if fadt.pm1a_control == Some(port) || fadt.pm1b_control == Some(port)
+    )
+}
+
+/// Checks a guest OUT to one of the trapped ports. If it resets or powers off the platform,
+/// the VMM steps aside and replays the write on bare metal. Otherwise the caller passes it
+/// through.
+pub fn guest_out(port: u16, size: usize, value: u32) -> bool {
+    let reason = match port {
+        _ if is_acpi_reset(port, value) => ShutdownReason::AcpiReset,
+        RESET_CONTROL_PORT if size == 1 && value as u8 & RESET_CONTROL_RST_CPU != 0 => {
+            ShutdownReason::ResetControl { value: value as u8 }
+        }
+        KBC_COMMAND_PORT if value as u8 == KBC_PULSE_RESET => ShutdownReason::KeyboardController,
+        _ if is_pm1_control(port) && value & PM1_CNT_SLP_EN != 0 => ShutdownReason::AcpiSleep {
+            sleep_type: ((value >> PM1_CNT_SLP_TYP_SHIFT) & PM1_CNT_SLP_TYP_MASK) as u8,
+        },
+        _ => return false,
+    };
+    shutdown(reason, || unsafe { replay_out(port, size, value) })
+}
+
+unsafe fn replay_out(port: u16, size: usize, value: u32) {
+    match size {
+        1 => PortWriteOnly::<u8>::new(port).write(value as u8),
+        2 => PortWriteOnly::<u16>::new(port).write(value as u16),
+        _ => PortWriteOnly::<u32>::new(port).write(value),
+    }
+}
+
+/// A triple fault shuts the processor down, which the chipset turns into a reset.
+pub fn triple_fault() -> ! {
+    shutdown(ShutdownReason::TripleFault, || unsafe {
+        PortWriteOnly::<u8>::new(RESET_CONTROL_PORT).write(RESET_CONTROL_HARD_RESET);
+    })
+}
+
+/// Leaves VMX operation and runs `action`, which resets or powers off the platform.
+/// Sleep states other than S5 wake up through the firmware, without the VMM.
+fn shutdown(reason: ShutdownReason, action: impl FnOnce()) -> ! {
+    serial_println!("guest shutdown: {reason:?}");
+    // The VMM only runs on the BSP. The other processors are outside VMX operation, where
+    // INIT is not blocked, so it parks them before the platform goes down.
+    lapic::send_init_all_excluding_self();
+    let bsp = unsafe { BSP.as_ptr().as_mut().unwrap() };
+    if let Err(e) = bsp.disable_virtualization() {
+        serial_println!("failed to leave VMX operation: {e}");
+    }
+    action();
+    // The keyboard controller reset is the last resort if a reset did not take.
+    if !matches!(reason, ShutdownReason::AcpiSleep { .. }) {
+        unsafe { PortWriteOnly::<u8>::new(KBC_COMMAND_PORT).write(KBC_PULSE_RESET) };
+    }
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
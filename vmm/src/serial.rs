@@ -1,4 +1,7 @@
-use crate::ioapic;
+use crate::{interrupt, ioapic};
+use alloc::collections::VecDeque;
+use crossbeam::atomic::AtomicCell;
+use lazy_static::lazy_static;
 use x86_64::instructions::{
     interrupts::without_interrupts,
     port::{PortReadOnly, PortWriteOnly},
@@ -22,9 +25,6 @@ const IRQ3: u32 = 3;
 
 pub unsafe fn init(com: u16) {
     without_interrupts(|| {
-        // 8259 PIC Disable
-        PortWriteOnly::<u8>::new(0xa1).write(0xff);
-        PortWriteOnly::<u8>::new(0x21).write(0xff);
         // 16550A UART Enable
         PortWriteOnly::<u8>::new(com + 1).write(0); // disable all interrupts
         PortWriteOnly::<u8>::new(com + 3).write(0x80); // DLAB set 1
@@ -41,10 +41,35 @@ pub unsafe fn init(com: u16) {
         PortReadOnly::<u16>::new(com + 2).read();
         PortReadOnly::<u16>::new(com).read();
 
-        ioapic::enable(IRQ, 0);
+        if let Some(vector) = ioapic::enable(IRQ, 0) {
+            interrupt::register(vector, handle_interrupt);
+        }
     });
 }
 
+lazy_static! {
+    static ref RX_BUFFER: AtomicCell<VecDeque<u8>> = AtomicCell::new(VecDeque::new());
+}
+
+fn rx_buffer() -> &'static mut VecDeque<u8> {
+    unsafe { RX_BUFFER.as_ptr().as_mut().unwrap() }
+}
+
+/// Drains the receive FIFO of `COM` into the RX buffer.
+fn handle_interrupt(_vector: u8) {
+    unsafe {
+        while PortReadOnly::<u8>::new(COM + 5).read() & 1 != 0 {
+            rx_buffer().push_back(PortReadOnly::<u8>::new(COM).read());
+        }
+    }
+}
+
+/// Returns the oldest byte received on `COM`.
+#[allow(unused)]
+pub fn read() -> Option<u8> {
+    rx_buffer().pop_front()
+}
+
 pub unsafe fn write(com: u16, c: u8) {
     while (PortReadOnly::<u16>::new(com + 5).read() & 0b10_0000) >> 5 != 1 {
         x86_64::instructions::nop();